@@ -0,0 +1,33 @@
+#!/bin/bash
+# Compare the --experimental-simd tape layout against the default
+# layout, by benchmarking the sample programs with both.
+#
+# We turn off speculative execution, so the executables run the whole
+# program rather than printing output computed at compile time.
+
+BFC=${BFC:-./target/release/bfc}
+RUNS=${RUNS:-10}
+
+function bench {
+    local test_program=$1
+    shift
+
+    local input=sample_programs/${test_program}.in
+    local input_args=()
+    if [ -f $input ]; then
+        input_args=(--input $input)
+    fi
+
+    $BFC bench sample_programs/$test_program --runs $RUNS --no-cache \
+         --max-speculative-steps=0 --quiet "${input_args[@]}" "$@"
+}
+
+for test_program in mandelbrot.bf factor.bf life.bf bottles.bf; do
+    for cell_size in 8 32; do
+        echo "$test_program, $cell_size-bit cells:"
+        echo -n "  default: "
+        bench $test_program --cell-size=$cell_size
+        echo -n "  simd:    "
+        bench $test_program --cell-size=$cell_size --experimental-simd
+    done
+done
@@ -5,19 +5,182 @@
 //! It also provides functions for generating ASTs from source code,
 //! producing good error messages on malformed inputs.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::io;
 use std::num::Wrapping;
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
+#[cfg(test)]
+use quickcheck::{quickcheck, TestResult};
 
 use self::AstNode::*;
+use crate::bounds::{highest_cell_index, DEFAULT_TAPE_SIZE};
 
 /// A cell is the fundamental BF datatype that we work with. BF
-/// requires this to be at least one byte, we provide a cell of
-/// exactly one byte.
-pub type Cell = Wrapping<i8>;
+/// requires this to be at least one byte. We store cells as 32-bit
+/// values, and `CellWidth` says how many of those bits a cell has.
+pub type Cell = Wrapping<i32>;
+
+/// The number of bits in each cell. Cells wrap at this width, so
+/// `-` on a zero cell gives 255 with 8-bit cells, but 65535 with
+/// 16-bit cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+impl CellWidth {
+    /// The width with this many bits, if we support it.
+    pub fn from_bits(bits: u32) -> Option<CellWidth> {
+        match bits {
+            8 => Some(CellWidth::Bits8),
+            16 => Some(CellWidth::Bits16),
+            32 => Some(CellWidth::Bits32),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::Bits8 => 8,
+            CellWidth::Bits16 => 16,
+            CellWidth::Bits32 => 32,
+        }
+    }
+
+    /// Wrap `value` to a cell of this width. We sign-extend the
+    /// result, so every cell value has exactly one representation,
+    /// e.g. 255 and -1 are both -1 with 8-bit cells.
+    pub fn wrap(self, value: Cell) -> Cell {
+        match self {
+            CellWidth::Bits8 => Wrapping(i32::from(value.0 as i8)),
+            CellWidth::Bits16 => Wrapping(i32::from(value.0 as i16)),
+            CellWidth::Bits32 => value,
+        }
+    }
+
+    /// The largest value a cell of this width can hold, e.g. 255
+    /// with 8-bit cells.
+    pub fn max_value(self) -> i64 {
+        (1 << self.bits()) - 1
+    }
+
+    /// The value of `cell` as an unsigned number, e.g. 255 rather
+    /// than -1 with 8-bit cells.
+    pub fn unsigned(self, cell: Cell) -> i64 {
+        i64::from(cell.0) & self.max_value()
+    }
+}
+
+/// What happens when an increment takes a cell past 0 or its largest
+/// value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Wrap around, so `-` on a zero cell gives 255 with 8-bit cells.
+    #[default]
+    Wrap,
+    /// End the program with an error.
+    Abort,
+    /// Clamp the cell to 0 or its largest value.
+    Saturate,
+}
+
+impl Overflow {
+    /// The overflow behaviour with this name, as passed to
+    /// `--overflow`.
+    pub fn from_name(name: &str) -> Option<Overflow> {
+        match name {
+            "wrap" => Some(Overflow::Wrap),
+            "abort" => Some(Overflow::Abort),
+            "saturate" => Some(Overflow::Saturate),
+            _ => None,
+        }
+    }
+
+    /// Add `amount` to a cell of `width` holding `value`. Returns
+    /// None if the program aborts.
+    pub fn add(self, width: CellWidth, value: Cell, amount: i64) -> Option<Cell> {
+        if self == Overflow::Wrap {
+            return Some(width.wrap(value + Wrapping(amount as i32)));
+        }
+
+        let sum = width.unsigned(value).saturating_add(amount);
+        let max = width.max_value();
+        if (0..=max).contains(&sum) {
+            Some(width.wrap(Wrapping(sum as i32)))
+        } else if self == Overflow::Abort {
+            None
+        } else {
+            Some(width.wrap(Wrapping(sum.max(0).min(max) as i32)))
+        }
+    }
+}
+
+/// What a read stores in the cell when there's no more input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eof {
+    /// Set the cell to 0.
+    Zero,
+    /// Set the cell to -1, i.e. 255 with 8-bit cells, like the value
+    /// `getchar` returns.
+    #[default]
+    MinusOne,
+    /// Leave the cell as it was.
+    Unchanged,
+}
+
+impl Eof {
+    /// The EOF behaviour with this name, as passed to `--eof`.
+    pub fn from_name(name: &str) -> Option<Eof> {
+        match name {
+            "zero" => Some(Eof::Zero),
+            "minus-one" => Some(Eof::MinusOne),
+            "unchanged" => Some(Eof::Unchanged),
+            _ => None,
+        }
+    }
+
+    /// The value of a cell holding `cell` after a read at EOF.
+    pub fn value(self, cell: Cell) -> Cell {
+        match self {
+            Eof::Zero => Wrapping(0),
+            Eof::MinusOne => Wrapping(-1),
+            Eof::Unchanged => cell,
+        }
+    }
+}
+
+/// What happens when the program moves the pointer off either end
+/// of the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Bounds {
+    /// Nothing checks the pointer, so the program accesses memory
+    /// outside the tape and will probably crash.
+    #[default]
+    Unchecked,
+    /// The tape is circular: moving left from cell #0 reaches the
+    /// last cell.
+    Wrap,
+    /// End the program with an error.
+    Abort,
+}
+
+impl Bounds {
+    /// The bounds behaviour with this name, as passed to `--bounds`.
+    pub fn from_name(name: &str) -> Option<Bounds> {
+        match name {
+            "unchecked" => Some(Bounds::Unchecked),
+            "wrap" => Some(Bounds::Wrap),
+            "abort" => Some(Bounds::Abort),
+            _ => None,
+        }
+    }
+}
 
 /// An inclusive range used for tracking positions in source code.
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -61,7 +224,11 @@ impl Combine<Option<Position>> for Option<Position> {
                     Some(pos2)
                 }
             }
-            _ => None,
+            // Instructions we generate may have no position. Keep
+            // the position we know, so diagnostics can still point
+            // at the source.
+            (Some(pos), None) | (None, Some(pos)) => Some(pos),
+            (None, None) => None,
         }
     }
 }
@@ -90,13 +257,61 @@ pub enum AstNode {
     },
     // These instruction have no direct equivalent in BF, but we
     // generate them during optimisation.
+    /// A loop that runs at most once, because its body always
+    /// zeroes the current cell.
+    If {
+        body: Vec<AstNode>,
+        position: Option<Position>,
+    },
     Set {
         amount: Cell,
         offset: isize,
         position: Option<Position>,
     },
+    /// Add the current cell, multiplied by each factor, to the cell
+    /// at each offset, then zero the current cell. The changes are
+    /// ordered by offset, so --dump-ir and the code we generate are
+    /// the same on every run.
     MultiplyMove {
-        changes: HashMap<isize, Cell>,
+        changes: BTreeMap<isize, Cell>,
+        position: Option<Position>,
+    },
+    /// Write values that we know at compile time.
+    WriteConst {
+        values: Vec<i8>,
+        position: Option<Position>,
+    },
+    /// Set `length` adjacent cells, starting at `offset`, to `amount`.
+    SetRange {
+        amount: Cell,
+        offset: isize,
+        length: usize,
+        position: Option<Position>,
+    },
+    /// Move the pointer by `step` until the current cell is zero,
+    /// like `[>]` or `[<<]`.
+    Scan {
+        step: isize,
+        position: Option<Position>,
+    },
+    /// Print the cell pointer and the first cells to stderr, for `#`
+    /// with `--breakpoints`. This observes the whole tape, so the
+    /// optimiser can't move instructions past it.
+    Breakpoint {
+        position: Option<Position>,
+    },
+    /// End the program, for `@` with `--extensions`.
+    Halt {
+        position: Option<Position>,
+    },
+    /// Copy the current cell to the storage cell, for `$` with
+    /// `--extensions`.
+    Store {
+        position: Option<Position>,
+    },
+    /// Copy the storage cell to the current cell, for `!` with
+    /// `--extensions`.
+    Load {
         position: Option<Position>,
     },
 }
@@ -119,6 +334,17 @@ fn fmt_with_indent(instr: &AstNode, indent: i32, f: &mut fmt::Formatter) {
                 fmt_with_indent(loop_instr, indent + 1, f);
             }
         }
+        &If {
+            body: ref if_body,
+            position,
+        } => {
+            let _ = write!(f, "If position: {:?}", position);
+
+            for if_instr in if_body {
+                let _ = writeln!(f);
+                fmt_with_indent(if_instr, indent + 1, f);
+            }
+        }
         instr => {
             let _ = write!(f, "{:?}", instr);
         }
@@ -139,8 +365,293 @@ pub fn get_position(instr: &AstNode) -> Option<Position> {
         Read { position } => position,
         Write { position } => position,
         Loop { position, .. } => position,
+        If { position, .. } => position,
         Set { position, .. } => position,
         MultiplyMove { position, .. } => position,
+        WriteConst { position, .. } => position,
+        SetRange { position, .. } => position,
+        Scan { position, .. } => position,
+        Breakpoint { position } => position,
+        Halt { position } => position,
+        Store { position } => position,
+        Load { position } => position,
+    }
+}
+
+/// Return the net pointer movement after executing `instrs`, or None
+/// if it depends on runtime values. For example, `>>[-]<` moves the
+/// pointer by 1, but `[>]` moves the pointer an unknown amount.
+///
+/// Loops whose body has a net movement of zero are balanced: they
+/// always return the pointer to the cell they started on.
+pub fn net_pointer_movement(instrs: &[AstNode]) -> Option<isize> {
+    let mut movement = 0;
+    for instr in instrs {
+        match *instr {
+            PointerIncrement { amount, .. } => movement += amount,
+            Loop { ref body, .. } | If { ref body, .. } => {
+                if net_pointer_movement(body) != Some(0) {
+                    return None;
+                }
+            }
+            Scan { .. } => return None,
+            Increment { .. }
+            | Set { .. }
+            | Read { .. }
+            | Write { .. }
+            | MultiplyMove { .. }
+            | WriteConst { .. }
+            | SetRange { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Store { .. }
+            | Load { .. } => {}
+        }
+    }
+    Some(movement)
+}
+
+/// Does `instrs` contain any reads? Programs that don't read input
+/// always produce the same output.
+pub fn reads_input(instrs: &[AstNode]) -> bool {
+    fold_instructions(instrs, false, &mut |found, instr| {
+        found || matches!(instr, Read { .. })
+    })
+}
+
+/// Loops and Ifs may nest at most this deeply. Passes recurse into
+/// bodies, so much deeper programs could overflow the stack.
+pub const MAX_NESTING_DEPTH: usize = 1_000;
+
+/// Check the structural invariants that the optimiser relies on:
+/// no instruction is a no-op because of a zero amount, step or
+/// length, `MultiplyMove` never changes the cell it zeroes, and
+/// loops don't nest too deeply. Return a description of the first
+/// problem we find.
+pub fn validate(instrs: &[AstNode]) -> Result<(), String> {
+    validate_at_depth(instrs, 0)
+}
+
+fn validate_at_depth(instrs: &[AstNode], depth: usize) -> Result<(), String> {
+    if depth > MAX_NESTING_DEPTH {
+        return Err(format!(
+            "Loops are nested more than {} deep",
+            MAX_NESTING_DEPTH
+        ));
+    }
+
+    for instr in instrs {
+        let problem = match *instr {
+            Increment { amount, .. } if amount.0 == 0 => Some("Increment by zero"),
+            PointerIncrement { amount: 0, .. } => Some("PointerIncrement by zero"),
+            MultiplyMove { ref changes, .. } if changes.contains_key(&0) => {
+                Some("MultiplyMove changes the cell it zeroes")
+            }
+            MultiplyMove { ref changes, .. } if changes.values().any(|factor| factor.0 == 0) => {
+                Some("MultiplyMove with a zero factor")
+            }
+            WriteConst { ref values, .. } if values.is_empty() => Some("WriteConst with no values"),
+            SetRange { length: 0, .. } => Some("SetRange of zero cells"),
+            Scan { step: 0, .. } => Some("Scan with a step of zero"),
+            Loop { ref body, .. } | If { ref body, .. } => {
+                validate_at_depth(body, depth + 1)?;
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(problem) = problem {
+            return Err(match get_position(instr) {
+                Some(position) => format!("{} at {:?}", problem, position),
+                None => problem.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// FNV-1a, which we use for fingerprints and cache keys because,
+/// unlike `DefaultHasher`, it gives the same result on every platform
+/// and with every Rust release.
+pub struct Fingerprinter {
+    hash: u64,
+}
+
+impl Default for Fingerprinter {
+    fn default() -> Self {
+        Fingerprinter {
+            hash: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+}
+
+impl Fingerprinter {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash ^= u64::from(byte);
+            self.hash = self.hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn write_int(&mut self, value: i64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    /// The hash of everything written so far.
+    pub fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write_instrs(&mut self, instrs: &[AstNode]) {
+        // Write the length first, so a body is distinct from the
+        // instructions after it.
+        self.write_int(instrs.len() as i64);
+        for instr in instrs {
+            match *instr {
+                Increment { amount, offset, .. } => {
+                    self.write(&[0]);
+                    self.write_int(i64::from(amount.0));
+                    self.write_int(offset as i64);
+                }
+                PointerIncrement { amount, .. } => {
+                    self.write(&[1]);
+                    self.write_int(amount as i64);
+                }
+                Read { .. } => self.write(&[2]),
+                Write { .. } => self.write(&[3]),
+                Loop { ref body, .. } => {
+                    self.write(&[4]);
+                    self.write_instrs(body);
+                }
+                If { ref body, .. } => {
+                    self.write(&[5]);
+                    self.write_instrs(body);
+                }
+                Set { amount, offset, .. } => {
+                    self.write(&[6]);
+                    self.write_int(i64::from(amount.0));
+                    self.write_int(offset as i64);
+                }
+                MultiplyMove { ref changes, .. } => {
+                    // A BTreeMap is ordered by offset, so the order
+                    // the changes were added doesn't matter.
+                    self.write(&[7]);
+                    self.write_int(changes.len() as i64);
+                    for (&offset, &factor) in changes {
+                        self.write_int(offset as i64);
+                        self.write_int(i64::from(factor.0));
+                    }
+                }
+                WriteConst { ref values, .. } => {
+                    self.write(&[8]);
+                    self.write_int(values.len() as i64);
+                    for &value in values {
+                        self.write(&value.to_le_bytes());
+                    }
+                }
+                SetRange {
+                    amount,
+                    offset,
+                    length,
+                    ..
+                } => {
+                    self.write(&[9]);
+                    self.write_int(i64::from(amount.0));
+                    self.write_int(offset as i64);
+                    self.write_int(length as i64);
+                }
+                Scan { step, .. } => {
+                    self.write(&[10]);
+                    self.write_int(step as i64);
+                }
+                Breakpoint { .. } => self.write(&[11]),
+                Halt { .. } => self.write(&[12]),
+                Store { .. } => self.write(&[13]),
+                Load { .. } => self.write(&[14]),
+            }
+        }
+    }
+}
+
+/// A hash of `instrs` that ignores positions, so the same program
+/// from different sources has the same fingerprint. Fingerprints
+/// don't change between runs or platforms, so they're safe to store.
+pub fn fingerprint(instrs: &[AstNode]) -> u64 {
+    let mut fingerprinter = Fingerprinter::default();
+    fingerprinter.write_instrs(instrs);
+    fingerprinter.finish()
+}
+
+/// Apply `f` to every instruction in `instrs`, including
+/// instructions inside Loop and If bodies. Bodies are mapped before
+/// the instruction that contains them, so `f` sees the new body.
+pub fn map_instructions<F>(instrs: Vec<AstNode>, f: &mut F) -> Vec<AstNode>
+where
+    F: FnMut(AstNode) -> AstNode,
+{
+    instrs
+        .into_iter()
+        .map(|instr| {
+            let instr = match instr {
+                Loop { body, position } => Loop {
+                    body: map_instructions(body, f),
+                    position,
+                },
+                If { body, position } => If {
+                    body: map_instructions(body, f),
+                    position,
+                },
+                other => other,
+            };
+            f(instr)
+        })
+        .collect()
+}
+
+/// Combine `init` with every instruction in `instrs`, including
+/// instructions inside Loop and If bodies. A Loop or If is passed to
+/// `f` before the instructions in its body.
+pub fn fold_instructions<T, F>(instrs: &[AstNode], init: T, f: &mut F) -> T
+where
+    F: FnMut(T, &AstNode) -> T,
+{
+    instrs.iter().fold(init, |acc, instr| {
+        let acc = f(acc, instr);
+        match *instr {
+            Loop { ref body, .. } | If { ref body, .. } => fold_instructions(body, acc, f),
+            _ => acc,
+        }
+    })
+}
+
+/// Walks BF IR, calling `visit_instr` on each instruction. The
+/// default methods visit every Loop and If body, so implementations
+/// only need to override the methods they care about.
+pub trait Visitor {
+    /// Called for each instruction, including Loops and Ifs.
+    fn visit_instr(&mut self, instr: &AstNode) {
+        walk_instr(self, instr);
+    }
+
+    /// Called for the body of each Loop or If, with the instruction
+    /// that contains it.
+    fn visit_body(&mut self, _parent: &AstNode, body: &[AstNode]) {
+        walk_instrs(self, body);
+    }
+}
+
+/// Visit each instruction in `instrs` in order.
+pub fn walk_instrs<V: Visitor + ?Sized>(visitor: &mut V, instrs: &[AstNode]) {
+    for instr in instrs {
+        visitor.visit_instr(instr);
+    }
+}
+
+/// Visit the body of `instr`, if it's a Loop or If.
+pub fn walk_instr<V: Visitor + ?Sized>(visitor: &mut V, instr: &AstNode) {
+    match *instr {
+        Loop { ref body, .. } | If { ref body, .. } => visitor.visit_body(instr, body),
+        _ => {}
     }
 }
 
@@ -150,17 +661,110 @@ pub struct ParseError {
     pub position: Position,
 }
 
+/// A run of characters in BF source that aren't BF commands.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Comment {
+    pub text: String,
+    pub position: Position,
+}
+
 /// Given a string of BF source code, parse and return our BF IR
-/// representation. If parsing fails, return an Info describing what
-/// went wrong.
-pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
+/// representation. If parsing fails, return every unmatched bracket,
+/// in the order they occur in the source.
+pub fn parse(source: &str) -> Result<Vec<AstNode>, Vec<ParseError>> {
+    parse_with_comments(source).map(|(instrs, _)| instrs)
+}
+
+/// Parse BF source code, like `parse`, but also return the comments
+/// between instructions, so tools can reproduce them.
+pub fn parse_with_comments(source: &str) -> Result<(Vec<AstNode>, Vec<Comment>), Vec<ParseError>> {
+    parse_source(source, false, false, None)
+}
+
+/// Parse BF source code, like `parse`, but treat `#` as a
+/// breakpoint rather than a comment.
+pub fn parse_with_breakpoints(source: &str) -> Result<Vec<AstNode>, Vec<ParseError>> {
+    parse_source(source, true, false, None).map(|(instrs, _)| instrs)
+}
+
+/// Parse BF source code, like `parse`, but also accept `@` (end the
+/// program), `$` (store the current cell) and `!` (load the stored
+/// cell) from Extended Brainfuck Type I.
+pub fn parse_with_extensions(
+    source: &str,
+    breakpoints: bool,
+) -> Result<Vec<AstNode>, Vec<ParseError>> {
+    parse_source(source, breakpoints, true, None).map(|(instrs, _)| instrs)
+}
+
+/// Parse BF source produced by the macro preprocessor. `positions`
+/// is the index in the original file of each character in `source`,
+/// so positions refer to the original file.
+pub fn parse_expanded(
+    source: &str,
+    positions: &[usize],
+    breakpoints: bool,
+) -> Result<Vec<AstNode>, Vec<ParseError>> {
+    parse_source(source, breakpoints, false, Some(positions)).map(|(instrs, _)| instrs)
+}
+
+/// Split BF source at the first `!`, the convention BF test corpora
+/// use for embedding a program's input in the same file. Returns the
+/// program and the bytes after the `!`, or None if there's no `!`.
+pub fn split_bang_input(source: &str) -> (&str, Option<&[u8]>) {
+    match source.find('!') {
+        Some(index) => (&source[..index], Some(&source.as_bytes()[index + 1..])),
+        None => (source, None),
+    }
+}
+
+fn parse_source(
+    source: &str,
+    breakpoints: bool,
+    extensions: bool,
+    positions: Option<&[usize]>,
+) -> Result<(Vec<AstNode>, Vec<Comment>), Vec<ParseError>> {
     // AstNodes in the current loop (or toplevel).
     let mut instructions = vec![];
     // Contains the instructions of open parent loops (or toplevel),
     // and the starting indices of the loops.
     let mut stack = vec![];
 
+    let mut comments = vec![];
+    // The comment we're currently reading, if any.
+    let mut comment: Option<Comment> = None;
+
+    // Unmatched brackets. We keep parsing after an unmatched ], so
+    // we can report every problem at once.
+    let mut errors = vec![];
+
     for (index, c) in source.chars().enumerate() {
+        let index = positions.map_or(index, |positions| positions[index]);
+        let is_command = "+-<>,.[]".contains(c)
+            || (breakpoints && c == '#')
+            || (extensions && "@$!".contains(c));
+        if !is_command {
+            match comment {
+                Some(ref mut comment) => {
+                    comment.text.push(c);
+                    comment.position.end = index;
+                }
+                None => {
+                    comment = Some(Comment {
+                        text: c.to_string(),
+                        position: Position {
+                            start: index,
+                            end: index,
+                        },
+                    });
+                }
+            }
+            continue;
+        }
+        if let Some(comment) = comment.take() {
+            comments.push(comment);
+        }
+
         match c {
             '+' => instructions.push(Increment {
                 amount: Wrapping(1),
@@ -204,6 +808,30 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
                     end: index,
                 }),
             }),
+            '#' => instructions.push(Breakpoint {
+                position: Some(Position {
+                    start: index,
+                    end: index,
+                }),
+            }),
+            '@' => instructions.push(Halt {
+                position: Some(Position {
+                    start: index,
+                    end: index,
+                }),
+            }),
+            '$' => instructions.push(Store {
+                position: Some(Position {
+                    start: index,
+                    end: index,
+                }),
+            }),
+            '!' => instructions.push(Load {
+                position: Some(Position {
+                    start: index,
+                    end: index,
+                }),
+            }),
             '[' => {
                 stack.push((instructions, index));
                 instructions = vec![];
@@ -219,7 +847,7 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
                     });
                     instructions = parent_instr;
                 } else {
-                    return Err(ParseError {
+                    errors.push(ParseError {
                         message: "This ] has no matching [".to_owned(),
                         position: Position {
                             start: index,
@@ -232,9 +860,8 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
         }
     }
 
-    if !stack.is_empty() {
-        let pos = stack.last().unwrap().1;
-        return Err(ParseError {
+    for (_, pos) in stack {
+        errors.push(ParseError {
             message: "This [ has no matching ]".to_owned(),
             position: Position {
                 start: pos,
@@ -242,68 +869,486 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
             },
         });
     }
+    if !errors.is_empty() {
+        errors.sort_by_key(|error| error.position.start);
+        return Err(errors);
+    }
 
-    Ok(instructions)
-}
+    if let Some(comment) = comment {
+        comments.push(comment);
+    }
 
-#[test]
-fn parse_increment() {
-    assert_eq!(
-        parse("+").unwrap(),
-        [Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        }]
-    );
-    assert_eq!(
-        parse("++").unwrap(),
-        [
-            Increment {
-                amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Increment {
-                amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 1, end: 1 }),
-            }
-        ]
-    );
+    Ok((instructions, comments))
 }
 
-#[test]
-fn parse_decrement() {
-    assert_eq!(
-        parse("-").unwrap(),
-        [Increment {
-            amount: Wrapping(-1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        }]
-    );
-}
+/// How many bytes `parse_reader` reads at a time.
+const PARSE_CHUNK_SIZE: usize = 64 * 1024;
 
-#[test]
-fn parse_pointer_increment() {
-    assert_eq!(
-        parse(">").unwrap(),
-        [PointerIncrement {
-            amount: 1,
-            position: Some(Position { start: 0, end: 0 }),
-        }]
-    );
-}
+/// Parse BF source from `reader`, like `parse`, but without holding
+/// the whole source in memory, so we can handle very large generated
+/// programs.
+///
+/// Most of a large program is runs of `+-` and `<>`, and an AstNode
+/// is much bigger than a character, so we combine each run into a
+/// single Increment or PointerIncrement as we go. Comments are
+/// discarded. Positions count characters, as in `parse`.
+#[allow(dead_code)]
+pub fn parse_reader<R: io::Read>(mut reader: R) -> Result<Vec<AstNode>, ParseError> {
+    // AstNodes in the current loop (or toplevel).
+    let mut instructions = vec![];
+    // Contains the instructions of open parent loops (or toplevel),
+    // and the starting indices of the loops.
+    let mut stack = vec![];
 
-#[test]
-fn parse_pointer_decrement() {
-    assert_eq!(
-        parse("<").unwrap(),
-        [PointerIncrement {
-            amount: -1,
-            position: Some(Position { start: 0, end: 0 }),
-        }]
+    let mut index = 0;
+    let mut chunk = vec![0; PARSE_CHUNK_SIZE];
+    loop {
+        let chunk_len = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(chunk_len) => chunk_len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                return Err(ParseError {
+                    message: format!("Could not read source: {}", e),
+                    position: Position {
+                        start: index,
+                        end: index,
+                    },
+                });
+            }
+        };
+
+        for &byte in &chunk[..chunk_len] {
+            // UTF-8 continuation bytes don't start a new character.
+            if byte & 0xC0 == 0x80 {
+                continue;
+            }
+
+            let position = Position {
+                start: index,
+                end: index,
+            };
+            match byte {
+                b'+' | b'-' => {
+                    let delta = if byte == b'+' {
+                        Wrapping(1)
+                    } else {
+                        Wrapping(-1)
+                    };
+                    match instructions.last_mut() {
+                        Some(&mut Increment {
+                            ref mut amount,
+                            offset: 0,
+                            position: Some(ref mut run_position),
+                        }) => {
+                            *amount += delta;
+                            run_position.end = index;
+                        }
+                        _ => instructions.push(Increment {
+                            amount: delta,
+                            offset: 0,
+                            position: Some(position),
+                        }),
+                    }
+                }
+                b'>' | b'<' => {
+                    let delta = if byte == b'>' { 1 } else { -1 };
+                    match instructions.last_mut() {
+                        Some(&mut PointerIncrement {
+                            ref mut amount,
+                            position: Some(ref mut run_position),
+                        }) => {
+                            *amount += delta;
+                            run_position.end = index;
+                        }
+                        _ => instructions.push(PointerIncrement {
+                            amount: delta,
+                            position: Some(position),
+                        }),
+                    }
+                }
+                b',' => instructions.push(Read {
+                    position: Some(position),
+                }),
+                b'.' => instructions.push(Write {
+                    position: Some(position),
+                }),
+                b'[' => {
+                    stack.push((instructions, index));
+                    instructions = vec![];
+                }
+                b']' => {
+                    if let Some((mut parent_instr, open_index)) = stack.pop() {
+                        parent_instr.push(Loop {
+                            body: instructions,
+                            position: Some(Position {
+                                start: open_index,
+                                end: index,
+                            }),
+                        });
+                        instructions = parent_instr;
+                    } else {
+                        return Err(ParseError {
+                            message: "This ] has no matching [".to_owned(),
+                            position,
+                        });
+                    }
+                }
+                _ => (),
+            }
+            index += 1;
+        }
+    }
+
+    if let Some(&(_, pos)) = stack.last() {
+        return Err(ParseError {
+            message: "This [ has no matching ]".to_owned(),
+            position: Position {
+                start: pos,
+                end: pos,
+            },
+        });
+    }
+
+    Ok(instructions)
+}
+
+/// Convert instructions from `parse_with_comments` back to BF
+/// source, including their comments. Returns None if `instrs` has
+/// been optimised, so there's no BF command for every instruction.
+#[allow(dead_code)]
+pub fn to_source(instrs: &[AstNode], comments: &[Comment]) -> Option<String> {
+    fn push_commands(instrs: &[AstNode], commands: &mut Vec<(usize, String)>) -> Option<()> {
+        for instr in instrs {
+            let command = match *instr {
+                Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    ..
+                } => '+',
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    ..
+                } => '-',
+                PointerIncrement { amount: 1, .. } => '>',
+                PointerIncrement { amount: -1, .. } => '<',
+                Read { .. } => ',',
+                Write { .. } => '.',
+                Breakpoint { .. } => '#',
+                Halt { .. } => '@',
+                Store { .. } => '$',
+                Load { .. } => '!',
+                Loop {
+                    ref body,
+                    position: Some(position),
+                } => {
+                    commands.push((position.start, "[".to_owned()));
+                    push_commands(body, commands)?;
+                    commands.push((position.end, "]".to_owned()));
+                    continue;
+                }
+                _ => return None,
+            };
+            commands.push((get_position(instr)?.start, command.to_string()));
+        }
+        Some(())
+    }
+
+    let mut fragments = vec![];
+    push_commands(instrs, &mut fragments)?;
+    for comment in comments {
+        fragments.push((comment.position.start, comment.text.clone()));
+    }
+    fragments.sort_by_key(|&(start, _)| start);
+
+    Some(fragments.into_iter().map(|(_, text)| text).collect())
+}
+
+/// Converts IR to plain BF for `to_bf`.
+struct BfWriter {
+    result: String,
+    /// Where the BF pointer is, relative to the cell pointer of the
+    /// IR. We only move the BF pointer when we need a cell.
+    offset: isize,
+    /// The cell pointer of the IR, if we know it at compile time.
+    absolute: Option<isize>,
+    /// A cell that the program never uses, so it's always zero.
+    free_cell: Option<isize>,
+    /// Cells whose value we know, by offset from the cell pointer.
+    known: BTreeMap<isize, Cell>,
+    cell_width: CellWidth,
+    overflow: Overflow,
+}
+
+impl BfWriter {
+    fn move_to(&mut self, offset: isize) {
+        let command = if offset > self.offset { '>' } else { '<' };
+        let distance = (offset - self.offset).unsigned_abs();
+        self.result.push_str(&command.to_string().repeat(distance));
+        self.offset = offset;
+    }
+
+    /// Add `amount` to the current cell, with whichever of `+` or `-`
+    /// is shorter once the cell wraps. If cells don't wrap, we add
+    /// exactly `amount`.
+    fn add(&mut self, amount: Cell) {
+        let amount = if self.overflow == Overflow::Wrap {
+            self.cell_width.wrap(amount).0
+        } else {
+            amount.0
+        };
+        let command = if amount > 0 { '+' } else { '-' };
+        self.result
+            .push_str(&command.to_string().repeat(amount.unsigned_abs() as usize));
+    }
+
+    /// Change the current cell from `from` to `to`. If cells don't
+    /// wrap, we must not go past 0 or the largest value on the way.
+    fn change(&mut self, from: Cell, to: Cell) {
+        if self.overflow == Overflow::Wrap {
+            self.add(to - from);
+        } else {
+            let difference = self.cell_width.unsigned(to) - self.cell_width.unsigned(from);
+            self.add(Wrapping(difference as i32));
+        }
+    }
+
+    fn push(&mut self, command: char) {
+        self.move_to(0);
+        self.result.push(command);
+    }
+
+    /// Set the cell at `offset` to `amount`.
+    fn set(&mut self, amount: Cell, offset: isize) {
+        self.move_to(offset);
+        match self.known.get(&offset) {
+            Some(&value) => self.change(value, amount),
+            None => {
+                self.result.push_str("[-]");
+                self.change(Wrapping(0), amount);
+            }
+        }
+        self.known.insert(offset, amount);
+    }
+
+    /// Emit `body` inside brackets. The body runs any number of
+    /// times, so we only know the cell pointer afterwards if the
+    /// body doesn't move it.
+    fn bracketed(&mut self, body: &[AstNode]) -> Option<()> {
+        let absolute = if net_pointer_movement(body) == Some(0) {
+            self.absolute
+        } else {
+            None
+        };
+
+        self.push('[');
+        self.absolute = absolute;
+        self.known.clear();
+        self.instrs(body)?;
+        self.push(']');
+        self.absolute = absolute;
+
+        // We only leave the loop once the cell is zero.
+        self.known.clear();
+        self.known.insert(0, Wrapping(0));
+        Some(())
+    }
+
+    /// Write `values` by setting a cell we know to each value in
+    /// turn, then restoring it.
+    fn write_const(&mut self, values: &[i8]) -> Option<()> {
+        let free_cell = match (self.absolute, self.free_cell) {
+            (Some(absolute), Some(free_cell)) => Some((free_cell - absolute, Wrapping(0))),
+            _ => None,
+        };
+        let current_offset = self.offset;
+        let (offset, original) = self
+            .known
+            .iter()
+            .map(|(&offset, &value)| (offset, value))
+            .chain(free_cell)
+            .min_by_key(|&(offset, _)| (offset - current_offset).abs())?;
+
+        self.move_to(offset);
+        let mut current = original;
+        for &value in values {
+            // Without wrapping, we can't reach 255 by subtracting 1.
+            let value = if self.overflow == Overflow::Wrap {
+                Wrapping(i32::from(value))
+            } else {
+                Wrapping(i32::from(value as u8))
+            };
+            self.change(current, value);
+            self.result.push('.');
+            current = value;
+        }
+        self.change(current, original);
+        Some(())
+    }
+
+    fn instrs(&mut self, instrs: &[AstNode]) -> Option<()> {
+        for instr in instrs {
+            match *instr {
+                Increment { amount, offset, .. } => {
+                    self.move_to(offset);
+                    self.add(amount);
+                    if let Some(&value) = self.known.get(&offset) {
+                        match self
+                            .overflow
+                            .add(self.cell_width, value, i64::from(amount.0))
+                        {
+                            Some(value) => self.known.insert(offset, value),
+                            None => self.known.remove(&offset),
+                        };
+                    }
+                }
+                PointerIncrement { amount, .. } => {
+                    self.offset -= amount;
+                    self.absolute = self.absolute.map(|absolute| absolute + amount);
+                    self.known = self
+                        .known
+                        .iter()
+                        .map(|(&offset, &value)| (offset - amount, value))
+                        .collect();
+                }
+                Read { .. } => {
+                    self.push(',');
+                    self.known.remove(&0);
+                }
+                Load { .. } => {
+                    self.push('!');
+                    self.known.remove(&0);
+                }
+                Write { .. } => self.push('.'),
+                // Ifs always zero the cell, so their body never runs
+                // twice.
+                Loop { ref body, .. } | If { ref body, .. } => self.bracketed(body)?,
+                Set { amount, offset, .. } => self.set(amount, offset),
+                SetRange {
+                    amount,
+                    offset,
+                    length,
+                    ..
+                } => {
+                    for cell_offset in offset..offset + length as isize {
+                        self.set(amount, cell_offset);
+                    }
+                }
+                MultiplyMove { ref changes, .. } => {
+                    self.push('[');
+                    self.add(Wrapping(-1));
+                    for (&cell_offset, &factor) in changes {
+                        self.move_to(cell_offset);
+                        self.add(factor);
+                        self.known.remove(&cell_offset);
+                    }
+                    self.push(']');
+                    self.known.insert(0, Wrapping(0));
+                }
+                WriteConst { ref values, .. } => self.write_const(values)?,
+                Scan { step, .. } => {
+                    self.push('[');
+                    self.move_to(step);
+                    self.offset = 0;
+                    self.push(']');
+                    self.absolute = None;
+                    self.known.clear();
+                    self.known.insert(0, Wrapping(0));
+                }
+                Breakpoint { .. } => self.push('#'),
+                Halt { .. } => self.push('@'),
+                Store { .. } => self.push('$'),
+            }
+        }
+        Some(())
+    }
+}
+
+/// Convert `instrs`, which may have been optimised, back to BF that
+/// any BF implementation can run. We expand instructions like Set
+/// and MultiplyMove to the loops they stand for, and omit trailing
+/// pointer movement.
+///
+/// WriteConst needs a cell whose value we know, or a cell that the
+/// program never uses. Returns None if there isn't one.
+pub fn to_bf(instrs: &[AstNode], cell_width: CellWidth, overflow: Overflow) -> Option<String> {
+    let highest_index = highest_cell_index(instrs, DEFAULT_TAPE_SIZE);
+    let mut writer = BfWriter {
+        result: String::new(),
+        offset: 0,
+        absolute: Some(0),
+        free_cell: if highest_index < DEFAULT_TAPE_SIZE - 1 {
+            Some(highest_index as isize + 1)
+        } else {
+            None
+        },
+        known: BTreeMap::new(),
+        cell_width,
+        overflow,
+    };
+    writer.instrs(instrs)?;
+    Some(writer.result)
+}
+
+#[test]
+fn parse_increment() {
+    assert_eq!(
+        parse("+").unwrap(),
+        [Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }]
+    );
+    assert_eq!(
+        parse("++").unwrap(),
+        [
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            }
+        ]
+    );
+}
+
+#[test]
+fn parse_decrement() {
+    assert_eq!(
+        parse("-").unwrap(),
+        [Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }]
+    );
+}
+
+#[test]
+fn parse_pointer_increment() {
+    assert_eq!(
+        parse(">").unwrap(),
+        [PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        }]
+    );
+}
+
+#[test]
+fn parse_pointer_decrement() {
+    assert_eq!(
+        parse("<").unwrap(),
+        [PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        }]
     );
 }
 
@@ -387,11 +1432,358 @@ fn parse_unbalanced_loop() {
     assert!(parse("[][").is_err());
 }
 
+#[test]
+fn parse_reports_every_unbalanced_bracket() {
+    let errors = parse("[[]]]+[ ]]").unwrap_err();
+    let positions: Vec<_> = errors.iter().map(|error| error.position.start).collect();
+    assert_eq!(positions, vec![4, 9]);
+
+    let errors = parse("[ [[] ]]] [").unwrap_err();
+    let messages: Vec<_> = errors
+        .iter()
+        .map(|error| (error.position.start, error.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (8, "This ] has no matching ["),
+            (10, "This [ has no matching ]")
+        ]
+    );
+}
+
+#[test]
+fn parse_reports_unmatched_open_brackets_in_order() {
+    let errors = parse("[+[").unwrap_err();
+    let positions: Vec<_> = errors.iter().map(|error| error.position.start).collect();
+    assert_eq!(positions, vec![0, 2]);
+}
+
+#[test]
+fn parse_breakpoint() {
+    assert_eq!(parse("#").unwrap(), []);
+    assert_eq!(
+        parse_with_breakpoints("a#").unwrap(),
+        [Breakpoint {
+            position: Some(Position { start: 1, end: 1 }),
+        }]
+    );
+}
+
+#[test]
+fn parse_extensions() {
+    assert_eq!(parse("@$!").unwrap(), []);
+    assert_eq!(
+        parse_with_extensions("$a!@#", false).unwrap(),
+        [
+            Store {
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Load {
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Halt {
+                position: Some(Position { start: 3, end: 3 }),
+            },
+        ]
+    );
+}
+
+#[test]
+fn split_bang_input_at_first_bang() {
+    assert_eq!(split_bang_input(",.!ab!c"), (",.", Some(&b"ab!c"[..])));
+    assert_eq!(split_bang_input(",.!"), (",.", Some(&b""[..])));
+    assert_eq!(split_bang_input(",."), (",.", None));
+}
+
 #[test]
 fn parse_comment() {
     assert_eq!(parse("foo! ").unwrap(), []);
 }
 
+#[test]
+fn parse_comments() {
+    let (instrs, comments) = parse_with_comments("a+ b[c]").unwrap();
+    assert_eq!(instrs, parse("a+ b[c]").unwrap());
+    assert_eq!(
+        comments,
+        vec![
+            Comment {
+                text: "a".to_owned(),
+                position: Position { start: 0, end: 0 },
+            },
+            Comment {
+                text: " b".to_owned(),
+                position: Position { start: 2, end: 3 },
+            },
+            Comment {
+                text: "c".to_owned(),
+                position: Position { start: 5, end: 5 },
+            },
+        ]
+    );
+}
+
+#[test]
+fn parse_reader_combines_runs() {
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 1, end: 3 }),
+        },
+        Loop {
+            body: vec![
+                PointerIncrement {
+                    amount: 1,
+                    position: Some(Position { start: 5, end: 7 }),
+                },
+                Write {
+                    position: Some(Position { start: 8, end: 8 }),
+                },
+            ],
+            position: Some(Position { start: 4, end: 9 }),
+        },
+    ];
+    assert_eq!(parse_reader("é++-[>><.]".as_bytes()).unwrap(), expected);
+}
+
+#[test]
+fn parse_reader_runs_across_chunks() {
+    let source = "+".repeat(PARSE_CHUNK_SIZE + 1);
+    let expected = vec![Increment {
+        amount: Wrapping(PARSE_CHUNK_SIZE as i32 + 1),
+        offset: 0,
+        position: Some(Position {
+            start: 0,
+            end: PARSE_CHUNK_SIZE,
+        }),
+    }];
+    assert_eq!(parse_reader(source.as_bytes()).unwrap(), expected);
+}
+
+#[test]
+fn parse_reader_unbalanced_loop() {
+    let error = parse_reader("[]]".as_bytes()).unwrap_err();
+    assert_eq!(error.message, "This ] has no matching [");
+    assert_eq!(error.position, Position { start: 2, end: 2 });
+
+    let error = parse_reader("+[".as_bytes()).unwrap_err();
+    assert_eq!(error.message, "This [ has no matching ]");
+    assert_eq!(error.position, Position { start: 1, end: 1 });
+}
+
+#[test]
+fn quickcheck_parse_reader_agrees_on_errors() {
+    fn agrees_on_errors(source: String) -> bool {
+        parse(&source).is_ok() == parse_reader(source.as_bytes()).is_ok()
+    }
+    quickcheck(agrees_on_errors as fn(String) -> bool);
+}
+
+#[test]
+fn parse_non_ascii_comments() {
+    // Positions count characters, so multi-byte comments don't
+    // shift them.
+    let (instrs, comments) = parse_with_comments("日本語+ ö.").unwrap();
+    assert_eq!(
+        instrs,
+        [
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            Write {
+                position: Some(Position { start: 6, end: 6 }),
+            }
+        ]
+    );
+    assert_eq!(comments[1].position, Position { start: 4, end: 5 });
+}
+
+#[test]
+fn to_source_optimised() {
+    let instrs = vec![Set {
+        amount: Wrapping(0),
+        offset: 0,
+        position: None,
+    }];
+    assert_eq!(to_source(&instrs, &[]), None);
+}
+
+#[test]
+fn quickcheck_to_source_round_trips() {
+    fn round_trips(source: String) -> TestResult {
+        match parse_with_comments(&source) {
+            Ok((instrs, comments)) => {
+                TestResult::from_bool(to_source(&instrs, &comments) == Some(source))
+            }
+            Err(_) => TestResult::discard(),
+        }
+    }
+    quickcheck(round_trips as fn(String) -> TestResult);
+}
+
+#[test]
+fn to_bf_omits_trailing_movement() {
+    let instrs = parse(">+<>>").unwrap();
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits8, Overflow::Wrap),
+        Some(">+".to_owned())
+    );
+}
+
+#[test]
+fn to_bf_offsets_and_wrapping() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(255),
+            offset: 0,
+            position: None,
+        },
+        Loop {
+            body: vec![PointerIncrement {
+                amount: 1,
+                position: None,
+            }],
+            position: None,
+        },
+    ];
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits8, Overflow::Wrap),
+        Some(">++<-[>]".to_owned())
+    );
+}
+
+#[test]
+fn to_bf_wraps_at_cell_width() {
+    let instrs = vec![Increment {
+        amount: Wrapping(-2),
+        offset: 0,
+        position: None,
+    }];
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits16, Overflow::Wrap),
+        Some("--".to_owned())
+    );
+
+    let instrs = vec![Increment {
+        amount: Wrapping(255),
+        offset: 0,
+        position: None,
+    }];
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits16, Overflow::Wrap),
+        Some("+".repeat(255))
+    );
+}
+
+#[test]
+fn to_bf_without_wrapping() {
+    // With wrapping cells, - is the shortest way to get 255.
+    let instrs = vec![Set {
+        amount: Wrapping(-1),
+        offset: 0,
+        position: None,
+    }];
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits8, Overflow::Wrap),
+        Some("[-]-".to_owned())
+    );
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits8, Overflow::Saturate),
+        Some(format!("[-]{}", "+".repeat(255)))
+    );
+}
+
+#[test]
+fn to_bf_expands_set_and_multiply_move() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(2));
+    changes.insert(-1, Wrapping(-1));
+    let instrs = vec![
+        Set {
+            amount: Wrapping(3),
+            offset: 0,
+            position: None,
+        },
+        MultiplyMove {
+            changes,
+            position: None,
+        },
+        SetRange {
+            amount: Wrapping(1),
+            offset: 0,
+            length: 2,
+            position: None,
+        },
+    ];
+    // The MultiplyMove leaves the cell zero, so we don't need [-].
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits8, Overflow::Wrap),
+        Some("[-]+++[-<->>++<]+>[-]+".to_owned())
+    );
+}
+
+#[test]
+fn to_bf_write_const_uses_free_cell() {
+    let instrs = [
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: None,
+        },
+        WriteConst {
+            values: vec![2, 1],
+            position: None,
+        },
+    ];
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits8, Overflow::Wrap),
+        Some(">+>++.-.-".to_owned())
+    );
+}
+
+#[test]
+fn to_bf_write_const_uses_known_cell() {
+    let instrs = [
+        Scan {
+            step: 1,
+            position: None,
+        },
+        WriteConst {
+            values: vec![1],
+            position: None,
+        },
+    ];
+    assert_eq!(
+        to_bf(&instrs, CellWidth::Bits8, Overflow::Wrap),
+        Some("[>]+.-".to_owned())
+    );
+}
+
+#[test]
+fn to_bf_write_const_unknown_cells() {
+    let instrs = [
+        Scan {
+            step: 1,
+            position: None,
+        },
+        Read { position: None },
+        WriteConst {
+            values: vec![1],
+            position: None,
+        },
+    ];
+    assert_eq!(to_bf(&instrs, CellWidth::Bits8, Overflow::Wrap), None);
+}
+
 #[test]
 fn test_combine_pos() {
     let pos1 = Some(Position { start: 1, end: 2 });
@@ -416,6 +1808,15 @@ fn test_combine_pos_not_consecutive() {
     assert_eq!(pos1.combine(pos2), Some(Position { start: 4, end: 5 }));
 }
 
+#[test]
+fn test_combine_pos_unknown() {
+    let pos = Some(Position { start: 1, end: 2 });
+
+    assert_eq!(pos.combine(None), pos);
+    assert_eq!(None.combine(pos), pos);
+    assert_eq!(None.combine(None), None);
+}
+
 #[test]
 fn test_combine_pos_overlap() {
     let pos1 = Some(Position { start: 1, end: 1 });
@@ -423,3 +1824,243 @@ fn test_combine_pos_overlap() {
 
     assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 3 }));
 }
+
+#[test]
+fn net_pointer_movement_flat() {
+    let instrs = parse(">>+<.").unwrap();
+    assert_eq!(net_pointer_movement(&instrs), Some(1));
+}
+
+#[test]
+fn net_pointer_movement_balanced_loop() {
+    let instrs = parse(">[->+<]>").unwrap();
+    assert_eq!(net_pointer_movement(&instrs), Some(2));
+}
+
+#[test]
+fn net_pointer_movement_unbalanced_loop() {
+    let instrs = parse("[>]").unwrap();
+    assert_eq!(net_pointer_movement(&instrs), None);
+}
+
+#[test]
+fn reads_input_nested() {
+    assert!(reads_input(&parse("+[>[,]<]").unwrap()));
+    assert!(!reads_input(&parse("+[>[.]<]").unwrap()));
+}
+
+#[test]
+fn fingerprint_ignores_positions() {
+    let instrs = parse("+[->+<]").unwrap();
+    let moved = parse("x +[-  >+<]").unwrap();
+    assert_eq!(fingerprint(&instrs), fingerprint(&moved));
+    assert_ne!(
+        fingerprint(&instrs),
+        fingerprint(&parse("+[->+<].").unwrap())
+    );
+}
+
+#[test]
+fn fingerprint_nesting() {
+    // The same instructions, but only the first has [-] inside the
+    // outer loop.
+    let nested = parse("[[-]]-").unwrap();
+    let after = parse("[[-]-]").unwrap();
+    assert_ne!(fingerprint(&nested), fingerprint(&after));
+}
+
+#[test]
+fn fingerprint_multiply_move_order() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(2));
+    changes.insert(-1, Wrapping(3));
+    let mut reversed = BTreeMap::new();
+    reversed.insert(-1, Wrapping(3));
+    reversed.insert(1, Wrapping(2));
+
+    let instr = |changes| MultiplyMove {
+        changes,
+        position: None,
+    };
+    assert_eq!(
+        fingerprint(&[instr(changes)]),
+        fingerprint(&[instr(reversed)])
+    );
+}
+
+#[test]
+fn fingerprint_is_stable() {
+    // Fingerprints may be stored, so they must not change.
+    assert_eq!(fingerprint(&parse("+[-]").unwrap()), 0xa8b5_1548_2607_e62b);
+}
+
+#[test]
+fn map_instructions_nested() {
+    // Replace every Write with a Read, at any depth.
+    let instrs = map_instructions(parse(".[.[.]]").unwrap(), &mut |instr| match instr {
+        Write { position } => Read { position },
+        other => other,
+    });
+    assert_eq!(instrs, parse(",[,[,]]").unwrap());
+}
+
+#[test]
+fn fold_instructions_nested() {
+    let instrs = parse("+[>[-]<]").unwrap();
+    let count = fold_instructions(&instrs, 0, &mut |count, _| count + 1);
+    assert_eq!(count, 6);
+}
+
+#[test]
+fn visitor_nesting_depth() {
+    struct MaxDepth {
+        depth: usize,
+        max_depth: usize,
+    }
+
+    impl Visitor for MaxDepth {
+        fn visit_body(&mut self, _parent: &AstNode, body: &[AstNode]) {
+            self.depth += 1;
+            self.max_depth = std::cmp::max(self.max_depth, self.depth);
+            walk_instrs(self, body);
+            self.depth -= 1;
+        }
+    }
+
+    let mut visitor = MaxDepth {
+        depth: 0,
+        max_depth: 0,
+    };
+    walk_instrs(&mut visitor, &parse("[[]][[[-]]]").unwrap());
+    assert_eq!(visitor.max_depth, 3);
+}
+
+#[test]
+fn validate_parsed_program() {
+    assert_eq!(validate(&parse("+[->+<]>.").unwrap()), Ok(()));
+}
+
+#[test]
+fn validate_zero_amounts() {
+    let instrs = vec![Increment {
+        amount: Wrapping(0),
+        offset: 0,
+        position: Some(Position { start: 3, end: 3 }),
+    }];
+    assert_eq!(validate(&instrs), Err("Increment by zero at 3".to_owned()));
+
+    let instrs = vec![Loop {
+        body: vec![Scan {
+            step: 0,
+            position: None,
+        }],
+        position: None,
+    }];
+    assert_eq!(
+        validate(&instrs),
+        Err("Scan with a step of zero".to_owned())
+    );
+}
+
+#[test]
+fn validate_multiply_move_offsets() {
+    let mut changes = BTreeMap::new();
+    changes.insert(0, Wrapping(2));
+    let instrs = vec![MultiplyMove {
+        changes,
+        position: None,
+    }];
+    assert_eq!(
+        validate(&instrs),
+        Err("MultiplyMove changes the cell it zeroes".to_owned())
+    );
+}
+
+#[test]
+fn validate_nesting_depth() {
+    let mut instrs = vec![];
+    for _ in 0..=MAX_NESTING_DEPTH + 1 {
+        instrs = vec![Loop {
+            body: instrs,
+            position: None,
+        }];
+    }
+    assert!(validate(&instrs).is_err());
+}
+
+#[test]
+fn cell_width_from_bits() {
+    assert_eq!(CellWidth::from_bits(16), Some(CellWidth::Bits16));
+    assert_eq!(CellWidth::from_bits(12), None);
+}
+
+#[test]
+fn overflow_add() {
+    let max = Wrapping(-1);
+    assert_eq!(
+        Overflow::Wrap.add(CellWidth::Bits8, max, 1),
+        Some(Wrapping(0))
+    );
+    assert_eq!(Overflow::Abort.add(CellWidth::Bits8, max, 1), None);
+    assert_eq!(Overflow::Saturate.add(CellWidth::Bits8, max, 1), Some(max));
+
+    let zero = Wrapping(0);
+    assert_eq!(Overflow::Wrap.add(CellWidth::Bits8, zero, -1), Some(max));
+    assert_eq!(Overflow::Abort.add(CellWidth::Bits8, zero, -1), None);
+    assert_eq!(
+        Overflow::Saturate.add(CellWidth::Bits8, zero, -300),
+        Some(zero)
+    );
+
+    // 255 fits in a 16-bit cell.
+    assert_eq!(
+        Overflow::Abort.add(CellWidth::Bits16, zero, 255),
+        Some(Wrapping(255))
+    );
+    assert_eq!(
+        Overflow::Abort.add(CellWidth::Bits32, max, -1),
+        Some(Wrapping(-2))
+    );
+}
+
+#[test]
+fn eof_value() {
+    assert_eq!(Eof::Zero.value(Wrapping(5)), Wrapping(0));
+    assert_eq!(Eof::MinusOne.value(Wrapping(5)), Wrapping(-1));
+    assert_eq!(Eof::Unchanged.value(Wrapping(5)), Wrapping(5));
+    assert_eq!(Eof::from_name("minus-one"), Some(Eof::MinusOne));
+    assert_eq!(Eof::from_name("-1"), None);
+}
+
+#[test]
+fn bounds_from_name() {
+    assert_eq!(Bounds::from_name("wrap"), Some(Bounds::Wrap));
+    assert_eq!(Bounds::from_name("abort"), Some(Bounds::Abort));
+    assert_eq!(Bounds::from_name("circular"), None);
+}
+
+#[test]
+fn cell_width_wraps() {
+    assert_eq!(CellWidth::Bits8.wrap(Wrapping(255)), Wrapping(-1));
+    assert_eq!(CellWidth::Bits8.wrap(Wrapping(256)), Wrapping(0));
+    assert_eq!(CellWidth::Bits16.wrap(Wrapping(255)), Wrapping(255));
+    assert_eq!(CellWidth::Bits16.wrap(Wrapping(65_536)), Wrapping(0));
+    assert_eq!(CellWidth::Bits32.wrap(Wrapping(65_536)), Wrapping(65_536));
+}
+
+#[test]
+fn multiply_move_changes_are_ordered() {
+    let mut changes = BTreeMap::new();
+    changes.insert(3, Wrapping(4));
+    changes.insert(-1, Wrapping(2));
+    changes.insert(1, Wrapping(3));
+    let instr = MultiplyMove {
+        changes,
+        position: None,
+    };
+
+    assert_eq!(
+        format!("{}", instr),
+        "MultiplyMove { changes: {-1: 2, 1: 3, 3: 4}, position: None }"
+    );
+}
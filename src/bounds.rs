@@ -1,43 +1,51 @@
 #![warn(trivial_numeric_casts)]
 
-//! Calculate the maximum cell accessed by a BF program.
+//! Calculate the maximum cell accessed by a BF program, and find
+//! programs that move the pointer left of cell #0.
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 #[cfg(test)]
 use quickcheck::quickcheck;
 #[cfg(test)]
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 #[cfg(test)]
 use std::num::Wrapping;
 
 use std::cmp::{max, Ord, Ordering};
 use std::ops::Add;
 
-use crate::bfir::AstNode;
 use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Position};
 
 #[cfg(test)]
-use crate::bfir::{parse, Position};
+use crate::bfir::parse;
 
-// 100,000 cells, zero-indexed.
-pub const MAX_CELL_INDEX: usize = 99999;
+/// The number of cells programs get, unless the user passes
+/// `--tape-size`.
+pub const DEFAULT_TAPE_SIZE: usize = 100_000;
+
+/// The largest `--tape-size` we accept. We allocate the whole tape
+/// when executing at compile time, and the generated code indexes
+/// cells with 32-bit integers.
+pub const MAX_TAPE_SIZE: usize = 1 << 24;
 
 /// Return the highest cell index that can be reached during program
-/// execution. Zero-indexed.
-pub fn highest_cell_index(instrs: &[AstNode]) -> usize {
+/// execution, on a tape of `tape_size` cells. Zero-indexed.
+pub fn highest_cell_index(instrs: &[AstNode], tape_size: usize) -> usize {
     let (highest_index, _) = overall_movement(instrs);
+    let max_cell_index = tape_size - 1;
 
     match highest_index {
         SaturatingInt::Number(x) => {
-            if x > MAX_CELL_INDEX as i64 {
+            if x > max_cell_index as i64 {
                 // TODO: generate a warning here.
-                MAX_CELL_INDEX
+                max_cell_index
             } else {
                 x as usize
             }
         }
-        SaturatingInt::Max => MAX_CELL_INDEX,
+        SaturatingInt::Max => max_cell_index,
     }
 }
 
@@ -114,6 +122,10 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
             SaturatingInt::Number(offset as i64),
             SaturatingInt::Number(0),
         ),
+        SetRange { offset, length, .. } => (
+            SaturatingInt::Number(offset as i64 + length as i64 - 1),
+            SaturatingInt::Number(0),
+        ),
         MultiplyMove { ref changes, .. } => {
             let mut highest_affected = 0;
             for cell in changes.keys() {
@@ -150,29 +162,50 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 }
             }
         }
-        Read { .. } | Write { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
+        If { ref body, .. } => {
+            let (max_in_body, net_in_body) = overall_movement(body);
+
+            // The body runs at most once, so we end up either where
+            // we started or at the end of the body.
+            (max_in_body, max(net_in_body, SaturatingInt::Number(0)))
+        }
+        Scan { step, .. } => {
+            // Like a loop whose body is a pointer increment.
+            if step > 0 {
+                (SaturatingInt::Max, SaturatingInt::Max)
+            } else {
+                (SaturatingInt::Number(0), SaturatingInt::Number(0))
+            }
+        }
+        Read { .. }
+        | Write { .. }
+        | WriteConst { .. }
+        | Breakpoint { .. }
+        | Halt { .. }
+        | Store { .. }
+        | Load { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
     }
 }
 
 #[test]
 fn one_cell_bounds() {
     let instrs = parse("+-.,").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 0);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 0);
 }
 
 #[test]
 fn ptr_increment_bounds() {
     let instrs = parse(">").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 1);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 1);
 }
 
 #[test]
 fn ptr_increment_sequence_bounds() {
     let instrs = parse(">>.<").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 2);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 2);
 
     let instrs = parse(">><>>").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 3);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 3);
 }
 
 #[test]
@@ -181,12 +214,12 @@ fn multiple_ptr_increment_bounds() {
         amount: 2,
         position: Some(Position { start: 0, end: 0 }),
     }];
-    assert_eq!(highest_cell_index(&instrs), 2);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 2);
 }
 
 #[test]
 fn multiply_move_bounds() {
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(1, Wrapping(3));
     dest_cells.insert(4, Wrapping(1));
     let instrs = vec![
@@ -203,14 +236,14 @@ fn multiply_move_bounds() {
         },
     ];
 
-    assert_eq!(highest_cell_index(&instrs), 4);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 4);
 }
 
 /// Multiply move uses offsets to the current pointer value.
 /// Verify we add to the current pointer value.
 #[test]
 fn multiply_move_bounds_are_relative() {
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(1, Wrapping(5));
     let instrs = vec![
         // Move to cell #2.
@@ -225,12 +258,12 @@ fn multiply_move_bounds_are_relative() {
         },
     ];
 
-    assert_eq!(highest_cell_index(&instrs), 3);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 3);
 }
 
 #[test]
 fn multiply_move_backwards_bounds() {
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(-1, Wrapping(2));
     let instrs = vec![
         PointerIncrement {
@@ -243,48 +276,63 @@ fn multiply_move_backwards_bounds() {
         },
     ];
 
-    assert_eq!(highest_cell_index(&instrs), 1);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 1);
 }
 
 #[test]
 fn unbounded_movement() {
     let instrs = parse("[>]").unwrap();
-    assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
+    assert_eq!(
+        highest_cell_index(&instrs, DEFAULT_TAPE_SIZE),
+        DEFAULT_TAPE_SIZE - 1
+    );
 
     let instrs = parse(">[<]").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 1);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 1);
 }
 
 #[test]
 fn excessive_bounds_truncated() {
     // TODO: we should generate a warning in this situation.
     let instrs = vec![PointerIncrement {
-        amount: MAX_CELL_INDEX as isize + 1,
+        amount: DEFAULT_TAPE_SIZE as isize,
         position: Some(Position { start: 0, end: 0 }),
     }];
-    assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
+    assert_eq!(
+        highest_cell_index(&instrs, DEFAULT_TAPE_SIZE),
+        DEFAULT_TAPE_SIZE - 1
+    );
+}
+
+#[test]
+fn bounds_limited_by_tape_size() {
+    let instrs = parse(">>>>").unwrap();
+    assert_eq!(highest_cell_index(&instrs, 3), 2);
+
+    let instrs = parse("[>]").unwrap();
+    assert_eq!(highest_cell_index(&instrs, 10), 9);
 }
 
 #[test]
 fn loop_with_no_net_movement() {
     // Max cell index 1, final cell position 0.
     let instrs = parse("[->+<]").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 1);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 1);
 
     // Max cell index 1, final cell position 1.
     let instrs = parse("[->+<]>").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 1);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 1);
 
     // Max cell index 2, final cell position 2.
     let instrs = parse("[->+<]>>").unwrap();
-    assert_eq!(highest_cell_index(&instrs), 2);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 2);
 }
 
 #[test]
 fn quickcheck_highest_cell_index_in_bounds() {
     fn highest_cell_index_in_bounds(instrs: Vec<AstNode>) -> bool {
-        let index = highest_cell_index(&instrs);
-        index <= MAX_CELL_INDEX
+        let index = highest_cell_index(&instrs, DEFAULT_TAPE_SIZE);
+        index < DEFAULT_TAPE_SIZE
     }
     quickcheck(highest_cell_index_in_bounds as fn(Vec<AstNode>) -> bool);
 }
@@ -296,7 +344,7 @@ fn increment_offset_bounds() {
         offset: 5,
         position: Some(Position { start: 0, end: 0 }),
     }];
-    assert_eq!(highest_cell_index(&instrs), 5);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 5);
 }
 
 #[test]
@@ -313,5 +361,136 @@ fn set_offset_bounds() {
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(highest_cell_index(&instrs), 11);
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 11);
+}
+
+#[test]
+fn set_range_bounds() {
+    let instrs = [SetRange {
+        amount: Wrapping(2),
+        offset: 3,
+        length: 4,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    assert_eq!(highest_cell_index(&instrs, DEFAULT_TAPE_SIZE), 6);
+}
+
+/// Where the pointer is as we walk through a program.
+enum Walk {
+    /// The pointer is at this cell index on every path so far.
+    At(isize),
+    /// A loop moved the pointer by an amount we don't know, or the
+    /// program halted, so we stop looking.
+    Unknown,
+    /// We accessed this cell index, left of cell #0.
+    LeftOfStart(isize, Option<Position>),
+}
+
+/// Find the first instruction that can access a cell left of cell
+/// #0, returning the cell index and the instruction's position. This
+/// is the most common BF bug, and would otherwise crash at runtime.
+///
+/// Like `--bounds=abort`, we only count accesses, so moves such as
+/// `<>` that cancel out are fine. We only follow the pointer while we
+/// know where it is. Loops that move the pointer, such as `[<]`,
+/// depend on the cells, so we stop looking after them.
+pub fn left_of_start(instrs: &[AstNode]) -> Option<(isize, Option<Position>)> {
+    match walk(instrs, 0) {
+        Walk::LeftOfStart(index, position) => Some((index, position)),
+        Walk::At(_) | Walk::Unknown => None,
+    }
+}
+
+fn walk(instrs: &[AstNode], start: isize) -> Walk {
+    let mut index = start;
+    for instr in instrs {
+        // The lowest offset from the pointer that this instruction
+        // accesses, if it accesses any cells.
+        let lowest_offset = match *instr {
+            PointerIncrement { amount, .. } => {
+                index += amount;
+                None
+            }
+            Increment { offset, .. } | Set { offset, .. } | SetRange { offset, .. } => Some(offset),
+            MultiplyMove { ref changes, .. } => {
+                Some(changes.keys().next().map_or(0, |&offset| offset.min(0)))
+            }
+            Loop { ref body, .. } | If { ref body, .. } => {
+                // The loop checks the current cell before running
+                // the body.
+                if index < 0 {
+                    return Walk::LeftOfStart(index, get_position(instr));
+                }
+                match walk(body, index) {
+                    // A body that ends where it started leaves the
+                    // pointer where it was, whether or not it runs.
+                    Walk::At(end) if end == index => None,
+                    Walk::At(_) | Walk::Unknown => return Walk::Unknown,
+                    left_of_start => return left_of_start,
+                }
+            }
+            Scan { .. } | Halt { .. } => return Walk::Unknown,
+            Read { .. } | Write { .. } | Store { .. } | Load { .. } => Some(0),
+            WriteConst { .. } | Breakpoint { .. } => None,
+        };
+        if let Some(offset) = lowest_offset {
+            if index + offset < 0 {
+                return Walk::LeftOfStart(index + offset, get_position(instr));
+            }
+        }
+    }
+    Walk::At(index)
+}
+
+#[test]
+fn left_of_start_after_moving_left() {
+    let instrs = parse(">+<<+").unwrap();
+    assert_eq!(
+        left_of_start(&instrs),
+        Some((-1, Some(Position { start: 4, end: 4 })))
+    );
+}
+
+#[test]
+fn left_of_start_inside_loop() {
+    // The loop body accesses cell -1 if the loop runs.
+    let instrs = parse(",[<+>-]").unwrap();
+    assert_eq!(
+        left_of_start(&instrs),
+        Some((-1, Some(Position { start: 3, end: 3 })))
+    );
+}
+
+#[test]
+fn left_of_start_balanced() {
+    let instrs = parse(">[<+>-]<.<>").unwrap();
+    assert_eq!(left_of_start(&instrs), None);
+}
+
+#[test]
+fn left_of_start_stops_after_unknown_movement() {
+    // [<] is a common idiom for finding the start of some data, so
+    // we don't know where the pointer is afterwards.
+    let instrs = parse(">+>+[<]<<").unwrap();
+    assert_eq!(left_of_start(&instrs), None);
+}
+
+#[test]
+fn left_of_start_multiply_move() {
+    let mut changes = BTreeMap::new();
+    changes.insert(-2, Wrapping(1));
+    let instrs = [
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 1, end: 5 }),
+        },
+    ];
+    assert_eq!(
+        left_of_start(&instrs),
+        Some((-1, Some(Position { start: 1, end: 5 })))
+    );
 }
@@ -0,0 +1,131 @@
+//! A cache of the object files we compile, so compiling a program
+//! we've seen before skips speculative execution, LLVM and codegen.
+//!
+//! Entries are keyed on a hash of everything that affects the object
+//! file: the optimised BF IR and the compile options. Each entry is
+//! `KEY.o`, plus `KEY.notes` with the messages compiling printed.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::bfir::Fingerprinter;
+
+/// The directory we keep the cache in: `$BFC_CACHE_DIR` if set,
+/// otherwise `bfc` in the user's cache directory.
+pub fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("BFC_CACHE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME") {
+        return Some(Path::new(&dir).join("bfc"));
+    }
+    env::var_os("HOME").map(|home| Path::new(&home).join(".cache").join("bfc"))
+}
+
+/// A key for the cache, built from each of `parts`.
+pub fn key(parts: &[&[u8]]) -> String {
+    // We hash the length of each part too, so moving bytes from
+    // one part to the next changes the key.
+    let mut fingerprinter = Fingerprinter::default();
+    for part in parts {
+        fingerprinter.write_int(part.len() as i64);
+        fingerprinter.write(part);
+    }
+    format!("{:016x}", fingerprinter.finish())
+}
+
+/// A compilation we've cached.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub object_path: PathBuf,
+    /// The messages we printed when we compiled this, which don't
+    /// refer to positions in the source.
+    pub notes: Vec<String>,
+}
+
+/// The entry for `key` in `dir`, if we have one.
+pub fn lookup(dir: &Path, key: &str) -> Option<Entry> {
+    let object_path = dir.join(format!("{}.o", key));
+    let notes = fs::read_to_string(dir.join(format!("{}.notes", key))).ok()?;
+    if !object_path.is_file() {
+        return None;
+    }
+    Some(Entry {
+        object_path,
+        notes: notes.lines().map(str::to_owned).collect(),
+    })
+}
+
+/// Add the object file at `object_path` to the cache as `key`.
+pub fn store(dir: &Path, key: &str, object_path: &Path, notes: &[String]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    // Write to temporary files then rename them, so another bfc
+    // compiling the same program never sees half an entry. We write
+    // the notes last, as lookup() reads them first.
+    let object = tempfile::NamedTempFile::new_in(dir)?;
+    fs::copy(object_path, object.path())?;
+    object
+        .persist(dir.join(format!("{}.o", key)))
+        .map_err(|e| e.error)?;
+
+    let notes_file = tempfile::NamedTempFile::new_in(dir)?;
+    fs::write(notes_file.path(), notes.join("\n"))?;
+    notes_file
+        .persist(dir.join(format!("{}.notes", key)))
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Delete every entry in the cache, returning how many we deleted.
+/// We leave any other files alone, in case `$BFC_CACHE_DIR` points
+/// somewhere shared.
+pub fn clean(dir: &Path) -> io::Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut entries = 0;
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("o") => {
+                fs::remove_file(&path)?;
+                entries += 1;
+            }
+            Some("notes") => fs::remove_file(&path)?,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+#[test]
+fn key_depends_on_every_part() {
+    assert_eq!(key(&[b"ab", b"c"]), key(&[b"ab", b"c"]));
+    assert_ne!(key(&[b"ab", b"c"]), key(&[b"a", b"bc"]));
+    assert_ne!(key(&[b"ab", b"c"]), key(&[b"ab", b"d"]));
+}
+
+#[test]
+fn store_and_lookup() {
+    let dir = tempfile::tempdir().unwrap();
+    let object = dir.path().join("foo.o");
+    fs::write(&object, b"object code").unwrap();
+    let cache = dir.path().join("cache");
+
+    assert_eq!(lookup(&cache, "abc"), None);
+    let notes = vec!["note\tThis program reads no input".to_owned()];
+    store(&cache, "abc", &object, &notes).unwrap();
+
+    let entry = lookup(&cache, "abc").unwrap();
+    assert_eq!(fs::read(&entry.object_path).unwrap(), b"object code");
+    assert_eq!(entry.notes, notes);
+
+    assert_eq!(clean(&cache).unwrap(), 1);
+    assert_eq!(lookup(&cache, "abc"), None);
+}
@@ -0,0 +1,186 @@
+//! Project settings. A `bfc.toml` in the working directory gives
+//! default values for bfc's options, one per line, such as
+//! `cell-size = 16` or `stats = true`. Options given on the command
+//! line take precedence.
+//!
+//! We only need a small part of TOML: comments, and keys with
+//! string, integer or boolean values.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// The file we read settings from.
+pub const CONFIG_PATH: &str = "bfc.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+/// A setting for the long option `name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub name: String,
+    pub value: Value,
+}
+
+impl Setting {
+    /// The command line argument for this setting. A false boolean
+    /// is the same as not giving the flag, so it has no argument.
+    pub fn to_arg(&self) -> Option<String> {
+        match self.value {
+            Value::String(ref value) => Some(format!("--{}={}", self.name, value)),
+            Value::Integer(value) => Some(format!("--{}={}", self.name, value)),
+            Value::Boolean(true) => Some(format!("--{}", self.name)),
+            Value::Boolean(false) => None,
+        }
+    }
+}
+
+/// Parse a TOML string starting at the opening quote of `text`,
+/// returning its value and the text after the closing quote.
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[index + 1..])),
+            '\\' => {
+                let escaped = match chars.next()?.1 {
+                    '"' => '"',
+                    '\\' => '\\',
+                    'n' => '\n',
+                    't' => '\t',
+                    _ => return None,
+                };
+                value.push(escaped);
+            }
+            _ => value.push(c),
+        }
+    }
+    None
+}
+
+/// Parse the value of a setting, which may be followed by a comment.
+fn parse_value(text: &str) -> Option<Value> {
+    if text.starts_with('"') {
+        let (value, rest) = parse_string(text)?;
+        let rest = rest.trim_start();
+        if rest.is_empty() || rest.starts_with('#') {
+            return Some(Value::String(value));
+        }
+        return None;
+    }
+
+    let text = match text.find('#') {
+        Some(index) => text[..index].trim_end(),
+        None => text,
+    };
+    match text {
+        "true" => Some(Value::Boolean(true)),
+        "false" => Some(Value::Boolean(false)),
+        _ => text.replace('_', "").parse().ok().map(Value::Integer),
+    }
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Parse the settings in a `bfc.toml`.
+pub fn parse(text: &str) -> Result<Vec<Setting>, String> {
+    let mut settings: Vec<Setting> = vec![];
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid_line = || format!("Invalid setting on line {}: {}", line_index + 1, line);
+
+        let equals = line.find('=').ok_or_else(invalid_line)?;
+        let name = line[..equals].trim();
+        if !is_bare_key(name) {
+            return Err(invalid_line());
+        }
+        let value = parse_value(line[equals + 1..].trim()).ok_or_else(invalid_line)?;
+
+        if settings.iter().any(|setting| setting.name == name) {
+            return Err(format!(
+                "Line {} sets {} a second time",
+                line_index + 1,
+                name
+            ));
+        }
+        settings.push(Setting {
+            name: name.to_owned(),
+            value,
+        });
+    }
+
+    Ok(settings)
+}
+
+#[test]
+fn parse_settings() {
+    let text =
+        "# Project defaults\n\ncell-size = 16\nout-dir = \"build\" # comment\nstats = true\n";
+    assert_eq!(
+        parse(text),
+        Ok(vec![
+            Setting {
+                name: "cell-size".to_owned(),
+                value: Value::Integer(16),
+            },
+            Setting {
+                name: "out-dir".to_owned(),
+                value: Value::String("build".to_owned()),
+            },
+            Setting {
+                name: "stats".to_owned(),
+                value: Value::Boolean(true),
+            },
+        ])
+    );
+}
+
+#[test]
+fn parse_string_escapes() {
+    assert_eq!(
+        parse_value(r#""a \"b\" # c""#),
+        Some(Value::String("a \"b\" # c".to_owned()))
+    );
+    assert_eq!(parse_value(r#""unterminated"#), None);
+    assert_eq!(parse_value("1_000"), Some(Value::Integer(1000)));
+}
+
+#[test]
+fn parse_invalid_settings() {
+    assert!(parse("cell-size").is_err());
+    assert!(parse("cell size = 16").is_err());
+    assert!(parse("[build]").is_err());
+    assert!(parse("opt = two").is_err());
+    assert!(parse("opt = 2\nopt = 3").is_err());
+}
+
+#[test]
+fn settings_to_args() {
+    let setting = |value| Setting {
+        name: "stats".to_owned(),
+        value,
+    };
+    assert_eq!(
+        setting(Value::Boolean(true)).to_arg(),
+        Some("--stats".to_owned())
+    );
+    assert_eq!(setting(Value::Boolean(false)).to_arg(), None);
+    assert_eq!(
+        setting(Value::Integer(-1)).to_arg(),
+        Some("--stats=-1".to_owned())
+    );
+}
@@ -1,10 +1,18 @@
 //! Human-readable warnings and errors for the CLI.
 
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+#[cfg(test)]
+use quickcheck::quickcheck;
+
 use colored::*;
 use std::fmt;
 
 use crate::bfir::Position;
 
+#[cfg(test)]
+use crate::bfir::{get_position, parse};
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Warning {
     pub message: String,
@@ -15,6 +23,7 @@ pub struct Warning {
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum Level {
+    Note,
     Warning,
     Error,
 }
@@ -27,44 +36,194 @@ pub struct Info {
     pub filename: String,
     pub message: String,
     pub position: Option<Position>,
+    /// The line and column of the start of `position`.
+    pub location: Option<Location>,
     pub source: Option<String>,
 }
 
-// Given an index into a string, return the line number and column
-// count (both zero-indexed).
-fn position(s: &str, i: usize) -> (usize, usize) {
-    let mut char_count = 0;
-    for (line_idx, line) in s.split('\n').enumerate() {
-        let line_length = line.len();
-        if char_count + line_length >= i {
-            return (line_idx, i - char_count);
+/// Where a character is in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// The line and column, both zero-indexed. Columns count
+    /// characters, not bytes.
+    pub line: usize,
+    pub column: usize,
+    /// The offset of the character in bytes, for tools that work
+    /// with bytes.
+    pub byte_offset: usize,
+}
+
+impl Location {
+    /// The location of the character at char index `index` in
+    /// `source`, or of the end of `source` if it's shorter.
+    pub fn new(source: &str, index: usize) -> Self {
+        let mut location = Location {
+            line: 0,
+            column: 0,
+            byte_offset: 0,
+        };
+        for (char_index, (byte_offset, c)) in source.char_indices().enumerate() {
+            location.byte_offset = byte_offset;
+            if char_index == index {
+                return location;
+            }
+
+            if c == '\n' {
+                location.line += 1;
+                location.column = 0;
+            } else {
+                location.column += 1;
+            }
         }
 
-        char_count += line_length + 1;
+        location.byte_offset = source.len();
+        location
     }
+}
 
-    unreachable!()
+/// The BF files that make up a program. Each file gets its own range
+/// of positions, starting with the main file at 0, so a position
+/// identifies both the file and the characters within it.
+#[derive(Debug, Clone)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+#[derive(Debug, Clone)]
+struct SourceFile {
+    path: String,
+    source: String,
+    /// The position of the first character in this file.
+    start: usize,
+}
+
+impl SourceMap {
+    pub fn new(path: &str, source: &str) -> Self {
+        SourceMap {
+            files: vec![SourceFile {
+                path: path.to_owned(),
+                source: source.to_owned(),
+                start: 0,
+            }],
+        }
+    }
+
+    pub fn main_path(&self) -> &str {
+        &self.files[0].path
+    }
+
+    pub fn main_source(&self) -> &str {
+        &self.files[0].source
+    }
+
+    /// Add another file, and return the position of its first
+    /// character.
+    pub fn add(&mut self, path: &str, source: &str) -> usize {
+        let last = self.files.last().unwrap();
+        // Leave a gap, so even empty files have a position.
+        let start = last.start + last.source.chars().count() + 1;
+        self.files.push(SourceFile {
+            path: path.to_owned(),
+            source: source.to_owned(),
+            start,
+        });
+        start
+    }
+
+    /// An Info for `message` that points at `position` in whichever
+    /// file it belongs to.
+    pub fn info(&self, level: Level, message: String, position: Option<Position>) -> Info {
+        let file = match position {
+            Some(position) => self
+                .files
+                .iter()
+                .rev()
+                .find(|file| file.start <= position.start)
+                .unwrap(),
+            None => &self.files[0],
+        };
+
+        let position = position.map(|position| {
+            let start = position.start - file.start;
+            let end = position.end - file.start;
+            // Loops may start and end in different files, so
+            // only point at the start.
+            if end < file.source.chars().count() {
+                Position { start, end }
+            } else {
+                Position { start, end: start }
+            }
+        });
+
+        Info {
+            level,
+            filename: file.path.clone(),
+            message,
+            position,
+            location: position.map(|position| Location::new(&file.source, position.start)),
+            source: Some(file.source.clone()),
+        }
+    }
+}
+
+/// The line `line_idx` of `source`, and a line of carets that
+/// highlights `width` characters after the one at `column_idx`.
+/// Columns count characters, so the carets line up with multi-byte
+/// characters, and we copy tabs so they line up with tabs too.
+fn highlight(source: &str, line_idx: usize, column_idx: usize, width: usize) -> (String, String) {
+    let line = source.split('\n').nth(line_idx).unwrap_or("");
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    let mut carets: String = line
+        .chars()
+        .take(column_idx)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    carets.push('^');
+
+    // Spans may continue on later lines, such as a loop, but we only
+    // show this line.
+    let line_len = line.chars().count();
+    let width = width.min(line_len.saturating_sub(column_idx + 1));
+    carets.push_str(&"~".repeat(width));
+
+    (line.to_owned(), carets)
+}
+
+impl Info {
+    /// The file name, and the line and column if we have a location,
+    /// e.g. "foo.bf:2:5".
+    pub fn location_text(&self) -> String {
+        match (&self.position, &self.location) {
+            (&Some(_), &Some(location)) => format!(
+                "{}:{}:{}",
+                self.filename,
+                location.line + 1,
+                location.column + 1
+            ),
+            _ => self.filename.clone(),
+        }
+    }
 }
 
 impl fmt::Display for Info {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        let mut file_text = self.filename.to_owned();
+        let file_text = self.location_text();
 
-        // Find line and column offsets, if we have an index.
-        let offsets = match (&self.position, &self.source) {
-            (&Some(range), &Some(ref source)) => {
+        // Show the line and column, if we have a location.
+        let offsets = match (&self.position, &self.location) {
+            (&Some(range), &Some(location)) => {
                 debug_assert!(range.start <= range.end);
-
-                let (line_idx, column_idx) = position(source, range.start);
-
-                file_text = file_text + &format!(":{}:{}", line_idx + 1, column_idx + 1);
-                Some((line_idx, column_idx, range.end - range.start))
+                Some((location.line, location.column, range.end - range.start))
             }
             _ => None,
         };
 
         let level_text;
         match self.level {
+            Level::Note => {
+                level_text = " note: ".cyan();
+            }
             Level::Warning => {
                 level_text = " warning: ".purple();
             }
@@ -76,21 +235,9 @@ impl fmt::Display for Info {
         let mut context_line = "".to_owned();
         let mut caret_line = "".to_owned();
         if let (Some((line_idx, column_idx, width)), &Some(ref source)) = (offsets, &self.source) {
-            // The faulty line of code.
-            let line = source.split('\n').nth(line_idx).unwrap();
-            context_line = "\n".to_owned() + line;
-
-            // Highlight the faulty characters on that line.
-            caret_line += "\n";
-            for _ in 0..column_idx {
-                caret_line += " ";
-            }
-            caret_line += "^";
-            if width > 0 {
-                for _ in 0..width {
-                    caret_line += "~";
-                }
-            }
+            let (line, carets) = highlight(source, line_idx, column_idx, width);
+            context_line = "\n".to_owned() + &line;
+            caret_line = "\n".to_owned() + &carets;
         }
 
         write!(f, "{}", file_text.bold())?;
@@ -100,3 +247,127 @@ impl fmt::Display for Info {
         write!(f, "{}", caret_line.bold().red())
  }
 }
+
+#[test]
+fn location_counts_lines_and_columns() {
+    assert_eq!(
+        Location::new("+\n-[.", 3),
+        Location {
+            line: 1,
+            column: 1,
+            byte_offset: 3,
+        }
+    );
+}
+
+#[test]
+fn location_counts_characters_not_bytes() {
+    // 'é' is two bytes in UTF-8.
+    assert_eq!(
+        Location::new("é\né+", 3),
+        Location {
+            line: 1,
+            column: 1,
+            byte_offset: 5,
+        }
+    );
+}
+
+#[test]
+fn location_at_end_of_source() {
+    assert_eq!(
+        Location::new("+\n", 2),
+        Location {
+            line: 1,
+            column: 0,
+            byte_offset: 2,
+        }
+    );
+}
+
+#[test]
+fn highlight_after_multibyte_characters() {
+    let source = "héllo 日本 [\n+";
+    assert_eq!(
+        highlight(source, 0, 9, 0),
+        ("héllo 日本 [".to_owned(), "         ^".to_owned())
+    );
+}
+
+#[test]
+fn highlight_keeps_tabs() {
+    assert_eq!(
+        highlight("\t→ [", 0, 3, 0),
+        ("\t→ [".to_owned(), "\t  ^".to_owned())
+    );
+}
+
+#[test]
+fn highlight_strips_carriage_return() {
+    assert_eq!(
+        highlight("コメント\r\n+]\r\n", 1, 1, 0),
+        ("+]".to_owned(), " ^".to_owned())
+    );
+}
+
+#[test]
+fn highlight_stops_at_end_of_line() {
+    // A loop whose ] is on a later line.
+    assert_eq!(
+        highlight("é[-\n]", 0, 1, 3),
+        ("é[-".to_owned(), " ^~".to_owned())
+    );
+}
+
+#[test]
+fn info_in_multibyte_comment() {
+    let source = "ünïcödé comment ]";
+    let sources = SourceMap::new("foo.bf", source);
+    let info = sources.info(
+        Level::Error,
+        "Unmatched".to_owned(),
+        Some(Position { start: 16, end: 16 }),
+    );
+    assert_eq!(
+        info.location,
+        Some(Location {
+            line: 0,
+            column: 16,
+            byte_offset: source.find(']').unwrap(),
+        })
+    );
+    assert!(source.is_char_boundary(info.location.unwrap().byte_offset));
+}
+
+#[test]
+fn info_location_text() {
+    let sources = SourceMap::new("foo.bf", "+\n-");
+    let info = sources.info(
+        Level::Error,
+        "Overflow".to_owned(),
+        Some(Position { start: 2, end: 2 }),
+    );
+    assert_eq!(info.location_text(), "foo.bf:2:1");
+
+    let info = sources.info(Level::Error, "Overflow".to_owned(), None);
+    assert_eq!(info.location_text(), "foo.bf");
+}
+
+#[test]
+fn quickcheck_info_for_any_position() {
+    fn points_at_char_boundary(source: String) -> bool {
+        let positions: Vec<Position> = match parse(&source) {
+            Ok(instrs) => instrs.iter().filter_map(get_position).collect(),
+            Err(errors) => errors.into_iter().map(|error| error.position).collect(),
+        };
+
+        let sources = SourceMap::new("foo.bf", &source);
+        positions.into_iter().all(|position| {
+            let info = sources.info(Level::Warning, "foo".to_owned(), Some(position));
+            // Formatting shouldn't panic either.
+            let _ = info.to_string();
+            source.is_char_boundary(info.location.unwrap().byte_offset)
+        })
+    }
+    quickcheck(points_at_char_boundary as fn(String) -> bool);
+}
@@ -3,9 +3,10 @@
 //! Compile time execution of BF programs.
 
 #[cfg(test)]
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::env;
 use std::num::Wrapping;
+use std::time::Instant;
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
@@ -13,47 +14,96 @@ use pretty_assertions::assert_eq;
 use quickcheck::quickcheck;
 
 #[cfg(test)]
-use crate::bfir::{parse, Position};
+use crate::bfir::{parse, parse_with_breakpoints, parse_with_extensions};
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, Cell};
+use crate::bfir::{AstNode, Bounds, Cell, CellWidth, Eof, Overflow, Position};
 
 use crate::diagnostics::Warning;
 
-#[cfg(test)]
-use crate::bounds::MAX_CELL_INDEX;
-
 use crate::bounds::highest_cell_index;
 
+#[cfg(test)]
+use crate::bounds::DEFAULT_TAPE_SIZE;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ExecutionState<'a> {
     pub start_instr: Option<&'a AstNode>,
     pub cells: Vec<Cell>,
     pub cell_ptr: isize,
+    /// Cells wrap at this width.
+    pub cell_width: CellWidth,
+    /// What increments do when a cell overflows.
+    pub overflow: Overflow,
+    /// What happens when the pointer leaves the tape.
+    pub bounds: Bounds,
     pub outputs: Vec<i8>,
+    /// The storage cell that `Store` and `Load` use.
+    pub storage: Cell,
 }
 
 impl<'a> ExecutionState<'a> {
-    pub fn initial(instrs: &[AstNode]) -> Self {
+    /// The state before running `instrs` on a tape of `tape_size`
+    /// cells. We only allocate the cells the program can reach.
+    pub fn initial(instrs: &[AstNode], tape_size: usize) -> Self {
         ExecutionState {
             start_instr: None,
-            cells: vec![Wrapping(0); highest_cell_index(instrs) + 1],
+            cells: vec![Wrapping(0); highest_cell_index(instrs, tape_size) + 1],
             cell_ptr: 0,
+            cell_width: CellWidth::default(),
+            overflow: Overflow::default(),
+            bounds: Bounds::default(),
             outputs: vec![],
+            storage: Wrapping(0),
         }
     }
+
+    /// The state before running `instrs` with `bounds`. A circular
+    /// tape needs all `tape_size` cells, since moving left from cell
+    /// #0 reaches the last cell.
+    pub fn initial_with_bounds(instrs: &[AstNode], tape_size: usize, bounds: Bounds) -> Self {
+        let mut state = ExecutionState::initial(instrs, tape_size);
+        state.bounds = bounds;
+        if bounds == Bounds::Wrap {
+            state.cells.resize(tape_size, Wrapping(0));
+        }
+        state
+    }
+
+    /// The index of the cell `offset` cells from the current cell.
+    /// With `Bounds::Wrap`, this wraps around the tape, so it's
+    /// always a valid index.
+    fn cell_index(&self, offset: isize) -> isize {
+        let index = self.cell_ptr + offset;
+        if self.bounds == Bounds::Wrap {
+            index.rem_euclid(self.cells.len() as isize)
+        } else {
+            index
+        }
+    }
+
+    fn is_on_tape(&self, index: isize) -> bool {
+        index >= 0 && index < self.cells.len() as isize
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Outcome {
     // Return the number of steps remaining at completion.
     Completed(u64),
+    /// We reached a `Halt`, so the whole program has finished.
+    Halted,
     ReachedRuntimeValue,
     RuntimeError(Warning),
     OutOfSteps,
 }
 
-/// The maximum number of steps we should execute at compile time.
+/// How many steps we execute between checking whether we've passed
+/// the deadline. This must be a power of two.
+const DEADLINE_CHECK_INTERVAL: u64 = 1 << 16;
+
+/// The maximum number of steps we should execute at compile time,
+/// unless the user passes `--max-speculative-steps`.
 pub fn max_steps() -> u64 {
     // It takes around 1 million steps to finish executing bottles.bf
     // at compile time. This is intolerably slow for debug builds of
@@ -66,18 +116,54 @@ pub fn max_steps() -> u64 {
     steps
 }
 
+/// The warning for an instruction that accesses cell `index`, which
+/// is off the tape.
+fn off_tape_access(state: &ExecutionState, index: isize, position: Option<Position>) -> Warning {
+    Warning {
+        message: format!(
+            "This instruction accesses cell {}, but the cells are 0 to {}.",
+            index,
+            state.cells.len() - 1
+        ),
+        position,
+    }
+}
+
 /// Compile time speculative execution of instructions. We return the
 /// final state of the cells, any print side effects, and the point in
 /// the code we reached.
-pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warning>) {
-    let mut state = ExecutionState::initial(instrs);
-    let outcome = execute_with_state(instrs, &mut state, steps, None);
+pub fn execute(
+    instrs: &[AstNode],
+    steps: u64,
+    cell_width: CellWidth,
+    overflow: Overflow,
+    bounds: Bounds,
+    tape_size: usize,
+) -> (ExecutionState, Option<Warning>) {
+    execute_with_deadline(instrs, steps, None, cell_width, overflow, bounds, tape_size)
+}
+
+/// As `execute`, but also stop once `deadline` has passed, as if we'd
+/// run out of steps.
+pub fn execute_with_deadline(
+    instrs: &[AstNode],
+    steps: u64,
+    deadline: Option<Instant>,
+    cell_width: CellWidth,
+    overflow: Overflow,
+    bounds: Bounds,
+    tape_size: usize,
+) -> (ExecutionState<'_>, Option<Warning>) {
+    let mut state = ExecutionState::initial_with_bounds(instrs, tape_size, bounds);
+    state.cell_width = cell_width;
+    state.overflow = overflow;
+    let outcome = execute_with_state(instrs, &mut state, steps, deadline, None);
 
     // Sanity check: if we have a start instruction we
     // can't have executed the entire program at compile time.
     match state.start_instr {
         Some(_) => debug_assert!(!matches!(outcome, Outcome::Completed(_))),
-        None => debug_assert!(matches!(outcome, Outcome::Completed(_))),
+        None => debug_assert!(matches!(outcome, Outcome::Completed(_) | Outcome::Halted)),
     }
 
     match outcome {
@@ -86,38 +172,180 @@ pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warnin
     }
 }
 
+/// If execution stopped at a read at the top level of `instrs`,
+/// return a program that behaves like the rest of the execution:
+/// Sets that restore the cells we computed, then the remaining
+/// instructions, starting with the read.
+///
+/// Optimising this program treats the value read as unknown, but
+/// folds everything that doesn't depend on it, using the cell values
+/// we reached at compile time.
+pub fn checkpoint_program(instrs: &[AstNode], state: &ExecutionState) -> Option<Vec<AstNode>> {
+    let start_instr = state.start_instr?;
+    let start_idx = instrs
+        .iter()
+        .position(|instr| std::ptr::eq(instr, start_instr))?;
+    if !matches!(instrs[start_idx], Read { .. }) {
+        return None;
+    }
+    // We have no instruction that sets the storage cell.
+    if state.storage.0 != 0 {
+        return None;
+    }
+
+    let mut checkpoint = vec![];
+    for (index, cell) in state.cells.iter().enumerate() {
+        if cell.0 != 0 {
+            checkpoint.push(Set {
+                amount: *cell,
+                offset: index as isize,
+                position: None,
+            });
+        }
+    }
+    if state.cell_ptr != 0 {
+        checkpoint.push(PointerIncrement {
+            amount: state.cell_ptr,
+            position: None,
+        });
+    }
+
+    checkpoint.extend(instrs[start_idx..].iter().cloned());
+    Some(checkpoint)
+}
+
 /// Execute the instructions given, updating the state as we go.
-/// To avoid infinite loops, stop execution after `steps` steps.
+/// To avoid infinite loops, stop execution after `steps` steps, or
+/// once `deadline` has passed.
 ///
-/// Execution also stops if we encounter a read instruction.  Users may
+/// Execution also stops if we encounter a read instruction or a
+/// breakpoint, or a halt that ends the program.  Users may
 /// alternatively pass in a dummy value for the read (used in testing).
 pub fn execute_with_state<'a>(
     instrs: &'a [AstNode],
     state: &mut ExecutionState<'a>,
     steps: u64,
+    deadline: Option<Instant>,
     dummy_read_value: Option<i8>,
 ) -> Outcome {
+    execute_with_reads(instrs, state, steps, deadline, &mut |_| {
+        dummy_read_value.map(|value| Wrapping(i32::from(value)))
+    })
+}
+
+/// Execute the instructions given, reading bytes from `input`. Once
+/// `input` is exhausted, reads set the cell as `eof` says.
+pub fn execute_with_input<'a>(
+    instrs: &'a [AstNode],
+    state: &mut ExecutionState<'a>,
+    steps: u64,
+    input: &[u8],
+    eof: Eof,
+) -> Outcome {
+    let mut bytes = input.iter();
+    execute_with_reads(instrs, state, steps, None, &mut |cell| {
+        Some(match bytes.next() {
+            Some(byte) => Wrapping(i32::from(*byte)),
+            None => eof.value(cell),
+        })
+    })
+}
+
+/// Execute the instructions given, calling `read_value` with the
+/// current cell for the value of each read. If it returns None,
+/// execution stops at the read. If `deadline` passes, we stop as if
+/// we'd run out of steps.
+fn execute_with_reads<'a>(
+    instrs: &'a [AstNode],
+    state: &mut ExecutionState<'a>,
+    steps: u64,
+    deadline: Option<Instant>,
+    read_value: &mut dyn FnMut(Cell) -> Option<Cell>,
+) -> Outcome {
+    let width = state.cell_width;
     let mut steps_left = steps;
     let mut instr_idx = 0;
     while instr_idx < instrs.len() && steps_left > 0 {
+        // Checking the time is slow compared with a step, so only
+        // check occasionally.
+        if steps_left & (DEADLINE_CHECK_INTERVAL - 1) == 0
+            && deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            steps_left = 0;
+            break;
+        }
         let cell_ptr = state.cell_ptr as usize;
 
         match instrs[instr_idx] {
-            Increment { amount, offset, .. } => {
-                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
-                state.cells[target_cell_ptr] += amount;
+            Increment {
+                amount,
+                offset,
+                position,
+            } => {
+                let target_cell_ptr = state.cell_index(offset);
+                if !state.is_on_tape(target_cell_ptr) {
+                    let warning = off_tape_access(state, target_cell_ptr, position);
+                    state.start_instr = Some(&instrs[instr_idx]);
+                    return Outcome::RuntimeError(warning);
+                }
+                let target_cell_ptr = target_cell_ptr as usize;
+                let target_cell = state.cells[target_cell_ptr];
+                match state.overflow.add(width, target_cell, i64::from(amount.0)) {
+                    Some(value) => {
+                        state.cells[target_cell_ptr] = value;
+                        instr_idx += 1;
+                    }
+                    None => {
+                        // The program will abort when it reaches
+                        // this instruction at runtime.
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        let message = format!(
+                            "This instruction makes cell {} overflow, so the program aborts.",
+                            target_cell_ptr
+                        );
+                        return Outcome::RuntimeError(Warning { message, position });
+                    }
+                }
+            }
+            Set {
+                amount,
+                offset,
+                position,
+            } => {
+                let target_cell_ptr = state.cell_index(offset);
+                if !state.is_on_tape(target_cell_ptr) {
+                    let warning = off_tape_access(state, target_cell_ptr, position);
+                    state.start_instr = Some(&instrs[instr_idx]);
+                    return Outcome::RuntimeError(warning);
+                }
+                state.cells[target_cell_ptr as usize] = width.wrap(amount);
                 instr_idx += 1;
             }
-            Set { amount, offset, .. } => {
-                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
-                state.cells[target_cell_ptr] = amount;
+            SetRange {
+                amount,
+                offset,
+                length,
+                position,
+            } => {
+                for end in &[offset, offset + length as isize - 1] {
+                    let target_cell_ptr = state.cell_index(*end);
+                    if !state.is_on_tape(target_cell_ptr) {
+                        let warning = off_tape_access(state, target_cell_ptr, position);
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(warning);
+                    }
+                }
+                for index in 0..length {
+                    let target_cell_ptr = state.cell_index(offset + index as isize) as usize;
+                    state.cells[target_cell_ptr] = width.wrap(amount);
+                }
                 instr_idx += 1;
             }
             PointerIncrement {
                 amount, position, ..
             } => {
-                let new_cell_ptr = state.cell_ptr + amount;
-                if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
+                let new_cell_ptr = state.cell_index(amount);
+                if !state.is_on_tape(new_cell_ptr) {
                     // We can't execute this instruction, so we'll
                     // execute it at runtime (it'll probably be an
                     // error).
@@ -152,8 +380,10 @@ pub fn execute_with_state<'a>(
                 if cell_value.0 != 0 {
                     // We will multiply by the current cell value.
 
-                    for (cell_offset, factor) in changes {
-                        let dest_ptr = cell_ptr as isize + *cell_offset;
+                    // Check all the offsets before touching any cells,
+                    // so we don't leave the state half updated.
+                    for cell_offset in changes.keys() {
+                        let dest_ptr = state.cell_index(*cell_offset);
                         if dest_ptr < 0 {
                             // Tried to access a cell before cell #0.
                             state.start_instr = Some(&instrs[instr_idx]);
@@ -182,9 +412,27 @@ pub fn execute_with_state<'a>(
                                 position,
                             });
                         }
+                    }
 
-                        let current_val = state.cells[dest_ptr as usize];
-                        state.cells[dest_ptr as usize] = current_val + cell_value * (*factor);
+                    let mut new_values = vec![];
+                    for (cell_offset, factor) in changes {
+                        let dest_ptr = state.cell_index(*cell_offset) as usize;
+                        let amount = width.unsigned(cell_value) * i64::from(factor.0);
+                        match state.overflow.add(width, state.cells[dest_ptr], amount) {
+                            Some(value) => new_values.push((dest_ptr, value)),
+                            None => {
+                                state.start_instr = Some(&instrs[instr_idx]);
+                                let message = format!(
+                                    "This multiply loop makes cell {} overflow, so the \
+                                     program aborts.",
+                                    dest_ptr
+                                );
+                                return Outcome::RuntimeError(Warning { message, position });
+                            }
+                        }
+                    }
+                    for (dest_ptr, value) in new_values {
+                        state.cells[dest_ptr] = value;
                     }
 
                     // Finally, zero the cell we used.
@@ -193,16 +441,43 @@ pub fn execute_with_state<'a>(
 
                 instr_idx += 1;
             }
+            Scan { step, position } => {
+                if state.cells[cell_ptr].0 == 0 {
+                    instr_idx += 1;
+                } else {
+                    let new_cell_ptr = state.cell_index(step);
+                    if !state.is_on_tape(new_cell_ptr) {
+                        // Runtime execution can resume the scan
+                        // from the current cell.
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        let message = format!(
+                            "This scan moves the pointer to cell {}, but the cells are 0 to {}.",
+                            new_cell_ptr,
+                            state.cells.len() - 1
+                        );
+                        return Outcome::RuntimeError(Warning { message, position });
+                    }
+                    // Move one step each time, so a long scan
+                    // counts as many steps, like the loop it
+                    // replaces.
+                    state.cell_ptr = new_cell_ptr;
+                }
+            }
             Write { .. } => {
+                // Like putchar, we only write the low byte.
                 let cell_value = state.cells[state.cell_ptr as usize];
-                state.outputs.push(cell_value.0);
+                state.outputs.push(cell_value.0 as i8);
+                instr_idx += 1;
+            }
+            WriteConst { ref values, .. } => {
+                state.outputs.extend(values);
                 instr_idx += 1;
             }
             Read { .. } => {
-                if let Some(read_value) = dummy_read_value {
-                    // If we're given a dummy value to use for the
-                    // read, pretend that we've read that value.
-                    state.cells[state.cell_ptr as usize] = Wrapping(read_value);
+                if let Some(read_value) = read_value(state.cells[cell_ptr]) {
+                    // If we're given a value to use for the read,
+                    // pretend that we've read that value.
+                    state.cells[state.cell_ptr as usize] = width.wrap(read_value);
                     instr_idx += 1
                 } else {
                     // Otherwise, we cannot proceed at compile time,
@@ -211,6 +486,23 @@ pub fn execute_with_state<'a>(
                     return Outcome::ReachedRuntimeValue;
                 }
             }
+            Breakpoint { .. } => {
+                // The dump should show the tape when the program
+                // runs, so we stop here, like we do for reads.
+                state.start_instr = Some(&instrs[instr_idx]);
+                return Outcome::ReachedRuntimeValue;
+            }
+            Halt { .. } => {
+                return Outcome::Halted;
+            }
+            Store { .. } => {
+                state.storage = state.cells[cell_ptr];
+                instr_idx += 1;
+            }
+            Load { .. } => {
+                state.cells[cell_ptr] = state.storage;
+                instr_idx += 1;
+            }
             Loop { ref body, .. } => {
                 if state.cells[state.cell_ptr as usize].0 == 0 {
                     // Step over the loop because the current cell is
@@ -219,7 +511,7 @@ pub fn execute_with_state<'a>(
                 } else {
                     // Execute the loop body.
                     let loop_outcome =
-                        execute_with_state(body, state, steps_left, dummy_read_value);
+                        execute_with_reads(body, state, steps_left, deadline, read_value);
                     match loop_outcome {
                         Outcome::Completed(remaining_steps) => {
                             // We've run several steps during the loop
@@ -227,6 +519,7 @@ pub fn execute_with_state<'a>(
                             // that.
                             steps_left = remaining_steps;
                         }
+                        Outcome::Halted => return Outcome::Halted,
                         Outcome::ReachedRuntimeValue
                         | Outcome::RuntimeError(..)
                         | Outcome::OutOfSteps => {
@@ -241,6 +534,31 @@ pub fn execute_with_state<'a>(
                     }
                 }
             }
+            If { ref body, .. } => {
+                if state.cells[state.cell_ptr as usize].0 != 0 {
+                    let if_outcome =
+                        execute_with_reads(body, state, steps_left, deadline, read_value);
+                    match if_outcome {
+                        Outcome::Completed(remaining_steps) => {
+                            steps_left = remaining_steps;
+                        }
+                        Outcome::Halted => return Outcome::Halted,
+                        Outcome::ReachedRuntimeValue
+                        | Outcome::RuntimeError(..)
+                        | Outcome::OutOfSteps => {
+                            // If we ran out of steps at the end of
+                            // the body, the current cell is now zero,
+                            // so starting from the If is a no-op.
+                            if state.start_instr.is_none() {
+                                state.start_instr = Some(&instrs[instr_idx]);
+                            }
+                            return if_outcome;
+                        }
+                    }
+                }
+                // Unlike a loop, we never execute the body again.
+                instr_idx += 1;
+            }
         }
 
         steps_left -= 1;
@@ -267,7 +585,15 @@ pub fn execute_with_state<'a>(
 #[test]
 fn cant_evaluate_inputs() {
     let instrs = parse(",.").unwrap();
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -275,15 +601,149 @@ fn cant_evaluate_inputs() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
 
+#[test]
+fn execution_stops_at_breakpoint() {
+    let instrs = parse_with_breakpoints("+#.").unwrap();
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+
+    assert_eq!(final_state.start_instr, Some(&instrs[1]));
+    assert_eq!(final_state.cells, vec![Wrapping(1)]);
+    assert_eq!(final_state.outputs, vec![]);
+}
+
+#[test]
+fn execution_halts_inside_loop() {
+    let instrs = parse_with_extensions("+[.@]+.", false).unwrap();
+    let (final_state, warning) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+
+    assert_eq!(final_state.start_instr, None);
+    assert_eq!(final_state.cells, vec![Wrapping(1)]);
+    assert_eq!(final_state.outputs, vec![1]);
+    assert_eq!(warning, None);
+}
+
+#[test]
+fn execution_stops_at_deadline() {
+    // Without a deadline, this would run until it ran out of steps.
+    let instrs = parse("+.[]").unwrap();
+    let (final_state, warning) = execute_with_deadline(
+        &instrs,
+        DEADLINE_CHECK_INTERVAL + 2,
+        Some(Instant::now()),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+
+    assert_eq!(final_state.start_instr, Some(&instrs[2]));
+    assert_eq!(final_state.outputs, vec![1]);
+    assert_eq!(warning, None);
+}
+
+#[test]
+fn execution_store_and_load() {
+    let instrs = parse_with_extensions("++$>!", false).unwrap();
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+
+    assert_eq!(final_state.cells, vec![Wrapping(2), Wrapping(2)]);
+    assert_eq!(final_state.storage, Wrapping(2));
+}
+
+#[test]
+fn checkpoint_after_read() {
+    let instrs = parse(">++>+<,.").unwrap();
+    let state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+
+    let expected = vec![
+        Set {
+            amount: Wrapping(2),
+            offset: 1,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(1),
+            offset: 2,
+            position: None,
+        },
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+        instrs[6].clone(),
+        instrs[7].clone(),
+    ];
+    assert_eq!(checkpoint_program(&instrs, &state), Some(expected));
+}
+
+/// We can't resume in the middle of a loop.
+#[test]
+fn no_checkpoint_after_read_in_loop() {
+    let instrs = parse("+[,.]").unwrap();
+    let state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+    assert_eq!(checkpoint_program(&instrs, &state), None);
+}
+
 #[test]
 fn increment_executed() {
     let instrs = parse("+").unwrap();
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -291,14 +751,18 @@ fn increment_executed() {
             start_instr: None,
             cells: vec![Wrapping(1)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
 
 #[test]
 fn multiply_move_executed() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(2));
     changes.insert(3, Wrapping(3));
 
@@ -328,14 +792,26 @@ fn multiply_move_executed() {
         },
     ];
 
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
     assert_eq!(
         final_state,
         ExecutionState {
             start_instr: None,
             cells: vec![Wrapping(0), Wrapping(5), Wrapping(0), Wrapping(6)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -345,7 +821,7 @@ fn multiply_move_executed() {
 /// undefined behaviour when we have a multiply move instruction.
 #[test]
 fn multiply_move_when_current_cell_is_zero() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(-1, Wrapping(2));
 
     let instrs = [MultiplyMove {
@@ -353,7 +829,14 @@ fn multiply_move_when_current_cell_is_zero() {
         position: None,
     }];
 
-    let (final_state, warning) = execute(&instrs, max_steps());
+    let (final_state, warning) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
     assert_eq!(warning, None);
     assert_eq!(
         final_state,
@@ -361,14 +844,18 @@ fn multiply_move_when_current_cell_is_zero() {
             start_instr: None,
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
 
 #[test]
 fn multiply_move_wrapping() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(3));
     let instrs = [
         Increment {
@@ -382,7 +869,15 @@ fn multiply_move_wrapping() {
         },
     ];
 
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
     assert_eq!(
         final_state,
         ExecutionState {
@@ -390,15 +885,19 @@ fn multiply_move_wrapping() {
             // 100 * 3 mod 256 == 44
             cells: vec![Wrapping(0), Wrapping(44)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
 
 #[test]
 fn multiply_move_offset_too_high() {
-    let mut changes: HashMap<isize, Cell> = HashMap::new();
-    changes.insert(MAX_CELL_INDEX as isize + 1, Wrapping(1));
+    let mut changes: BTreeMap<isize, Cell> = BTreeMap::new();
+    changes.insert(DEFAULT_TAPE_SIZE as isize, Wrapping(1));
     let instrs = [
         Increment {
             amount: Wrapping(1),
@@ -411,8 +910,16 @@ fn multiply_move_offset_too_high() {
         },
     ];
 
-    let final_state = execute(&instrs, max_steps()).0;
-    let mut expected_cells = vec![Wrapping(0); MAX_CELL_INDEX + 1];
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+    let mut expected_cells = vec![Wrapping(0); DEFAULT_TAPE_SIZE];
     expected_cells[0] = Wrapping(1);
     assert_eq!(
         final_state,
@@ -420,14 +927,18 @@ fn multiply_move_offset_too_high() {
             start_instr: Some(&instrs[1]),
             cells: expected_cells,
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
 
 #[test]
 fn multiply_move_offset_too_low() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(-1, Wrapping(1));
     let instrs = [
         Increment {
@@ -441,14 +952,116 @@ fn multiply_move_offset_too_low() {
         },
     ];
 
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
     assert_eq!(
         final_state,
         ExecutionState {
             start_instr: Some(&instrs[1]),
             cells: vec![Wrapping(1)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        }
+    );
+}
+
+#[test]
+fn multiply_move_out_of_bounds_leaves_cells_unchanged() {
+    let mut changes = BTreeMap::new();
+    changes.insert(-1, Wrapping(1));
+    changes.insert(1, Wrapping(2));
+    let instrs = [
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+
+    let (final_state, warning) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+    assert_eq!(
+        final_state,
+        ExecutionState {
+            start_instr: Some(&instrs[1]),
+            cells: vec![Wrapping(1), Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        }
+    );
+    assert!(warning.unwrap().message.contains("cell -1"));
+}
+
+#[test]
+fn if_executed_once() {
+    let instrs = [
+        Set {
+            amount: Wrapping(3),
+            offset: 0,
+            position: None,
+        },
+        If {
+            body: vec![
+                Increment {
+                    amount: Wrapping(1),
+                    offset: 1,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: None,
+                },
+            ],
+            position: None,
+        },
+    ];
+
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+    assert_eq!(
+        final_state,
+        ExecutionState {
+            start_instr: None,
+            cells: vec![Wrapping(0), Wrapping(1)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -460,7 +1073,15 @@ fn set_executed() {
         offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -468,7 +1089,11 @@ fn set_executed() {
             start_instr: None,
             cells: vec![Wrapping(2)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -480,7 +1105,15 @@ fn set_wraps() {
         offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -488,7 +1121,44 @@ fn set_wraps() {
             start_instr: None,
             cells: vec![Wrapping(-1)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
+        }
+    );
+}
+
+#[test]
+fn set_range_executed() {
+    let instrs = [SetRange {
+        amount: Wrapping(3),
+        offset: 1,
+        length: 2,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+
+    assert_eq!(
+        final_state,
+        ExecutionState {
+            start_instr: None,
+            cells: vec![Wrapping(0), Wrapping(3), Wrapping(3)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -496,7 +1166,15 @@ fn set_wraps() {
 #[test]
 fn decrement_executed() {
     let instrs = parse("-").unwrap();
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -504,7 +1182,11 @@ fn decrement_executed() {
             start_instr: None,
             cells: vec![Wrapping(-1)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -523,7 +1205,15 @@ fn increment_wraps() {
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -531,7 +1221,11 @@ fn increment_wraps() {
             start_instr: None,
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -539,7 +1233,15 @@ fn increment_wraps() {
 #[test]
 fn ptr_increment_executed() {
     let instrs = parse(">").unwrap();
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -547,7 +1249,11 @@ fn ptr_increment_executed() {
             start_instr: None,
             cells: vec![Wrapping(0), Wrapping(0)],
             cell_ptr: 1,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -555,7 +1261,14 @@ fn ptr_increment_executed() {
 #[test]
 fn ptr_out_of_range() {
     let instrs = parse("<").unwrap();
-    let (final_state, warning) = execute(&instrs, max_steps());
+    let (final_state, warning) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
 
     assert_eq!(
         final_state,
@@ -563,7 +1276,11 @@ fn ptr_out_of_range() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 
@@ -573,7 +1290,15 @@ fn ptr_out_of_range() {
 #[test]
 fn limit_to_steps_specified() {
     let instrs = parse("++++").unwrap();
-    let final_state = execute(&instrs, 2).0;
+    let final_state = execute(
+        &instrs,
+        2,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -581,7 +1306,11 @@ fn limit_to_steps_specified() {
             start_instr: Some(&instrs[2]),
             cells: vec![Wrapping(2)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -589,7 +1318,15 @@ fn limit_to_steps_specified() {
 #[test]
 fn write_executed() {
     let instrs = parse("+.").unwrap();
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -597,7 +1334,11 @@ fn write_executed() {
             start_instr: None,
             cells: vec![Wrapping(1)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![1],
+            storage: Wrapping(0),
         }
     );
 }
@@ -605,7 +1346,15 @@ fn write_executed() {
 #[test]
 fn loop_executed() {
     let instrs = parse("++[-]").unwrap();
-    let final_state = execute(&instrs, max_steps()).0;
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -613,7 +1362,11 @@ fn loop_executed() {
             start_instr: None,
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -623,7 +1376,15 @@ fn loop_executed() {
 #[test]
 fn partially_execute_up_to_runtime_value() {
     let instrs = parse("+[[,]]").unwrap();
-    let final_state = execute(&instrs, 10).0;
+    let final_state = execute(
+        &instrs,
+        10,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     // Get the inner read instruction
     let start_instr = match instrs[1] {
@@ -648,7 +1409,11 @@ fn partially_execute_up_to_runtime_value() {
             start_instr: Some(start_instr),
             cells: vec![Wrapping(1)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -657,8 +1422,8 @@ fn partially_execute_up_to_runtime_value() {
 fn execute_read_with_dummy_value() {
     let instrs = parse(",").unwrap();
 
-    let mut state = ExecutionState::initial(&instrs[..]);
-    execute_with_state(&instrs[..], &mut state, 5, Some(1));
+    let mut state = ExecutionState::initial(&instrs[..], DEFAULT_TAPE_SIZE);
+    execute_with_state(&instrs[..], &mut state, 5, None, Some(1));
 
     assert_eq!(state.cells[0], Wrapping(1));
 }
@@ -668,10 +1433,32 @@ fn execute_read_with_dummy_value_nested_loop() {
     // Regression test.
     let instrs = parse("+[[,]]").unwrap();
 
-    let mut state = ExecutionState::initial(&instrs[..]);
-    let outcome = execute_with_state(&instrs[..], &mut state, 20, Some(0));
+    let mut state = ExecutionState::initial(&instrs[..], DEFAULT_TAPE_SIZE);
+    let outcome = execute_with_state(&instrs[..], &mut state, 20, None, Some(0));
+
+    assert!(matches!(outcome, Outcome::Completed(_)));
+}
+
+#[test]
+fn execute_with_input_reads_bytes_then_eof() {
+    let instrs = parse(",.,.,.").unwrap();
+
+    let mut state = ExecutionState::initial(&instrs[..], DEFAULT_TAPE_SIZE);
+    let outcome = execute_with_input(&instrs[..], &mut state, 10, b"ab", Eof::MinusOne);
 
     assert!(matches!(outcome, Outcome::Completed(_)));
+    assert_eq!(state.outputs, vec![97, 98, -1]);
+}
+
+#[test]
+fn execute_with_input_eof_unchanged() {
+    let instrs = parse("+++,.").unwrap();
+
+    let mut state = ExecutionState::initial(&instrs[..], DEFAULT_TAPE_SIZE);
+    let outcome = execute_with_input(&instrs[..], &mut state, 10, b"", Eof::Unchanged);
+
+    assert!(matches!(outcome, Outcome::Completed(_)));
+    assert_eq!(state.outputs, vec![3]);
 }
 
 /// Ensure that we have the correct InstrPosition when we finish
@@ -679,7 +1466,15 @@ fn execute_read_with_dummy_value_nested_loop() {
 #[test]
 fn partially_execute_complete_toplevel_loop() {
     let instrs = parse("+[-],").unwrap();
-    let final_state = execute(&instrs, 10).0;
+    let final_state = execute(
+        &instrs,
+        10,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -687,7 +1482,11 @@ fn partially_execute_complete_toplevel_loop() {
             start_instr: Some(&instrs[2]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -695,7 +1494,15 @@ fn partially_execute_complete_toplevel_loop() {
 #[test]
 fn partially_execute_up_to_step_limit() {
     let instrs = parse("+[++++]").unwrap();
-    let final_state = execute(&instrs, 3).0;
+    let final_state = execute(
+        &instrs,
+        3,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     let start_instr = match instrs[1] {
         Loop { ref body, .. } => &body[2],
@@ -708,7 +1515,11 @@ fn partially_execute_up_to_step_limit() {
             start_instr: Some(start_instr),
             cells: vec![Wrapping(3)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -718,7 +1529,15 @@ fn loop_up_to_step_limit() {
     let instrs = parse("++[-]").unwrap();
     // Assuming we take one step to enter the loop, we will execute
     // the loop body once.
-    let final_state = execute(&instrs, 4).0;
+    let final_state = execute(
+        &instrs,
+        4,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -726,7 +1545,11 @@ fn loop_up_to_step_limit() {
             start_instr: Some(&instrs[2]),
             cells: vec![Wrapping(1)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -736,7 +1559,15 @@ fn loop_with_read_body() {
     // We can't execute the whole loop, so our start instruction
     // should be the read.
     let instrs = parse("+[+,]").unwrap();
-    let final_state = execute(&instrs, 4).0;
+    let final_state = execute(
+        &instrs,
+        4,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     // Get the inner read instruction
     let start_instr = match instrs[1] {
@@ -756,7 +1587,11 @@ fn loop_with_read_body() {
             start_instr: Some(start_instr),
             cells: vec![Wrapping(2)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -764,7 +1599,15 @@ fn loop_with_read_body() {
 #[test]
 fn up_to_infinite_loop_executed() {
     let instrs = parse("++[]").unwrap();
-    let final_state = execute(&instrs, 20).0;
+    let final_state = execute(
+        &instrs,
+        20,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -772,7 +1615,11 @@ fn up_to_infinite_loop_executed() {
             start_instr: Some(&instrs[2]),
             cells: vec![Wrapping(2)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -780,7 +1627,15 @@ fn up_to_infinite_loop_executed() {
 #[test]
 fn up_to_nonempty_infinite_loop() {
     let instrs = parse("+[+]").unwrap();
-    let final_state = execute(&instrs, 20).0;
+    let final_state = execute(
+        &instrs,
+        20,
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
 
     assert_eq!(
         final_state,
@@ -788,7 +1643,11 @@ fn up_to_nonempty_infinite_loop() {
             start_instr: Some(&instrs[1]),
             cells: vec![Wrapping(11)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         }
     );
 }
@@ -796,7 +1655,15 @@ fn up_to_nonempty_infinite_loop() {
 #[test]
 fn quickcheck_cell_ptr_in_bounds() {
     fn cell_ptr_in_bounds(instrs: Vec<AstNode>) -> bool {
-        let state = execute(&instrs, 100).0;
+        let state = execute(
+            &instrs,
+            100,
+            CellWidth::Bits8,
+            Overflow::Wrap,
+            Bounds::Unchecked,
+            DEFAULT_TAPE_SIZE,
+        )
+        .0;
         (state.cell_ptr >= 0) && (state.cell_ptr < state.cells.len() as isize)
     }
     quickcheck(cell_ptr_in_bounds as fn(Vec<AstNode>) -> bool);
@@ -808,5 +1675,135 @@ fn arithmetic_error_nested_loops() {
     // mandlebrot.bf. Previously, if the first element in a loop was
     // another loop, we had arithmetic overflow.
     let instrs = parse("+[[>>>>>>>>>]+>>>>>>>>>-]").unwrap();
-    execute(&instrs, max_steps());
+    execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+}
+
+#[test]
+fn cells_wrap_at_cell_width() {
+    let instrs = parse("-").unwrap();
+
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+    assert_eq!(final_state.cells[0], Wrapping(-1));
+
+    // With 16-bit cells, `-` gives 65535, and one `+` takes it back
+    // to zero.
+    let instrs = parse("-[+>]").unwrap();
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits16,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+    assert_eq!(
+        final_state.cells[..2].to_vec(),
+        vec![Wrapping(0), Wrapping(0)]
+    );
+
+    let instrs = parse(&"+".repeat(256)).unwrap();
+    let final_state = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits16,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    )
+    .0;
+    assert_eq!(final_state.cells[0], Wrapping(256));
+}
+
+#[test]
+fn execute_overflow_abort() {
+    let instrs = parse("+.--").unwrap();
+    let (final_state, warning) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Abort,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+    // We stop at the second -, so the program aborts there at runtime.
+    assert_eq!(final_state.start_instr, Some(&instrs[3]));
+    assert_eq!(final_state.outputs, vec![1]);
+    assert_eq!(
+        warning.unwrap().position,
+        Some(Position { start: 3, end: 3 })
+    );
+}
+
+#[test]
+fn execute_overflow_saturate() {
+    let instrs = parse("-->+++[->+++<]").unwrap();
+    let mut state = ExecutionState::initial(&instrs, DEFAULT_TAPE_SIZE);
+    state.overflow = Overflow::Saturate;
+    state.cells[2] = Wrapping(-2);
+    execute_with_state(&instrs, &mut state, max_steps(), None, None);
+    assert_eq!(state.cells, vec![Wrapping(0), Wrapping(0), Wrapping(-1)]);
+}
+
+#[test]
+fn execute_bounds_wrap() {
+    // Moving left from cell #0 reaches the last cell, and a multiply
+    // loop's target wraps around too.
+    let instrs = parse("<+++[>>+<<-]").unwrap();
+    let (final_state, warning) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Wrap,
+        3,
+    );
+    assert_eq!(warning, None);
+    assert_eq!(final_state.cell_ptr, 2);
+    assert_eq!(
+        final_state.cells,
+        vec![Wrapping(0), Wrapping(3), Wrapping(0)]
+    );
+}
+
+#[test]
+fn execute_offset_off_tape() {
+    let instrs = vec![
+        Write { position: None },
+        Increment {
+            amount: Wrapping(1),
+            offset: -1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+    ];
+    let (final_state, warning) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Abort,
+        DEFAULT_TAPE_SIZE,
+    );
+    // We leave the increment to runtime, which checks the pointer.
+    assert_eq!(final_state.start_instr, Some(&instrs[1]));
+    assert_eq!(final_state.outputs, vec![0]);
+    assert_eq!(
+        warning.unwrap().position,
+        Some(Position { start: 1, end: 1 })
+    );
 }
@@ -0,0 +1,309 @@
+//! Formatting BF source for `bfc fmt`. We parse the source, keeping
+//! its comments, then lay out the same commands indented by loop
+//! depth, so the formatted program is identical to the original.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use std::iter::Peekable;
+use std::num::Wrapping;
+use std::vec::IntoIter;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, parse_with_comments, AstNode, Comment, ParseError};
+
+#[cfg(test)]
+use crate::bfir::parse;
+
+/// How many spaces we indent each loop level by.
+const INDENT_WIDTH: usize = 2;
+
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Wrap runs of commands so lines are at most this many
+    /// characters. Comments may make a line longer.
+    pub line_width: usize,
+    /// Start the comments at the end of consecutive lines in the
+    /// same column.
+    pub align_comments: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            line_width: 80,
+            align_comments: false,
+        }
+    }
+}
+
+/// A line of formatted output: commands, then an optional comment.
+struct Line {
+    depth: usize,
+    code: String,
+    comment: Option<String>,
+}
+
+struct Formatter<'a> {
+    options: &'a FormatOptions,
+    comments: Peekable<IntoIter<Comment>>,
+    lines: Vec<Line>,
+    /// The line we're currently adding commands to.
+    current: Line,
+}
+
+/// The BF command for an instruction from `parse_with_comments`.
+fn command(instr: &AstNode) -> char {
+    match *instr {
+        Increment {
+            amount: Wrapping(1),
+            ..
+        } => '+',
+        Increment { .. } => '-',
+        PointerIncrement { amount: 1, .. } => '>',
+        PointerIncrement { .. } => '<',
+        Read { .. } => ',',
+        Write { .. } => '.',
+        _ => unreachable!("Unexpected instruction in parsed source: {:?}", instr),
+    }
+}
+
+impl<'a> Formatter<'a> {
+    fn width(&self, depth: usize, len: usize) -> usize {
+        depth * INDENT_WIDTH + len
+    }
+
+    fn flush(&mut self) {
+        let depth = self.current.depth;
+        let line = std::mem::replace(
+            &mut self.current,
+            Line {
+                depth,
+                code: String::new(),
+                comment: None,
+            },
+        );
+        if !line.code.is_empty() || line.comment.is_some() {
+            self.lines.push(line);
+        }
+    }
+
+    fn set_depth(&mut self, depth: usize) {
+        self.flush();
+        self.current.depth = depth;
+    }
+
+    /// Add `text` to the current line, starting a new line if it
+    /// wouldn't fit.
+    fn push_code(&mut self, text: &str) {
+        let len = self.current.code.len() + text.len();
+        if !self.current.code.is_empty()
+            && self.width(self.current.depth, len) > self.options.line_width
+        {
+            self.flush();
+        }
+        self.current.code.push_str(text);
+    }
+
+    /// Add a comment. A comment that started on the same line as the
+    /// previous commands stays at the end of that line.
+    fn push_comment(&mut self, text: &str) {
+        let starts_line = text
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .any(|c| c == '\n');
+        if starts_line {
+            self.flush();
+        }
+
+        for piece in text
+            .lines()
+            .map(str::trim)
+            .filter(|piece| !piece.is_empty())
+        {
+            self.current.comment = Some(piece.to_owned());
+            self.flush();
+        }
+    }
+
+    /// Add the comments that start before `index` in the source.
+    fn push_comments_before(&mut self, index: usize) {
+        while self.has_comments_before(index) {
+            let comment = self.comments.next().unwrap();
+            self.push_comment(&comment.text);
+        }
+    }
+
+    fn has_comments_before(&mut self, index: usize) -> bool {
+        self.comments
+            .peek()
+            .map_or(false, |comment| comment.position.start < index)
+    }
+
+    /// The source of `body` on one line, if it has no nested loops.
+    fn inline_body(body: &[AstNode]) -> Option<String> {
+        body.iter()
+            .map(|instr| match *instr {
+                Loop { .. } => None,
+                _ => Some(command(instr)),
+            })
+            .collect()
+    }
+
+    fn push_instrs(&mut self, instrs: &[AstNode], depth: usize) {
+        for instr in instrs {
+            let position = get_position(instr).unwrap();
+            self.push_comments_before(position.start);
+
+            match *instr {
+                Loop { ref body, .. } => {
+                    // Keep short loops like [-] on one line.
+                    if let Some(body_source) = Formatter::inline_body(body) {
+                        let loop_source = format!("[{}]", body_source);
+                        if !self.has_comments_before(position.end)
+                            && self.width(depth, loop_source.len()) <= self.options.line_width
+                        {
+                            self.push_code(&loop_source);
+                            continue;
+                        }
+                    }
+
+                    self.push_code("[");
+                    self.set_depth(depth + 1);
+                    self.push_instrs(body, depth + 1);
+                    self.push_comments_before(position.end);
+                    self.set_depth(depth);
+                    self.push_code("]");
+                    self.flush();
+                }
+                _ => self.push_code(&command(instr).to_string()),
+            }
+        }
+    }
+
+    fn render(mut self) -> String {
+        self.push_comments_before(usize::MAX);
+        self.flush();
+
+        // With align_comments, each run of lines with both code and
+        // a comment starts its comments in the same column.
+        let mut columns = vec![0; self.lines.len()];
+        if self.options.align_comments {
+            let has_trailing_comment =
+                |line: &Line| !line.code.is_empty() && line.comment.is_some();
+            let mut start = 0;
+            while start < self.lines.len() {
+                let run_len = self.lines[start..]
+                    .iter()
+                    .take_while(|line| has_trailing_comment(line))
+                    .count();
+                let column = self.lines[start..start + run_len]
+                    .iter()
+                    .map(|line| self.width(line.depth, line.code.len()))
+                    .max()
+                    .unwrap_or(0);
+                for line_column in &mut columns[start..start + run_len] {
+                    *line_column = column;
+                }
+                start += run_len.max(1);
+            }
+        }
+
+        let mut result = String::new();
+        for (line, column) in self.lines.iter().zip(columns) {
+            let mut text = " ".repeat(line.depth * INDENT_WIDTH);
+            text.push_str(&line.code);
+            if let Some(ref comment) = line.comment {
+                if !line.code.is_empty() {
+                    let padding = column.saturating_sub(text.len());
+                    text.push_str(&" ".repeat(padding + 1));
+                }
+                text.push_str(comment);
+            }
+            result.push_str(&text);
+            result.push('\n');
+        }
+        result
+    }
+}
+
+/// Format BF source code, keeping its comments. The result has the
+/// same commands in the same order as `source`.
+pub fn format_source(source: &str, options: &FormatOptions) -> Result<String, Vec<ParseError>> {
+    let (instrs, comments) = parse_with_comments(source)?;
+
+    let mut formatter = Formatter {
+        options,
+        comments: comments.into_iter().peekable(),
+        lines: vec![],
+        current: Line {
+            depth: 0,
+            code: String::new(),
+            comment: None,
+        },
+    };
+    formatter.push_instrs(&instrs, 0);
+    Ok(formatter.render())
+}
+
+#[test]
+fn format_indents_loops() {
+    let source = "++[>+++[>++<-]<-]";
+    let options = FormatOptions {
+        line_width: 9,
+        align_comments: false,
+    };
+    assert_eq!(
+        format_source(source, &options).unwrap(),
+        "++[\n  >+++\n  [>++<-]\n  <-\n]\n"
+    );
+}
+
+#[test]
+fn format_wraps_long_lines() {
+    let options = FormatOptions {
+        line_width: 4,
+        align_comments: false,
+    };
+    assert_eq!(format_source("++++++>>", &options).unwrap(), "++++\n++>>\n");
+}
+
+#[test]
+fn format_keeps_comments() {
+    let source = "+++ set up\n\nprint it\n.";
+    assert_eq!(
+        format_source(source, &FormatOptions::default()).unwrap(),
+        "+++ set up\nprint it\n.\n"
+    );
+}
+
+#[test]
+fn format_aligns_comments() {
+    let source = "+ one\n+++ three\n";
+    let options = FormatOptions {
+        line_width: 80,
+        align_comments: true,
+    };
+    assert_eq!(
+        format_source(source, &options).unwrap(),
+        "+   one\n+++ three\n"
+    );
+}
+
+#[test]
+fn format_preserves_program() {
+    let source = "x+[ a >[-]< b\n [.>] ]c,";
+    let formatted = format_source(source, &FormatOptions::default()).unwrap();
+    let commands =
+        |source: &str| -> String { source.chars().filter(|c| "+-<>,.[]".contains(*c)).collect() };
+    assert_eq!(commands(&formatted), commands(source));
+    assert_eq!(
+        parse(&formatted).unwrap().len(),
+        parse(source).unwrap().len()
+    );
+}
+
+#[test]
+fn format_unbalanced() {
+    assert!(format_source("[", &FormatOptions::default()).is_err());
+}
@@ -0,0 +1,426 @@
+//! A text format for BF IR, so we can write IR by hand and read back
+//! what the optimiser produced.
+//!
+//! Each line holds one instruction. Offsets are written `@+1` and
+//! may be omitted when they're zero. Loop and If bodies go between
+//! braces, and `#` starts a comment:
+//!
+//! ```text
+//! Set 3 @+1
+//! Loop {
+//!   Mul {1:2, 2:3}
+//!   Inc -1
+//! }
+//! Ptr 2
+//! Scan -1
+//! SetRange 0 @-1 x4
+//! WriteConst [72, 105]
+//! ```
+//!
+//! Positions aren't part of the format, so parsed instructions have
+//! no position.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+#[cfg(test)]
+use quickcheck::quickcheck;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::num::Wrapping;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{AstNode, Cell, ParseError, Position};
+
+fn offset_text(offset: isize) -> String {
+    if offset == 0 {
+        String::new()
+    } else {
+        format!(" @{:+}", offset)
+    }
+}
+
+fn write_instrs(instrs: &[AstNode], indent: usize, text: &mut String) {
+    for instr in instrs {
+        let _ = write!(text, "{}", "  ".repeat(indent));
+        let _ = match *instr {
+            Increment { amount, offset, .. } => {
+                writeln!(text, "Inc {}{}", amount.0, offset_text(offset))
+            }
+            Set { amount, offset, .. } => writeln!(text, "Set {}{}", amount.0, offset_text(offset)),
+            SetRange {
+                amount,
+                offset,
+                length,
+                ..
+            } => writeln!(
+                text,
+                "SetRange {}{} x{}",
+                amount.0,
+                offset_text(offset),
+                length
+            ),
+            PointerIncrement { amount, .. } => writeln!(text, "Ptr {}", amount),
+            Scan { step, .. } => writeln!(text, "Scan {}", step),
+            Read { .. } => writeln!(text, "Read"),
+            Write { .. } => writeln!(text, "Write"),
+            Breakpoint { .. } => writeln!(text, "Breakpoint"),
+            Halt { .. } => writeln!(text, "Halt"),
+            Store { .. } => writeln!(text, "Store"),
+            Load { .. } => writeln!(text, "Load"),
+            WriteConst { ref values, .. } => writeln!(text, "WriteConst {:?}", values),
+            MultiplyMove { ref changes, .. } => {
+                let changes: Vec<_> = changes
+                    .iter()
+                    .map(|(offset, factor)| format!("{}:{}", offset, factor.0))
+                    .collect();
+                writeln!(text, "Mul {{{}}}", changes.join(", "))
+            }
+            Loop { ref body, .. } | If { ref body, .. } => {
+                let name = if let Loop { .. } = *instr {
+                    "Loop"
+                } else {
+                    "If"
+                };
+                let _ = writeln!(text, "{} {{", name);
+                write_instrs(body, indent + 1, text);
+                writeln!(text, "{}}}", "  ".repeat(indent))
+            }
+        };
+    }
+}
+
+/// Convert `instrs` to the text format.
+pub fn to_text(instrs: &[AstNode]) -> String {
+    let mut text = String::new();
+    write_instrs(instrs, 0, &mut text);
+    text
+}
+
+/// Parse a cell value. We accept both signed and unsigned 32-bit
+/// values. The optimiser wraps them to the cell width, so with 8-bit
+/// cells 255 and -1 are the same value.
+fn parse_cell(word: &str) -> Option<Cell> {
+    let value = word.parse::<i64>().ok()?;
+    if (i64::from(i32::MIN)..=i64::from(u32::MAX)).contains(&value) {
+        Some(Wrapping(value as i32))
+    } else {
+        None
+    }
+}
+
+/// Parse an output byte. As with cells, 255 and -1 are the same.
+fn parse_byte(word: &str) -> Option<i8> {
+    match word.parse::<i16>() {
+        Ok(value) if (-128..=255).contains(&value) => Some(value as i8),
+        _ => None,
+    }
+}
+
+fn parse_offset(word: &str) -> Option<isize> {
+    word.strip_prefix('@')?.parse().ok()
+}
+
+fn parse_length(word: &str) -> Option<usize> {
+    word.strip_prefix('x')?.parse().ok()
+}
+
+/// Parse the arguments after `Mul`, e.g. `{1:2, -1:3}`.
+fn parse_changes(args: &str) -> Option<BTreeMap<isize, Cell>> {
+    let args = args.trim();
+    if !args.starts_with('{') || !args.ends_with('}') {
+        return None;
+    }
+
+    let mut changes = BTreeMap::new();
+    let inner = args[1..args.len() - 1].trim();
+    if inner.is_empty() {
+        return Some(changes);
+    }
+    for change in inner.split(',') {
+        let mut parts = change.splitn(2, ':');
+        let offset = parts.next()?.trim().parse().ok()?;
+        let factor = parse_cell(parts.next()?.trim())?;
+        changes.insert(offset, factor);
+    }
+    Some(changes)
+}
+
+/// Parse the arguments after `WriteConst`, e.g. `[72, 105]`.
+fn parse_values(args: &str) -> Option<Vec<i8>> {
+    let args = args.trim();
+    if !args.starts_with('[') || !args.ends_with(']') {
+        return None;
+    }
+
+    let inner = args[1..args.len() - 1].trim();
+    if inner.is_empty() {
+        return Some(vec![]);
+    }
+    inner
+        .split(',')
+        .map(|value| parse_byte(value.trim()))
+        .collect()
+}
+
+/// Parse an amount and an optional offset, e.g. `3 @+1`.
+fn parse_amount_and_offset(words: &[&str]) -> Option<(Cell, isize)> {
+    match *words {
+        [amount] => Some((parse_cell(amount)?, 0)),
+        [amount, offset] => Some((parse_cell(amount)?, parse_offset(offset)?)),
+        _ => None,
+    }
+}
+
+/// Parse a single instruction that isn't a Loop or If.
+fn parse_instr(line: &str) -> Option<AstNode> {
+    let words: Vec<_> = line.split_whitespace().collect();
+    let position = None;
+
+    let instr = match words[0] {
+        "Inc" => {
+            let (amount, offset) = parse_amount_and_offset(&words[1..])?;
+            Increment {
+                amount,
+                offset,
+                position,
+            }
+        }
+        "Set" => {
+            let (amount, offset) = parse_amount_and_offset(&words[1..])?;
+            Set {
+                amount,
+                offset,
+                position,
+            }
+        }
+        "SetRange" => {
+            let (amount, offset, length) = match words[1..] {
+                [amount, length] => (parse_cell(amount)?, 0, parse_length(length)?),
+                [amount, offset, length] => (
+                    parse_cell(amount)?,
+                    parse_offset(offset)?,
+                    parse_length(length)?,
+                ),
+                _ => return None,
+            };
+            SetRange {
+                amount,
+                offset,
+                length,
+                position,
+            }
+        }
+        "Ptr" if words.len() == 2 => PointerIncrement {
+            amount: words[1].parse().ok()?,
+            position,
+        },
+        "Scan" if words.len() == 2 => Scan {
+            step: words[1].parse().ok()?,
+            position,
+        },
+        "Read" if words.len() == 1 => Read { position },
+        "Write" if words.len() == 1 => Write { position },
+        "Breakpoint" if words.len() == 1 => Breakpoint { position },
+        "Halt" if words.len() == 1 => Halt { position },
+        "Store" if words.len() == 1 => Store { position },
+        "Load" if words.len() == 1 => Load { position },
+        "WriteConst" => WriteConst {
+            values: parse_values(&line["WriteConst".len()..])?,
+            position,
+        },
+        "Mul" => MultiplyMove {
+            changes: parse_changes(&line["Mul".len()..])?,
+            position,
+        },
+        _ => return None,
+    };
+    Some(instr)
+}
+
+/// Parse IR in the text format. Errors report the position of the
+/// line that we couldn't parse.
+pub fn parse(text: &str) -> Result<Vec<AstNode>, ParseError> {
+    // Instructions in the current body (or toplevel).
+    let mut instrs = vec![];
+    // The instructions of enclosing bodies, the line that opened
+    // each body, and whether it's a Loop.
+    let mut stack = vec![];
+
+    let mut line_start = 0;
+    for raw_line in text.split('\n') {
+        let line_len = raw_line.chars().count();
+        let position = Position {
+            start: line_start,
+            end: line_start + line_len.saturating_sub(1),
+        };
+        line_start += line_len + 1;
+
+        let line = match raw_line.find('#') {
+            Some(index) => &raw_line[..index],
+            None => raw_line,
+        };
+        let line = line.trim();
+
+        match line {
+            "" => {}
+            "Loop {" | "If {" => {
+                stack.push((instrs, position, line == "Loop {"));
+                instrs = vec![];
+            }
+            "}" => match stack.pop() {
+                Some((mut parent_instrs, _, is_loop)) => {
+                    let body = instrs;
+                    parent_instrs.push(if is_loop {
+                        Loop {
+                            body,
+                            position: None,
+                        }
+                    } else {
+                        If {
+                            body,
+                            position: None,
+                        }
+                    });
+                    instrs = parent_instrs;
+                }
+                None => {
+                    return Err(ParseError {
+                        message: "This } has no matching {".to_owned(),
+                        position,
+                    });
+                }
+            },
+            _ => match parse_instr(line) {
+                Some(instr) => instrs.push(instr),
+                None => {
+                    return Err(ParseError {
+                        message: format!("Invalid instruction: {}", line),
+                        position,
+                    });
+                }
+            },
+        }
+    }
+
+    if let Some((_, position, _)) = stack.pop() {
+        return Err(ParseError {
+            message: "This { has no matching }".to_owned(),
+            position,
+        });
+    }
+
+    Ok(instrs)
+}
+
+#[test]
+fn parse_all_instructions() {
+    let text = "Inc 1\n\
+                Set 3 @+1\n\
+                SetRange 0 @-1 x4\n\
+                Ptr -2\n\
+                Scan 3\n\
+                Read\n\
+                Write\n\
+                Breakpoint\n\
+                Halt\n\
+                Store\n\
+                Load\n\
+                WriteConst [72, 105]\n\
+                Mul {-1:2, 2:255}\n";
+
+    let mut changes = BTreeMap::new();
+    changes.insert(-1, Wrapping(2));
+    changes.insert(2, Wrapping(255));
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(3),
+            offset: 1,
+            position: None,
+        },
+        SetRange {
+            amount: Wrapping(0),
+            offset: -1,
+            length: 4,
+            position: None,
+        },
+        PointerIncrement {
+            amount: -2,
+            position: None,
+        },
+        Scan {
+            step: 3,
+            position: None,
+        },
+        Read { position: None },
+        Write { position: None },
+        Breakpoint { position: None },
+        Halt { position: None },
+        Store { position: None },
+        Load { position: None },
+        WriteConst {
+            values: vec![72, 105],
+            position: None,
+        },
+        MultiplyMove {
+            changes,
+            position: None,
+        },
+    ];
+    assert_eq!(parse(text).unwrap(), expected);
+}
+
+#[test]
+fn parse_nested_bodies_and_comments() {
+    let text = "Loop { # outer\n  If {\n    Write\n  }\n}\n";
+    let expected = vec![Loop {
+        body: vec![If {
+            body: vec![Write { position: None }],
+            position: None,
+        }],
+        position: None,
+    }];
+    assert_eq!(parse(text).unwrap(), expected);
+}
+
+#[test]
+fn parse_invalid_instruction() {
+    let error = parse("Read\nInc x\n").unwrap_err();
+    assert_eq!(error.message, "Invalid instruction: Inc x");
+    assert_eq!(error.position, Position { start: 5, end: 9 });
+}
+
+#[test]
+fn parse_unbalanced_braces() {
+    assert_eq!(
+        parse("Loop {\nRead\n").unwrap_err().message,
+        "This { has no matching }"
+    );
+    assert_eq!(
+        parse("Read\n}\n").unwrap_err().message,
+        "This } has no matching {"
+    );
+}
+
+#[test]
+fn to_text_nested() {
+    let instrs = crate::bfir::parse("+>[-<.]").unwrap();
+    assert_eq!(
+        to_text(&instrs),
+        "Inc 1\nPtr 1\nLoop {\n  Inc -1\n  Ptr -1\n  Write\n}\n"
+    );
+}
+
+#[test]
+fn quickcheck_text_round_trips() {
+    fn round_trips(instrs: Vec<AstNode>) -> bool {
+        let text = to_text(&instrs);
+        to_text(&parse(&text).unwrap()) == text
+    }
+    quickcheck(round_trips as fn(Vec<AstNode>) -> bool);
+}
@@ -0,0 +1,196 @@
+//! JSON export of BF IR, for tools that want to consume bfc's
+//! analysis.
+//!
+//! Each instruction is an object with a `type` field naming its
+//! AstNode variant, the variant's fields, and a `position` that is
+//! either null or an inclusive `{"start": ..., "end": ...}` range of
+//! characters in the source.
+//!
+//! We also describe warnings and errors as JSON, for
+//! `--message-format=json`.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::num::Wrapping;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Position};
+use crate::diagnostics::{Info, Level};
+
+#[cfg(test)]
+use crate::bfir::parse;
+#[cfg(test)]
+use crate::diagnostics::Location;
+
+fn position_json(position: Option<Position>) -> String {
+    match position {
+        Some(position) => format!(
+            "{{\"start\": {}, \"end\": {}}}",
+            position.start, position.end
+        ),
+        None => "null".to_owned(),
+    }
+}
+
+fn instr_json(instr: &AstNode) -> String {
+    let fields = match *instr {
+        Increment { amount, offset, .. } => format!(
+            "\"type\": \"Increment\", \"amount\": {}, \"offset\": {}",
+            amount.0, offset
+        ),
+        PointerIncrement { amount, .. } => {
+            format!("\"type\": \"PointerIncrement\", \"amount\": {}", amount)
+        }
+        Scan { step, .. } => format!("\"type\": \"Scan\", \"step\": {}", step),
+        Read { .. } => "\"type\": \"Read\"".to_owned(),
+        Write { .. } => "\"type\": \"Write\"".to_owned(),
+        Breakpoint { .. } => "\"type\": \"Breakpoint\"".to_owned(),
+        Halt { .. } => "\"type\": \"Halt\"".to_owned(),
+        Store { .. } => "\"type\": \"Store\"".to_owned(),
+        Load { .. } => "\"type\": \"Load\"".to_owned(),
+        Loop { ref body, .. } => format!("\"type\": \"Loop\", \"body\": {}", to_json(body)),
+        If { ref body, .. } => format!("\"type\": \"If\", \"body\": {}", to_json(body)),
+        Set { amount, offset, .. } => format!(
+            "\"type\": \"Set\", \"amount\": {}, \"offset\": {}",
+            amount.0, offset
+        ),
+        MultiplyMove { ref changes, .. } => {
+            let changes: Vec<_> = changes
+                .iter()
+                .map(|(offset, factor)| {
+                    format!("{{\"offset\": {}, \"factor\": {}}}", offset, factor.0)
+                })
+                .collect();
+            format!(
+                "\"type\": \"MultiplyMove\", \"changes\": [{}]",
+                changes.join(", ")
+            )
+        }
+        WriteConst { ref values, .. } => {
+            format!("\"type\": \"WriteConst\", \"values\": {:?}", values)
+        }
+        SetRange {
+            amount,
+            offset,
+            length,
+            ..
+        } => format!(
+            "\"type\": \"SetRange\", \"amount\": {}, \"offset\": {}, \"length\": {}",
+            amount.0, offset, length
+        ),
+    };
+
+    format!(
+        "{{{}, \"position\": {}}}",
+        fields,
+        position_json(get_position(instr))
+    )
+}
+
+/// Convert `instrs` to a JSON array of instructions.
+pub fn to_json(instrs: &[AstNode]) -> String {
+    let instrs: Vec<_> = instrs.iter().map(instr_json).collect();
+    format!("[{}]", instrs.join(", "))
+}
+
+/// `text` as a JSON string literal.
+pub fn quote(text: &str) -> String {
+    let mut result = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Describe a warning or error as a JSON object. `line` and `column`
+/// start from 1, as they do in the messages we print.
+pub fn info_json(info: &Info) -> String {
+    let level = match info.level {
+        Level::Note => "note",
+        Level::Warning => "warning",
+        Level::Error => "error",
+    };
+    let (line, column) = match (info.position, info.location) {
+        (Some(_), Some(location)) => (
+            (location.line + 1).to_string(),
+            (location.column + 1).to_string(),
+        ),
+        _ => ("null".to_owned(), "null".to_owned()),
+    };
+    format!(
+        "{{\"reason\": \"message\", \"file\": {}, \"level\": \"{}\", \"message\": {}, \
+         \"line\": {}, \"column\": {}, \"position\": {}}}",
+        quote(&info.filename),
+        level,
+        quote(&info.message),
+        line,
+        column,
+        position_json(info.position)
+    )
+}
+
+#[test]
+fn json_nested_loop() {
+    let instrs = parse("+[.]").unwrap();
+    assert_eq!(
+        to_json(&instrs),
+        "[{\"type\": \"Increment\", \"amount\": 1, \"offset\": 0, \
+         \"position\": {\"start\": 0, \"end\": 0}}, \
+         {\"type\": \"Loop\", \"body\": [{\"type\": \"Write\", \
+         \"position\": {\"start\": 2, \"end\": 2}}], \
+         \"position\": {\"start\": 1, \"end\": 3}}]"
+    );
+}
+
+#[test]
+fn json_multiply_move() {
+    let mut changes = BTreeMap::new();
+    changes.insert(-1, Wrapping(2));
+    changes.insert(3, Wrapping(-1));
+    let instrs = vec![MultiplyMove {
+        changes,
+        position: None,
+    }];
+    assert_eq!(
+        to_json(&instrs),
+        "[{\"type\": \"MultiplyMove\", \"changes\": [{\"offset\": -1, \"factor\": 2}, \
+         {\"offset\": 3, \"factor\": -1}], \"position\": null}]"
+    );
+}
+
+#[test]
+fn json_quote() {
+    assert_eq!(
+        quote("a \"b\"\\\n\u{1b}é"),
+        "\"a \\\"b\\\"\\\\\\n\\u001bé\""
+    );
+}
+
+#[test]
+fn json_info() {
+    let info = Info {
+        level: Level::Warning,
+        filename: "foo.bf".to_owned(),
+        message: "Unused".to_owned(),
+        position: Some(Position { start: 2, end: 3 }),
+        location: Some(Location::new("+\n-[.", 2)),
+        source: None,
+    };
+    assert_eq!(
+        info_json(&info),
+        "{\"reason\": \"message\", \"file\": \"foo.bf\", \"level\": \"warning\", \
+         \"message\": \"Unused\", \"line\": 2, \"column\": 1, \
+         \"position\": {\"start\": 2, \"end\": 3}}"
+    );
+}
@@ -1,33 +1,47 @@
 //! The LLVM module handles converting a BF AST to LLVM IR.
 
 use itertools::Itertools;
+use llvm_sys::analysis::{LLVMVerifierFailureAction, LLVMVerifyFunction, LLVMVerifyModule};
+use llvm_sys::bit_writer::LLVMWriteBitcodeToFile;
 use llvm_sys::core::*;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
 use llvm_sys::target_machine::*;
 use llvm_sys::transforms::pass_manager_builder::*;
-use llvm_sys::{LLVMBuilder, LLVMIntPredicate, LLVMModule};
+use llvm_sys::{
+    LLVMAttributeFunctionIndex, LLVMBuilder, LLVMIntPredicate, LLVMLinkage, LLVMModule,
+};
 
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_uint, c_ulonglong};
 use std::ptr::null_mut;
-use std::str;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::num::Wrapping;
+use std::rc::Rc;
+use std::sync::Once;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, Cell};
+use crate::bfir::{fingerprint, AstNode, Bounds, Cell, CellWidth, Eof, Overflow, Position};
+use crate::diagnostics::{Level, SourceMap};
 
 use crate::execution::ExecutionState;
+use crate::peephole::instr_count;
+use crate::profile::Profile;
 
 const LLVM_FALSE: LLVMBool = 0;
 const LLVM_TRUE: LLVMBool = 1;
 
 /// A struct that keeps ownership of all the strings we've passed to
 /// the LLVM API until we destroy the `LLVMModule`.
+///
+/// Each module has its own LLVM context, rather than using LLVM's
+/// global context, so we can build several modules at once on
+/// different threads.
 pub struct Module {
     module: *mut LLVMModule,
+    context: LLVMContextRef,
     strings: Vec<CString>,
 }
 
@@ -63,6 +77,50 @@ impl Module {
             module_string
         }
     }
+
+    /// Check that this module is valid LLVM IR. Invalid IR is a bug
+    /// in bfc, so the error says so, and includes the IR of the first
+    /// invalid function to make it easy to report.
+    pub fn verify(&self) -> Result<(), String> {
+        unsafe {
+            let mut message_ptr = null_mut();
+            let invalid = LLVMVerifyModule(
+                self.module,
+                LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                &mut message_ptr,
+            );
+            let message = if message_ptr.is_null() {
+                String::new()
+            } else {
+                take_llvm_message(message_ptr)
+            };
+            if invalid == 0 {
+                return Ok(());
+            }
+
+            let mut error = format!(
+                "Internal compiler error: bfc generated invalid LLVM IR. \
+                 Please report this as a bfc bug.\n{}",
+                message.trim_end()
+            );
+            let mut function = LLVMGetFirstFunction(self.module);
+            while !function.is_null() {
+                let invalid_function =
+                    LLVMVerifyFunction(function, LLVMVerifierFailureAction::LLVMReturnStatusAction)
+                        != 0;
+                if invalid_function {
+                    let function_ir = take_llvm_message(LLVMPrintValueToString(function));
+                    error.push_str(&format!(
+                        "\n\nIn this function:\n{}",
+                        function_ir.trim_end()
+                    ));
+                    break;
+                }
+                function = LLVMGetNextFunction(function);
+            }
+            Err(error)
+        }
+    }
 }
 
 impl Drop for Module {
@@ -70,6 +128,7 @@ impl Drop for Module {
         // Rust requires that drop() is a safe function.
         unsafe {
             LLVMDisposeModule(self.module);
+            LLVMContextDispose(self.context);
         }
     }
 }
@@ -81,11 +140,11 @@ struct Builder {
 }
 
 impl Builder {
-    /// Create a new Builder in LLVM's global context.
-    fn new() -> Self {
+    /// Create a new Builder in the context of `module`.
+    fn new(module: &Module) -> Self {
         unsafe {
             Builder {
-                builder: LLVMCreateBuilder(),
+                builder: LLVMCreateBuilderInContext(module.context),
             }
         }
     }
@@ -106,39 +165,199 @@ impl Drop for Builder {
     }
 }
 
+/// Options that control the code we generate.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Pad the tape to a multiple of `simd_width` cells, and use
+    /// vector operations for increments of adjacent cells.
+    pub experimental_simd: bool,
+    /// Compile loops that occur more than once as functions, and
+    /// call them, so we only generate their code once.
+    pub opt_size: bool,
+    /// Count how often each loop runs, and write the counts to this
+    /// file when the program exits.
+    pub profile_generate: Option<String>,
+    /// Loop counts from a previous run, used to tell LLVM which
+    /// branches are likely and to keep hot loops inline.
+    pub profile: Option<Rc<Profile>>,
+    /// With `opt_size`, loop bodies with fewer instructions than this
+    /// aren't worth the cost of a call.
+    pub min_outlined_body_len: usize,
+    /// The width of each cell on the tape.
+    pub cell_width: CellWidth,
+    /// Input baked into the executable. Reads consume these bytes,
+    /// then return EOF, instead of reading stdin.
+    pub input: Option<Vec<u8>>,
+    /// What happens when an increment takes a cell out of range.
+    pub overflow: Overflow,
+    /// The program's source, so runtime errors can say where in the
+    /// source they happened.
+    pub source_map: Option<Rc<SourceMap>>,
+    /// What reads store in the cell at EOF.
+    pub eof: Eof,
+    /// What happens when the pointer leaves the tape.
+    pub bounds: Bounds,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            experimental_simd: false,
+            opt_size: false,
+            profile_generate: None,
+            profile: None,
+            min_outlined_body_len: MIN_OUTLINED_BODY_LEN,
+            cell_width: CellWidth::default(),
+            input: None,
+            overflow: Overflow::default(),
+            source_map: None,
+            eof: Eof::default(),
+            bounds: Bounds::default(),
+        }
+    }
+}
+
+/// The number of cells we operate on at once with
+/// `--experimental-simd`: as many as fit in a 128-bit register, so
+/// 16 8-bit cells, but only 4 32-bit cells.
+fn simd_width(cell_width: CellWidth) -> usize {
+    (128 / cell_width.bits()) as usize
+}
+
+/// The default for `CompileOptions::min_outlined_body_len`.
+pub const MIN_OUTLINED_BODY_LEN: usize = 8;
+
+/// The number of cells, from cell #0, that a breakpoint prints.
+const BREAKPOINT_CELLS: usize = 10;
+
+/// A loop body without positions, and the function we compiled it
+/// to, if we have yet.
+type OutlinedBody = (Vec<AstNode>, Option<LLVMValueRef>);
+
+/// Loop bodies that occur more than once in the program, which we
+/// compile as functions with `--opt-size`.
+#[derive(Default)]
+struct OutlinedLoops {
+    /// Loop bodies grouped by `fingerprint`.
+    bodies: HashMap<u64, Vec<OutlinedBody>>,
+    functions_created: usize,
+}
+
+/// The counters we've added with `--profile-generate`.
+struct LoopCounter {
+    /// The start of the loop's position in the source.
+    start: usize,
+    /// How many times we've entered the loop.
+    entries: LLVMValueRef,
+    /// How many times we've executed the loop body.
+    iterations: LLVMValueRef,
+}
+
+/// The current cell index as an SSA value, and the basic block we
+/// can use it in. This saves us loading from and storing to
+/// cell_index_ptr for every instruction.
+#[derive(Clone)]
+struct KnownCellIndex {
+    bb: LLVMBasicBlockRef,
+    value: LLVMValueRef,
+    /// Have we stored `value` to cell_index_ptr? We only store it
+    /// when we leave the basic block.
+    spilled: bool,
+    /// Pointers to cells at offsets from `value`, so we only compute
+    /// each pointer once.
+    cell_ptrs: HashMap<isize, LLVMValueRef>,
+}
+
+impl KnownCellIndex {
+    fn new(bb: LLVMBasicBlockRef, value: LLVMValueRef, spilled: bool) -> Self {
+        KnownCellIndex {
+            bb,
+            value,
+            spilled,
+            cell_ptrs: HashMap::new(),
+        }
+    }
+
+    /// The same cell index and cell pointers, used from `bb`. The
+    /// block we computed them in must dominate `bb`.
+    fn in_block(&self, bb: LLVMBasicBlockRef) -> Self {
+        KnownCellIndex { bb, ..self.clone() }
+    }
+}
+
 #[derive(Clone)]
 struct CompileContext {
     cells: LLVMValueRef,
+    num_cells: usize,
     cell_index_ptr: LLVMValueRef,
+    cell_index: Rc<RefCell<Option<KnownCellIndex>>>,
     main_fn: LLVMValueRef,
+    options: CompileOptions,
+    outlined: Rc<RefCell<OutlinedLoops>>,
+    loop_counters: Rc<RefCell<Vec<LoopCounter>>>,
+    baked_input: Option<BakedInput>,
+    /// The value of the storage cell when runtime execution starts.
+    initial_storage: Cell,
+}
+
+/// The globals holding the input baked into the executable, and how
+/// many bytes we've read so far.
+#[derive(Clone, Copy)]
+struct BakedInput {
+    bytes: LLVMValueRef,
+    len: usize,
+    index: LLVMValueRef,
 }
 
 /// Convert this integer to LLVM's representation of a constant
 /// integer.
-unsafe fn int8(val: c_ulonglong) -> LLVMValueRef {
-    LLVMConstInt(LLVMInt8Type(), val, LLVM_FALSE)
+unsafe fn int8(module: &Module, val: c_ulonglong) -> LLVMValueRef {
+    LLVMConstInt(int8_type(module), val, LLVM_FALSE)
 }
 /// Convert this integer to LLVM's representation of a constant
 /// integer.
 // TODO: this should be a machine word size rather than hard-coding 32-bits.
-fn int32(val: c_ulonglong) -> LLVMValueRef {
-    unsafe { LLVMConstInt(LLVMInt32Type(), val, LLVM_FALSE) }
+fn int32(module: &Module, val: c_ulonglong) -> LLVMValueRef {
+    unsafe { LLVMConstInt(int32_type(module), val, LLVM_FALSE) }
+}
+
+fn int64(module: &Module, val: c_ulonglong) -> LLVMValueRef {
+    unsafe { LLVMConstInt(int64_type(module), val, LLVM_FALSE) }
+}
+
+fn int1_type(module: &Module) -> LLVMTypeRef {
+    unsafe { LLVMInt1TypeInContext(module.context) }
+}
+
+fn int8_type(module: &Module) -> LLVMTypeRef {
+    unsafe { LLVMInt8TypeInContext(module.context) }
+}
+
+fn int32_type(module: &Module) -> LLVMTypeRef {
+    unsafe { LLVMInt32TypeInContext(module.context) }
+}
+
+fn int64_type(module: &Module) -> LLVMTypeRef {
+    unsafe { LLVMInt64TypeInContext(module.context) }
 }
 
-fn int1_type() -> LLVMTypeRef {
-    unsafe { LLVMInt1Type() }
+fn int8_ptr_type(module: &Module) -> LLVMTypeRef {
+    unsafe { LLVMPointerType(int8_type(module), 0) }
 }
 
-fn int8_type() -> LLVMTypeRef {
-    unsafe { LLVMInt8Type() }
+/// The LLVM type of a single cell.
+fn cell_type(module: &Module, width: CellWidth) -> LLVMTypeRef {
+    unsafe { LLVMIntTypeInContext(module.context, width.bits()) }
 }
 
-fn int32_type() -> LLVMTypeRef {
-    unsafe { LLVMInt32Type() }
+fn cell_ptr_type(module: &Module, width: CellWidth) -> LLVMTypeRef {
+    unsafe { LLVMPointerType(cell_type(module, width), 0) }
 }
 
-fn int8_ptr_type() -> LLVMTypeRef {
-    unsafe { LLVMPointerType(LLVMInt8Type(), 0) }
+/// Convert this cell value to LLVM's representation of a constant
+/// cell.
+fn cell_const(module: &Module, value: Cell, width: CellWidth) -> LLVMValueRef {
+    unsafe { LLVMConstInt(cell_type(module, width), value.0 as c_ulonglong, LLVM_FALSE) }
 }
 
 fn add_function(
@@ -156,36 +375,49 @@ fn add_function(
 fn add_c_declarations(module: &mut Module) {
     let void;
     unsafe {
-        void = LLVMVoidType();
+        void = LLVMVoidTypeInContext(module.context);
     }
 
     add_function(
         module,
         "llvm.memset.p0i8.i32",
         &mut [
-            int8_ptr_type(),
-            int8_type(),
-            int32_type(),
-            int32_type(),
-            int1_type(),
+            int8_ptr_type(module),
+            int8_type(module),
+            int32_type(module),
+            int1_type(module),
         ],
         void,
     );
 
-    add_function(module, "malloc", &mut [int32_type()], int8_ptr_type());
+    add_function(
+        module,
+        "malloc",
+        &mut [int32_type(module)],
+        int8_ptr_type(module),
+    );
 
-    add_function(module, "free", &mut [int8_ptr_type()], void);
+    add_function(module, "free", &mut [int8_ptr_type(module)], void);
 
     add_function(
         module,
         "write",
-        &mut [int32_type(), int8_ptr_type(), int32_type()],
-        int32_type(),
+        &mut [
+            int32_type(module),
+            int8_ptr_type(module),
+            int32_type(module),
+        ],
+        int32_type(module),
     );
 
-    add_function(module, "putchar", &mut [int32_type()], int32_type());
+    add_function(
+        module,
+        "putchar",
+        &mut [int32_type(module)],
+        int32_type(module),
+    );
 
-    add_function(module, "getchar", &mut [], int32_type());
+    add_function(module, "getchar", &mut [], int32_type(module));
 }
 
 unsafe fn add_function_call(
@@ -195,7 +427,7 @@ unsafe fn add_function_call(
     args: &mut [LLVMValueRef],
     name: &str,
 ) -> LLVMValueRef {
-    let builder = Builder::new();
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
     let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
@@ -229,29 +461,63 @@ where
 }
 
 fn add_cells_init(
-    init_values: &[Wrapping<i8>],
+    init_values: &[Cell],
+    width: CellWidth,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
 ) -> LLVMValueRef {
-    let builder = Builder::new();
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
     unsafe {
-        // char* cells = malloc(num_cells);
-        let num_cells = int32(init_values.len() as c_ulonglong);
-        let mut malloc_args = vec![num_cells];
+        // char* cells = malloc(num_cells * sizeof(cell));
+        let cell_bytes = width.bits() as usize / 8;
+        let num_bytes = int32(module, (init_values.len() * cell_bytes) as c_ulonglong);
+        let mut malloc_args = vec![num_bytes];
         let cells_ptr = add_function_call(module, bb, "malloc", &mut malloc_args, "cells");
 
-        let one = int32(1);
-        let false_ = LLVMConstInt(int1_type(), 1, LLVM_FALSE);
+        let false_ = LLVMConstInt(int1_type(module), 1, LLVM_FALSE);
+
+        if width != CellWidth::Bits8 {
+            // memset can only set bytes, so zero the whole tape, then
+            // store the cells that aren't zero.
+            let mut memset_args = vec![cells_ptr, int8(module, 0), num_bytes, false_];
+            add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
+
+            let cells_ptr = LLVMBuildPointerCast(
+                builder.builder,
+                cells_ptr,
+                cell_ptr_type(module, width),
+                module.new_string_ptr("cells"),
+            );
+            for (index, cell_val) in init_values.iter().enumerate() {
+                if cell_val.0 == 0 {
+                    continue;
+                }
+                let mut offset_vec = vec![int32(module, index as c_ulonglong)];
+                let offset_cell_ptr = LLVMBuildGEP(
+                    builder.builder,
+                    cells_ptr,
+                    offset_vec.as_mut_ptr(),
+                    offset_vec.len() as u32,
+                    module.new_string_ptr("offset_cell_ptr"),
+                );
+                LLVMBuildStore(
+                    builder.builder,
+                    cell_const(module, *cell_val, width),
+                    offset_cell_ptr,
+                );
+            }
+            return cells_ptr;
+        }
 
         let mut offset = 0;
         for (cell_val, cell_count) in run_length_encode(init_values) {
-            let llvm_cell_val = int8(cell_val.0 as c_ulonglong);
-            let llvm_cell_count = int32(cell_count as c_ulonglong);
+            let llvm_cell_val = int8(module, cell_val.0 as c_ulonglong);
+            let llvm_cell_count = int32(module, cell_count as c_ulonglong);
 
             // TODO: factor out a build_gep function.
-            let mut offset_vec = vec![int32(offset as c_ulonglong)];
+            let mut offset_vec = vec![int32(module, offset as c_ulonglong)];
             let offset_cell_ptr = LLVMBuildGEP(
                 builder.builder,
                 cells_ptr,
@@ -260,8 +526,7 @@ fn add_cells_init(
                 module.new_string_ptr("offset_cell_ptr"),
             );
 
-            let mut memset_args =
-                vec![offset_cell_ptr, llvm_cell_val, llvm_cell_count, one, false_];
+            let mut memset_args = vec![offset_cell_ptr, llvm_cell_val, llvm_cell_count, false_];
             add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
 
             offset += cell_count;
@@ -272,11 +537,17 @@ fn add_cells_init(
 }
 
 fn add_cells_cleanup(module: &mut Module, bb: LLVMBasicBlockRef, cells: LLVMValueRef) {
-    let builder = Builder::new();
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
     unsafe {
         // free(cells);
+        let cells = LLVMBuildPointerCast(
+            builder.builder,
+            cells,
+            int8_ptr_type(module),
+            module.new_string_ptr("cells_bytes"),
+        );
         let mut free_args = vec![cells];
         add_function_call(module, bb, "free", &mut free_args, "");
     }
@@ -286,12 +557,15 @@ fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
     let c_module_name = CString::new(module_name).unwrap();
     let module_name_char_ptr = c_module_name.to_bytes_with_nul().as_ptr() as *const _;
 
+    let context;
     let llvm_module;
     unsafe {
-        llvm_module = LLVMModuleCreateWithName(module_name_char_ptr);
+        context = LLVMContextCreate();
+        llvm_module = LLVMModuleCreateWithNameInContext(module_name_char_ptr, context);
     }
     let mut module = Module {
         module: llvm_module,
+        context,
         strings: vec![c_module_name],
     };
 
@@ -316,7 +590,7 @@ fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
 fn add_main_fn(module: &mut Module) -> LLVMValueRef {
     let mut main_args = vec![];
     unsafe {
-        let main_type = LLVMFunctionType(int32_type(), main_args.as_mut_ptr(), 0, LLVM_FALSE);
+        let main_type = LLVMFunctionType(int32_type(module), main_args.as_mut_ptr(), 0, LLVM_FALSE);
         // TODO: use add_function() here instead.
         LLVMAddFunction(module.module, module.new_string_ptr("main"), main_type)
     }
@@ -330,10 +604,15 @@ fn add_initial_bbs(
     unsafe {
         // This basic block is empty, but we will add a branch during
         // compilation according to InstrPosition.
-        let init_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("init"));
+        let init_bb =
+            LLVMAppendBasicBlockInContext(module.context, main_fn, module.new_string_ptr("init"));
 
         // We'll begin by appending instructions here.
-        let beginning_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("beginning"));
+        let beginning_bb = LLVMAppendBasicBlockInContext(
+            module.context,
+            main_fn,
+            module.new_string_ptr("beginning"),
+        );
 
         (init_bb, beginning_bb)
     }
@@ -347,55 +626,283 @@ unsafe fn add_cell_index_init(
     bb: LLVMBasicBlockRef,
     module: &mut Module,
 ) -> LLVMValueRef {
-    let builder = Builder::new();
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
     // int cell_index = 0;
     let cell_index_ptr = LLVMBuildAlloca(
         builder.builder,
-        int32_type(),
+        int32_type(module),
         module.new_string_ptr("cell_index_ptr"),
     );
-    let cell_ptr_init = int32(init_value as c_ulonglong);
+    let cell_ptr_init = int32(module, init_value as c_ulonglong);
     LLVMBuildStore(builder.builder, cell_ptr_init, cell_index_ptr);
 
     cell_index_ptr
 }
 
 /// Add prologue to main function.
-unsafe fn add_main_cleanup(bb: LLVMBasicBlockRef) {
-    let builder = Builder::new();
+unsafe fn add_main_cleanup(module: &Module, bb: LLVMBasicBlockRef) {
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
-    let zero = int32(0);
+    let zero = int32(module, 0);
     LLVMBuildRet(builder.builder, zero);
 }
 
-/// Add LLVM IR instructions for accessing the current cell, and
-/// return a reference to the current cell, and to a current cell pointer.
-unsafe fn add_current_cell_access(
+/// Return the current cell index as an SSA value. We only load it
+/// from cell_index_ptr if we don't already know it in this basic
+/// block.
+unsafe fn current_cell_index(
     module: &mut Module,
     bb: LLVMBasicBlockRef,
-    cells: LLVMValueRef,
-    cell_index_ptr: LLVMValueRef,
-) -> (LLVMValueRef, LLVMValueRef) {
-    let builder = Builder::new();
+    ctx: &CompileContext,
+) -> LLVMValueRef {
+    if let Some(ref known) = *ctx.cell_index.borrow() {
+        if known.bb == bb {
+            return known.value;
+        }
+        debug_assert!(
+            known.spilled,
+            "Left a basic block without storing the cell index"
+        );
+    }
+
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
     let cell_index = LLVMBuildLoad(
         builder.builder,
-        cell_index_ptr,
+        ctx.cell_index_ptr,
         module.new_string_ptr("cell_index"),
     );
+    *ctx.cell_index.borrow_mut() = Some(KnownCellIndex::new(bb, cell_index, true));
+    cell_index
+}
+
+/// Store the current cell index to cell_index_ptr, if we haven't
+/// already. Other basic blocks load the cell index from
+/// cell_index_ptr, so we must call this before we branch out of
+/// `bb`.
+unsafe fn spill_cell_index(module: &Module, bb: LLVMBasicBlockRef, ctx: &CompileContext) {
+    if let Some(ref mut known) = *ctx.cell_index.borrow_mut() {
+        if known.bb == bb && !known.spilled {
+            let builder = Builder::new(module);
+            builder.position_at_end(bb);
+            LLVMBuildStore(builder.builder, known.value, ctx.cell_index_ptr);
+            known.spilled = true;
+        }
+    }
+}
+
+/// Return a pointer to the cell at `offset` from the current cell.
+/// `position` is the instruction accessing the cell, for bounds
+/// errors.
+unsafe fn cell_ptr(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    offset: isize,
+    position: Option<Position>,
+) -> LLVMValueRef {
+    let cell_index = current_cell_index(module, bb, ctx);
+    if let Some(ref known) = *ctx.cell_index.borrow() {
+        if let Some(ptr) = known.cell_ptrs.get(&offset) {
+            return *ptr;
+        }
+    }
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let offset_cell_index = offset_cell_index(
+        cell_index,
+        offset,
+        position,
+        "offset_cell_index",
+        module,
+        bb,
+        ctx,
+    );
 
-    let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
+    let mut indices = vec![offset_cell_index];
+    let ptr = LLVMBuildGEP(
         builder.builder,
-        cells,
+        ctx.cells,
         indices.as_mut_ptr(),
-        indices.len() as u32,
+        indices.len() as c_uint,
         module.new_string_ptr("current_cell_ptr"),
     );
+
+    if let Some(ref mut known) = *ctx.cell_index.borrow_mut() {
+        known.cell_ptrs.insert(offset, ptr);
+    }
+    ptr
+}
+
+/// Return the index of the cell `offset` cells from `cell_index`,
+/// computed in `bb` and called `name`. `cell_index` must be on the
+/// tape.
+///
+/// With `Bounds::Wrap` we wrap the index around the tape. With
+/// `Bounds::Abort` we exit with an error that points at `position`
+/// if the index is off the tape.
+unsafe fn offset_cell_index(
+    cell_index: LLVMValueRef,
+    offset: isize,
+    position: Option<Position>,
+    name: &str,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMValueRef {
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    match ctx.options.bounds {
+        Bounds::Wrap => {
+            let num_cells = ctx.num_cells as isize;
+            let offset = offset.rem_euclid(num_cells);
+            if offset == 0 {
+                return cell_index;
+            }
+            // The offset is now less than a whole tape, so we go
+            // past the end at most once.
+            let sum = LLVMBuildAdd(
+                builder.builder,
+                cell_index,
+                int32(module, offset as c_ulonglong),
+                module.new_string_ptr("unwrapped_cell_index"),
+            );
+            let past_end = LLVMBuildICmp(
+                builder.builder,
+                LLVMIntPredicate::LLVMIntUGE,
+                sum,
+                int32(module, num_cells as c_ulonglong),
+                module.new_string_ptr("past_end"),
+            );
+            let wrapped = LLVMBuildSub(
+                builder.builder,
+                sum,
+                int32(module, num_cells as c_ulonglong),
+                module.new_string_ptr("wrapped_cell_index"),
+            );
+            LLVMBuildSelect(
+                builder.builder,
+                past_end,
+                wrapped,
+                sum,
+                module.new_string_ptr(name),
+            )
+        }
+        Bounds::Unchecked | Bounds::Abort => {
+            if offset == 0 {
+                return cell_index;
+            }
+            let sum = LLVMBuildAdd(
+                builder.builder,
+                cell_index,
+                int32(module, offset as c_ulonglong),
+                module.new_string_ptr(name),
+            );
+            if ctx.options.bounds == Bounds::Abort {
+                let message = "This instruction moves the pointer off the tape, so the program \
+                               aborts.";
+                let message_bytes = runtime_error_format(message, position, ctx);
+                let message_ptr =
+                    add_const_bytes(module, &builder, &message_bytes, "bounds_message");
+
+                let check_fn = check_cell_index_fn(module, ctx);
+                let mut args = vec![sum, message_ptr];
+                builder.position_at_end(bb);
+                LLVMBuildCall(
+                    builder.builder,
+                    check_fn,
+                    args.as_mut_ptr(),
+                    args.len() as c_uint,
+                    module.new_string_ptr(""),
+                );
+            }
+            sum
+        }
+    }
+}
+
+/// Return the function that exits with the error message it's
+/// given if a cell index is off the tape, adding it if this is the
+/// first use.
+unsafe fn check_cell_index_fn(module: &mut Module, ctx: &CompileContext) -> LLVMValueRef {
+    let fn_name = "check_cell_index";
+    let existing = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+    if !existing.is_null() {
+        return existing;
+    }
+    declare_exit(module);
+    declare_dprintf(module);
+
+    // void check_cell_index(i32 index, i8* message)
+    let mut args = vec![int32_type(module), int8_ptr_type(module)];
+    let fn_type = LLVMFunctionType(
+        LLVMVoidTypeInContext(module.context),
+        args.as_mut_ptr(),
+        args.len() as u32,
+        LLVM_FALSE,
+    );
+    let function = LLVMAddFunction(module.module, module.new_string_ptr(fn_name), fn_type);
+    LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage);
+    let index = LLVMGetParam(function, 0);
+    LLVMSetValueName2(index, module.new_string_ptr("index"), "index".len());
+    let message = LLVMGetParam(function, 1);
+    LLVMSetValueName2(message, module.new_string_ptr("message"), "message".len());
+
+    let entry_bb =
+        LLVMAppendBasicBlockInContext(module.context, function, module.new_string_ptr("entry"));
+    let off_tape_bb =
+        LLVMAppendBasicBlockInContext(module.context, function, module.new_string_ptr("off_tape"));
+    let on_tape_bb =
+        LLVMAppendBasicBlockInContext(module.context, function, module.new_string_ptr("on_tape"));
+
+    let builder = Builder::new(module);
+    builder.position_at_end(entry_bb);
+    // Negative indexes are also too big when unsigned.
+    let is_off_tape = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntUGE,
+        index,
+        int32(module, ctx.num_cells as c_ulonglong),
+        module.new_string_ptr("is_off_tape"),
+    );
+    LLVMBuildCondBr(builder.builder, is_off_tape, off_tape_bb, on_tape_bb);
+
+    add_function_call(
+        module,
+        off_tape_bb,
+        "dprintf",
+        &mut [int32(module, 2), message],
+        "",
+    );
+    add_function_call(module, off_tape_bb, "exit", &mut [int32(module, 1)], "");
+    builder.position_at_end(off_tape_bb);
+    LLVMBuildUnreachable(builder.builder);
+
+    builder.position_at_end(on_tape_bb);
+    LLVMBuildRetVoid(builder.builder);
+
+    function
+}
+
+/// Add LLVM IR instructions for accessing the current cell, and
+/// return a reference to the current cell, and to a current cell pointer.
+unsafe fn add_current_cell_access(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> (LLVMValueRef, LLVMValueRef) {
+    let current_cell_ptr = cell_ptr(module, bb, ctx, 0, None);
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
     let current_cell = LLVMBuildLoad(
         builder.builder,
         current_cell_ptr,
@@ -408,34 +915,15 @@ unsafe fn add_current_cell_access(
 unsafe fn compile_increment(
     amount: Cell,
     offset: isize,
+    position: Option<Position>,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
-
-    let offset_cell_index = LLVMBuildAdd(
-        builder.builder,
-        cell_index,
-        int32(offset as c_ulonglong),
-        module.new_string_ptr("offset_cell_index"),
-    );
-
-    let mut indices = vec![offset_cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as c_uint,
-        module.new_string_ptr("current_cell_ptr"),
-    );
+    let current_cell_ptr = cell_ptr(module, bb, &ctx, offset, position);
 
     let cell_val = LLVMBuildLoad(
         builder.builder,
@@ -443,7 +931,18 @@ unsafe fn compile_increment(
         module.new_string_ptr("cell_value"),
     );
 
-    let increment_amount = int8(amount.0 as c_ulonglong);
+    if ctx.options.overflow != Overflow::Wrap {
+        let message = "This instruction makes a cell overflow, so the program aborts.";
+        let amount = int64(module, i64::from(amount.0) as c_ulonglong);
+        let (new_cell_val, bb) =
+            compile_checked_add(cell_val, amount, message, position, module, bb, &ctx);
+
+        builder.position_at_end(bb);
+        LLVMBuildStore(builder.builder, new_cell_val, current_cell_ptr);
+        return bb;
+    }
+
+    let increment_amount = cell_const(module, amount, ctx.options.cell_width);
     let new_cell_val = LLVMBuildAdd(
         builder.builder,
         cell_val,
@@ -455,100 +954,371 @@ unsafe fn compile_increment(
     bb
 }
 
-unsafe fn compile_set(
-    amount: Cell,
-    offset: isize,
+/// Add `amount`, an i64, to `cell_val` when cells don't wrap, and
+/// return the new cell value and the basic block to continue in.
+///
+/// With `Overflow::Saturate` we clamp the sum to the cell's range.
+/// With `Overflow::Abort` we branch to a block that prints `message`
+/// with the source location of `position` and exits.
+unsafe fn compile_checked_add(
+    cell_val: LLVMValueRef,
+    amount: LLVMValueRef,
+    message: &str,
+    position: Option<Position>,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
-    ctx: CompileContext,
-) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
+    ctx: &CompileContext,
+) -> (LLVMValueRef, LLVMBasicBlockRef) {
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
+    let width = ctx.options.cell_width;
+    // Cells are unsigned, so zero extend them. The sum can't
+    // overflow an i64, even with 32-bit cells.
+    let wide_cell_val = LLVMBuildZExt(
         builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
+        cell_val,
+        int64_type(module),
+        module.new_string_ptr("wide_cell_value"),
     );
-
-    let offset_cell_index = LLVMBuildAdd(
+    let sum = LLVMBuildAdd(
         builder.builder,
-        cell_index,
-        int32(offset as c_ulonglong),
-        module.new_string_ptr("offset_cell_index"),
+        wide_cell_val,
+        amount,
+        module.new_string_ptr("wide_new_cell_value"),
     );
+    let max = int64(module, width.max_value() as c_ulonglong);
 
-    let mut indices = vec![offset_cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
+    let (new_val, bb) = match ctx.options.overflow {
+        Overflow::Saturate => {
+            let too_big = LLVMBuildICmp(
+                builder.builder,
+                LLVMIntPredicate::LLVMIntSGT,
+                sum,
+                max,
+                module.new_string_ptr("too_big"),
+            );
+            let too_small = LLVMBuildICmp(
+                builder.builder,
+                LLVMIntPredicate::LLVMIntSLT,
+                sum,
+                int64(module, 0),
+                module.new_string_ptr("too_small"),
+            );
+            let at_most_max = LLVMBuildSelect(
+                builder.builder,
+                too_big,
+                max,
+                sum,
+                module.new_string_ptr("at_most_max"),
+            );
+            let clamped = LLVMBuildSelect(
+                builder.builder,
+                too_small,
+                int64(module, 0),
+                at_most_max,
+                module.new_string_ptr("clamped"),
+            );
+            (clamped, bb)
+        }
+        Overflow::Abort => {
+            // Negative sums are also greater than max when unsigned.
+            let overflows = LLVMBuildICmp(
+                builder.builder,
+                LLVMIntPredicate::LLVMIntUGT,
+                sum,
+                max,
+                module.new_string_ptr("overflows"),
+            );
+            let overflow_bb = LLVMAppendBasicBlockInContext(
+                module.context,
+                ctx.main_fn,
+                module.new_string_ptr("overflow"),
+            );
+            let no_overflow_bb = LLVMAppendBasicBlockInContext(
+                module.context,
+                ctx.main_fn,
+                module.new_string_ptr("no_overflow"),
+            );
+            LLVMBuildCondBr(builder.builder, overflows, overflow_bb, no_overflow_bb);
+
+            compile_overflow_abort(message, position, module, overflow_bb, ctx);
+
+            // bb dominates no_overflow_bb, so we can keep using the
+            // index and cell pointers from bb.
+            if let Some(ref mut known) = *ctx.cell_index.borrow_mut() {
+                known.bb = no_overflow_bb;
+            }
+            (sum, no_overflow_bb)
+        }
+        Overflow::Wrap => unreachable!(),
+    };
+
+    builder.position_at_end(bb);
+    let new_cell_val = LLVMBuildTrunc(
         builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as c_uint,
-        module.new_string_ptr("current_cell_ptr"),
+        new_val,
+        cell_type(module, width),
+        module.new_string_ptr("new_cell_value"),
     );
+    (new_cell_val, bb)
+}
 
-    LLVMBuildStore(
-        builder.builder,
-        int8(amount.0 as c_ulonglong),
-        current_cell_ptr,
+/// The null-terminated printf format for a runtime error: `message`
+/// with the source location of `position`.
+fn runtime_error_format(
+    message: &str,
+    position: Option<Position>,
+    ctx: &CompileContext,
+) -> Vec<i8> {
+    let error = match ctx.options.source_map {
+        Some(ref source_map) => {
+            let info = source_map.info(Level::Error, message.to_owned(), position);
+            format!("{}: error: {}", info.location_text(), message)
+        }
+        None => format!("error: {}", message),
+    };
+    // The message is a printf format, so escape any % in paths.
+    let format = format!("{}\n\0", error.replace('%', "%%"));
+    format.bytes().map(|byte| byte as i8).collect()
+}
+
+/// Print `message` and the source location of `position` to stderr,
+/// then exit with an error, in `bb`.
+unsafe fn compile_overflow_abort(
+    message: &str,
+    position: Option<Position>,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) {
+    declare_exit(module);
+    declare_dprintf(module);
+
+    let format_bytes = runtime_error_format(message, position, ctx);
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+    let format_ptr = add_const_bytes(module, &builder, &format_bytes, "overflow_message");
+
+    add_function_call(
+        module,
+        bb,
+        "dprintf",
+        &mut [int32(module, 2), format_ptr],
+        "",
     );
-    bb
+    add_function_call(module, bb, "exit", &mut [int32(module, 1)], "");
+    builder.position_at_end(bb);
+    LLVMBuildUnreachable(builder.builder);
 }
 
-unsafe fn compile_multiply_move(
-    changes: &HashMap<isize, Cell>,
+/// Increment adjacent cells, starting at `offset`, using a single
+/// vector add.
+unsafe fn compile_vector_increment(
+    amounts: &[Cell],
+    offset: isize,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
-    let multiply_body = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("multiply_body"));
-    let multiply_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("multiply_after"));
-
-    let builder = Builder::new();
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
-    // First, get the current cell value.
-    let (cell_val, cell_val_ptr) =
-        add_current_cell_access(module, bb, ctx.cells, ctx.cell_index_ptr);
+    let current_cell_ptr = cell_ptr(module, bb, &ctx, offset, None);
 
-    // Check if the current cell is zero, as we only do the multiply
-    // if it's non-zero.
-    let zero = int8(0);
-    let cell_val_is_zero = LLVMBuildICmp(
+    let vector_type = LLVMVectorType(
+        cell_type(module, ctx.options.cell_width),
+        amounts.len() as c_uint,
+    );
+    let vector_ptr = LLVMBuildPointerCast(
         builder.builder,
-        LLVMIntPredicate::LLVMIntEQ,
-        zero,
-        cell_val,
-        module.new_string_ptr("cell_value_is_zero"),
+        current_cell_ptr,
+        LLVMPointerType(vector_type, 0),
+        module.new_string_ptr("cells_vector_ptr"),
     );
-    LLVMBuildCondBr(
+
+    // The cell index is arbitrary, so we can't assume any alignment.
+    let cells_val = LLVMBuildLoad(
         builder.builder,
-        cell_val_is_zero,
-        multiply_after,
-        multiply_body,
+        vector_ptr,
+        module.new_string_ptr("cells_vector"),
     );
+    LLVMSetAlignment(cells_val, 1);
 
-    // In the multiply body, do the mulitply
-    builder.position_at_end(multiply_body);
+    let mut llvm_amounts: Vec<_> = amounts
+        .iter()
+        .map(|amount| cell_const(module, *amount, ctx.options.cell_width))
+        .collect();
+    let increment_amounts =
+        LLVMConstVector(llvm_amounts.as_mut_ptr(), llvm_amounts.len() as c_uint);
 
-    // Zero the current cell.
-    LLVMBuildStore(builder.builder, int8(0), cell_val_ptr);
+    let new_cells_val = LLVMBuildAdd(
+        builder.builder,
+        cells_val,
+        increment_amounts,
+        module.new_string_ptr("new_cells_vector"),
+    );
 
-    let mut targets: Vec<_> = changes.keys().collect();
-    targets.sort();
+    let store = LLVMBuildStore(builder.builder, new_cells_val, vector_ptr);
+    LLVMSetAlignment(store, 1);
+    bb
+}
+
+unsafe fn compile_set(
+    amount: Cell,
+    offset: isize,
+    position: Option<Position>,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let current_cell_ptr = cell_ptr(module, bb, &ctx, offset, position);
+
+    LLVMBuildStore(
+        builder.builder,
+        cell_const(module, amount, ctx.options.cell_width),
+        current_cell_ptr,
+    );
+    bb
+}
+
+/// Set `length` adjacent cells, starting at `offset`. We use a single
+/// memset when every byte of the range gets the same value, i.e. for
+/// 8-bit cells or when zeroing, and a store per cell otherwise. We
+/// also store each cell when we check bounds, since the range may
+/// wrap around or leave the tape.
+unsafe fn compile_set_range(
+    amount: Cell,
+    offset: isize,
+    length: usize,
+    position: Option<Position>,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let width = ctx.options.cell_width;
+    if (width != CellWidth::Bits8 && amount.0 != 0) || ctx.options.bounds != Bounds::Unchecked {
+        for index in 0..length {
+            let target_cell_ptr = cell_ptr(module, bb, &ctx, offset + index as isize, position);
+            LLVMBuildStore(
+                builder.builder,
+                cell_const(module, amount, width),
+                target_cell_ptr,
+            );
+        }
+        return bb;
+    }
+
+    let current_cell_ptr = cell_ptr(module, bb, &ctx, offset, None);
+    let current_cell_ptr = LLVMBuildPointerCast(
+        builder.builder,
+        current_cell_ptr,
+        int8_ptr_type(module),
+        module.new_string_ptr("range_ptr"),
+    );
+
+    let num_bytes = length * width.bits() as usize / 8;
+    let is_volatile = LLVMConstInt(int1_type(module), 0, LLVM_FALSE);
+    let mut memset_args = vec![
+        current_cell_ptr,
+        int8(module, amount.0 as c_ulonglong),
+        int32(module, num_bytes as c_ulonglong),
+        is_volatile,
+    ];
+    add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
+    bb
+}
+
+unsafe fn compile_multiply_move(
+    changes: &BTreeMap<isize, Cell>,
+    position: Option<Position>,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let mut multiply_body = LLVMAppendBasicBlockInContext(
+        module.context,
+        ctx.main_fn,
+        module.new_string_ptr("multiply_body"),
+    );
+    let multiply_after = LLVMAppendBasicBlockInContext(
+        module.context,
+        ctx.main_fn,
+        module.new_string_ptr("multiply_after"),
+    );
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    // First, get the current cell value.
+    let (cell_val, cell_val_ptr) = add_current_cell_access(module, bb, &ctx);
+    let cell_index = current_cell_index(module, bb, &ctx);
+
+    // Check if the current cell is zero, as we only do the multiply
+    // if it's non-zero.
+    let zero = cell_const(module, Wrapping(0), ctx.options.cell_width);
+    let cell_val_is_zero = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        zero,
+        cell_val,
+        module.new_string_ptr("cell_value_is_zero"),
+    );
+    LLVMBuildCondBr(
+        builder.builder,
+        cell_val_is_zero,
+        multiply_after,
+        multiply_body,
+    );
+
+    // In the multiply body, do the mulitply
+    builder.position_at_end(multiply_body);
+
+    // Zero the current cell.
+    LLVMBuildStore(builder.builder, zero, cell_val_ptr);
 
     // For each cell that we should change, multiply the current cell
     // value then add it.
-    for target in targets {
-        // Calculate the position of this target cell.
-        let mut indices = vec![int32(*target as c_ulonglong)];
-        let target_cell_ptr = LLVMBuildGEP(
-            builder.builder,
-            cell_val_ptr,
-            indices.as_mut_ptr(),
-            indices.len() as c_uint,
-            module.new_string_ptr("target_cell_ptr"),
-        );
+    for (target, factor) in changes {
+        // Calculate the position of this target cell. We only check
+        // bounds if we get this far, as the loop doesn't run when
+        // the current cell is zero.
+        let target_cell_ptr = if ctx.options.bounds == Bounds::Unchecked {
+            let mut indices = vec![int32(module, *target as c_ulonglong)];
+            LLVMBuildGEP(
+                builder.builder,
+                cell_val_ptr,
+                indices.as_mut_ptr(),
+                indices.len() as c_uint,
+                module.new_string_ptr("target_cell_ptr"),
+            )
+        } else {
+            let target_index = offset_cell_index(
+                cell_index,
+                *target,
+                position,
+                "target_cell_index",
+                module,
+                multiply_body,
+                &ctx,
+            );
+            builder.position_at_end(multiply_body);
+            let mut indices = vec![target_index];
+            LLVMBuildGEP(
+                builder.builder,
+                ctx.cells,
+                indices.as_mut_ptr(),
+                indices.len() as c_uint,
+                module.new_string_ptr("target_cell_ptr"),
+            )
+        };
 
         // Get the current value of the target cell.
         let target_cell_val = LLVMBuildLoad(
@@ -557,12 +1327,40 @@ unsafe fn compile_multiply_move(
             module.new_string_ptr("target_cell_val"),
         );
 
+        if ctx.options.overflow != Overflow::Wrap {
+            let message = "This multiply loop makes a cell overflow, so the program aborts.";
+            let wide_cell_val = LLVMBuildZExt(
+                builder.builder,
+                cell_val,
+                int64_type(module),
+                module.new_string_ptr("wide_cell_value"),
+            );
+            let additional_val = LLVMBuildMul(
+                builder.builder,
+                wide_cell_val,
+                int64(module, i64::from(factor.0) as c_ulonglong),
+                module.new_string_ptr("additional_val"),
+            );
+            let (new_target_val, next_bb) = compile_checked_add(
+                target_cell_val,
+                additional_val,
+                message,
+                position,
+                module,
+                multiply_body,
+                &ctx,
+            );
+            multiply_body = next_bb;
+            builder.position_at_end(multiply_body);
+            LLVMBuildStore(builder.builder, new_target_val, target_cell_ptr);
+            continue;
+        }
+
         // Calculate the new value.
-        let factor = *changes.get(target).unwrap();
         let additional_val = LLVMBuildMul(
             builder.builder,
             cell_val,
-            int8(factor.0 as c_ulonglong),
+            cell_const(module, *factor, ctx.options.cell_width),
             module.new_string_ptr("additional_val"),
         );
         let new_target_val = LLVMBuildAdd(
@@ -577,223 +1375,1344 @@ unsafe fn compile_multiply_move(
     // Finally, continue execution from multiply after.
     LLVMBuildBr(builder.builder, multiply_after);
 
+    // The cell index is the same on both paths to multiply_after,
+    // and bb dominates multiply_after, so we can keep using the
+    // index and cell pointers from bb.
+    if let Some(ref mut known) = *ctx.cell_index.borrow_mut() {
+        known.bb = multiply_after;
+    }
+
     multiply_after
 }
 
-unsafe fn compile_ptr_increment(
-    amount: isize,
-    module: &mut Module,
-    bb: LLVMBasicBlockRef,
-    ctx: CompileContext,
-) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
-    builder.position_at_end(bb);
+unsafe fn compile_ptr_increment(
+    amount: isize,
+    position: Option<Position>,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let cell_index = current_cell_index(module, bb, &ctx);
+    let new_cell_index = offset_cell_index(
+        cell_index,
+        amount,
+        position,
+        "new_cell_index",
+        module,
+        bb,
+        &ctx,
+    );
+
+    // We store the new index when we leave this basic block.
+    *ctx.cell_index.borrow_mut() = Some(KnownCellIndex::new(bb, new_cell_index, false));
+    bb
+}
+
+unsafe fn compile_read(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let current_cell_ptr = cell_ptr(module, bb, &ctx, 0, None);
+
+    let input_char = match ctx.baked_input {
+        Some(baked_input) => read_baked_input(module, &builder, baked_input),
+        None => {
+            let mut getchar_args = vec![];
+            add_function_call(module, bb, "getchar", &mut getchar_args, "input_char")
+        }
+    };
+    let input_byte = LLVMBuildTrunc(
+        builder.builder,
+        input_char,
+        cell_type(module, ctx.options.cell_width),
+        module.new_string_ptr("input_byte"),
+    );
+
+    // getchar returns -1 at EOF, which truncates to -1 in the cell.
+    let new_cell_val = match ctx.options.eof {
+        Eof::MinusOne => input_byte,
+        Eof::Zero | Eof::Unchanged => {
+            let is_eof = LLVMBuildICmp(
+                builder.builder,
+                LLVMIntPredicate::LLVMIntEQ,
+                input_char,
+                int32(module, -1i32 as c_ulonglong),
+                module.new_string_ptr("is_eof"),
+            );
+            let eof_val = if ctx.options.eof == Eof::Zero {
+                cell_const(module, Wrapping(0), ctx.options.cell_width)
+            } else {
+                LLVMBuildLoad(
+                    builder.builder,
+                    current_cell_ptr,
+                    module.new_string_ptr("cell_value"),
+                )
+            };
+            LLVMBuildSelect(
+                builder.builder,
+                is_eof,
+                eof_val,
+                input_byte,
+                module.new_string_ptr("read_value"),
+            )
+        }
+    };
+
+    LLVMBuildStore(builder.builder, new_cell_val, current_cell_ptr);
+    bb
+}
+
+/// Add globals holding `input`, and the index of the next byte to
+/// read.
+unsafe fn add_baked_input(module: &mut Module, input: &[u8]) -> BakedInput {
+    let mut llvm_values: Vec<_> = input
+        .iter()
+        .map(|byte| int8(module, c_ulonglong::from(*byte)))
+        .collect();
+    // Pad with a zero, so we always have a byte to load, even at EOF.
+    llvm_values.push(int8(module, 0));
+
+    let bytes_type = LLVMArrayType(int8_type(module), llvm_values.len() as c_uint);
+    let bytes = LLVMAddGlobal(module.module, bytes_type, module.new_string_ptr("input"));
+    LLVMSetInitializer(
+        bytes,
+        LLVMConstArray(
+            int8_type(module),
+            llvm_values.as_mut_ptr(),
+            llvm_values.len() as c_uint,
+        ),
+    );
+    LLVMSetGlobalConstant(bytes, LLVM_TRUE);
+    LLVMSetLinkage(bytes, LLVMLinkage::LLVMInternalLinkage);
+
+    let index = LLVMAddGlobal(
+        module.module,
+        int32_type(module),
+        module.new_string_ptr("input_index"),
+    );
+    LLVMSetInitializer(index, int32(module, 0));
+    LLVMSetLinkage(index, LLVMLinkage::LLVMInternalLinkage);
+
+    BakedInput {
+        bytes,
+        len: input.len(),
+        index,
+    }
+}
+
+/// Return the next byte of the baked input as an i32, or -1 if we've
+/// read all of it, like getchar.
+unsafe fn read_baked_input(
+    module: &mut Module,
+    builder: &Builder,
+    input: BakedInput,
+) -> LLVMValueRef {
+    let len = int32(module, input.len as c_ulonglong);
+    let index = LLVMBuildLoad(
+        builder.builder,
+        input.index,
+        module.new_string_ptr("input_index"),
+    );
+    let in_range = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntULT,
+        index,
+        len,
+        module.new_string_ptr("input_in_range"),
+    );
+
+    // At EOF, we load the padding byte and leave the index alone.
+    let load_index = LLVMBuildSelect(
+        builder.builder,
+        in_range,
+        index,
+        len,
+        module.new_string_ptr("load_index"),
+    );
+    let mut indices = vec![int32(module, 0), load_index];
+    let byte_ptr = LLVMBuildGEP(
+        builder.builder,
+        input.bytes,
+        indices.as_mut_ptr(),
+        indices.len() as c_uint,
+        module.new_string_ptr("input_byte_ptr"),
+    );
+    let byte = LLVMBuildLoad(
+        builder.builder,
+        byte_ptr,
+        module.new_string_ptr("baked_byte"),
+    );
+    let byte = LLVMBuildZExt(
+        builder.builder,
+        byte,
+        int32_type(module),
+        module.new_string_ptr("baked_char"),
+    );
+
+    let next_index = LLVMBuildAdd(
+        builder.builder,
+        index,
+        int32(module, 1),
+        module.new_string_ptr("next_input_index"),
+    );
+    let next_index = LLVMBuildSelect(
+        builder.builder,
+        in_range,
+        next_index,
+        index,
+        module.new_string_ptr("new_input_index"),
+    );
+    LLVMBuildStore(builder.builder, next_index, input.index);
+
+    LLVMBuildSelect(
+        builder.builder,
+        in_range,
+        byte,
+        int32(module, -1i32 as c_ulonglong),
+        module.new_string_ptr("input_char"),
+    )
+}
+
+unsafe fn compile_write(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let cell_val = add_current_cell_access(module, bb, &ctx).0;
+    // Like putchar, we only write the low byte.
+    let cell_val_as_char = LLVMBuildSExtOrBitCast(
+        builder.builder,
+        cell_val,
+        int32_type(module),
+        module.new_string_ptr("cell_val_as_char"),
+    );
+
+    let mut putchar_args = vec![cell_val_as_char];
+    add_function_call(module, bb, "putchar", &mut putchar_args, "");
+    bb
+}
+
+/// Declare exit, if we haven't already. We only declare it in
+/// modules that need it.
+unsafe fn declare_exit(module: &mut Module) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("exit")).is_null() {
+        add_function(
+            module,
+            "exit",
+            &mut [int32_type(module)],
+            LLVMVoidTypeInContext(module.context),
+        );
+    }
+}
+
+/// Declare dprintf, if we haven't already. We only declare it in
+/// modules that need it.
+unsafe fn declare_dprintf(module: &mut Module) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("dprintf")).is_null() {
+        // int dprintf(int fd, const char *format, ...)
+        let mut dprintf_args = vec![int32_type(module), int8_ptr_type(module)];
+        let dprintf_type = LLVMFunctionType(
+            int32_type(module),
+            dprintf_args.as_mut_ptr(),
+            dprintf_args.len() as u32,
+            LLVM_TRUE,
+        );
+        LLVMAddFunction(
+            module.module,
+            module.new_string_ptr("dprintf"),
+            dprintf_type,
+        );
+    }
+}
+
+/// End the program, flushing any output, for `Halt`.
+unsafe fn compile_halt(module: &mut Module, bb: LLVMBasicBlockRef) -> LLVMBasicBlockRef {
+    declare_exit(module);
+    add_function_call(module, bb, "exit", &mut [int32(module, 0)], "");
+    bb
+}
+
+/// Return the global holding the storage cell that `Store` and
+/// `Load` use, adding it if this is the first use.
+unsafe fn storage_global(module: &mut Module, ctx: &CompileContext) -> LLVMValueRef {
+    let storage = LLVMGetNamedGlobal(module.module, module.new_string_ptr("storage"));
+    if !storage.is_null() {
+        return storage;
+    }
+
+    let width = ctx.options.cell_width;
+    let storage = LLVMAddGlobal(
+        module.module,
+        cell_type(module, width),
+        module.new_string_ptr("storage"),
+    );
+    LLVMSetInitializer(storage, cell_const(module, ctx.initial_storage, width));
+    LLVMSetLinkage(storage, LLVMLinkage::LLVMInternalLinkage);
+    storage
+}
+
+/// Copy the current cell to the storage cell.
+unsafe fn compile_store(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let storage = storage_global(module, &ctx);
+    let cell_val = add_current_cell_access(module, bb, &ctx).0;
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+    LLVMBuildStore(builder.builder, cell_val, storage);
+    bb
+}
+
+/// Copy the storage cell to the current cell.
+unsafe fn compile_load(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let storage = storage_global(module, &ctx);
+    let current_cell_ptr = cell_ptr(module, bb, &ctx, 0, None);
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+    let storage_val = LLVMBuildLoad(
+        builder.builder,
+        storage,
+        module.new_string_ptr("storage_value"),
+    );
+    LLVMBuildStore(builder.builder, storage_val, current_cell_ptr);
+    bb
+}
+
+/// Print the cell pointer and the first cells to stderr, e.g.
+/// "cell pointer: 1, cells: 3 0 255".
+unsafe fn compile_breakpoint(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    declare_dprintf(module);
+
+    let cell_index = current_cell_index(module, bb, &ctx);
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let num_cells = BREAKPOINT_CELLS.min(ctx.num_cells);
+    let format = format!("cell pointer: %d, cells:{}\n\0", " %u".repeat(num_cells));
+    let format_bytes: Vec<_> = format.bytes().map(|byte| byte as i8).collect();
+    let format_ptr = add_const_bytes(module, &builder, &format_bytes, "breakpoint_format");
+
+    let stderr_fd = int32(module, 2);
+    let mut args = vec![stderr_fd, format_ptr, cell_index];
+    for index in 0..num_cells {
+        let mut indices = vec![int32(module, index as c_ulonglong)];
+        let cell_ptr = LLVMBuildGEP(
+            builder.builder,
+            ctx.cells,
+            indices.as_mut_ptr(),
+            indices.len() as c_uint,
+            module.new_string_ptr("breakpoint_cell_ptr"),
+        );
+        let cell_val = LLVMBuildLoad(
+            builder.builder,
+            cell_ptr,
+            module.new_string_ptr("breakpoint_cell"),
+        );
+        // Print cells as unsigned, so 8-bit cells are 0 to 255.
+        args.push(LLVMBuildZExtOrBitCast(
+            builder.builder,
+            cell_val,
+            int32_type(module),
+            module.new_string_ptr("breakpoint_cell_val"),
+        ));
+    }
+    add_function_call(module, bb, "dprintf", &mut args, "");
+    bb
+}
+
+fn ptr_equal<T>(a: *const T, b: *const T) -> bool {
+    a == b
+}
+
+unsafe fn compile_loop(
+    loop_body: &[AstNode],
+    position: Option<Position>,
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let counter = match (&ctx.options.profile_generate, position) {
+        (Some(_), Some(position)) => Some(add_loop_counter(module, position.start, &ctx)),
+        _ => None,
+    };
+    if let Some((entries, _)) = counter {
+        add_counter_increment(module, bb, entries);
+    }
+
+    let loop_body_bb = LLVMAppendBasicBlockInContext(
+        module.context,
+        ctx.main_fn,
+        module.new_string_ptr("loop_body"),
+    );
+    let loop_after = LLVMAppendBasicBlockInContext(
+        module.context,
+        ctx.main_fn,
+        module.new_string_ptr("loop_after"),
+    );
+
+    // We generate rotated loops, testing the current cell before
+    // the first iteration and then at the end of every iteration.
+    // This saves a branch per iteration.
+    //
+    // bb:
+    //   %cell_value_is_zero = icmp ...
+    //   br %cell_value_is_zero, %loop_after, %loop_body
+    // loop_body:
+    //   ...
+    //   %cell_value_is_zero = icmp ...
+    //   br %cell_value_is_zero, %loop_after, %loop_body
+    compile_loop_test(module, bb, loop_after, loop_body_bb, &ctx);
+
+    if let Some((_, iterations)) = counter {
+        add_counter_increment(module, loop_body_bb, iterations);
+    }
+
+    // Recursively compile instructions in the loop body.
+    let loop_body_end_bb = compile_instrs(
+        loop_body,
+        start_instr,
+        module,
+        main_fn,
+        loop_body_bb,
+        ctx.clone(),
+    );
+
+    let latch_br = compile_loop_test(module, loop_body_end_bb, loop_after, loop_body_bb, &ctx);
+
+    // Every entry to the loop exits it once, and the entries that
+    // didn't exit at the first test went round at least once, so
+    // the profile tells us roughly how often the latch is taken.
+    if let Some(ref profile) = ctx.options.profile {
+        if let Some(counts) = profile.loop_counts(position) {
+            let repeats = counts.iterations.saturating_sub(counts.entries);
+            add_branch_weights(module, latch_br, counts.entries, repeats);
+        }
+    }
+
+    &mut *loop_after
+}
+
+/// Append a test of the current cell to bb, branching to
+/// `zero_bb` if it's zero and `nonzero_bb` otherwise. Returns the
+/// branch instruction.
+///
+/// We reach the blocks we branch to from more than one place, so
+/// they load the cell index from cell_index_ptr.
+unsafe fn compile_loop_test(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    zero_bb: LLVMBasicBlockRef,
+    nonzero_bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMValueRef {
+    let cell_val = add_current_cell_access(module, bb, ctx).0;
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let zero = cell_const(module, Wrapping(0), ctx.options.cell_width);
+    let cell_val_is_zero = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        zero,
+        cell_val,
+        module.new_string_ptr("cell_value_is_zero"),
+    );
+
+    spill_cell_index(module, bb, ctx);
+    builder.position_at_end(bb);
+    LLVMBuildCondBr(builder.builder, cell_val_is_zero, zero_bb, nonzero_bb)
+}
+
+/// Add zero-initialised counters for the entries and iterations of
+/// the loop starting at `start`, and remember them so we can write
+/// them out at exit.
+unsafe fn add_loop_counter(
+    module: &mut Module,
+    start: usize,
+    ctx: &CompileContext,
+) -> (LLVMValueRef, LLVMValueRef) {
+    let mut counters = ctx.loop_counters.borrow_mut();
+
+    let mut add_counter = |name: String| {
+        let global = LLVMAddGlobal(
+            module.module,
+            int64_type(module),
+            module.new_string_ptr(&name),
+        );
+        LLVMSetInitializer(global, int64(module, 0));
+        LLVMSetLinkage(global, LLVMLinkage::LLVMInternalLinkage);
+        global
+    };
+    let entries = add_counter(format!("loop{}_entries", counters.len()));
+    let iterations = add_counter(format!("loop{}_iterations", counters.len()));
+
+    counters.push(LoopCounter {
+        start,
+        entries,
+        iterations,
+    });
+    (entries, iterations)
+}
+
+/// Append instructions to bb that add one to `counter`.
+unsafe fn add_counter_increment(module: &mut Module, bb: LLVMBasicBlockRef, counter: LLVMValueRef) {
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let count = LLVMBuildLoad(builder.builder, counter, module.new_string_ptr("count"));
+    let new_count = LLVMBuildAdd(
+        builder.builder,
+        count,
+        int64(module, 1),
+        module.new_string_ptr("new_count"),
+    );
+    LLVMBuildStore(builder.builder, new_count, counter);
+}
+
+/// Tell LLVM how often the test at the end of a loop exits the
+/// loop and how often it goes round again, so it can lay out the
+/// hot path first.
+unsafe fn add_branch_weights(
+    module: &mut Module,
+    cond_br: LLVMValueRef,
+    exit_count: u64,
+    body_count: u64,
+) {
+    // Branch weights are 32-bit, so scale large counts down.
+    let scale = exit_count.max(body_count) / u64::from(u32::MAX) + 1;
+
+    let name = "branch_weights";
+    let mut weights = vec![
+        LLVMMDStringInContext(
+            module.context,
+            module.new_string_ptr(name),
+            name.len() as c_uint,
+        ),
+        int32(module, exit_count / scale),
+        int32(module, body_count / scale),
+    ];
+    let weights_node = LLVMMDNodeInContext(
+        module.context,
+        weights.as_mut_ptr(),
+        weights.len() as c_uint,
+    );
+
+    let prof_kind = LLVMGetMDKindIDInContext(
+        module.context,
+        module.new_string_ptr("prof"),
+        "prof".len() as c_uint,
+    );
+    LLVMSetMetadata(cond_br, prof_kind, weights_node);
+}
+
+/// Compile `Scan 1`, i.e. `[>]`, to a memchr call, which is much
+/// faster than checking one cell per iteration.
+///
+/// We don't handle other steps or wider cells, because memchr only
+/// finds adjacent bytes and memrchr isn't available on every target.
+///
+/// If there's no zero cell between the current cell and the end of
+/// the tape, the loop would have accessed cells out of range, which
+/// is undefined anyway.
+unsafe fn compile_forward_scan(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    // Only declare memchr in modules that need it.
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("memchr")).is_null() {
+        add_function(
+            module,
+            "memchr",
+            &mut [
+                int8_ptr_type(module),
+                int32_type(module),
+                int32_type(module),
+            ],
+            int8_ptr_type(module),
+        );
+    }
+
+    let cell_index = current_cell_index(module, bb, &ctx);
+    let current_cell_ptr = cell_ptr(module, bb, &ctx, 0, None);
+
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    // memchr(cells + cell_index, 0, num_cells - cell_index)
+    let remaining_cells = LLVMBuildSub(
+        builder.builder,
+        int32(module, ctx.num_cells as c_ulonglong),
+        cell_index,
+        module.new_string_ptr("remaining_cells"),
+    );
+    let zero_cell_ptr = add_function_call(
+        module,
+        bb,
+        "memchr",
+        &mut [current_cell_ptr, int32(module, 0), remaining_cells],
+        "zero_cell_ptr",
+    );
+
+    // The new cell index is the distance between the pointers. This
+    // is less than 2^32, so it's fine to truncate the addresses first.
+    let zero_cell_addr = LLVMBuildPtrToInt(
+        builder.builder,
+        zero_cell_ptr,
+        int32_type(module),
+        module.new_string_ptr("zero_cell_addr"),
+    );
+    let cells_addr = LLVMBuildPtrToInt(
+        builder.builder,
+        ctx.cells,
+        int32_type(module),
+        module.new_string_ptr("cells_addr"),
+    );
+    let new_cell_index = LLVMBuildSub(
+        builder.builder,
+        zero_cell_addr,
+        cells_addr,
+        module.new_string_ptr("new_cell_index"),
+    );
+
+    // We already have a pointer to the new current cell, so
+    // instructions that modify it, e.g. the `+` in `[>]+`, don't
+    // need to compute it again.
+    let mut known = KnownCellIndex::new(bb, new_cell_index, false);
+    known.cell_ptrs.insert(0, zero_cell_ptr);
+    *ctx.cell_index.borrow_mut() = Some(known);
+
+    bb
+}
+
+/// Compile an If. This is like a loop, but we don't need to test
+/// the current cell again at the end of the body, because the body
+/// always zeroes it.
+unsafe fn compile_if(
+    if_body: &[AstNode],
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new(module);
+
+    let mut if_body_bb = LLVMAppendBasicBlockInContext(
+        module.context,
+        ctx.main_fn,
+        module.new_string_ptr("if_body"),
+    );
+    let if_after = LLVMAppendBasicBlockInContext(
+        module.context,
+        ctx.main_fn,
+        module.new_string_ptr("if_after"),
+    );
+
+    // bb:
+    //   %cell_value = ...
+    //   %cell_value_is_zero = icmp ...
+    //   br %cell_value_is_zero, %if_after, %if_body
+    builder.position_at_end(bb);
+
+    let cell_val = add_current_cell_access(module, bb, &ctx).0;
+
+    let zero = cell_const(module, Wrapping(0), ctx.options.cell_width);
+    let cell_val_is_zero = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        zero,
+        cell_val,
+        module.new_string_ptr("cell_value_is_zero"),
+    );
+    spill_cell_index(module, bb, &ctx);
+    builder.position_at_end(bb);
+    LLVMBuildCondBr(builder.builder, cell_val_is_zero, if_after, if_body_bb);
+
+    let known_index = ctx.cell_index.borrow().clone().unwrap();
+    *ctx.cell_index.borrow_mut() = Some(known_index.in_block(if_body_bb));
+
+    if_body_bb = compile_instrs(
+        if_body,
+        start_instr,
+        module,
+        main_fn,
+        if_body_bb,
+        ctx.clone(),
+    );
+
+    spill_cell_index(module, if_body_bb, &ctx);
+    builder.position_at_end(if_body_bb);
+    LLVMBuildBr(builder.builder, if_after);
+
+    &mut *if_after
+}
+
+/// Append LLVM IR instructions to bb acording to the BF instruction
+/// passed in.
+unsafe fn compile_instr(
+    instr: &AstNode,
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    match *instr {
+        Increment {
+            amount,
+            offset,
+            position,
+        } => compile_increment(amount, offset, position, module, bb, ctx),
+        Set {
+            amount,
+            offset,
+            position,
+        } => compile_set(amount, offset, position, module, bb, ctx),
+        SetRange {
+            amount,
+            offset,
+            length,
+            position,
+        } => compile_set_range(amount, offset, length, position, module, bb, ctx),
+        MultiplyMove {
+            ref changes,
+            position,
+        } => compile_multiply_move(changes, position, module, bb, ctx),
+        PointerIncrement { amount, position } => {
+            compile_ptr_increment(amount, position, module, bb, ctx)
+        }
+        Read { .. } => compile_read(module, bb, ctx),
+        Write { .. } => compile_write(module, bb, ctx),
+        WriteConst { ref values, .. } => compile_write_const(values, module, bb),
+        Breakpoint { .. } => compile_breakpoint(module, bb, ctx),
+        Halt { .. } => compile_halt(module, bb),
+        Store { .. } => compile_store(module, bb, ctx),
+        Load { .. } => compile_load(module, bb, ctx),
+        Scan { step, position } => {
+            // memchr doesn't know where the tape ends, so we only
+            // use it when we don't check bounds.
+            if step == 1
+                && ctx.options.cell_width == CellWidth::Bits8
+                && ctx.options.bounds == Bounds::Unchecked
+            {
+                compile_forward_scan(module, bb, ctx)
+            } else {
+                let body = [PointerIncrement {
+                    amount: step,
+                    position,
+                }];
+                compile_loop(&body, position, start_instr, module, main_fn, bb, ctx)
+            }
+        }
+        Loop { ref body, position } => {
+            if should_outline(&ctx.options, position) {
+                if let Some(function) = outlined_loop_fn(body, position, start_instr, module, &ctx)
+                {
+                    return compile_outlined_loop_call(function, module, bb, ctx);
+                }
+            }
+            compile_loop(body, position, start_instr, module, main_fn, bb, ctx)
+        }
+        If { ref body, .. } => compile_if(body, start_instr, module, main_fn, bb, ctx),
+    }
+}
+
+/// Return a copy of `instrs` with all positions removed.
+fn without_positions(instrs: &[AstNode]) -> Vec<AstNode> {
+    instrs
+        .iter()
+        .map(|instr| {
+            let mut instr = match *instr {
+                Loop { ref body, .. } => Loop {
+                    body: without_positions(body),
+                    position: None,
+                },
+                If { ref body, .. } => If {
+                    body: without_positions(body),
+                    position: None,
+                },
+                ref other => other.clone(),
+            };
+            match instr {
+                Increment {
+                    ref mut position, ..
+                }
+                | PointerIncrement {
+                    ref mut position, ..
+                }
+                | Read { ref mut position }
+                | Write { ref mut position }
+                | Breakpoint { ref mut position }
+                | Halt { ref mut position }
+                | Store { ref mut position }
+                | Load { ref mut position }
+                | Set {
+                    ref mut position, ..
+                }
+                | MultiplyMove {
+                    ref mut position, ..
+                }
+                | WriteConst {
+                    ref mut position, ..
+                }
+                | SetRange {
+                    ref mut position, ..
+                }
+                | Scan {
+                    ref mut position, ..
+                }
+                | Loop {
+                    ref mut position, ..
+                }
+                | If {
+                    ref mut position, ..
+                } => *position = None,
+            }
+            instr
+        })
+        .collect()
+}
+
+/// Does `instrs`, or any loop body inside it, contain `instr`?
+fn contains_instr(instrs: &[AstNode], instr: &AstNode) -> bool {
+    instrs.iter().any(|other| {
+        ptr_equal(other, instr)
+            || match *other {
+                Loop { ref body, .. } | If { ref body, .. } => contains_instr(body, instr),
+                _ => false,
+            }
+    })
+}
+
+/// Should we compile the loop at `position` as a call, if it's
+/// repeated? We don't outline loops the profile shows are hot, as
+/// the call makes them slower, and we don't outline when profiling,
+/// so every copy of a loop gets its own counts.
+fn should_outline(options: &CompileOptions, position: Option<Position>) -> bool {
+    let is_hot = match options.profile {
+        Some(ref profile) => profile.is_hot(position),
+        None => false,
+    };
+    options.opt_size && options.profile_generate.is_none() && !is_hot
+}
+
+/// Can we compile the loop with this body as a call? We can't if
+/// execution starts in the middle of it.
+fn can_outline(body: &[AstNode], start_instr: &AstNode, options: &CompileOptions) -> bool {
+    instr_count(body) >= options.min_outlined_body_len && !contains_instr(body, start_instr)
+}
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
+/// Find the loop bodies in `instrs` that occur more than once.
+fn find_repeated_loops(
+    instrs: &[AstNode],
+    start_instr: &AstNode,
+    options: &CompileOptions,
+) -> OutlinedLoops {
+    fn count_bodies(
+        instrs: &[AstNode],
+        start_instr: &AstNode,
+        options: &CompileOptions,
+        counts: &mut HashMap<u64, Vec<(Vec<AstNode>, usize)>>,
+    ) {
+        for instr in instrs {
+            match *instr {
+                Loop { ref body, position } => {
+                    let outline = should_outline(options, position)
+                        && can_outline(body, start_instr, options);
+                    if outline {
+                        let body = without_positions(body);
+                        let bodies = counts.entry(fingerprint(&body)).or_default();
+                        match bodies.iter_mut().find(|(other, _)| *other == body) {
+                            Some((_, count)) => *count += 1,
+                            None => bodies.push((body, 1)),
+                        }
+                    }
+                    count_bodies(body, start_instr, options, counts);
+                }
+                If { ref body, .. } => count_bodies(body, start_instr, options, counts),
+                _ => {}
+            }
+        }
+    }
 
-    let new_cell_index = LLVMBuildAdd(
-        builder.builder,
-        cell_index,
-        int32(amount as c_ulonglong),
-        module.new_string_ptr("new_cell_index"),
-    );
+    let mut counts = HashMap::new();
+    count_bodies(instrs, start_instr, options, &mut counts);
+
+    let bodies = counts
+        .into_iter()
+        .map(|(hash, bodies)| {
+            let repeated = bodies
+                .into_iter()
+                .filter(|&(_, count)| count > 1)
+                .map(|(body, _)| (body, None))
+                .collect();
+            (hash, repeated)
+        })
+        .collect();
 
-    LLVMBuildStore(builder.builder, new_cell_index, ctx.cell_index_ptr);
-    bb
+    OutlinedLoops {
+        bodies,
+        functions_created: 0,
+    }
 }
 
-unsafe fn compile_read(
+/// If the loop with this body should be compiled as a call, return
+/// the function to call, compiling it if we haven't already.
+unsafe fn outlined_loop_fn(
+    body: &[AstNode],
+    position: Option<Position>,
+    start_instr: &AstNode,
     module: &mut Module,
-    bb: LLVMBasicBlockRef,
-    ctx: CompileContext,
-) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
-    builder.position_at_end(bb);
+    ctx: &CompileContext,
+) -> Option<LLVMValueRef> {
+    if !can_outline(body, start_instr, &ctx.options) {
+        return None;
+    }
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
+    let hash = fingerprint(body);
+    let stripped_body = without_positions(body);
+    let index = {
+        let outlined = ctx.outlined.borrow();
+        let bodies = outlined.bodies.get(&hash)?;
+        let index = bodies
+            .iter()
+            .position(|(other, _)| *other == stripped_body)?;
+        if let Some(function) = bodies[index].1 {
+            return Some(function);
+        }
+        index
+    };
+
+    // void outlined_loop(i8* cells, i32* cell_index_ptr)
+    let fn_name = format!("outlined_loop{}", ctx.outlined.borrow().functions_created);
+    let mut args = vec![
+        cell_ptr_type(module, ctx.options.cell_width),
+        LLVMPointerType(int32_type(module), 0),
+    ];
+    let fn_type = LLVMFunctionType(
+        LLVMVoidTypeInContext(module.context),
+        args.as_mut_ptr(),
+        args.len() as u32,
+        LLVM_FALSE,
     );
+    let function = LLVMAddFunction(module.module, module.new_string_ptr(&fn_name), fn_type);
+    LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage);
+
+    // Stop LLVM inlining the loop back into every caller.
+    let noinline = module.new_string_ptr("noinline");
+    let noinline_kind = LLVMGetEnumAttributeKindForName(noinline, "noinline".len());
+    let noinline_attr = LLVMCreateEnumAttribute(module.context, noinline_kind, 0);
+    LLVMAddAttributeAtIndex(function, LLVMAttributeFunctionIndex, noinline_attr);
+
+    {
+        let mut outlined = ctx.outlined.borrow_mut();
+        outlined.functions_created += 1;
+        outlined.bodies.get_mut(&hash).unwrap()[index].1 = Some(function);
+    }
 
-    let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as u32,
-        module.new_string_ptr("current_cell_ptr"),
+    let cells = LLVMGetParam(function, 0);
+    LLVMSetValueName2(cells, module.new_string_ptr("cells"), "cells".len());
+    let cell_index_ptr = LLVMGetParam(function, 1);
+    LLVMSetValueName2(
+        cell_index_ptr,
+        module.new_string_ptr("cell_index_ptr"),
+        "cell_index_ptr".len(),
     );
 
-    let mut getchar_args = vec![];
-    let input_char = add_function_call(module, bb, "getchar", &mut getchar_args, "input_char");
-    let input_byte = LLVMBuildTrunc(
-        builder.builder,
-        input_char,
-        int8_type(),
-        module.new_string_ptr("input_byte"),
+    let fn_ctx = CompileContext {
+        cells,
+        num_cells: ctx.num_cells,
+        cell_index_ptr,
+        cell_index: Rc::new(RefCell::new(None)),
+        main_fn: function,
+        options: ctx.options.clone(),
+        outlined: ctx.outlined.clone(),
+        loop_counters: ctx.loop_counters.clone(),
+        baked_input: ctx.baked_input,
+        initial_storage: ctx.initial_storage,
+    };
+
+    let entry_bb =
+        LLVMAppendBasicBlockInContext(module.context, function, module.new_string_ptr("entry"));
+    let after_bb = compile_loop(
+        body,
+        position,
+        start_instr,
+        module,
+        function,
+        entry_bb,
+        fn_ctx,
     );
 
-    LLVMBuildStore(builder.builder, input_byte, current_cell_ptr);
-    bb
+    let builder = Builder::new(module);
+    builder.position_at_end(after_bb);
+    LLVMBuildRetVoid(builder.builder);
+
+    Some(function)
 }
 
-unsafe fn compile_write(
+/// Call a loop that we've compiled as a function.
+unsafe fn compile_outlined_loop_call(
+    function: LLVMValueRef,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
+    // The loop reads the cell index from cell_index_ptr.
+    spill_cell_index(module, bb, &ctx);
+
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
 
-    let cell_val = add_current_cell_access(module, bb, ctx.cells, ctx.cell_index_ptr).0;
-    let cell_val_as_char = LLVMBuildSExt(
+    let mut args = vec![ctx.cells, ctx.cell_index_ptr];
+    LLVMBuildCall(
         builder.builder,
-        cell_val,
-        int32_type(),
-        module.new_string_ptr("cell_val_as_char"),
+        function,
+        args.as_mut_ptr(),
+        args.len() as c_uint,
+        module.new_string_ptr(""),
     );
 
-    let mut putchar_args = vec![cell_val_as_char];
-    add_function_call(module, bb, "putchar", &mut putchar_args, "");
+    // The loop may have moved the cell pointer.
+    *ctx.cell_index.borrow_mut() = None;
     bb
 }
 
-fn ptr_equal<T>(a: *const T, b: *const T) -> bool {
-    a == b
+/// Should we use vector operations? Vector adds always wrap, and we
+/// can't wrap or check the pointer for each cell in a vector.
+fn uses_simd(options: &CompileOptions) -> bool {
+    options.experimental_simd
+        && options.overflow == Overflow::Wrap
+        && options.bounds == Bounds::Unchecked
 }
 
-unsafe fn compile_loop(
-    loop_body: &[AstNode],
+/// If `instrs` starts with increments of adjacent cells, return
+/// how many increments we can combine into a single vector
+/// operation.
+fn vector_increment_len(instrs: &[AstNode], start_instr: &AstNode, cell_width: CellWidth) -> usize {
+    let mut prev_offset = match instrs.first() {
+        Some(&Increment { offset, .. }) => offset,
+        _ => return 0,
+    };
+
+    let mut len = 1;
+    for instr in instrs.iter().skip(1).take(simd_width(cell_width) - 1) {
+        match *instr {
+            // We can't start execution in the middle of a vector
+            // operation.
+            _ if ptr_equal(instr, start_instr) => break,
+            Increment { offset, .. } if offset == prev_offset + 1 => {
+                prev_offset = offset;
+                len += 1;
+            }
+            _ => break,
+        }
+    }
+    len
+}
+
+/// Append LLVM IR instructions to bb for every BF instruction in
+/// `instrs`, returning the basic block that execution continues in.
+unsafe fn compile_instrs(
+    instrs: &[AstNode],
     start_instr: &AstNode,
     module: &mut Module,
     main_fn: LLVMValueRef,
-    bb: LLVMBasicBlockRef,
+    mut bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
+    let mut index = 0;
+    while index < instrs.len() {
+        let instr = &instrs[index];
+        if ptr_equal(instr, start_instr) {
+            // This is the point we want to start execution from.
+            spill_cell_index(module, bb, &ctx);
+            bb = set_entry_point_after(module, main_fn, bb);
+        }
 
-    // First, we branch into the loop header from the previous basic
-    // block.
-    let loop_header_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_header"));
-    builder.position_at_end(bb);
-    LLVMBuildBr(builder.builder, loop_header_bb);
+        if uses_simd(&ctx.options) {
+            let run_len =
+                vector_increment_len(&instrs[index..], start_instr, ctx.options.cell_width);
+            if run_len > 1 {
+                let run = &instrs[index..index + run_len];
+                let amounts: Vec<_> = run
+                    .iter()
+                    .map(|instr| match *instr {
+                        Increment { amount, .. } => amount,
+                        _ => unreachable!(),
+                    })
+                    .collect();
+                let offset = match *instr {
+                    Increment { offset, .. } => offset,
+                    _ => unreachable!(),
+                };
 
-    let mut loop_body_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_body"));
-    let loop_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_after"));
+                bb = compile_vector_increment(&amounts, offset, module, bb, ctx.clone());
+                index += run_len;
+                continue;
+            }
+        }
 
-    // loop_header:
-    //   %cell_value = ...
-    //   %cell_value_is_zero = icmp ...
-    //   br %cell_value_is_zero, %loop_after, %loop_body
-    builder.position_at_end(loop_header_bb);
+        bb = compile_instr(instr, start_instr, module, main_fn, bb, ctx.clone());
+        index += 1;
+    }
+    bb
+}
 
-    let cell_val =
-        add_current_cell_access(module, &mut *loop_header_bb, ctx.cells, ctx.cell_index_ptr).0;
+/// Add a constant global array holding `values`, and return an i8*
+/// pointing to its first element.
+unsafe fn add_const_bytes(
+    module: &mut Module,
+    builder: &Builder,
+    values: &[i8],
+    name: &str,
+) -> LLVMValueRef {
+    let mut llvm_values = vec![];
+    for value in values {
+        llvm_values.push(int8(module, *value as c_ulonglong));
+    }
 
-    let zero = int8(0);
-    let cell_val_is_zero = LLVMBuildICmp(
-        builder.builder,
-        LLVMIntPredicate::LLVMIntEQ,
-        zero,
-        cell_val,
-        module.new_string_ptr("cell_value_is_zero"),
+    let buf_type = LLVMArrayType(int8_type(module), llvm_values.len() as c_uint);
+    let llvm_values_arr = LLVMConstArray(
+        int8_type(module),
+        llvm_values.as_mut_ptr(),
+        llvm_values.len() as c_uint,
     );
-    LLVMBuildCondBr(builder.builder, cell_val_is_zero, loop_after, loop_body_bb);
 
-    // Recursively compile instructions in the loop body.
-    for instr in loop_body {
-        if ptr_equal(instr, start_instr) {
-            // This is the point we want to start execution from.
-            loop_body_bb = set_entry_point_after(module, main_fn, loop_body_bb);
-        }
+    let global = LLVMAddGlobal(module.module, buf_type, module.new_string_ptr(name));
+    LLVMSetInitializer(global, llvm_values_arr);
+    LLVMSetGlobalConstant(global, LLVM_TRUE);
 
-        loop_body_bb = compile_instr(
-            instr,
-            start_instr,
+    LLVMBuildPointerCast(
+        builder.builder,
+        global,
+        int8_ptr_type(module),
+        module.new_string_ptr(&format!("{}_ptr", name)),
+    )
+}
+
+fn compile_static_outputs(module: &mut Module, bb: LLVMBasicBlockRef, outputs: &[i8]) {
+    unsafe {
+        let builder = Builder::new(module);
+        builder.position_at_end(bb);
+
+        let known_outputs_ptr = add_const_bytes(module, &builder, outputs, "known_outputs");
+
+        let stdout_fd = int32(module, 1);
+        let llvm_num_outputs = int32(module, outputs.len() as c_ulonglong);
+
+        add_function_call(
             module,
-            main_fn,
-            loop_body_bb,
-            ctx.clone(),
+            bb,
+            "write",
+            &mut [stdout_fd, known_outputs_ptr, llvm_num_outputs],
+            "",
         );
     }
+}
 
-    // When the loop is finished, jump back to the beginning of the
-    // loop.
-    builder.position_at_end(loop_body_bb);
-    LLVMBuildBr(builder.builder, loop_header_bb);
-
-    &mut *loop_after
+/// The name of the C global holding the `FILE *` for stdout on this
+/// module's target.
+unsafe fn stdout_symbol(module: &Module) -> &'static str {
+    let target_triple = CStr::from_ptr(LLVMGetTarget(module.module));
+    if target_triple.to_string_lossy().contains("apple") {
+        "__stdoutp"
+    } else {
+        "stdout"
+    }
 }
 
-/// Append LLVM IR instructions to bb acording to the BF instruction
-/// passed in.
-unsafe fn compile_instr(
-    instr: &AstNode,
-    start_instr: &AstNode,
+/// Write values known at compile time with a single fwrite call.
+///
+/// We can't use write() here (as we do for outputs before the first
+/// instruction), because Write uses putchar. stdout is buffered, so
+/// mixing the two would reorder our output.
+unsafe fn compile_write_const(
+    values: &[i8],
     module: &mut Module,
-    main_fn: LLVMValueRef,
     bb: LLVMBasicBlockRef,
-    ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
-    match *instr {
-        Increment { amount, offset, .. } => compile_increment(amount, offset, module, bb, ctx),
-        Set { amount, offset, .. } => compile_set(amount, offset, module, bb, ctx),
-        MultiplyMove { ref changes, .. } => compile_multiply_move(changes, module, bb, ctx),
-        PointerIncrement { amount, .. } => compile_ptr_increment(amount, module, bb, ctx),
-        Read { .. } => compile_read(module, bb, ctx),
-        Write { .. } => compile_write(module, bb, ctx),
-        Loop { ref body, .. } => compile_loop(body, start_instr, module, main_fn, bb, ctx),
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    // Only declare fwrite and stdout in modules that need them.
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("fwrite")).is_null() {
+        add_function(
+            module,
+            "fwrite",
+            &mut [
+                int8_ptr_type(module),
+                int32_type(module),
+                int32_type(module),
+                int8_ptr_type(module),
+            ],
+            int32_type(module),
+        );
+    }
+    let stdout_name = stdout_symbol(module);
+    let mut stdout_global = LLVMGetNamedGlobal(module.module, module.new_string_ptr(stdout_name));
+    if stdout_global.is_null() {
+        stdout_global = LLVMAddGlobal(
+            module.module,
+            int8_ptr_type(module),
+            module.new_string_ptr(stdout_name),
+        );
     }
+
+    let values_ptr = add_const_bytes(module, &builder, values, "const_output");
+    let stdout_ptr = LLVMBuildLoad(
+        builder.builder,
+        stdout_global,
+        module.new_string_ptr("stdout"),
+    );
+
+    add_function_call(
+        module,
+        bb,
+        "fwrite",
+        &mut [
+            values_ptr,
+            int32(module, 1),
+            int32(module, values.len() as c_ulonglong),
+            stdout_ptr,
+        ],
+        "",
+    );
+
+    bb
 }
 
-fn compile_static_outputs(module: &mut Module, bb: LLVMBasicBlockRef, outputs: &[i8]) {
-    unsafe {
-        let builder = Builder::new();
-        builder.position_at_end(bb);
+/// Write the loop counters to `path`, in the format that
+/// `Profile::parse` reads. If we can't open the file, we silently
+/// skip writing the profile, so the program still exits normally.
+unsafe fn compile_profile_dump(
+    path: &str,
+    counters: &[LoopCounter],
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+) -> LLVMBasicBlockRef {
+    add_function(
+        module,
+        "fopen",
+        &mut [int8_ptr_type(module), int8_ptr_type(module)],
+        int8_ptr_type(module),
+    );
+    add_function(
+        module,
+        "fclose",
+        &mut [int8_ptr_type(module)],
+        int32_type(module),
+    );
 
-        let mut llvm_outputs = vec![];
-        for value in outputs {
-            llvm_outputs.push(int8(*value as c_ulonglong));
-        }
+    // int fprintf(FILE *stream, const char *format, ...)
+    let mut fprintf_args = vec![int8_ptr_type(module), int8_ptr_type(module)];
+    let fprintf_type = LLVMFunctionType(
+        int32_type(module),
+        fprintf_args.as_mut_ptr(),
+        fprintf_args.len() as u32,
+        LLVM_TRUE,
+    );
+    LLVMAddFunction(
+        module.module,
+        module.new_string_ptr("fprintf"),
+        fprintf_type,
+    );
 
-        let output_buf_type = LLVMArrayType(int8_type(), llvm_outputs.len() as c_uint);
-        let llvm_outputs_arr = LLVMConstArray(
-            int8_type(),
-            llvm_outputs.as_mut_ptr(),
-            llvm_outputs.len() as c_uint,
-        );
+    let c_string = |s: &str| -> Vec<i8> { s.bytes().chain(Some(0)).map(|b| b as i8).collect() };
 
-        let known_outputs = LLVMAddGlobal(
-            module.module,
-            output_buf_type,
-            module.new_string_ptr("known_outputs"),
-        );
-        LLVMSetInitializer(known_outputs, llvm_outputs_arr);
-        LLVMSetGlobalConstant(known_outputs, LLVM_TRUE);
+    let builder = Builder::new(module);
+    builder.position_at_end(bb);
+
+    let path_ptr = add_const_bytes(module, &builder, &c_string(path), "profile_path");
+    let mode_ptr = add_const_bytes(module, &builder, &c_string("w"), "profile_mode");
+    let format_ptr = add_const_bytes(
+        module,
+        &builder,
+        &c_string("%llu %llu %llu\n"),
+        "profile_format",
+    );
+
+    let file = add_function_call(
+        module,
+        bb,
+        "fopen",
+        &mut [path_ptr, mode_ptr],
+        "profile_file",
+    );
+
+    let write_bb = LLVMAppendBasicBlockInContext(
+        module.context,
+        main_fn,
+        module.new_string_ptr("profile_write"),
+    );
+    let after_bb = LLVMAppendBasicBlockInContext(
+        module.context,
+        main_fn,
+        module.new_string_ptr("profile_after"),
+    );
 
-        let stdout_fd = int32(1);
-        let llvm_num_outputs = int32(outputs.len() as c_ulonglong);
+    let file_is_null = LLVMBuildIsNull(
+        builder.builder,
+        file,
+        module.new_string_ptr("profile_file_is_null"),
+    );
+    LLVMBuildCondBr(builder.builder, file_is_null, after_bb, write_bb);
 
-        let known_outputs_ptr = LLVMBuildPointerCast(
+    builder.position_at_end(write_bb);
+    for counter in counters {
+        let entries = LLVMBuildLoad(
             builder.builder,
-            known_outputs,
-            int8_ptr_type(),
-            module.new_string_ptr("known_outputs_ptr"),
+            counter.entries,
+            module.new_string_ptr("entries"),
+        );
+        let iterations = LLVMBuildLoad(
+            builder.builder,
+            counter.iterations,
+            module.new_string_ptr("iterations"),
         );
-
         add_function_call(
             module,
-            bb,
-            "write",
-            &mut [stdout_fd, known_outputs_ptr, llvm_num_outputs],
+            write_bb,
+            "fprintf",
+            &mut [
+                file,
+                format_ptr,
+                int64(module, counter.start as c_ulonglong),
+                entries,
+                iterations,
+            ],
             "",
         );
     }
+    add_function_call(module, write_bb, "fclose", &mut [file], "");
+    LLVMBuildBr(builder.builder, after_bb);
+
+    after_bb
 }
 
 /// Ensure that execution starts after the basic block we pass in.
@@ -802,10 +2721,11 @@ unsafe fn set_entry_point_after(
     main_fn: LLVMValueRef,
     bb: LLVMBasicBlockRef,
 ) -> LLVMBasicBlockRef {
-    let after_init_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("after_init"));
+    let after_init_bb =
+        LLVMAppendBasicBlockInContext(module.context, main_fn, module.new_string_ptr("after_init"));
 
     // From the current bb, we want to continue execution in after_init.
-    let builder = Builder::new();
+    let builder = Builder::new(module);
     builder.position_at_end(bb);
     LLVMBuildBr(builder.builder, after_init_bb);
 
@@ -823,11 +2743,13 @@ pub fn compile_to_module(
     target_triple: Option<String>,
     instrs: &[AstNode],
     initial_state: &ExecutionState,
+    options: &CompileOptions,
 ) -> Module {
     let mut module = create_module(module_name, target_triple);
     let main_fn = add_main_fn(&mut module);
 
     let (init_bb, mut bb) = add_initial_bbs(&mut module, main_fn);
+    let loop_counters = Rc::new(RefCell::new(vec![]));
 
     if !initial_state.outputs.is_empty() {
         compile_static_outputs(&mut module, init_bb, &initial_state.outputs);
@@ -840,64 +2762,140 @@ pub fn compile_to_module(
             Some(start_instr) => {
                 // TODO: decide on a consistent order between module and init_bb as
                 // parameters.
-                let llvm_cells = add_cells_init(&initial_state.cells, &mut module, init_bb);
+                let mut init_cells = initial_state.cells.clone();
+                if uses_simd(options) {
+                    // Pad the tape so it's a whole number of vectors.
+                    let width = simd_width(options.cell_width);
+                    let padded_len = init_cells.len().div_ceil(width) * width;
+                    init_cells.resize(padded_len, Wrapping(0));
+                }
+
+                let llvm_cells =
+                    add_cells_init(&init_cells, options.cell_width, &mut module, init_bb);
                 let llvm_cell_index =
                     add_cell_index_init(initial_state.cell_ptr, init_bb, &mut module);
 
                 let ctx = CompileContext {
                     cells: llvm_cells,
+                    num_cells: init_cells.len(),
                     cell_index_ptr: llvm_cell_index,
+                    cell_index: Rc::new(RefCell::new(None)),
                     main_fn,
+                    options: options.clone(),
+                    outlined: Rc::new(RefCell::new(if options.opt_size {
+                        find_repeated_loops(instrs, start_instr, options)
+                    } else {
+                        OutlinedLoops::default()
+                    })),
+                    loop_counters: loop_counters.clone(),
+                    baked_input: options
+                        .input
+                        .as_ref()
+                        .map(|input| add_baked_input(&mut module, input)),
+                    initial_storage: initial_state.storage,
                 };
 
-                for instr in instrs {
-                    if ptr_equal(instr, start_instr) {
-                        // This is the point we want to start execution from.
-                        bb = set_entry_point_after(&mut module, main_fn, bb);
-                    }
-
-                    bb = compile_instr(instr, start_instr, &mut module, main_fn, bb, ctx.clone());
-                }
+                bb = compile_instrs(instrs, start_instr, &mut module, main_fn, bb, ctx);
 
                 add_cells_cleanup(&mut module, bb, llvm_cells);
             }
             None => {
                 // We won't have called set_entry_point_after, so set
                 // the entry point.
-                let builder = Builder::new();
+                let builder = Builder::new(&module);
                 builder.position_at_end(init_bb);
                 LLVMBuildBr(builder.builder, bb);
             }
         }
 
-        add_main_cleanup(bb);
+        if let Some(ref path) = options.profile_generate {
+            bb = compile_profile_dump(path, &loop_counters.borrow(), &mut module, main_fn, bb);
+        }
+
+        add_main_cleanup(&module, bb);
 
         module
     }
 }
 
-pub fn optimise_ir(module: &mut Module, llvm_opt: i64) {
-    // TODO: add a verifier pass too.
-    unsafe {
-        let builder = LLVMPassManagerBuilderCreate();
-        // E.g. if llvm_opt is 3, we want a pass equivalent to -O3.
-        LLVMPassManagerBuilderSetOptLevel(builder, llvm_opt as u32);
+/// Wraps LLVM's pass managers, ensuring we always dispose them.
+struct PassManager {
+    function_pass_manager: LLVMPassManagerRef,
+    module_pass_manager: LLVMPassManagerRef,
+}
+
+impl PassManager {
+    /// Create pass managers with the passes equivalent to -O`llvm_opt`.
+    /// Like clang, we run function passes over each function before
+    /// running the module passes. We don't add an inliner, as it
+    /// would undo outlining large loop bodies.
+    fn new(module: &Module, llvm_opt: u8) -> Self {
+        unsafe {
+            let builder = LLVMPassManagerBuilderCreate();
+            // E.g. if llvm_opt is 3, we want a pass equivalent to -O3.
+            LLVMPassManagerBuilderSetOptLevel(builder, c_uint::from(llvm_opt));
+
+            let function_pass_manager = LLVMCreateFunctionPassManagerForModule(module.module);
+            LLVMPassManagerBuilderPopulateFunctionPassManager(builder, function_pass_manager);
+            let module_pass_manager = LLVMCreatePassManager();
+            LLVMPassManagerBuilderPopulateModulePassManager(builder, module_pass_manager);
 
-        let pass_manager = LLVMCreatePassManager();
-        LLVMPassManagerBuilderPopulateModulePassManager(builder, pass_manager);
+            LLVMPassManagerBuilderDispose(builder);
+
+            PassManager {
+                function_pass_manager,
+                module_pass_manager,
+            }
+        }
+    }
 
-        LLVMPassManagerBuilderDispose(builder);
+    fn run(&self, module: &mut Module) {
+        unsafe {
+            LLVMInitializeFunctionPassManager(self.function_pass_manager);
+            let mut function = LLVMGetFirstFunction(module.module);
+            while !function.is_null() {
+                LLVMRunFunctionPassManager(self.function_pass_manager, function);
+                function = LLVMGetNextFunction(function);
+            }
+            LLVMFinalizeFunctionPassManager(self.function_pass_manager);
 
-        // Run twice. This is a hack, we should really work out which
-        // optimisations need to run twice. See
-        // http://llvm.org/docs/Frontend/PerformanceTips.html#pass-ordering
-        LLVMRunPassManager(pass_manager, module.module);
-        LLVMRunPassManager(pass_manager, module.module);
+            LLVMRunPassManager(self.module_pass_manager, module.module);
+        }
+    }
+}
 
-        LLVMDisposePassManager(pass_manager);
+impl Drop for PassManager {
+    fn drop(&mut self) {
+        // Rust requires that drop() is a safe function.
+        unsafe {
+            LLVMDisposePassManager(self.function_pass_manager);
+            LLVMDisposePassManager(self.module_pass_manager);
+        }
     }
 }
 
+/// Optimise `module` with LLVM's passes for -O`llvm_opt`, which is 0
+/// to 3.
+pub fn optimise_ir(module: &mut Module, llvm_opt: u8) {
+    let pass_manager = PassManager::new(module, llvm_opt);
+
+    // Run twice. This is a hack, we should really work out which
+    // optimisations need to run twice. See
+    // http://llvm.org/docs/Frontend/PerformanceTips.html#pass-ordering
+    pass_manager.run(module);
+    pass_manager.run(module);
+}
+
+/// Convert an error message allocated by LLVM to a String, and free
+/// LLVM's copy.
+unsafe fn take_llvm_message(message_ptr: *mut i8) -> String {
+    let message = CStr::from_ptr(message_ptr as *const _)
+        .to_string_lossy()
+        .into_owned();
+    LLVMDisposeMessage(message_ptr);
+    message
+}
+
 pub fn get_default_target_triple() -> CString {
     let target_triple;
     unsafe {
@@ -909,12 +2907,51 @@ pub fn get_default_target_triple() -> CString {
     target_triple
 }
 
+/// The major version of LLVM that llvm-sys links against. llvm-sys
+/// 100 requires LLVM 10, which has no API to report its version.
+pub const LLVM_VERSION: &str = "10";
+
+/// The name of the host CPU, such as `skylake`.
+pub fn host_cpu_name() -> String {
+    unsafe { take_llvm_message(LLVMGetHostCPUName()) }
+}
+
+/// The name and description of each target this LLVM can generate
+/// code for. Call `init_llvm` first.
+pub fn targets() -> Vec<(String, String)> {
+    let mut targets = vec![];
+    unsafe {
+        let mut target = LLVMGetFirstTarget();
+        while !target.is_null() {
+            let name = CStr::from_ptr(LLVMGetTargetName(target));
+            let description = CStr::from_ptr(LLVMGetTargetDescription(target));
+            targets.push((
+                name.to_string_lossy().into_owned(),
+                description.to_string_lossy().into_owned(),
+            ));
+            target = LLVMGetNextTarget(target);
+        }
+    }
+    targets.sort();
+    targets
+}
+
 struct TargetMachine {
     tm: LLVMTargetMachineRef,
 }
 
+/// The code generator optimisation level matching -O`llvm_opt`.
+fn codegen_opt_level(llvm_opt: u8) -> LLVMCodeGenOptLevel {
+    match llvm_opt {
+        0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+        1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+        2 => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+        _ => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+    }
+}
+
 impl TargetMachine {
-    fn new(target_triple: *const i8) -> Result<Self, String> {
+    fn new(target_triple: *const i8, llvm_opt: u8) -> Result<Self, String> {
         let mut target = null_mut();
         let mut err_msg_ptr = null_mut();
         unsafe {
@@ -924,9 +2961,7 @@ impl TargetMachine {
                 // so it should have given us an error message.
                 assert!(!err_msg_ptr.is_null());
 
-                let err_msg_cstr = CStr::from_ptr(err_msg_ptr as *const _);
-                let err_msg = str::from_utf8(err_msg_cstr.to_bytes()).unwrap();
-                return Err(err_msg.to_owned());
+                return Err(take_llvm_message(err_msg_ptr));
             }
         }
 
@@ -943,7 +2978,7 @@ impl TargetMachine {
                 target_triple,
                 cpu.as_ptr() as *const _,
                 features.as_ptr() as *const _,
-                LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+                codegen_opt_level(llvm_opt),
                 LLVMRelocMode::LLVMRelocPIC,
                 LLVMCodeModel::LLVMCodeModelDefault,
             );
@@ -961,33 +2996,68 @@ impl Drop for TargetMachine {
     }
 }
 
+/// Initialise LLVM's targets. This only does work the first time
+/// it's called, so we can call it before compiling each file.
 pub fn init_llvm() {
-    unsafe {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
         // TODO: are all these necessary? Are there docs?
         LLVM_InitializeAllTargetInfos();
         LLVM_InitializeAllTargets();
         LLVM_InitializeAllTargetMCs();
         LLVM_InitializeAllAsmParsers();
         LLVM_InitializeAllAsmPrinters();
-    }
+    });
+}
+
+/// Write `module` as an object file, generating code at -O`llvm_opt`.
+pub fn write_object_file(module: &mut Module, path: &str, llvm_opt: u8) -> Result<(), String> {
+    emit_to_file(module, path, llvm_opt, LLVMCodeGenFileType::LLVMObjectFile)
+}
+
+/// Write `module` as assembly, generating code at -O`llvm_opt`.
+pub fn write_assembly_file(module: &mut Module, path: &str, llvm_opt: u8) -> Result<(), String> {
+    emit_to_file(
+        module,
+        path,
+        llvm_opt,
+        LLVMCodeGenFileType::LLVMAssemblyFile,
+    )
 }
 
-pub fn write_object_file(module: &mut Module, path: &str) -> Result<(), String> {
+fn emit_to_file(
+    module: &mut Module,
+    path: &str,
+    llvm_opt: u8,
+    file_type: LLVMCodeGenFileType,
+) -> Result<(), String> {
     unsafe {
         let target_triple = LLVMGetTarget(module.module);
-        let target_machine = TargetMachine::new(target_triple)?;
+        let target_machine = TargetMachine::new(target_triple, llvm_opt)?;
 
-        let mut obj_error = module.new_mut_string_ptr("Writing object file failed.");
+        let mut obj_error = null_mut();
         let result = LLVMTargetMachineEmitToFile(
             target_machine.tm,
             module.module,
             module.new_string_ptr(path) as *mut i8,
-            LLVMCodeGenFileType::LLVMObjectFile,
+            file_type,
             &mut obj_error,
         );
 
         if result != 0 {
-            panic!("obj_error: {:?}", CStr::from_ptr(obj_error as *const _));
+            if obj_error.is_null() {
+                return Err(format!("Writing {} failed.", path));
+            }
+            return Err(take_llvm_message(obj_error));
+        }
+    }
+    Ok(())
+}
+
+pub fn write_bitcode_file(module: &mut Module, path: &str) -> Result<(), String> {
+    unsafe {
+        if LLVMWriteBitcodeToFile(module.module, module.new_string_ptr(path)) != 0 {
+            return Err(format!("Writing {} failed.", path));
         }
     }
     Ok(())
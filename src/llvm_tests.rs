@@ -1,11 +1,15 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::ffi::CString;
 use std::num::Wrapping;
+use std::rc::Rc;
 
+use crate::bfir::parse;
 use crate::bfir::AstNode::*;
-use crate::bfir::Position;
-use crate::execution::ExecutionState;
-use crate::llvm::compile_to_module;
+use crate::bfir::{Bounds, CellWidth, Overflow, Position};
+use crate::bounds::DEFAULT_TAPE_SIZE;
+use crate::execution::{execute, max_steps, ExecutionState};
+use crate::llvm::{compile_to_module, CompileOptions};
+use crate::profile::Profile;
 
 use pretty_assertions::assert_eq;
 
@@ -43,15 +47,20 @@ fn compile_loop() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -67,7 +76,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -76,25 +85,194 @@ beginning:                                        ; No predecessors!
   br label %after_init
 
 after_init:                                       ; preds = %init, %beginning
-  br label %loop_header
-
-loop_header:                                      ; preds = %loop_body, %after_init
   %cell_index = load i32, i32* %cell_index_ptr
   %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
   %cell_value = load i8, i8* %current_cell_ptr
   %cell_value_is_zero = icmp eq i8 0, %cell_value
   br i1 %cell_value_is_zero, label %loop_after, label %loop_body
 
-loop_body:                                        ; preds = %loop_header
+loop_body:                                        ; preds = %loop_body, %after_init
   %cell_index1 = load i32, i32* %cell_index_ptr
-  %offset_cell_index = add i32 %cell_index1, 0
-  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %cell_index1
   %cell_value3 = load i8, i8* %current_cell_ptr2
   %new_cell_value = add i8 %cell_value3, 1
   store i8 %new_cell_value, i8* %current_cell_ptr2
-  br label %loop_header
+  %cell_value4 = load i8, i8* %current_cell_ptr2
+  %cell_value_is_zero5 = icmp eq i8 0, %cell_value4
+  br i1 %cell_value_is_zero5, label %loop_after, label %loop_body
+
+loop_after:                                       ; preds = %loop_body, %after_init
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_nested_loop() {
+    // The outer loop body ends in a different basic block to the
+    // one it starts in, but we should still branch back to the start.
+    let instrs = parse("[[-]>]").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %loop_after, label %loop_body
+
+loop_body:                                        ; preds = %loop_after2, %after_init
+  %cell_index3 = load i32, i32* %cell_index_ptr
+  %current_cell_ptr4 = getelementptr i8, i8* %cells, i32 %cell_index3
+  %cell_value5 = load i8, i8* %current_cell_ptr4
+  %cell_value_is_zero6 = icmp eq i8 0, %cell_value5
+  br i1 %cell_value_is_zero6, label %loop_after2, label %loop_body1
+
+loop_after:                                       ; preds = %loop_after2, %after_init
+  call void @free(i8* %cells)
+  ret i32 0
+
+loop_body1:                                       ; preds = %loop_body1, %loop_body
+  %cell_index7 = load i32, i32* %cell_index_ptr
+  %current_cell_ptr8 = getelementptr i8, i8* %cells, i32 %cell_index7
+  %cell_value9 = load i8, i8* %current_cell_ptr8
+  %new_cell_value = add i8 %cell_value9, -1
+  store i8 %new_cell_value, i8* %current_cell_ptr8
+  %cell_value10 = load i8, i8* %current_cell_ptr8
+  %cell_value_is_zero11 = icmp eq i8 0, %cell_value10
+  br i1 %cell_value_is_zero11, label %loop_after2, label %loop_body1
+
+loop_after2:                                      ; preds = %loop_body1, %loop_body
+  %cell_index12 = load i32, i32* %cell_index_ptr
+  %new_cell_index = add i32 %cell_index12, 1
+  %current_cell_ptr13 = getelementptr i8, i8* %cells, i32 %new_cell_index
+  %cell_value14 = load i8, i8* %current_cell_ptr13
+  %cell_value_is_zero15 = icmp eq i8 0, %cell_value14
+  store i32 %new_cell_index, i32* %cell_index_ptr
+  br i1 %cell_value_is_zero15, label %loop_after, label %loop_body
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_if() {
+    let instrs = vec![If {
+        body: vec![Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }],
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %if_after, label %if_body
 
-loop_after:                                       ; preds = %loop_header
+if_body:                                          ; preds = %after_init
+  store i8 0, i8* %current_cell_ptr
+  br label %if_after
+
+if_after:                                         ; preds = %if_body, %after_init
   call void @free(i8* %cells)
   ret i32 0
 }
@@ -114,15 +292,20 @@ fn compile_empty_program() {
             start_instr: None,
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -162,15 +345,20 @@ fn compile_set_with_offset() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0); 50],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -186,7 +374,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 50)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 50, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 50, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -209,6 +397,75 @@ attributes #0 = { argmemonly nounwind willreturn }
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
 
+#[test]
+fn compile_set_range() {
+    let instrs = vec![SetRange {
+        amount: Wrapping(1),
+        offset: 1,
+        length: 4,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 5],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 5)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 5, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %offset_cell_index = add i32 %cell_index, 1
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  call void @llvm.memset.p0i8.i32(i8* %current_cell_ptr, i8 1, i32 4, i1 false)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
 #[test]
 fn compile_read() {
     let instrs = vec![Read { position: None }];
@@ -221,16 +478,21 @@ fn compile_read() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
 
-    let expected = "; ModuleID = 'foo'
+    let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -246,7 +508,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -283,16 +545,21 @@ fn compile_write() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
 
-    let expected = "; ModuleID = 'foo'
+    let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -308,7 +575,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -332,6 +599,77 @@ attributes #0 = { argmemonly nounwind willreturn }
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
 
+#[test]
+fn compile_write_const() {
+    let instrs = vec![WriteConst {
+        values: vec![104, 105],
+        position: None,
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@stdout = external global i8*
+@const_output = constant [2 x i8] c\"hi\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %stdout = load i8*, i8** @stdout
+  %0 = call i32 @fwrite(i8* getelementptr inbounds ([2 x i8], [2 x i8]* @const_output, i32 0, i32 0), i32 1, i32 2, i8* %stdout)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+declare i32 @fwrite(i8*, i32, i32, i8*)
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
 #[test]
 fn respect_initial_cell_ptr() {
     let instrs = vec![PointerIncrement {
@@ -346,15 +684,20 @@ fn respect_initial_cell_ptr() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0); 10],
             cell_ptr: 8,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -370,7 +713,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 10)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 10, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 10, i1 true)
   %cell_index_ptr = alloca i32
   store i32 8, i32* %cell_index_ptr
   br label %after_init
@@ -381,7 +724,6 @@ beginning:                                        ; No predecessors!
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr
   %new_cell_index = add i32 %cell_index, 1
-  store i32 %new_cell_index, i32* %cell_index_ptr
   call void @free(i8* %cells)
   ret i32 0
 }
@@ -394,7 +736,7 @@ attributes #0 = { argmemonly nounwind willreturn }
 
 #[test]
 fn compile_multiply_move() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(2));
     changes.insert(2, Wrapping(3));
     let instrs = vec![MultiplyMove {
@@ -410,15 +752,20 @@ fn compile_multiply_move() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0); 3],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -434,7 +781,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 3)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 3, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 3, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -495,15 +842,20 @@ fn set_initial_cell_values() {
                 Wrapping(0),
             ],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -519,11 +871,11 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 6)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 1, i32 2, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 1, i32 2, i1 true)
   %offset_cell_ptr1 = getelementptr i8, i8* %cells, i32 2
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr1, i8 2, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr1, i8 2, i32 1, i1 true)
   %offset_cell_ptr2 = getelementptr i8, i8* %cells, i32 3
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr2, i8 0, i32 3, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr2, i8 0, i32 3, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -534,7 +886,6 @@ beginning:                                        ; No predecessors!
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr
   %new_cell_index = add i32 %cell_index, 1
-  store i32 %new_cell_index, i32* %cell_index_ptr
   call void @free(i8* %cells)
   ret i32 0
 }
@@ -555,8 +906,13 @@ fn compile_static_outputs() {
             start_instr: None,
             cells: vec![],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![5, 10],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
@@ -565,7 +921,7 @@ target triple = \"i686-pc-linux-gnu\"
 @known_outputs = constant [2 x i8] c\"\\05\\0A\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -593,28 +949,34 @@ attributes #0 = { argmemonly nounwind willreturn }
 }
 
 #[test]
-fn compile_ptr_increment() {
-    let instrs = vec![PointerIncrement {
-        amount: 1,
-        position: Some(Position { start: 0, end: 0 }),
-    }];
+fn compile_input_free_program() {
+    // A program without reads runs entirely at compile time, so we
+    // only need to write its output: no tape, no loops.
+    let instrs = parse("++++++++[>++++++<-]>+.+.").unwrap();
+    let (state, _) = execute(
+        &instrs,
+        max_steps(),
+        CellWidth::Bits8,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+
     let result = compile_to_module(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
         &instrs,
-        &ExecutionState {
-            start_instr: Some(&instrs[0]),
-            cells: vec![Wrapping(0); 2],
-            cell_ptr: 0,
-            outputs: vec![],
-        },
+        &state,
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
+@known_outputs = constant [2 x i8] c\"12\"
+
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -628,20 +990,292 @@ declare i32 @getchar()
 
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 2)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 2, i32 1, i1 true)
-  %cell_index_ptr = alloca i32
-  store i32 0, i32* %cell_index_ptr
-  br label %after_init
-
+  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @known_outputs, i32 0, i32 0), i32 2)
+  br label %beginning
+
+beginning:                                        ; preds = %init
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_ptr_increment() {
+    let instrs = vec![PointerIncrement {
+        amount: 1,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 2)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 2, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
 beginning:                                        ; No predecessors!
   br label %after_init
 
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr
   %new_cell_index = add i32 %cell_index, 1
-  store i32 %new_cell_index, i32* %cell_index_ptr
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+/// We should reuse the cell index we just computed, rather than
+/// loading it again.
+#[test]
+fn compile_ptr_increment_wrap() {
+    let instrs = parse("<.").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 3],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Wrap,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            bounds: Bounds::Wrap,
+            ..CompileOptions::default()
+        },
+    );
+
+    // Moving left by 1 is moving right by 2, wrapping past the end.
+    let ir = result.to_cstring();
+    let ir = ir.to_str().unwrap();
+    assert!(ir.contains("%unwrapped_cell_index = add i32 %cell_index, 2"));
+    assert!(ir.contains("%past_end = icmp uge i32 %unwrapped_cell_index, 3"));
+    assert!(ir.contains("%new_cell_index = select i1 %past_end"));
+}
+
+#[test]
+fn compile_ptr_increment_abort() {
+    let instrs = parse("<.").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 3],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Abort,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            bounds: Bounds::Abort,
+            ..CompileOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring();
+    let ir = ir.to_str().unwrap();
+    assert!(ir.contains("define internal void @check_cell_index(i32 %index, i8* %message)"));
+    assert!(ir.contains("%is_off_tape = icmp uge i32 %index, 3"));
+    assert!(ir.contains("call void @check_cell_index(i32 %new_cell_index"));
+    assert!(ir.contains("c\"error: This instruction moves the pointer off the tape"));
+}
+
+#[test]
+fn store_cell_index_before_loop() {
+    let instrs = parse(">>.[-]").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 3],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 3)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 3, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %new_cell_index = add i32 %cell_index, 1
+  %new_cell_index1 = add i32 %new_cell_index, 1
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %new_cell_index1
+  %cell_value = load i8, i8* %current_cell_ptr
+  %cell_val_as_char = sext i8 %cell_value to i32
+  %0 = call i32 @putchar(i32 %cell_val_as_char)
+  %cell_value2 = load i8, i8* %current_cell_ptr
+  %cell_value_is_zero = icmp eq i8 0, %cell_value2
+  store i32 %new_cell_index1, i32* %cell_index_ptr
+  br i1 %cell_value_is_zero, label %loop_after, label %loop_body
+
+loop_body:                                        ; preds = %loop_body, %after_init
+  %cell_index3 = load i32, i32* %cell_index_ptr
+  %current_cell_ptr4 = getelementptr i8, i8* %cells, i32 %cell_index3
+  %cell_value5 = load i8, i8* %current_cell_ptr4
+  %new_cell_value = add i8 %cell_value5, -1
+  store i8 %new_cell_value, i8* %current_cell_ptr4
+  %cell_value6 = load i8, i8* %current_cell_ptr4
+  %cell_value_is_zero7 = icmp eq i8 0, %cell_value6
+  br i1 %cell_value_is_zero7, label %loop_after, label %loop_body
+
+loop_after:                                       ; preds = %loop_body, %after_init
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn reuse_cell_index_after_ptr_increment() {
+    let instrs = vec![
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Write {
+            position: Some(Position { start: 1, end: 1 }),
+        },
+    ];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 2)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 2, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %new_cell_index = add i32 %cell_index, 1
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %new_cell_index
+  %cell_value = load i8, i8* %current_cell_ptr
+  %cell_val_as_char = sext i8 %cell_value to i32
+  %0 = call i32 @putchar(i32 %cell_val_as_char)
   call void @free(i8* %cells)
   ret i32 0
 }
@@ -667,15 +1301,20 @@ fn compile_increment() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -691,7 +1330,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -701,8 +1340,7 @@ beginning:                                        ; No predecessors!
 
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr
-  %offset_cell_index = add i32 %cell_index, 0
-  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
   %cell_value = load i8, i8* %current_cell_ptr
   %new_cell_value = add i8 %cell_value, 1
   store i8 %new_cell_value, i8* %current_cell_ptr
@@ -731,15 +1369,20 @@ fn compile_increment_with_offset() {
             start_instr: Some(&instrs[0]),
             cells: vec![Wrapping(0); 4],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -755,7 +1398,7 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 4)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 4, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 4, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -801,15 +1444,20 @@ fn compile_start_instr_midway() {
             start_instr: Some(&instrs[1]),
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
             outputs: vec![],
+            storage: Wrapping(0),
         },
+        &CompileOptions::default(),
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind willreturn
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
@@ -825,23 +1473,21 @@ define i32 @main() {
 init:
   %cells = call i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
 
 beginning:                                        ; No predecessors!
   %cell_index = load i32, i32* %cell_index_ptr
-  %offset_cell_index = add i32 %cell_index, 0
-  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
   store i8 1, i8* %current_cell_ptr
   br label %after_init
 
 after_init:                                       ; preds = %init, %beginning
   %cell_index1 = load i32, i32* %cell_index_ptr
-  %offset_cell_index2 = add i32 %cell_index1, 0
-  %current_cell_ptr3 = getelementptr i8, i8* %cells, i32 %offset_cell_index2
-  store i8 2, i8* %current_cell_ptr3
+  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %cell_index1
+  store i8 2, i8* %current_cell_ptr2
   call void @free(i8* %cells)
   ret i32 0
 }
@@ -851,3 +1497,877 @@ attributes #0 = { argmemonly nounwind willreturn }
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
+
+#[test]
+fn compile_vector_increment() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            experimental_simd: true,
+            ..CompileOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 16)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 16, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cells_vector_ptr = bitcast i8* %current_cell_ptr to <2 x i8>*
+  %cells_vector = load <2 x i8>, <2 x i8>* %cells_vector_ptr, align 1
+  %new_cells_vector = add <2 x i8> %cells_vector, <i8 1, i8 2>
+  store <2 x i8> %new_cells_vector, <2 x i8>* %cells_vector_ptr, align 1
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_vector_increment_wide_cells() {
+    let instrs: Vec<_> = (0..6)
+        .map(|offset| Increment {
+            amount: Wrapping(1),
+            offset,
+            position: None,
+        })
+        .collect();
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 6],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits32,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            experimental_simd: true,
+            cell_width: CellWidth::Bits32,
+            ..CompileOptions::default()
+        },
+    );
+
+    // Only 4 32-bit cells fit in a 128-bit register.
+    let ir = result.to_cstring();
+    let ir = ir.to_str().unwrap();
+    assert!(ir.contains("add <4 x i32>"));
+    assert!(ir.contains("add <2 x i32>"));
+}
+
+#[test]
+fn compile_repeated_loops_for_size() {
+    let instrs = parse("[->+>+<<.][->+>+<<.]").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 3],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            opt_size: true,
+            ..CompileOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 3)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 3, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  call void @outlined_loop0(i8* %cells, i32* %cell_index_ptr)
+  call void @outlined_loop0(i8* %cells, i32* %cell_index_ptr)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+; Function Attrs: noinline
+define internal void @outlined_loop0(i8* %cells, i32* %cell_index_ptr) #1 {
+entry:
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %loop_after, label %loop_body
+
+loop_body:                                        ; preds = %loop_body, %entry
+  %cell_index1 = load i32, i32* %cell_index_ptr
+  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %cell_index1
+  %cell_value3 = load i8, i8* %current_cell_ptr2
+  %new_cell_value = add i8 %cell_value3, -1
+  store i8 %new_cell_value, i8* %current_cell_ptr2
+  %new_cell_index = add i32 %cell_index1, 1
+  %current_cell_ptr4 = getelementptr i8, i8* %cells, i32 %new_cell_index
+  %cell_value5 = load i8, i8* %current_cell_ptr4
+  %new_cell_value6 = add i8 %cell_value5, 1
+  store i8 %new_cell_value6, i8* %current_cell_ptr4
+  %new_cell_index7 = add i32 %new_cell_index, 1
+  %current_cell_ptr8 = getelementptr i8, i8* %cells, i32 %new_cell_index7
+  %cell_value9 = load i8, i8* %current_cell_ptr8
+  %new_cell_value10 = add i8 %cell_value9, 1
+  store i8 %new_cell_value10, i8* %current_cell_ptr8
+  %new_cell_index11 = add i32 %new_cell_index7, -1
+  %new_cell_index12 = add i32 %new_cell_index11, -1
+  %current_cell_ptr13 = getelementptr i8, i8* %cells, i32 %new_cell_index12
+  %cell_value14 = load i8, i8* %current_cell_ptr13
+  %cell_val_as_char = sext i8 %cell_value14 to i32
+  %0 = call i32 @putchar(i32 %cell_val_as_char)
+  %cell_value15 = load i8, i8* %current_cell_ptr13
+  %cell_value_is_zero16 = icmp eq i8 0, %cell_value15
+  store i32 %new_cell_index12, i32* %cell_index_ptr
+  br i1 %cell_value_is_zero16, label %loop_after, label %loop_body
+
+loop_after:                                       ; preds = %loop_body, %entry
+  ret void
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+attributes #1 = { noinline }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn dont_compile_small_repeated_loops_for_size() {
+    let instrs = parse("[->+>+<<.][->+>+<<.]").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 3],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            opt_size: true,
+            min_outlined_body_len: 100,
+            ..CompileOptions::default()
+        },
+    );
+
+    // The loop bodies are too small to be worth a call.
+    let ir = result.to_cstring();
+    assert!(!ir.to_str().unwrap().contains("outlined_loop"));
+}
+
+#[test]
+fn compile_loop_with_profile_counters() {
+    let instrs = parse("[-]").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            profile_generate: Some("foo.profile".to_owned()),
+            ..CompileOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@loop0_entries = internal global i64 0
+@loop0_iterations = internal global i64 0
+@profile_path = constant [12 x i8] c\"foo.profile\\00\"
+@profile_mode = constant [2 x i8] c\"w\\00\"
+@profile_format = constant [16 x i8] c\"%llu %llu %llu\\0A\\00\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %count = load i64, i64* @loop0_entries
+  %new_count = add i64 %count, 1
+  store i64 %new_count, i64* @loop0_entries
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %loop_after, label %loop_body
+
+loop_body:                                        ; preds = %loop_body, %after_init
+  %count1 = load i64, i64* @loop0_iterations
+  %new_count2 = add i64 %count1, 1
+  store i64 %new_count2, i64* @loop0_iterations
+  %cell_index3 = load i32, i32* %cell_index_ptr
+  %current_cell_ptr4 = getelementptr i8, i8* %cells, i32 %cell_index3
+  %cell_value5 = load i8, i8* %current_cell_ptr4
+  %new_cell_value = add i8 %cell_value5, -1
+  store i8 %new_cell_value, i8* %current_cell_ptr4
+  %cell_value6 = load i8, i8* %current_cell_ptr4
+  %cell_value_is_zero7 = icmp eq i8 0, %cell_value6
+  br i1 %cell_value_is_zero7, label %loop_after, label %loop_body
+
+loop_after:                                       ; preds = %loop_body, %after_init
+  call void @free(i8* %cells)
+  %profile_file = call i8* @fopen(i8* getelementptr inbounds ([12 x i8], [12 x i8]* @profile_path, i32 0, i32 0), i8* getelementptr inbounds ([2 x i8], [2 x i8]* @profile_mode, i32 0, i32 0))
+  %profile_file_is_null = icmp eq i8* %profile_file, null
+  br i1 %profile_file_is_null, label %profile_after, label %profile_write
+
+profile_write:                                    ; preds = %loop_after
+  %entries = load i64, i64* @loop0_entries
+  %iterations = load i64, i64* @loop0_iterations
+  %0 = call i32 (i8*, i8*, ...) @fprintf(i8* %profile_file, i8* getelementptr inbounds ([16 x i8], [16 x i8]* @profile_format, i32 0, i32 0), i64 0, i64 %entries, i64 %iterations)
+  %1 = call i32 @fclose(i8* %profile_file)
+  br label %profile_after
+
+profile_after:                                    ; preds = %profile_write, %loop_after
+  ret i32 0
+}
+
+declare i8* @fopen(i8*, i8*)
+
+declare i32 @fclose(i8*)
+
+declare i32 @fprintf(i8*, i8*, ...)
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_loop_with_profile_weights() {
+    let instrs = parse("[-]").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            profile: Some(Rc::new(Profile::parse("0 2 10\n").unwrap())),
+            ..CompileOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %loop_after, label %loop_body
+
+loop_body:                                        ; preds = %loop_body, %after_init
+  %cell_index1 = load i32, i32* %cell_index_ptr
+  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %cell_index1
+  %cell_value3 = load i8, i8* %current_cell_ptr2
+  %new_cell_value = add i8 %cell_value3, -1
+  store i8 %new_cell_value, i8* %current_cell_ptr2
+  %cell_value4 = load i8, i8* %current_cell_ptr2
+  %cell_value_is_zero5 = icmp eq i8 0, %cell_value4
+  br i1 %cell_value_is_zero5, label %loop_after, label %loop_body, !prof !0
+
+loop_after:                                       ; preds = %loop_body, %after_init
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+
+!0 = !{!\"branch_weights\", i32 2, i32 8}
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_forward_scan_then_set() {
+    let instrs = vec![
+        Scan {
+            step: 1,
+            position: Some(Position { start: 0, end: 2 }),
+        },
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 4],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 4)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 4, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %remaining_cells = sub i32 4, %cell_index
+  %zero_cell_ptr = call i8* @memchr(i8* %current_cell_ptr, i32 0, i32 %remaining_cells)
+  %zero_cell_addr = ptrtoint i8* %zero_cell_ptr to i32
+  %cells_addr = ptrtoint i8* %cells to i32
+  %new_cell_index = sub i32 %zero_cell_addr, %cells_addr
+  store i8 1, i8* %zero_cell_ptr
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+declare i8* @memchr(i8*, i32, i32)
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_16_bit_cells() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Write {
+            position: Some(Position { start: 1, end: 1 }),
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0), Wrapping(300)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits16,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            cell_width: CellWidth::Bits16,
+            ..CompileOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 4)
+  call void @llvm.memset.p0i8.i32(i8* %cells, i8 0, i32 4, i1 true)
+  %cells1 = bitcast i8* %cells to i16*
+  %offset_cell_ptr = getelementptr i16, i16* %cells1, i32 1
+  store i16 300, i16* %offset_cell_ptr
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i16, i16* %cells1, i32 %cell_index
+  %cell_value = load i16, i16* %current_cell_ptr
+  %new_cell_value = add i16 %cell_value, -1
+  store i16 %new_cell_value, i16* %current_cell_ptr
+  %cell_value2 = load i16, i16* %current_cell_ptr
+  %cell_val_as_char = sext i16 %cell_value2 to i32
+  %0 = call i32 @putchar(i32 %cell_val_as_char)
+  %cells_bytes = bitcast i16* %cells1 to i8*
+  call void @free(i8* %cells_bytes)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_breakpoint() {
+    let instrs = vec![Breakpoint {
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(1), Wrapping(0)],
+            cell_ptr: 1,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@breakpoint_format = constant [32 x i8] c\"cell pointer: %d, cells: %u %u\\0A\\00\"
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 2)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 1, i32 1, i1 true)
+  %offset_cell_ptr1 = getelementptr i8, i8* %cells, i32 1
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr1, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 1, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %breakpoint_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  %breakpoint_cell = load i8, i8* %breakpoint_cell_ptr
+  %breakpoint_cell_val = zext i8 %breakpoint_cell to i32
+  %breakpoint_cell_ptr2 = getelementptr i8, i8* %cells, i32 1
+  %breakpoint_cell3 = load i8, i8* %breakpoint_cell_ptr2
+  %breakpoint_cell_val4 = zext i8 %breakpoint_cell3 to i32
+  %0 = call i32 (i32, i8*, ...) @dprintf(i32 2, i8* getelementptr inbounds ([32 x i8], [32 x i8]* @breakpoint_format, i32 0, i32 0), i32 %cell_index, i32 %breakpoint_cell_val, i32 %breakpoint_cell_val4)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+declare i32 @dprintf(i32, i8*, ...)
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_baked_input() {
+    let instrs = vec![Read {
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(0),
+        },
+        &CompileOptions {
+            input: Some(b"ab".to_vec()),
+            ..CompileOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@input = internal constant [3 x i8] c\"ab\\00\"
+@input_index = internal global i32 0
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_index = load i32, i32* @input_index
+  %input_in_range = icmp ult i32 %input_index, 2
+  %load_index = select i1 %input_in_range, i32 %input_index, i32 2
+  %input_byte_ptr = getelementptr [3 x i8], [3 x i8]* @input, i32 0, i32 %load_index
+  %baked_byte = load i8, i8* %input_byte_ptr
+  %baked_char = zext i8 %baked_byte to i32
+  %next_input_index = add i32 %input_index, 1
+  %new_input_index = select i1 %input_in_range, i32 %next_input_index, i32 %input_index
+  store i32 %new_input_index, i32* @input_index
+  %input_char = select i1 %input_in_range, i32 %baked_char, i32 -1
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_store_load_and_halt() {
+    let instrs = vec![
+        Store {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Load {
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Halt {
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            cell_width: CellWidth::Bits8,
+            overflow: Overflow::Wrap,
+            bounds: Bounds::Unchecked,
+            outputs: vec![],
+            storage: Wrapping(3),
+        },
+        &CompileOptions::default(),
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@storage = internal global i8 3
+
+; Function Attrs: argmemonly nounwind willreturn
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i1 true)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr
+  store i8 %cell_value, i8* @storage
+  %storage_value = load i8, i8* @storage
+  store i8 %storage_value, i8* %current_cell_ptr
+  call void @exit(i32 0)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+declare void @exit(i32)
+
+attributes #0 = { argmemonly nounwind willreturn }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compiled_modules_are_valid() {
+    let instrs = parse("+<[->+<],.").unwrap();
+    for &(overflow, bounds) in &[
+        (Overflow::Wrap, Bounds::Unchecked),
+        (Overflow::Abort, Bounds::Abort),
+        (Overflow::Saturate, Bounds::Wrap),
+    ] {
+        let result = compile_to_module(
+            "foo",
+            None,
+            &instrs,
+            &ExecutionState {
+                start_instr: Some(&instrs[0]),
+                cells: vec![Wrapping(0); 3],
+                cell_ptr: 0,
+                cell_width: CellWidth::Bits8,
+                overflow,
+                bounds,
+                outputs: vec![],
+                storage: Wrapping(0),
+            },
+            &CompileOptions {
+                overflow,
+                bounds,
+                ..CompileOptions::default()
+            },
+        );
+        assert_eq!(result.verify(), Ok(()));
+    }
+}
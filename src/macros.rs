@@ -0,0 +1,480 @@
+//! A preprocessor for BF source, enabled with `--macros`, that
+//! expands named macros, repetition counts and includes before
+//! parsing.
+//!
+//! `@name{body}` defines a macro, and `$name` expands it. A count in
+//! braces repeats the command or macro just before it, so `+{10}` is
+//! ten increments and `$clear{2}` expands `clear` twice. Macros must
+//! be defined before they're used, so they can't be recursive.
+//!
+//! `@include "lib.bf"` expands another file in place, so its macros
+//! are available afterwards. We look for the file next to the file
+//! that includes it, then in each `--include-path`. A file is only
+//! included once, however many times it's named.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+#[cfg(test)]
+use crate::diagnostics::Level;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+
+use crate::bfir::{self, AstNode, ParseError, Position};
+use crate::diagnostics::SourceMap;
+
+/// BF source with all the macros expanded.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Expansion {
+    pub source: String,
+    /// The position in `SourceMap` of each character in `source`.
+    /// Characters from a macro or a repetition have the position of
+    /// the `$` or the command that produced them.
+    pub positions: Vec<usize>,
+}
+
+/// Expand the macros in the main file of `sources`, then parse it.
+/// Any files we include are added to `sources`, and positions in the
+/// instructions and errors refer to `sources`.
+pub fn parse(
+    sources: &mut SourceMap,
+    include_paths: &[PathBuf],
+    breakpoints: bool,
+) -> Result<Vec<AstNode>, Vec<ParseError>> {
+    let expansion = expand(sources, include_paths).map_err(|error| vec![error])?;
+    bfir::parse_expanded(&expansion.source, &expansion.positions, breakpoints)
+}
+
+/// Expand all the macro definitions, macro uses, repetition counts
+/// and includes in the main file of `sources`.
+pub fn expand(sources: &mut SourceMap, include_paths: &[PathBuf]) -> Result<Expansion, ParseError> {
+    let path = PathBuf::from(sources.main_path());
+    let mut included = HashSet::new();
+    included.insert(fs::canonicalize(&path).unwrap_or_else(|_| path.clone()));
+
+    let mut expander = Expander {
+        file: File {
+            chars: sources.main_source().chars().collect(),
+            index: 0,
+            start: 0,
+            path,
+        },
+        macros: HashMap::new(),
+        sources,
+        include_paths,
+        included,
+    };
+    let expanded = expander.expand(None)?;
+
+    Ok(Expansion {
+        source: expanded.iter().map(|&(c, _)| c).collect(),
+        positions: expanded.iter().map(|&(_, position)| position).collect(),
+    })
+}
+
+/// A file that we're expanding.
+struct File {
+    path: PathBuf,
+    chars: Vec<char>,
+    /// The index of the next character to expand.
+    index: usize,
+    /// The position of the first character in the `SourceMap`.
+    start: usize,
+}
+
+struct Expander<'a> {
+    file: File,
+    /// The expanded body of each macro defined so far.
+    macros: HashMap<String, Vec<(char, usize)>>,
+    sources: &'a mut SourceMap,
+    include_paths: &'a [PathBuf],
+    /// The canonical path of every file we've expanded.
+    included: HashSet<PathBuf>,
+}
+
+impl<'a> Expander<'a> {
+    /// An error from `start` to `end` in the current file.
+    fn error(&self, message: String, start: usize, end: usize) -> ParseError {
+        ParseError {
+            message,
+            position: Position {
+                start: self.file.start + start,
+                end: self.file.start + end,
+            },
+        }
+    }
+
+    /// Expand characters until the end of the file or, if we're in
+    /// the body of a macro whose `{` is at `body_start`, the matching
+    /// `}`. Returns each character with its position.
+    fn expand(&mut self, body_start: Option<usize>) -> Result<Vec<(char, usize)>, ParseError> {
+        let mut output = vec![];
+        // Where the last command or macro use starts in `output`, if
+        // a count would repeat it.
+        let mut last_item: Option<usize> = None;
+
+        while self.file.index < self.file.chars.len() {
+            let index = self.file.index;
+            let c = self.file.chars[index];
+            self.file.index += 1;
+
+            match c {
+                '@' => {
+                    let name = self.name(index)?;
+                    if name == "include" {
+                        output.extend(self.include(index)?);
+                    } else {
+                        self.define(index, name)?;
+                    }
+                    last_item = None;
+                }
+                '$' => {
+                    let name = self.name(index)?;
+                    let body = match self.macros.get(&name) {
+                        Some(body) => body,
+                        None => {
+                            return Err(self.error(
+                                format!("Macro '{}' is not defined", name),
+                                index,
+                                self.file.index - 1,
+                            ))
+                        }
+                    };
+                    last_item = Some(output.len());
+                    let position = self.file.start + index;
+                    output.extend(body.iter().map(|&(c, _)| (c, position)));
+                }
+                '{' => {
+                    let count = self.count(index)?;
+                    let item_start = match last_item {
+                        Some(item_start) => item_start,
+                        None => {
+                            return Err(self.error(
+                                "This count doesn't follow a command or macro to repeat".to_owned(),
+                                index,
+                                self.file.index - 1,
+                            ))
+                        }
+                    };
+
+                    let item = output.split_off(item_start);
+                    for _ in 0..count {
+                        output.extend(item.iter().cloned());
+                    }
+                }
+                '}' => match body_start {
+                    Some(_) => return Ok(output),
+                    None => {
+                        return Err(self.error("This } has no matching {".to_owned(), index, index))
+                    }
+                },
+                _ => {
+                    last_item = if "+-<>,.[]#".contains(c) {
+                        Some(output.len())
+                    } else {
+                        None
+                    };
+                    output.push((c, self.file.start + index));
+                }
+            }
+        }
+
+        match body_start {
+            Some(body_start) => Err(self.error(
+                "This { has no matching }".to_owned(),
+                body_start,
+                body_start,
+            )),
+            None => Ok(output),
+        }
+    }
+
+    /// Read the name after the `@` or `$` at `sigil_index`.
+    fn name(&mut self, sigil_index: usize) -> Result<String, ParseError> {
+        let mut name = String::new();
+        while let Some(&c) = self.file.chars.get(self.file.index) {
+            if !(c.is_ascii_alphanumeric() || c == '_') {
+                break;
+            }
+            name.push(c);
+            self.file.index += 1;
+        }
+
+        if name.is_empty() {
+            return Err(self.error(
+                format!(
+                    "Expected a macro name after {}",
+                    self.file.chars[sigil_index]
+                ),
+                sigil_index,
+                sigil_index,
+            ));
+        }
+        Ok(name)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.file.chars.get(self.file.index), Some(c) if c.is_whitespace()) {
+            self.file.index += 1;
+        }
+    }
+
+    /// Read the definition of macro `name`, starting after the name.
+    fn define(&mut self, at_index: usize, name: String) -> Result<(), ParseError> {
+        let name_end = self.file.index - 1;
+        if self.macros.contains_key(&name) {
+            return Err(self.error(
+                format!("Macro '{}' is already defined", name),
+                at_index,
+                name_end,
+            ));
+        }
+
+        self.skip_whitespace();
+        if self.file.chars.get(self.file.index) != Some(&'{') {
+            return Err(self.error(
+                format!("Expected {{ after the name of macro '{}'", name),
+                at_index,
+                name_end,
+            ));
+        }
+        let body_start = self.file.index;
+        self.file.index += 1;
+
+        let body = self.expand(Some(body_start))?;
+        self.macros.insert(name, body);
+        Ok(())
+    }
+
+    /// Read the file name of an `@include`, starting after `include`,
+    /// and return the expanded contents of the file.
+    fn include(&mut self, at_index: usize) -> Result<Vec<(char, usize)>, ParseError> {
+        self.skip_whitespace();
+        let mut file_name = String::new();
+        let mut terminated = false;
+        if self.file.chars.get(self.file.index) == Some(&'"') {
+            self.file.index += 1;
+            while let Some(&c) = self.file.chars.get(self.file.index) {
+                self.file.index += 1;
+                if c == '"' {
+                    terminated = true;
+                    break;
+                }
+                file_name.push(c);
+            }
+        }
+        let end = self.file.index - 1;
+        if !terminated || file_name.is_empty() {
+            return Err(self.error(
+                "Expected a file name in quotes after @include".to_owned(),
+                at_index,
+                end,
+            ));
+        }
+
+        let mut candidates = vec![];
+        if let Some(dir) = self.file.path.parent() {
+            candidates.push(dir.join(&file_name));
+        }
+        candidates.extend(self.include_paths.iter().map(|dir| dir.join(&file_name)));
+        let path = match candidates.into_iter().find(|path| path.is_file()) {
+            Some(path) => path,
+            None => {
+                return Err(self.error(
+                    format!("Could not find '{}' to include", file_name),
+                    at_index,
+                    end,
+                ))
+            }
+        };
+
+        if !self
+            .included
+            .insert(fs::canonicalize(&path).unwrap_or_else(|_| path.clone()))
+        {
+            return Ok(vec![]);
+        }
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                return Err(self.error(
+                    format!("Could not read '{}': {}", path.display(), e),
+                    at_index,
+                    end,
+                ))
+            }
+        };
+
+        let start = self.sources.add(&path.to_string_lossy(), &source);
+        let including = mem::replace(
+            &mut self.file,
+            File {
+                chars: source.chars().collect(),
+                index: 0,
+                start,
+                path,
+            },
+        );
+        let expanded = self.expand(None);
+        self.file = including;
+        expanded
+    }
+
+    /// Read a repetition count, starting after the `{` at `brace_index`.
+    fn count(&mut self, brace_index: usize) -> Result<usize, ParseError> {
+        let mut digits = String::new();
+        while let Some(&c) = self.file.chars.get(self.file.index) {
+            self.file.index += 1;
+            if c == '}' {
+                if let Ok(count) = digits.parse() {
+                    return Ok(count);
+                }
+                break;
+            }
+            digits.push(c);
+        }
+
+        Err(self.error(
+            "Expected a repetition count, such as {10}".to_owned(),
+            brace_index,
+            self.file.index - 1,
+        ))
+    }
+}
+
+#[cfg(test)]
+fn expand_source(source: &str) -> Result<Expansion, ParseError> {
+    expand(&mut SourceMap::new("test.bf", source), &[])
+}
+
+#[test]
+fn expand_repetition() {
+    let expansion = expand_source("a+{3}>").unwrap();
+    assert_eq!(expansion.source, "a+++>");
+    assert_eq!(expansion.positions, vec![0, 1, 1, 1, 5]);
+}
+
+#[test]
+fn expand_zero_repetitions() {
+    assert_eq!(expand_source("+>{0}-").unwrap().source, "+-");
+}
+
+#[test]
+fn expand_macro() {
+    let expansion = expand_source("@clear{[-]} >$clear").unwrap();
+    assert_eq!(expansion.source, " >[-]");
+    assert_eq!(expansion.positions, vec![11, 12, 13, 13, 13]);
+}
+
+#[test]
+fn expand_macro_using_macro() {
+    let expansion = expand_source("@two{+{2}} @four{$two{2}} $four").unwrap();
+    assert_eq!(expansion.source, "  ++++");
+}
+
+#[test]
+fn expand_undefined_macro() {
+    let error = expand_source("+$foo").unwrap_err();
+    assert_eq!(error.message, "Macro 'foo' is not defined");
+    assert_eq!(error.position, Position { start: 1, end: 4 });
+}
+
+#[test]
+fn expand_recursive_macro() {
+    let error = expand_source("@foo{$foo}").unwrap_err();
+    assert_eq!(error.message, "Macro 'foo' is not defined");
+}
+
+#[test]
+fn expand_count_without_item() {
+    let error = expand_source("+ {2}").unwrap_err();
+    assert_eq!(error.position, Position { start: 2, end: 4 });
+}
+
+#[test]
+fn expand_invalid_count() {
+    let error = expand_source("+{x}").unwrap_err();
+    assert_eq!(error.message, "Expected a repetition count, such as {10}");
+    assert_eq!(error.position, Position { start: 1, end: 3 });
+}
+
+#[test]
+fn expand_unterminated_macro() {
+    let error = expand_source("@foo {+").unwrap_err();
+    assert_eq!(error.message, "This { has no matching }");
+    assert_eq!(error.position, Position { start: 5, end: 5 });
+}
+
+#[test]
+fn parse_positions_refer_to_original_source() {
+    let mut sources = SourceMap::new("test.bf", "@inc{+{2}} $inc>");
+    let instrs = parse(&mut sources, &[], false).unwrap();
+    let positions: Vec<_> = instrs.iter().map(bfir::get_position).collect();
+    assert_eq!(
+        positions,
+        vec![
+            Some(Position { start: 11, end: 11 }),
+            Some(Position { start: 11, end: 11 }),
+            Some(Position { start: 15, end: 15 }),
+        ]
+    );
+}
+
+#[test]
+fn parse_error_position_refers_to_original_source() {
+    let mut sources = SourceMap::new("test.bf", "@open{[} +$open");
+    let error = &parse(&mut sources, &[], false).unwrap_err()[0];
+    assert_eq!(error.message, "This [ has no matching ]");
+    assert_eq!(error.position, Position { start: 10, end: 10 });
+}
+
+#[test]
+fn expand_include() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("lib.bf"), "@clear{[-]}").unwrap();
+    let main_path = dir.path().join("main.bf");
+    let mut sources = SourceMap::new(
+        &main_path.to_string_lossy(),
+        "@include \"lib.bf\" @include \"lib.bf\"+$clear",
+    );
+
+    let expansion = expand(&mut sources, &[]).unwrap();
+    assert_eq!(expansion.source, " +[-]");
+}
+
+#[test]
+fn expand_include_from_include_path() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("lib.bf"), "+{2}").unwrap();
+    let mut sources = SourceMap::new("main.bf", "-@include \"lib.bf\"");
+
+    let expansion = expand(&mut sources, &[dir.path().to_owned()]).unwrap();
+    assert_eq!(expansion.source, "-++");
+    // The included file starts after the main file.
+    assert_eq!(expansion.positions, vec![0, 19, 19]);
+}
+
+#[test]
+fn expand_missing_include() {
+    let error = expand_source("@include \"missing.bf\"").unwrap_err();
+    assert_eq!(error.message, "Could not find 'missing.bf' to include");
+    assert_eq!(error.position, Position { start: 0, end: 20 });
+}
+
+#[test]
+fn include_error_refers_to_included_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib_path = dir.path().join("lib.bf");
+    fs::write(&lib_path, "\n+]").unwrap();
+    let mut sources = SourceMap::new("main.bf", "@include \"lib.bf\"");
+
+    let error = parse(&mut sources, &[dir.path().to_owned()], false)
+        .unwrap_err()
+        .remove(0);
+    let info = sources.info(Level::Error, error.message, Some(error.position));
+    assert_eq!(info.filename, lib_path.to_string_lossy());
+    assert_eq!(info.position, Some(Position { start: 2, end: 2 }));
+}
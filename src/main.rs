@@ -2,24 +2,53 @@
 
 //! bfc is a highly optimising compiler for BF.
 
-use crate::diagnostics::{Info, Level};
-use getopts::{Matches, Options};
+use crate::bfir::{AstNode, Bounds, CellWidth, Eof, Overflow};
+use crate::bounds::{DEFAULT_TAPE_SIZE, MAX_TAPE_SIZE};
+use crate::diagnostics::{Info, Level, SourceMap, Warning};
+use crate::format::FormatOptions;
+use crate::peephole::PassStats;
+use crate::profile::Profile;
+use crate::tokens::TokenMap;
+use getopts::{Fail, Matches, Options};
+use std::collections::hash_map::DefaultHasher;
 use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::prelude::Read;
-use std::path::Path;
-use tempfile::NamedTempFile;
+use std::hash::{Hash, Hasher};
+use std::io::prelude::{Read, Write};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::{NamedTempFile, TempDir};
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 
 mod bfir;
 mod bounds;
+mod cache;
+mod config;
 mod diagnostics;
 mod execution;
+mod format;
+mod irtext;
+mod json;
 mod llvm;
+mod macros;
+mod metrics;
+mod ook;
 mod peephole;
+mod profile;
+mod rewrite;
 mod shell;
+mod structure;
+mod tokens;
+mod verify;
 
 #[cfg(test)]
 mod llvm_tests;
@@ -28,38 +57,67 @@ mod peephole_tests;
 #[cfg(test)]
 mod soundness_tests;
 
-/// Read the contents of the file at path, and return a string of its
-/// contents. Return a diagnostic if we can't open or read the file.
-fn slurp(path: &str) -> Result<String, Info> {
-    let mut file = match File::open(path) {
-        Ok(file) => file,
-        Err(message) => {
-            return Err(Info {
-                level: Level::Error,
-                filename: path.to_owned(),
-                message: format!("{}", message),
-                position: None,
-                source: None,
-            });
-        }
-    };
+/// The first arguments that run a command other than compiling.
+const SUBCOMMANDS: &[&str] = &[
+    "verify-determinism",
+    "fmt",
+    "minify",
+    "run",
+    "check",
+    "clean-cache",
+    "bench",
+    "targets",
+];
 
-    let mut contents = String::new();
+/// The path that means "read from stdin", as in `bfc -`.
+const STDIN_PATH: &str = "-";
 
-    match file.read_to_string(&mut contents) {
-        Ok(_) => Ok(contents),
-        Err(message) => Err(Info {
+/// The name we show for `path` in diagnostics.
+fn display_path(path: &str) -> &str {
+    if path == STDIN_PATH {
+        "<stdin>"
+    } else {
+        path
+    }
+}
+
+/// Read the contents of the file at path, or stdin if path is `-`,
+/// and return a string of its contents. Return a diagnostic if we
+/// can't open or read the file.
+fn slurp(path: &str) -> Result<String, Box<Info>> {
+    let error_info = |message: std::io::Error| {
+        Box::new(Info {
             level: Level::Error,
-            filename: path.to_owned(),
+            filename: display_path(path).to_owned(),
             message: format!("{}", message),
             position: None,
+            location: None,
             source: None,
-        }),
+        })
+    };
+
+    let mut contents = String::new();
+    let read_result = if path == STDIN_PATH {
+        std::io::stdin().read_to_string(&mut contents)
+    } else {
+        match File::open(path) {
+            Ok(mut file) => file.read_to_string(&mut contents),
+            Err(message) => return Err(error_info(message)),
+        }
+    };
+
+    match read_result {
+        Ok(_) => Ok(contents),
+        Err(message) => Err(error_info(message)),
     }
 }
 
-/// Convert "foo.bf" to "foo".
+/// Convert "foo.bf" to "foo". Programs read from stdin are called
+/// "a.out", like C compilers do.
 fn executable_name(bf_path: &str) -> String {
+    if bf_path == STDIN_PATH {
+        return "a.out".to_owned();
+    }
     let bf_file_name = Path::new(bf_path).file_name().unwrap().to_str().unwrap();
 
     let mut name_parts: Vec<_> = bf_file_name.split('.').collect();
@@ -86,8 +144,19 @@ fn executable_name_relative_path() {
     assert_eq!(executable_name("bar/baz.bf"), "baz");
 }
 
+#[test]
+fn executable_name_stdin() {
+    assert_eq!(executable_name("-"), "a.out");
+}
+
 fn print_usage(bin_name: &str, opts: Options) {
-    let brief = format!("Usage: {} SOURCE_FILE [options]", bin_name);
+    let brief = format!(
+        "Usage: {} SOURCE_FILE... [options]\n       {} verify-determinism SOURCE_FILE [options]\n       \
+         {} fmt SOURCE_FILE [options]\n       {} minify SOURCE_FILE [options]\n       \
+         {} run SOURCE_FILE [options] [-- ARGS...]\n       {} check SOURCE_FILE [options]\n       \
+         {} clean-cache\n       {} bench SOURCE_FILE [options]\n       {} targets",
+        bin_name, bin_name, bin_name, bin_name, bin_name, bin_name, bin_name, bin_name, bin_name
+    );
     print!("{}", opts.usage(&brief));
 }
 
@@ -98,205 +167,2495 @@ fn convert_io_error<T>(result: Result<T, std::io::Error>) -> Result<T, String> {
     }
 }
 
-// TODO: return a Vec<Info> that may contain warnings or errors,
-// instead of printing in lots of different place shere.
-fn compile_file(matches: &Matches) -> Result<(), String> {
-    let path = &matches.free[0];
+/// Parse the numeric option `name`, returning `default` if the user
+/// didn't specify it.
+fn numeric_opt<T: FromStr>(matches: &Matches, name: &str, default: T) -> Result<T, String> {
+    match matches.opt_str(name) {
+        Some(value) => value
+            .parse::<T>()
+            .map_err(|_| format!("Invalid --{} value: {}", name, value)),
+        None => Ok(default),
+    }
+}
 
-    let src = match slurp(path) {
-        Ok(src) => src,
+/// The bfc optimisation level requested by the user:
+///
+/// * 0: no optimisations, useful for debugging miscompilations.
+/// * 1: peephole optimisations.
+/// * 2: peephole optimisations and speculative execution.
+/// * 3: as 2, plus loop unrolling.
+fn opt_level(matches: &Matches) -> Result<u8, String> {
+    let level = numeric_opt(matches, "opt", 2)?;
+    if level > 3 {
+        return Err(format!("Invalid --opt value: {}", level));
+    }
+    Ok(level)
+}
+
+/// When bfc's expensive analyses should give up, if the user passed
+/// `--compile-budget`. Each analysis gets the whole budget, starting
+/// when it calls this.
+fn compile_deadline(matches: &Matches) -> Result<Option<Instant>, String> {
+    if !matches.opt_present("compile-budget") {
+        return Ok(None);
+    }
+    let budget_ms = numeric_opt(matches, "compile-budget", 0)?;
+    Ok(Some(Instant::now() + Duration::from_millis(budget_ms)))
+}
+
+/// The cell width requested with `--cell-size`, 8 bits by default.
+fn cell_width(matches: &Matches) -> Result<CellWidth, String> {
+    let bits = numeric_opt(matches, "cell-size", 8)?;
+    CellWidth::from_bits(bits)
+        .ok_or_else(|| format!("Invalid --cell-size value: {} (expected 8, 16 or 32)", bits))
+}
+
+/// What happens when a cell overflows, as requested with
+/// `--overflow`. Cells wrap by default.
+fn overflow(matches: &Matches) -> Result<Overflow, String> {
+    match matches.opt_str("overflow") {
+        Some(name) => Overflow::from_name(&name).ok_or_else(|| {
+            format!(
+                "Invalid --overflow value: {} (expected wrap, abort or saturate)",
+                name
+            )
+        }),
+        None => Ok(Overflow::default()),
+    }
+}
+
+/// What reads store at EOF, as requested with `--eof`. Reads store
+/// -1 by default.
+fn eof(matches: &Matches) -> Result<Eof, String> {
+    match matches.opt_str("eof") {
+        Some(name) => Eof::from_name(&name).ok_or_else(|| {
+            format!(
+                "Invalid --eof value: {} (expected zero, minus-one or unchanged)",
+                name
+            )
+        }),
+        None => Ok(Eof::default()),
+    }
+}
+
+/// What happens when the pointer leaves the tape, as requested with
+/// `--bounds`. We don't check by default.
+fn bounds(matches: &Matches) -> Result<Bounds, String> {
+    match matches.opt_str("bounds") {
+        Some(name) => Bounds::from_name(&name).ok_or_else(|| {
+            format!(
+                "Invalid --bounds value: {} (expected unchecked, wrap or abort)",
+                name
+            )
+        }),
+        None => Ok(Bounds::default()),
+    }
+}
+
+/// The number of cells requested with `--tape-size`.
+fn tape_size(matches: &Matches) -> Result<usize, String> {
+    let size = numeric_opt(matches, "tape-size", DEFAULT_TAPE_SIZE)?;
+    if size == 0 || size > MAX_TAPE_SIZE {
+        return Err(format!(
+            "Invalid --tape-size value: {} (expected 1 to {})",
+            size, MAX_TAPE_SIZE
+        ));
+    }
+    Ok(size)
+}
+
+/// The default --max-unroll value at -O3.
+const DEFAULT_MAX_UNROLL: usize = 64;
+
+/// Convert a BF warning to an Info that we can show to the user.
+fn warning_info(sources: &SourceMap, warning: Warning) -> Info {
+    sources.info(Level::Warning, warning.message, warning.position)
+}
+
+/// Read the profile passed with `--profile-use`, if any.
+fn read_profile(matches: &Matches) -> Result<Option<Rc<Profile>>, String> {
+    let path = match matches.opt_str("profile-use") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let text = match slurp(&path) {
+        Ok(text) => text,
         Err(info) => {
             return Err(format!("{}", info));
         }
     };
+    let profile = Profile::parse(&text).map_err(|message| format!("{}: {}", path, message))?;
+    Ok(Some(Rc::new(profile)))
+}
 
-    let mut instrs = match bfir::parse(&src) {
-        Ok(instrs) => instrs,
-        Err(parse_error) => {
-            let info = Info {
-                level: Level::Error,
-                filename: path.to_owned(),
-                message: parse_error.message,
-                position: Some(parse_error.position),
-                source: Some(src),
-            };
+/// Read the token file passed with `--tokens`.
+fn read_token_map(path: &str) -> Result<TokenMap, String> {
+    let text = match slurp(path) {
+        Ok(text) => text,
+        Err(info) => {
             return Err(format!("{}", info));
         }
     };
+    TokenMap::parse(&text).map_err(|message| format!("{}: {}", path, message))
+}
 
-    let opt_level = matches.opt_str("opt").unwrap_or_else(|| String::from("2"));
-    if opt_level != "0" {
-        let pass_specification = matches.opt_str("passes");
-        let (opt_instrs, warnings) = peephole::optimize(instrs, &pass_specification);
-        instrs = opt_instrs;
+/// The format of the source file: `--input-format` (or `--lang`) if
+/// given, otherwise guessed from the file extension.
+fn input_format(matches: &Matches, path: &str) -> String {
+    if let Some(format) = matches
+        .opt_str("input-format")
+        .or_else(|| matches.opt_str("lang"))
+    {
+        return format;
+    }
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+    match extension {
+        Some("bfir") => "bfir".to_owned(),
+        Some("ook") => "ook".to_owned(),
+        _ => "bf".to_owned(),
+    }
+}
 
-        for warning in warnings {
-            let info = Info {
-                level: Level::Warning,
-                filename: path.to_owned(),
-                message: warning.message,
-                position: warning.position,
-                source: Some(src.clone()),
-            };
-            eprintln!("{}", info);
+#[test]
+fn lang_selects_input_format() {
+    let matches = options().parse(&["--lang=ook", "foo.bf"]).unwrap();
+    assert_eq!(input_format(&matches, "foo.bf"), "ook");
+}
+
+/// Frontend flags that change the meaning of the same characters, or
+/// that one frontend would silently ignore, so can't be used together.
+const CONFLICTING_FRONTEND_FLAGS: &[(&str, &str, &str)] = &[
+    ("macros", "tokens", "macros are written with BF commands"),
+    ("macros", "extensions", "macros already use '@' and '$'"),
+    ("tokens", "extensions", "token files only spell BF commands"),
+    ("tokens", "breakpoints", "token files can't spell '#'"),
+    ("bang-input", "extensions", "both use '!'"),
+    ("input-format", "lang", "--lang is another name for it"),
+];
+
+/// Frontend flags that only change how BF source is read, so other
+/// input formats would silently ignore them.
+const BF_ONLY_FRONTEND_FLAGS: &[&str] = &["macros", "tokens", "extensions", "breakpoints"];
+
+/// Check that the frontend flags in `matches` can be used together,
+/// and with the input format of `path`.
+fn check_frontend_flags(matches: &Matches, path: &str) -> Result<(), String> {
+    for &(first, second, reason) in CONFLICTING_FRONTEND_FLAGS {
+        if matches.opt_present(first) && matches.opt_present(second) {
+            return Err(format!(
+                "--{} can't be used with --{}, as {}.",
+                first, second, reason
+            ));
         }
     }
 
-    if matches.opt_present("dump-ir") {
-        for instr in &instrs {
-            println!("{}", instr);
+    let format = input_format(matches, path);
+    if format != "bf" {
+        for flag in BF_ONLY_FRONTEND_FLAGS {
+            if matches.opt_present(flag) {
+                return Err(format!(
+                    "--{} can't be used with --input-format={}, as it only applies to BF source.",
+                    flag, format
+                ));
+            }
         }
-        return Ok(());
     }
+    Ok(())
+}
 
-    let (state, execution_warning) = if opt_level == "2" {
-        execution::execute(&instrs, execution::max_steps())
-    } else {
-        let mut init_state = execution::ExecutionState::initial(&instrs[..]);
-        // TODO: this will crash on the empty program.
-        init_state.start_instr = Some(&instrs[0]);
-        (init_state, None)
+#[test]
+fn frontend_flags_conflict() {
+    let conflicts = [
+        ("--macros", "--tokens=tokens.txt"),
+        ("--macros", "--extensions"),
+        ("--tokens=tokens.txt", "--extensions"),
+        ("--tokens=tokens.txt", "--breakpoints"),
+        ("--bang-input", "--extensions"),
+        ("--input-format=ook", "--lang=ook"),
+    ];
+    for &(first, second) in &conflicts {
+        let matches = options().parse(&[first, second, "foo.bf"]).unwrap();
+        assert!(check_frontend_flags(&matches, "foo.bf").is_err());
+    }
+}
+
+#[test]
+fn frontend_flags_need_bf_input() {
+    let flags = [
+        "--macros",
+        "--tokens=tokens.txt",
+        "--extensions",
+        "--breakpoints",
+    ];
+    for &flag in &flags {
+        for &format in &["--input-format=ook", "--input-format=bfir"] {
+            let matches = options().parse(&[flag, format, "foo.bf"]).unwrap();
+            assert!(check_frontend_flags(&matches, "foo.bf").is_err());
+        }
+
+        // The format can also come from the file extension.
+        let matches = options().parse(&[flag, "foo.ook"]).unwrap();
+        assert_eq!(
+            check_frontend_flags(&matches, "foo.ook"),
+            Err(format!(
+                "{} can't be used with --input-format=ook, as it only applies to BF source.",
+                flag.split('=').next().unwrap()
+            ))
+        );
+    }
+
+    let matches = options()
+        .parse(&["--macros", "--input-format=bf", "foo.ook"])
+        .unwrap();
+    assert_eq!(check_frontend_flags(&matches, "foo.ook"), Ok(()));
+}
+
+#[test]
+fn frontend_flags_compatible() {
+    let compatible = [
+        vec!["--macros", "--breakpoints"],
+        vec!["--extensions", "--breakpoints"],
+        vec!["--bang-input", "--breakpoints"],
+        vec!["--bang-input", "--tokens=tokens.txt"],
+    ];
+    for flags in &compatible {
+        let matches = options().parse(flags).unwrap();
+        assert_eq!(check_frontend_flags(&matches, "foo.bf"), Ok(()));
+    }
+}
+
+/// Parse the source, which is BF unless `--input-format` or the file
+/// extension says otherwise. Returns the instructions and the files
+/// they came from.
+fn parse_source(
+    matches: &Matches,
+    path: &str,
+    src: &str,
+) -> Result<(Vec<AstNode>, SourceMap), String> {
+    check_frontend_flags(matches, path)?;
+
+    let mut sources = SourceMap::new(display_path(path), src);
+    let result = match input_format(matches, path).as_str() {
+        "bf" if matches.opt_present("macros") => {
+            let include_paths: Vec<_> = matches
+                .opt_strs("include-path")
+                .iter()
+                .map(PathBuf::from)
+                .collect();
+            macros::parse(
+                &mut sources,
+                &include_paths,
+                matches.opt_present("breakpoints"),
+            )
+        }
+        "bf" if matches.opt_present("tokens") => {
+            let token_path = matches.opt_str("tokens").unwrap();
+            read_token_map(&token_path)?.parse_source(src)
+        }
+        "bf" if matches.opt_present("extensions") => {
+            bfir::parse_with_extensions(src, matches.opt_present("breakpoints"))
+        }
+        "bf" if matches.opt_present("breakpoints") => bfir::parse_with_breakpoints(src),
+        "bf" => bfir::parse(src),
+        "bfir" => irtext::parse(src).map_err(|parse_error| vec![parse_error]),
+        "ook" => ook::parse(src),
+        format => {
+            return Err(format!(
+                "Unknown input format '{}'. Expected 'bf', 'bfir' or 'ook'.",
+                format
+            ))
+        }
     };
 
-    if let Some(execution_warning) = execution_warning {
-        let info = Info {
-            level: Level::Warning,
-            filename: path.to_owned(),
-            message: execution_warning.message,
-            position: execution_warning.position,
-            source: Some(src),
-        };
-        eprintln!("{}", info);
+    match result {
+        Ok(instrs) => {
+            let instrs = peephole::wrap_cells(instrs, cell_width(matches)?, overflow(matches)?);
+            Ok((instrs, sources))
+        }
+        Err(parse_errors) => {
+            let infos: Vec<_> = parse_errors
+                .into_iter()
+                .map(|parse_error| {
+                    let info = sources.info(
+                        Level::Error,
+                        parse_error.message,
+                        Some(parse_error.position),
+                    );
+                    format!("{}", info)
+                })
+                .collect();
+            Err(infos.join("\n"))
+        }
     }
+}
 
-    llvm::init_llvm();
-    let target_triple = matches.opt_str("target");
-    let mut llvm_module = llvm::compile_to_module(path, target_triple.clone(), &instrs, &state);
+/// With `--bang-input`, split the source into the program and the
+/// input after the first `!`. Otherwise, the whole file is the
+/// program.
+fn split_input<'a>(matches: &Matches, src: &'a str) -> (&'a str, Option<&'a [u8]>) {
+    if matches.opt_present("bang-input") {
+        bfir::split_bang_input(src)
+    } else {
+        (src, None)
+    }
+}
 
-    if matches.opt_present("dump-llvm") {
-        let llvm_ir_cstr = llvm_module.to_cstring();
-        let llvm_ir = String::from_utf8_lossy(llvm_ir_cstr.as_bytes());
-        println!("{}", llvm_ir);
-        return Ok(());
+/// Parse the BF source and apply the optimisations requested in
+/// `matches`. Any warnings are appended to `warnings`.
+fn parse_and_optimize(
+    matches: &Matches,
+    path: &str,
+    src: &str,
+    warnings: &mut Vec<Info>,
+) -> Result<(Vec<AstNode>, SourceMap), String> {
+    let (mut instrs, sources) = parse_source(matches, path, src)?;
+
+    // On a circular tape, cells left of cell #0 are at the end.
+    if bounds(matches)? != Bounds::Wrap {
+        if let Some((index, position)) = bounds::left_of_start(&instrs) {
+            let warning = Warning {
+                message: format!(
+                    "This instruction can access cell {}, left of cell #0.",
+                    index
+                ),
+                position,
+            };
+            warnings.push(warning_info(&sources, warning));
+        }
     }
 
-    let llvm_opt_raw = matches
-        .opt_str("llvm-opt")
-        .unwrap_or_else(|| "3".to_owned());
-    let mut llvm_opt = llvm_opt_raw.parse::<i64>().unwrap_or(3);
-    if llvm_opt < 0 || llvm_opt > 3 {
-        // TODO: warn on unrecognised input.
-        llvm_opt = 3;
+    if opt_level(matches)? > 0 {
+        let options = optimize_options(matches)?;
+        if matches.opt_present("explain-removals") {
+            for explanation in peephole::explain_removals(&instrs, &options) {
+                warnings.push(sources.info(Level::Note, explanation.message, explanation.position));
+            }
+        }
+
+        let (opt_instrs, opt_warnings) = if matches.opt_present("opt-stats") {
+            let (opt_instrs, opt_warnings, stats) = peephole::optimize_with_stats(instrs, &options);
+            print_pass_stats(&stats);
+            (opt_instrs, opt_warnings)
+        } else {
+            peephole::optimize(instrs, &options)
+        };
+        instrs = opt_instrs;
+
+        for warning in opt_warnings {
+            warnings.push(warning_info(&sources, warning));
+        }
     }
 
-    llvm::optimise_ir(&mut llvm_module, llvm_opt);
+    Ok((instrs, sources))
+}
 
-    // Compile the LLVM IR to a temporary object file.
-    let object_file = convert_io_error(NamedTempFile::new())?;
-    let obj_file_path = object_file.path().to_str().expect("path not valid utf-8");
-    llvm::write_object_file(&mut llvm_module, &obj_file_path)?;
+/// The peephole optimisation settings requested by the user.
+fn optimize_options(matches: &Matches) -> Result<peephole::OptimizeOptions, String> {
+    let passes = match matches.opt_str("passes") {
+        Some(specification) => peephole::passes_from_specification(&specification)?,
+        None => peephole::default_passes(),
+    };
+    let passes = peephole::without_passes(passes, &matches.opt_strs("disable-pass"))?;
+    let default_max_unroll = if opt_level(matches)? >= 3 {
+        DEFAULT_MAX_UNROLL
+    } else {
+        0
+    };
+    let pass_fuel = if matches.opt_present("pass-fuel") {
+        Some(numeric_opt(matches, "pass-fuel", 0)?)
+    } else {
+        None
+    };
+    let dump_ir_after = match matches.opt_str("dump-ir-after") {
+        Some(specification) => peephole::dump_ir_after_from_specification(&specification)?,
+        None => vec![],
+    };
+    Ok(peephole::OptimizeOptions {
+        passes,
+        max_unroll: numeric_opt(matches, "max-unroll", default_max_unroll)?,
+        remove_dead_reads: matches.opt_present("remove-dead-reads"),
+        fuse_loops: matches.opt_present("fuse-loops"),
+        max_iterations: numeric_opt(matches, "max-opt-iterations", peephole::MAX_OPT_ITERATIONS)?,
+        pass_fuel,
+        profile: read_profile(matches)?,
+        dump_ir_after,
+        cell_width: cell_width(matches)?,
+        overflow: overflow(matches)?,
+        eof: eof(matches)?,
+        bounds: bounds(matches)?,
+        deadline: compile_deadline(matches)?,
+    })
+}
 
-    let output_name = executable_name(path);
-    link_object_file(&obj_file_path, &output_name, target_triple)?;
+/// Check that the optimised program `instrs` writes the same output
+/// as the original source, on the inputs passed with
+/// `--verify-input`, or on generated inputs if there are none.
+fn verify_optimisation(
+    matches: &Matches,
+    path: &str,
+    src: &str,
+    instrs: &[AstNode],
+    warnings: &mut Vec<Info>,
+) -> Result<(), String> {
+    let (original, _) = parse_source(matches, path, src)?;
 
-    let strip_opt = matches.opt_str("strip").unwrap_or_else(|| "yes".to_owned());
-    if strip_opt == "yes" {
-        strip_executable(&output_name)?
+    let mut inputs = vec![];
+    for input_path in matches.opt_strs("verify-input") {
+        inputs.push(convert_io_error(fs::read(&input_path))?);
+    }
+    if inputs.is_empty() {
+        inputs = verify::generated_inputs();
     }
 
+    let checked = verify::verify_optimisation(
+        &original,
+        instrs,
+        &inputs,
+        &verify::VerifyOptions {
+            steps: execution::max_steps(),
+            cell_width: cell_width(matches)?,
+            overflow: overflow(matches)?,
+            bounds: bounds(matches)?,
+            tape_size: tape_size(matches)?,
+            eof: eof(matches)?,
+        },
+    )?;
+    if checked == 0 {
+        warnings.push(Info {
+            level: Level::Note,
+            filename: display_path(path).to_owned(),
+            message: "--verify-opt didn't check anything, because the program didn't \
+                      finish on any input."
+                .to_owned(),
+            position: None,
+            location: None,
+            source: None,
+        });
+    }
     Ok(())
 }
 
-fn link_object_file(
-    object_file_path: &str,
-    executable_path: &str,
-    target_triple: Option<String>,
-) -> Result<(), String> {
-    // Link the object file.
-    let clang_args = if let Some(ref target_triple) = target_triple {
-        vec![
-            object_file_path,
-            "-target",
-            &target_triple,
-            "-o",
-            &executable_path[..],
-        ]
-    } else {
-        vec![object_file_path, "-o", &executable_path[..]]
+/// Print a table showing how much each peephole pass did.
+fn print_pass_stats(stats: &[PassStats]) {
+    eprintln!(
+        "{:<16}{:>8}{:>16}{:>12}",
+        "pass", "runs", "instrs removed", "time (ms)"
+    );
+    for pass_stats in stats {
+        eprintln!(
+            "{:<16}{:>8}{:>16}{:>12.3}",
+            pass_stats.name,
+            pass_stats.runs,
+            pass_stats.instrs_removed,
+            pass_stats.duration.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+/// Warn if the program writes different output with 8-bit cells
+/// than with the cells of `state`, as it probably relies on cells
+/// wrapping at 8 bits. We compare the output written at compile time,
+/// since we can't run the rest of the program.
+fn check_cell_width(
+    path: &str,
+    instrs: &[AstNode],
+    max_steps: u64,
+    state: &execution::ExecutionState,
+) -> Option<Info> {
+    if state.cell_width == CellWidth::Bits8 {
+        return None;
+    }
+    // The state has as many cells as the program can reach, so
+    // running on a tape of that size gives the same cells.
+    let tape_size = state.cells.len();
+    let (byte_state, _) = execution::execute(
+        instrs,
+        max_steps,
+        CellWidth::Bits8,
+        state.overflow,
+        state.bounds,
+        tape_size,
+    );
+
+    let outputs = &state.outputs;
+    let byte_outputs = &byte_state.outputs;
+    let both_finished = state.start_instr.is_none() && byte_state.start_instr.is_none();
+    let differs_at = match outputs.iter().zip(byte_outputs).position(|(a, b)| a != b) {
+        Some(index) => index,
+        None if both_finished && outputs.len() != byte_outputs.len() => {
+            outputs.len().min(byte_outputs.len())
+        }
+        None => return None,
     };
 
-    shell::run_shell_command("clang", &clang_args[..])
+    Some(Info {
+        level: Level::Warning,
+        filename: display_path(path).to_owned(),
+        message: format!(
+            "This program writes different output with {}-bit cells than with 8-bit \
+             cells (from byte {}), so it may rely on cells wrapping at 8 bits.",
+            state.cell_width.bits(),
+            differs_at
+        ),
+        position: None,
+        location: None,
+        source: None,
+    })
 }
 
-fn strip_executable(executable_path: &str) -> Result<(), String> {
-    let strip_args = match std::env::consts::OS {
-        "macos" => vec![&executable_path[..]],
-        _ => vec!["-s", &executable_path[..]],
+#[test]
+fn check_cell_width_different_output() {
+    // 16 * 16 is 0 with 8-bit cells, so the second loop only runs
+    // with wider cells.
+    let instrs = bfir::parse("++++++++++++++++[>++++++++++++++++<-]>[>+<--]>.").unwrap();
+    let (state, _) = execution::execute(
+        &instrs,
+        10_000,
+        CellWidth::Bits16,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+    assert!(check_cell_width("foo.bf", &instrs, 10_000, &state).is_some());
+}
+
+#[test]
+fn check_cell_width_same_output() {
+    let instrs = bfir::parse("-.+++[>++<-]>.").unwrap();
+    let (state, _) = execution::execute(
+        &instrs,
+        10_000,
+        CellWidth::Bits32,
+        Overflow::Wrap,
+        Bounds::Unchecked,
+        DEFAULT_TAPE_SIZE,
+    );
+    assert!(check_cell_width("foo.bf", &instrs, 10_000, &state).is_none());
+}
+
+/// Run as much of `instrs` as we can at compile time, adding any
+/// problems we find to `warnings`.
+fn execute_at_compile_time<'a>(
+    matches: &Matches,
+    path: &str,
+    sources: &SourceMap,
+    instrs: &'a [AstNode],
+    warnings: &mut Vec<Info>,
+) -> Result<execution::ExecutionState<'a>, String> {
+    let opt_level = opt_level(matches)?;
+    let max_steps = numeric_opt(matches, "max-speculative-steps", execution::max_steps())?;
+    let cell_width = cell_width(matches)?;
+    let overflow = overflow(matches)?;
+    let bounds = bounds(matches)?;
+    let tape_size = tape_size(matches)?;
+    let deadline = compile_deadline(matches)?;
+    let (state, execution_warning) = if opt_level >= 2 && max_steps > 0 {
+        execution::execute_with_deadline(
+            instrs, max_steps, deadline, cell_width, overflow, bounds, tape_size,
+        )
+    } else {
+        let mut init_state =
+            execution::ExecutionState::initial_with_bounds(instrs, tape_size, bounds);
+        init_state.start_instr = instrs.first();
+        (init_state, None)
     };
-    shell::run_shell_command("strip", &strip_args[..])
+
+    // Programs without reads can always be run to completion at
+    // compile time, unless they hit a runtime error or take too long.
+    if opt_level >= 2 && max_steps > 0 && !bfir::reads_input(instrs) {
+        let message = if state.start_instr.is_none() {
+            Some(
+                "This program reads no input, so bfc ran it at compile time \
+                 and the executable only writes its output."
+                    .to_owned(),
+            )
+        } else if execution_warning.is_none()
+            && deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Some(
+                "This program reads no input, but didn't finish within the \
+                 --compile-budget at compile time."
+                    .to_owned(),
+            )
+        } else if execution_warning.is_none() {
+            Some(format!(
+                "This program reads no input, but didn't finish within {} \
+                 steps at compile time. Try a larger --max-speculative-steps.",
+                max_steps
+            ))
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            warnings.push(Info {
+                level: Level::Note,
+                filename: display_path(path).to_owned(),
+                message,
+                position: None,
+                location: None,
+                source: None,
+            });
+        }
+    }
+
+    if let Some(execution_warning) = execution_warning {
+        warnings.push(warning_info(sources, execution_warning));
+    }
+    if opt_level >= 2 && max_steps > 0 {
+        if let Some(info) = check_cell_width(path, instrs, max_steps, &state) {
+            warnings.push(info);
+        }
+    }
+
+    Ok(state)
 }
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+#[test]
+fn compile_time_execution_disabled_on_empty_program() {
+    let matches = options()
+        .parse(&["--max-speculative-steps=0", "foo.bf"])
+        .unwrap();
+    let sources = SourceMap::new("foo.bf", "");
+    let mut warnings = vec![];
+    let state = execute_at_compile_time(&matches, "foo.bf", &sources, &[], &mut warnings).unwrap();
 
-fn main() {
-    let args: Vec<_> = env::args().collect();
+    assert_eq!(state.start_instr, None);
+    assert!(warnings.is_empty());
+}
 
-    let mut opts = Options::new();
+/// Execute as much of the program as we can at compile time, then
+/// compile the remaining instructions to an LLVM module. Reads
+/// consume `input`, if given, rather than stdin. Any warnings are
+/// appended to `warnings`.
+fn compile_to_llvm(
+    matches: &Matches,
+    path: &str,
+    sources: &SourceMap,
+    input: Option<&[u8]>,
+    instrs: &[AstNode],
+    warnings: &mut Vec<Info>,
+) -> Result<llvm::Module, String> {
+    let opt_level = opt_level(matches)?;
+    let max_steps = numeric_opt(matches, "max-speculative-steps", execution::max_steps())?;
+    let cell_width = cell_width(matches)?;
+    let overflow = overflow(matches)?;
+    let bounds = bounds(matches)?;
+    let tape_size = tape_size(matches)?;
+    let state = execute_at_compile_time(matches, path, sources, instrs, warnings)?;
 
-    opts.optflag("h", "help", "print usage");
-    opts.optflag("v", "version", "print bfc version");
-    opts.optflag("", "dump-llvm", "print LLVM IR generated");
-    opts.optflag("", "dump-ir", "print BF IR generated");
+    // We can't execute past a read, but we can still optimise the
+    // rest of the program using the cell values we've computed. We
+    // don't do this with --pass-fuel, so bisecting only counts changes
+    // to the original program.
+    let checkpoint = if opt_level >= 2 && max_steps > 0 && !matches.opt_present("pass-fuel") {
+        execution::checkpoint_program(instrs, &state)
+    } else {
+        None
+    };
+    let checkpoint_instrs;
+    let (instrs, state) = match checkpoint {
+        Some(checkpoint) => {
+            // We've already warned about any problems in the original
+            // program, so ignore warnings here.
+            checkpoint_instrs = peephole::optimize(checkpoint, &optimize_options(matches)?).0;
+            let (mut checkpoint_state, _) = execution::execute_with_deadline(
+                &checkpoint_instrs,
+                max_steps,
+                compile_deadline(matches)?,
+                cell_width,
+                overflow,
+                bounds,
+                tape_size,
+            );
 
-    opts.optopt("O", "opt", "optimization level (0 to 2)", "LEVEL");
-    opts.optopt("", "llvm-opt", "LLVM optimization level (0 to 3)", "LEVEL");
-    opts.optopt(
-        "",
-        "passes",
-        "limit bfc optimisations to those specified",
-        "PASS-SPECIFICATION",
-    );
-    opts.optopt(
-        "",
-        "strip",
-        "strip symbols from the binary (default: yes)",
-        "yes|no",
-    );
+            let mut outputs = state.outputs;
+            outputs.extend(checkpoint_state.outputs);
+            checkpoint_state.outputs = outputs;
 
-    let default_triple_cstring = llvm::get_default_target_triple();
-    let default_triple = default_triple_cstring.to_str().unwrap();
+            (&checkpoint_instrs[..], checkpoint_state)
+        }
+        None => (instrs, state),
+    };
 
-    opts.optopt(
-        "",
-        "target",
-        &format!("LLVM target triple (default: {})", default_triple),
-        "TARGET",
+    let compile_options = llvm::CompileOptions {
+        experimental_simd: matches.opt_present("experimental-simd"),
+        opt_size: matches.opt_present("opt-size"),
+        profile_generate: if matches.opt_present("profile-generate") {
+            Some(format!("{}.profile", executable_name(path)))
+        } else {
+            None
+        },
+        profile: read_profile(matches)?,
+        min_outlined_body_len: numeric_opt(
+            matches,
+            "min-outline-size",
+            llvm::MIN_OUTLINED_BODY_LEN,
+        )?,
+        cell_width,
+        input: input.map(|input| input.to_vec()),
+        overflow,
+        source_map: Some(Rc::new(sources.clone())),
+        eof: eof(matches)?,
+        bounds,
+    };
+    let module = llvm::compile_to_module(
+        path,
+        matches.opt_str("target"),
+        instrs,
+        &state,
+        &compile_options,
     );
+    module.verify()?;
+    Ok(module)
+}
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(_) => {
-            print_usage(&args[0], opts);
-            std::process::exit(1);
+/// The LLVM optimisation level requested by the user, from 0 to 3.
+/// This controls both the LLVM passes we run and code generation.
+fn llvm_opt_level(matches: &Matches) -> Result<u8, String> {
+    let level = numeric_opt(matches, "llvm-opt", 3)?;
+    if level > 3 {
+        return Err(format!("Invalid --llvm-opt value: {}", level));
+    }
+    Ok(level)
+}
+
+/// How much to tell the user about what we're doing: -1 with
+/// `--quiet`, 0 by default, 1 with `-v` and 2 with `-vv`.
+fn verbosity(matches: &Matches) -> i64 {
+    if matches.opt_present("quiet") {
+        -1
+    } else {
+        matches.opt_count("verbose") as i64
+    }
+}
+
+/// Whether to colour our output, as chosen with `--color`. By
+/// default, we only use colour when stderr is a terminal, since
+/// that's where our diagnostics go.
+fn use_color(matches: &Matches) -> Result<bool, String> {
+    match matches.opt_str("color") {
+        Some(ref color) if color == "always" => Ok(true),
+        Some(ref color) if color == "never" => Ok(false),
+        Some(ref color) if color != "auto" => Err(format!(
+            "Invalid --color value: {} (expected auto, always or never)",
+            color
+        )),
+        // Errors in JSON records shouldn't contain terminal colours.
+        _ => Ok(std::io::stderr().is_terminal()
+            && env::var_os("NO_COLOR").is_none()
+            && !json_messages(matches)?),
+    }
+}
+
+/// Run `command`, printing it first with `-vv`.
+fn run_command(matches: &Matches, command: &str, args: &[&str]) -> Result<(), String> {
+    if verbosity(matches) >= 2 {
+        eprintln!("Running: {} {}", command, args.join(" "));
+    }
+    shell::run_shell_command(command, args)
+}
+
+/// Whether the user passed `--message-format=json`.
+fn json_messages(matches: &Matches) -> Result<bool, String> {
+    match matches.opt_str("message-format") {
+        Some(ref format) if format == "json" => Ok(true),
+        Some(ref format) if format == "human" => Ok(false),
+        Some(format) => Err(format!(
+            "Invalid --message-format value: {} (expected human or json)",
+            format
+        )),
+        None => Ok(false),
+    }
+}
+
+/// Print `warnings`, as JSON records on stdout with
+/// `--message-format=json`. With `--quiet`, we only print errors.
+fn print_warnings(matches: &Matches, warnings: &mut Vec<Info>) {
+    let json = json_messages(matches).unwrap_or(false);
+    for warning in warnings.drain(..) {
+        if json {
+            println!("{}", json::info_json(&warning));
+        } else if verbosity(matches) >= 0 || matches!(warning.level, Level::Error) {
+            eprintln!("{}", warning);
         }
-    };
+    }
+}
 
-    if matches.opt_present("h") {
-        print_usage(&args[0], opts);
-        return;
+/// With `--message-format=json`, report that we wrote the `kind`
+/// output of the program at `path` to `output`.
+fn report_artifact(matches: &Matches, path: &str, kind: &str, output: &str) {
+    if json_messages(matches).unwrap_or(false) {
+        println!(
+            "{{\"reason\": \"artifact\", \"file\": {}, \"kind\": \"{}\", \"path\": {}}}",
+            json::quote(path),
+            kind,
+            json::quote(output)
+        );
     }
+}
 
-    if matches.opt_present("v") {
-        println!("bfc {}", VERSION);
-        return;
+/// The options that change the object file we compile a program to,
+/// besides the program itself.
+const CODEGEN_OPTIONS: &[&str] = &[
+    "opt",
+    "llvm-opt",
+    "max-speculative-steps",
+    "compile-budget",
+    "experimental-simd",
+    "opt-size",
+    "min-outline-size",
+    "profile-generate",
+    "tape-size",
+    "bounds",
+    "cell-size",
+    "overflow",
+    "eof",
+    "target",
+    "pass-fuel",
+    "passes",
+    "disable-pass",
+    "max-unroll",
+    "max-opt-iterations",
+    "remove-dead-reads",
+    "fuse-loops",
+];
+
+/// Where we'd cache the object file for a program.
+struct CacheSlot {
+    dir: PathBuf,
+    key: String,
+}
+
+/// The cache slot for the optimised program `instrs`, compiled from
+/// `src` at `path`, or None if we shouldn't use the cache.
+fn cache_slot(
+    matches: &Matches,
+    path: &str,
+    src: &str,
+    input: Option<&[u8]>,
+    instrs: &[AstNode],
+) -> Result<Option<CacheSlot>, String> {
+    // With --keep-temps or --dump-ir-after, users want to see the
+    // intermediate steps, so compile from scratch.
+    if matches.opt_present("no-cache")
+        || matches.opt_present("keep-temps")
+        || matches.opt_present("dump-ir-after")
+    {
+        return Ok(None);
     }
+    let dir = match cache::cache_dir() {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
 
-    if matches.free.len() != 1 {
-        print_usage(&args[0], opts);
-        std::process::exit(1);
+    let fingerprint = bfir::fingerprint(instrs).to_le_bytes();
+    let mut parts: Vec<Vec<u8>> = vec![
+        VERSION.as_bytes().to_vec(),
+        fingerprint.to_vec(),
+        input.unwrap_or_default().to_vec(),
+    ];
+    for name in CODEGEN_OPTIONS {
+        parts.push(matches.opt_strs(name).join("\n").into_bytes());
+        parts.push(vec![matches.opt_count(name) as u8]);
+    }
+    if let Some(profile_path) = matches.opt_str("profile-use") {
+        parts.push(convert_io_error(fs::read(profile_path))?);
     }
+    // Runtime errors and profiles refer to the source, so they're
+    // only the same if the source is.
+    if overflow(matches)? == Overflow::Abort
+        || bounds(matches)? == Bounds::Abort
+        || matches.opt_present("profile-generate")
+    {
+        parts.push(path.as_bytes().to_vec());
+        parts.push(src.as_bytes().to_vec());
+    }
+
+    let parts: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+    Ok(Some(CacheSlot {
+        dir,
+        key: cache::key(&parts),
+    }))
+}
+
+/// The cached form of `warnings`, or None if they refer to the source,
+/// as the same program in a different source file would have
+/// different positions.
+fn cache_notes(warnings: &[Info]) -> Option<Vec<String>> {
+    warnings
+        .iter()
+        .map(|warning| {
+            let level = match warning.level {
+                Level::Note => "note",
+                Level::Warning => "warning",
+                Level::Error => "error",
+            };
+            if warning.position.is_some() || warning.message.contains('\n') {
+                return None;
+            }
+            Some(format!("{}\t{}", level, warning.message))
+        })
+        .collect()
+}
 
-    match compile_file(&matches) {
+/// The warnings for the program at `path` from the cached `notes`.
+fn cached_warnings(path: &str, notes: &[String]) -> Vec<Info> {
+    notes
+        .iter()
+        .filter_map(|note| {
+            let (level, message) = note.split_once('\t')?;
+            let level = match level {
+                "note" => Level::Note,
+                "warning" => Level::Warning,
+                _ => Level::Error,
+            };
+            Some(Info {
+                level,
+                filename: display_path(path).to_owned(),
+                message: message.to_owned(),
+                position: None,
+                location: None,
+                source: None,
+            })
+        })
+        .collect()
+}
+
+/// Add the object file at `object_path` to the cache. The cache is
+/// only an optimisation, so if we can't write to it we carry on.
+fn store_in_cache(matches: &Matches, slot: &CacheSlot, notes: &[String], object_path: &Path) {
+    if let Err(e) = cache::store(&slot.dir, &slot.key, object_path, notes) {
+        if verbosity(matches) >= 1 {
+            eprintln!("Could not write to the cache: {}", e);
+        }
+    }
+}
+
+/// Delete every object file in the cache.
+fn clean_cache() -> Result<(), String> {
+    let dir = cache::cache_dir().ok_or("Could not find the cache directory")?;
+    let entries = convert_io_error(cache::clean(&dir))?;
+    println!(
+        "Removed {} cached object files from {}",
+        entries,
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Compile the program at `path`. With `--message-format=json`, also
+/// report whether it compiled and how long it took.
+fn compile_and_report(matches: &Matches, path: &str) -> Result<(), String> {
+    let start = Instant::now();
+    let result = compile_file(matches, path);
+
+    if json_messages(matches)? {
+        let error = match result {
+            Ok(()) => "null".to_owned(),
+            Err(ref e) => json::quote(e),
+        };
+        println!(
+            "{{\"reason\": \"finished\", \"file\": {}, \"success\": {}, \"error\": {}, \
+             \"duration_ms\": {:.3}}}",
+            json::quote(path),
+            result.is_ok(),
+            error,
+            start.elapsed().as_secs_f64() * 1000.0
+        );
+    }
+    result
+}
+
+fn compile_file(matches: &Matches, path: &str) -> Result<(), String> {
+    if verbosity(matches) >= 1 {
+        eprintln!("Compiling {}", display_path(path));
+    }
+
+    let src = match slurp(path) {
+        Ok(src) => src,
+        Err(info) => {
+            return Err(format!("{}", info));
+        }
+    };
+    let (src, input) = split_input(matches, &src);
+
+    let mut warnings = vec![];
+    let (instrs, sources) = parse_and_optimize(matches, path, src, &mut warnings)?;
+    if matches.opt_present("verify-opt") {
+        verify_optimisation(matches, path, src, &instrs, &mut warnings)?;
+    }
+    print_warnings(matches, &mut warnings);
+
+    if matches.opt_present("stats") {
+        eprint!("{}", metrics::metrics(&instrs, tape_size(matches)?));
+    }
+
+    if matches.opt_present("dump-bf-ir") {
+        return dump_bf_ir(matches, path, src, &instrs);
+    }
+
+    if matches.opt_present("dump-ir") {
+        for instr in &instrs {
+            println!("{}", instr);
+        }
+        return Ok(());
+    }
+
+    if matches.opt_present("dump-structure") {
+        print!("{}", structure::to_dot(&instrs));
+        return Ok(());
+    }
+
+    let emit = matches.opt_str("emit").unwrap_or_else(|| "exe".to_owned());
+    let extension = match EMIT_KINDS.iter().find(|&&(kind, _)| kind == emit) {
+        Some(&(_, extension)) => extension,
+        None => {
+            let kinds: Vec<_> = EMIT_KINDS.iter().map(|&(kind, _)| kind).collect();
+            return Err(format!(
+                "Unknown output format '{}'. Expected one of: {}.",
+                emit,
+                kinds.join(", ")
+            ));
+        }
+    };
+    let output = output_path(
+        matches.opt_str("o"),
+        matches.opt_str("out-dir"),
+        path,
+        extension,
+    )?;
+
+    let text = match emit.as_ref() {
+        "bf" => Some(
+            bfir::to_bf(&instrs, cell_width(matches)?, overflow(matches)?)
+                .ok_or("Could not convert the program to BF")?
+                + "\n",
+        ),
+        "bfir" => Some(irtext::to_text(&instrs)),
+        "json-ir" => Some(json::to_json(&instrs) + "\n"),
+        _ => None,
+    };
+    if json_messages(matches)? && output == STDOUT_PATH {
+        return Err("-o - can't be used with --message-format=json.".to_owned());
+    }
+    if let Some(text) = text {
+        write_output(&output, text.as_bytes())?;
+        report_artifact(matches, path, &emit, &output);
+        return Ok(());
+    }
+
+    let cache_slot = if emit == "exe" || emit == "obj" {
+        cache_slot(matches, path, src, input, &instrs)?
+    } else {
+        None
+    };
+    if let Some(ref slot) = cache_slot {
+        if let Some(entry) = cache::lookup(&slot.dir, &slot.key) {
+            if verbosity(matches) >= 1 {
+                eprintln!("Using cached {}", entry.object_path.display());
+            }
+            print_warnings(matches, &mut cached_warnings(path, &entry.notes));
+            let object_path = entry.object_path.to_str().expect("path not valid utf-8");
+            if emit == "obj" {
+                write_output(&output, &convert_io_error(fs::read(object_path))?)?;
+            } else {
+                link_executable(matches, object_path, &output)?;
+            }
+            report_artifact(matches, path, &emit, &output);
+            return Ok(());
+        }
+    }
+
+    llvm::init_llvm();
+    let mut llvm_module = compile_to_llvm(matches, path, &sources, input, &instrs, &mut warnings)?;
+    let cache = match cache_slot {
+        Some(ref slot) => cache_notes(&warnings).map(|notes| (slot, notes)),
+        None => None,
+    };
+    print_warnings(matches, &mut warnings);
+
+    llvm::optimise_ir(&mut llvm_module, llvm_opt_level(matches)?);
+
+    match emit.as_ref() {
+        "ir" => write_output(&output, llvm_module.to_cstring().as_bytes())?,
+        "bc" => llvm::write_bitcode_file(&mut llvm_module, &output)?,
+        "asm" => llvm::write_assembly_file(&mut llvm_module, &output, llvm_opt_level(matches)?)?,
+        "obj" => {
+            llvm::write_object_file(&mut llvm_module, &output, llvm_opt_level(matches)?)?;
+            if let Some((slot, notes)) = cache {
+                store_in_cache(matches, slot, &notes, Path::new(&output));
+            }
+        }
+        _ => write_executable(matches, path, &mut llvm_module, &output, cache)?,
+    }
+    report_artifact(matches, path, &emit, &output);
+    Ok(())
+}
+
+/// The directory to create temporary files in, as chosen with
+/// `--temp-dir`.
+fn temp_dir(matches: &Matches) -> Result<PathBuf, String> {
+    match matches.opt_str("temp-dir") {
+        Some(dir) => {
+            convert_io_error(fs::create_dir_all(&dir))?;
+            Ok(PathBuf::from(dir))
+        }
+        None => Ok(env::temp_dir()),
+    }
+}
+
+/// Create a temporary file for compiling the program at `path`,
+/// named after the program so it's easy to find with `--keep-temps`.
+fn temp_file(matches: &Matches, path: &str, suffix: &str) -> Result<NamedTempFile, String> {
+    let prefix = executable_name(path) + "-";
+    convert_io_error(
+        tempfile::Builder::new()
+            .prefix(&prefix)
+            .suffix(suffix)
+            .tempfile_in(temp_dir(matches)?),
+    )
+}
+
+/// We've finished with the temporary file `file`. Keep it if the user
+/// passed `--keep-temps`, and print its path with `-v`.
+fn finish_temp_file(matches: &Matches, file: NamedTempFile) -> Result<(), String> {
+    if verbosity(matches) >= 1 {
+        eprintln!("Temporary file: {}", file.path().display());
+    }
+    if matches.opt_present("keep-temps") {
+        if let Err(e) = file.keep() {
+            return Err(format!("{}", e));
+        }
+    }
+    Ok(())
+}
+
+/// Link `llvm_module`, compiled from the program at `path`, into an
+/// executable at `output`. If `cache` is given, also add the object
+/// file to the cache, with the notes we printed when compiling it.
+fn write_executable(
+    matches: &Matches,
+    path: &str,
+    llvm_module: &mut llvm::Module,
+    output: &str,
+    cache: Option<(&CacheSlot, Vec<String>)>,
+) -> Result<(), String> {
+    // With --keep-temps, also keep the optimised LLVM IR, so users
+    // can see what we compiled.
+    if matches.opt_present("keep-temps") {
+        let ir_file = temp_file(matches, path, ".ll")?;
+        convert_io_error(fs::write(
+            ir_file.path(),
+            llvm_module.to_cstring().as_bytes(),
+        ))?;
+        finish_temp_file(matches, ir_file)?;
+    }
+
+    // Compile the LLVM IR to a temporary object file.
+    let object_file = temp_file(matches, path, ".o")?;
+    let obj_file_path = object_file.path().to_str().expect("path not valid utf-8");
+    llvm::write_object_file(llvm_module, obj_file_path, llvm_opt_level(matches)?)?;
+    if let Some((slot, notes)) = cache {
+        store_in_cache(matches, slot, &notes, object_file.path());
+    }
+
+    link_executable(matches, obj_file_path, output)?;
+    finish_temp_file(matches, object_file)
+}
+
+/// Link the object file at `obj_file_path` into an executable at
+/// `output`.
+fn link_executable(matches: &Matches, obj_file_path: &str, output: &str) -> Result<(), String> {
+    let clang_args = link_args(
+        obj_file_path,
+        output,
+        matches.opt_str("target"),
+        &matches.opt_strs("cc-arg"),
+        &matches.opt_strs("link-arg"),
+    );
+    let clang_args: Vec<&str> = clang_args.iter().map(String::as_str).collect();
+    if verbosity(matches) >= 1 {
+        eprintln!("Linking {}", output);
+    }
+    run_command(matches, "clang", &clang_args)?;
+
+    let strip_opt = matches.opt_str("strip").unwrap_or_else(|| "yes".to_owned());
+    if strip_opt == "yes" {
+        strip_executable(matches, output)?
+    }
+
+    Ok(())
+}
+
+/// Compile the program at `path` to an executable in a temporary
+/// directory. The directory, and the executable, are deleted when
+/// it's dropped, unless the user passed --keep-temps.
+fn temp_executable(matches: &Matches, path: &str) -> Result<(TempDir, PathBuf), String> {
+    let src = match slurp(path) {
+        Ok(src) => src,
+        Err(info) => {
+            return Err(format!("{}", info));
+        }
+    };
+    let (src, input) = split_input(matches, &src);
+
+    let mut warnings = vec![];
+    let (instrs, sources) = parse_and_optimize(matches, path, src, &mut warnings)?;
+    print_warnings(matches, &mut warnings);
+
+    llvm::init_llvm();
+    let mut llvm_module = compile_to_llvm(matches, path, &sources, input, &instrs, &mut warnings)?;
+    print_warnings(matches, &mut warnings);
+    llvm::optimise_ir(&mut llvm_module, llvm_opt_level(matches)?);
+
+    let dir = convert_io_error(tempfile::Builder::new().tempdir_in(temp_dir(matches)?))?;
+    let executable = dir.path().join(executable_name(path));
+    let executable_str = executable.to_str().expect("path not valid utf-8");
+    write_executable(matches, path, &mut llvm_module, executable_str, None)?;
+    if verbosity(matches) >= 1 {
+        eprintln!("Temporary file: {}", executable_str);
+    }
+    Ok((dir, executable))
+}
+
+/// We've finished with the temporary directory `dir`. Keep it if the
+/// user passed `--keep-temps`.
+fn finish_temp_dir(matches: &Matches, dir: TempDir) {
+    if matches.opt_present("keep-temps") {
+        let _ = dir.into_path();
+    }
+}
+
+/// Compile the program at `path` to a temporary executable and run
+/// it with `args`, reading our stdin and writing our stdout. Returns
+/// the program's exit code.
+fn run_file(matches: &Matches, path: &str, args: &[String]) -> Result<i32, String> {
+    let (dir, executable) = temp_executable(matches, path)?;
+    let status = convert_io_error(Command::new(&executable).args(args).status())?;
+    finish_temp_dir(matches, dir);
+    Ok(exit_code(status))
+}
+
+/// The median of `times`, which must not be empty.
+fn median(times: &[Duration]) -> Duration {
+    let mut times = times.to_vec();
+    times.sort();
+    let middle = times.len() / 2;
+    if times.len() % 2 == 1 {
+        times[middle]
+    } else {
+        (times[middle - 1] + times[middle]) / 2
+    }
+}
+
+#[test]
+fn median_odd_runs() {
+    let times = [3, 1, 2].map(Duration::from_millis);
+    assert_eq!(median(&times), Duration::from_millis(2));
+}
+
+#[test]
+fn median_even_runs() {
+    let times = [4, 1, 2, 8].map(Duration::from_millis);
+    assert_eq!(median(&times), Duration::from_millis(3));
+}
+
+/// Compile the program at `path`, then run it several times with the
+/// `--input` file as stdin, and report how long it took and a hash of
+/// what it wrote.
+fn bench_file(matches: &Matches, path: &str) -> Result<(), String> {
+    let runs = numeric_opt(matches, "runs", 10)?;
+    if runs < 1 {
+        return Err("--runs must be at least 1 to benchmark a program.".to_owned());
+    }
+    let input = match matches.opt_str("input") {
+        Some(input_path) => convert_io_error(fs::read(input_path))?,
+        None => vec![],
+    };
+
+    let (dir, executable) = temp_executable(matches, path)?;
+
+    let mut times = vec![];
+    let mut first_output: Option<Vec<u8>> = None;
+    for run in 1..=runs {
+        let start = Instant::now();
+        let mut child = convert_io_error(
+            Command::new(&executable)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn(),
+        )?;
+        // The program may exit without reading all its input, so
+        // ignore errors writing it.
+        let _ = child.stdin.take().unwrap().write_all(&input);
+        let output = convert_io_error(child.wait_with_output())?;
+        times.push(start.elapsed());
+
+        if !output.status.success() {
+            return Err(format!(
+                "Run {} exited with code {}.",
+                run,
+                exit_code(output.status)
+            ));
+        }
+        match first_output {
+            Some(ref first_output) if *first_output != output.stdout => {
+                return Err(format!("Run {} wrote different output to run 1.", run));
+            }
+            Some(_) => {}
+            None => first_output = Some(output.stdout),
+        }
+    }
+    finish_temp_dir(matches, dir);
+
+    let min = *times.iter().min().unwrap();
+    let median = median(&times);
+    let output = first_output.unwrap();
+    if json_messages(matches)? {
+        println!(
+            "{{\"reason\": \"bench\", \"file\": {}, \"runs\": {}, \"min_ms\": {:.3}, \
+             \"median_ms\": {:.3}, \"output_bytes\": {}, \"output_hash\": \"{:016x}\"}}",
+            json::quote(path),
+            runs,
+            min.as_secs_f64() * 1000.0,
+            median.as_secs_f64() * 1000.0,
+            output.len(),
+            hash_of(&output)
+        );
+    } else {
+        println!(
+            "{}: {} runs, min {:.3} ms, median {:.3} ms, output {} bytes, hash {:016x}",
+            display_path(path),
+            runs,
+            min.as_secs_f64() * 1000.0,
+            median.as_secs_f64() * 1000.0,
+            output.len(),
+            hash_of(&output)
+        );
+    }
+    Ok(())
+}
+
+/// The exit code to report for a program that finished with
+/// `status`. Like shells, we use 128 plus the signal number for
+/// programs killed by a signal.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+#[test]
+fn exit_code_of_program() {
+    let status = Command::new("sh").args(["-c", "exit 3"]).status().unwrap();
+    assert_eq!(exit_code(status), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn exit_code_of_killed_program() {
+    let status = Command::new("sh")
+        .args(["-c", "kill -9 $$"])
+        .status()
+        .unwrap();
+    assert_eq!(exit_code(status), 137);
+}
+
+/// The number of files to compile at once, as chosen with `--jobs`.
+fn jobs(matches: &Matches) -> Result<usize, String> {
+    let default_jobs = thread::available_parallelism().map_or(1, |jobs| jobs.get());
+    let jobs = numeric_opt(matches, "jobs", default_jobs)?;
+    if jobs == 0 {
+        return Err("--jobs must be at least 1.".to_owned());
+    }
+    Ok(jobs)
+}
+
+/// Call `f` on each of `items`, using up to `threads` threads, and
+/// return the results in the same order as `items`.
+fn parallel_map<T, R, F>(threads: usize, items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    // Each thread takes the next item that nobody has started yet.
+    let next_index = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let index = next_index.fetch_add(1, Ordering::SeqCst);
+                        let item = match items.get(index) {
+                            Some(item) => item,
+                            None => return results,
+                        };
+                        results.push((index, f(item)));
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            for (index, result) in worker.join().expect("a worker thread panicked") {
+                results[index] = Some(result);
+            }
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|result| result.expect("every item has a result"))
+        .collect()
+}
+
+#[test]
+fn parallel_map_keeps_order() {
+    let items: Vec<usize> = (0..100).collect();
+    let doubled = parallel_map(4, &items, |item| item * 2);
+    assert_eq!(doubled, (0..200).step_by(2).collect::<Vec<_>>());
+}
+
+/// Compile each file in `paths`, carrying on after a file fails,
+/// then report which files compiled. We compile up to `--jobs`
+/// files at once.
+fn compile_files(matches: &Matches, paths: &[String]) -> Result<(), String> {
+    if matches.opt_present("o") {
+        return Err("-o can only be used when compiling a single file.".to_owned());
+    }
+
+    let succeeded = parallel_map(jobs(matches)?, paths, |path| {
+        let result = compile_and_report(matches, path);
+        if let Err(ref e) = result {
+            eprintln!("{}", e);
+        }
+        result.is_ok()
+    });
+
+    let mut failures = 0;
+    let mut summary = vec![];
+    for (path, &success) in paths.iter().zip(&succeeded) {
+        let status = if success {
+            "ok"
+        } else {
+            failures += 1;
+            "failed"
+        };
+        summary.push(format!("{}: {}", display_path(path), status));
+    }
+
+    if verbosity(matches) >= 0 {
+        for line in summary {
+            eprintln!("{}", line);
+        }
+    }
+    if failures > 0 {
+        return Err(format!(
+            "{} of {} files failed to compile.",
+            failures,
+            paths.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Print the BF IR before optimisation, after optimisation
+/// (`optimized`), or both, as chosen with `--dump-bf-ir`.
+fn dump_bf_ir(
+    matches: &Matches,
+    path: &str,
+    src: &str,
+    optimized: &[AstNode],
+) -> Result<(), String> {
+    let stage = matches
+        .opt_str("dump-bf-ir")
+        .unwrap_or_else(|| "after".to_owned());
+    let (before, after) = match stage.as_ref() {
+        "before" => (true, false),
+        "after" => (false, true),
+        "both" => (true, true),
+        _ => {
+            return Err(format!(
+                "Invalid --dump-bf-ir value '{}'. Expected 'before', 'after' or 'both'.",
+                stage
+            ))
+        }
+    };
+
+    if before {
+        let (parsed, _) = parse_source(matches, path, src)?;
+        if after {
+            println!("*** BF IR before optimisation ***");
+        }
+        for instr in &parsed {
+            println!("{}", instr);
+        }
+    }
+    if after {
+        if before {
+            println!("*** BF IR after optimisation ***");
+        }
+        for instr in optimized {
+            println!("{}", instr);
+        }
+    }
+    Ok(())
+}
+
+/// Write `contents` to the file `output`, or to stdout if `output`
+/// is `-`.
+fn write_output(output: &str, contents: &[u8]) -> Result<(), String> {
+    if output == STDOUT_PATH {
+        convert_io_error(std::io::stdout().write_all(contents))
+    } else {
+        convert_io_error(fs::write(output, contents))
+    }
+}
+
+fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Return the first line that differs between `a` and `b`, as a
+/// one-indexed line number and the two versions of the line.
+fn first_difference<'a>(a: &'a str, b: &'a str) -> Option<(usize, &'a str, &'a str)> {
+    let mut a_lines = a.lines();
+    let mut b_lines = b.lines();
+    let mut line_number = 1;
+
+    loop {
+        match (a_lines.next(), b_lines.next()) {
+            (None, None) => return None,
+            (a_line, b_line) => {
+                let a_line = a_line.unwrap_or("");
+                let b_line = b_line.unwrap_or("");
+                if a_line != b_line {
+                    return Some((line_number, a_line, b_line));
+                }
+            }
+        }
+        line_number += 1;
+    }
+}
+
+#[test]
+fn first_difference_identical() {
+    assert_eq!(first_difference("foo\nbar", "foo\nbar"), None);
+}
+
+#[test]
+fn first_difference_changed_line() {
+    assert_eq!(
+        first_difference("foo\nbar\nbaz", "foo\nbaz\nbaz"),
+        Some((2, "bar", "baz"))
+    );
+}
+
+#[test]
+fn first_difference_extra_line() {
+    assert_eq!(first_difference("foo", "foo\nbar"), Some((2, "", "bar")));
+}
+
+/// The output of compiling a BF program, used to check that
+/// compilation is deterministic.
+struct CompilationOutput {
+    warnings: String,
+    llvm_ir: String,
+    object: Vec<u8>,
+}
+
+fn compile_for_comparison(
+    matches: &Matches,
+    path: &str,
+    src: &str,
+    input: Option<&[u8]>,
+) -> Result<CompilationOutput, String> {
+    let mut warnings = vec![];
+    let (instrs, sources) = parse_and_optimize(matches, path, src, &mut warnings)?;
+    let mut llvm_module = compile_to_llvm(matches, path, &sources, input, &instrs, &mut warnings)?;
+    llvm::optimise_ir(&mut llvm_module, llvm_opt_level(matches)?);
+
+    let llvm_ir_cstr = llvm_module.to_cstring();
+    let llvm_ir = String::from_utf8_lossy(llvm_ir_cstr.as_bytes()).into_owned();
+
+    let object_file = convert_io_error(NamedTempFile::new())?;
+    let obj_file_path = object_file.path().to_str().expect("path not valid utf-8");
+    llvm::write_object_file(&mut llvm_module, obj_file_path, llvm_opt_level(matches)?)?;
+    let object = convert_io_error(fs::read(obj_file_path))?;
+
+    let warnings = warnings
+        .iter()
+        .map(|warning| format!("{}\n", warning))
+        .collect();
+
+    Ok(CompilationOutput {
+        warnings,
+        llvm_ir,
+        object,
+    })
+}
+
+/// Print the BF program at `path`, indented by loop depth and
+/// wrapped to --line-width.
+fn format_file(matches: &Matches, path: &str) -> Result<(), String> {
+    let options = FormatOptions {
+        line_width: numeric_opt(matches, "line-width", 80)?,
+        align_comments: matches.opt_present("align-comments"),
+    };
+
+    let src = match slurp(path) {
+        Ok(src) => src,
+        Err(info) => {
+            return Err(format!("{}", info));
+        }
+    };
+
+    // Embedded input is data, so we keep it exactly as it is.
+    let (program, input) = split_input(matches, &src);
+
+    match format::format_source(program, &options) {
+        Ok(formatted) => {
+            print!("{}", formatted);
+            if let Some(input) = input {
+                print!("!{}", String::from_utf8_lossy(input));
+            }
+            Ok(())
+        }
+        Err(parse_errors) => {
+            let sources = SourceMap::new(display_path(path), &src);
+            let infos: Vec<_> = parse_errors
+                .into_iter()
+                .map(|parse_error| {
+                    let info = sources.info(
+                        Level::Error,
+                        parse_error.message,
+                        Some(parse_error.position),
+                    );
+                    format!("{}", info)
+                })
+                .collect();
+            Err(infos.join("\n"))
+        }
+    }
+}
+
+/// Print the BF program at `path` without comments. We print the
+/// optimised program if it's shorter, since expanding optimised
+/// instructions back to BF can make the program longer.
+fn minify_file(matches: &Matches, path: &str) -> Result<(), String> {
+    let src = match slurp(path) {
+        Ok(src) => src,
+        Err(info) => {
+            return Err(format!("{}", info));
+        }
+    };
+
+    let (program, input) = split_input(matches, &src);
+
+    let mut warnings = vec![];
+    let (instrs, _) = parse_and_optimize(matches, path, program, &mut warnings)?;
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+
+    let (unoptimized_instrs, _) = parse_source(matches, path, program)?;
+    let cell_width = cell_width(matches)?;
+    let overflow = overflow(matches)?;
+    let candidates = vec![
+        bfir::to_bf(&instrs, cell_width, overflow),
+        bfir::to_bf(&unoptimized_instrs, cell_width, overflow),
+    ];
+    let minified = candidates
+        .into_iter()
+        .flatten()
+        .min_by_key(String::len)
+        .ok_or_else(|| "Could not convert the program to BF".to_owned())?;
+    println!("{}", minified);
+    if let Some(input) = input {
+        print!("!{}", String::from_utf8_lossy(input));
+    }
+    Ok(())
+}
+
+/// Parse and optimise the program, and report any problems we find,
+/// without generating any code.
+fn check_file(matches: &Matches, path: &str) -> Result<(), String> {
+    let src = match slurp(path) {
+        Ok(src) => src,
+        Err(info) => {
+            return Err(format!("{}", info));
+        }
+    };
+    let (src, _) = split_input(matches, &src);
+
+    let mut warnings = vec![];
+    let (instrs, sources) = parse_and_optimize(matches, path, src, &mut warnings)?;
+    if matches.opt_present("verify-opt") {
+        verify_optimisation(matches, path, src, &instrs, &mut warnings)?;
+    }
+    if !instrs.is_empty() {
+        execute_at_compile_time(matches, path, &sources, &instrs, &mut warnings)?;
+    }
+    print_warnings(matches, &mut warnings);
+    Ok(())
+}
+
+/// Compile the program several times, and check that we get the
+/// same warnings, LLVM IR and object file every time. The first
+/// compilation runs on its own, as with `-j1`, and the rest run at
+/// the same time on up to `--jobs` threads (at least two by default),
+/// so output that depends on the thread count shows up as a
+/// difference too.
+fn verify_determinism(matches: &Matches, path: &str) -> Result<(), String> {
+    let runs = numeric_opt(matches, "runs", 5)?;
+    if runs < 2 {
+        return Err("--runs must be at least 2 to compare compilations.".to_owned());
+    }
+    // Even on one CPU, we want to compare against several threads.
+    let threads = if matches.opt_present("jobs") {
+        jobs(matches)?
+    } else {
+        jobs(matches)?.max(2)
+    };
+    let threads = threads.min(runs - 1);
+
+    let src = match slurp(path) {
+        Ok(src) => src,
+        Err(info) => {
+            return Err(format!("{}", info));
+        }
+    };
+
+    let (src, input) = split_input(matches, &src);
+
+    llvm::init_llvm();
+    let first = compile_for_comparison(matches, path, src, input)?;
+    println!(
+        "run 1 (-j1): IR {:016x}, object {:016x}",
+        hash_of(&first.llvm_ir),
+        hash_of(&first.object)
+    );
+
+    let later_runs: Vec<usize> = (2..=runs).collect();
+    let outputs = parallel_map(threads, &later_runs, |_| {
+        compile_for_comparison(matches, path, src, input)
+    });
+
+    for (&run, output) in later_runs.iter().zip(outputs) {
+        let output = output?;
+        println!(
+            "run {} (-j{}): IR {:016x}, object {:016x}",
+            run,
+            threads,
+            hash_of(&output.llvm_ir),
+            hash_of(&output.object)
+        );
+
+        let texts = [
+            ("warnings", &first.warnings, &output.warnings),
+            ("LLVM IR", &first.llvm_ir, &output.llvm_ir),
+        ];
+        for &(name, expected, actual) in &texts {
+            if let Some((line_number, expected_line, actual_line)) =
+                first_difference(expected, actual)
+            {
+                return Err(format!(
+                    "Run {} produced different {} on line {}:\n- {}\n+ {}",
+                    run, name, line_number, expected_line, actual_line
+                ));
+            }
+        }
+
+        if first.object != output.object {
+            return Err(format!("Run {} produced a different object file.", run));
+        }
+    }
+
+    println!("All {} runs produced identical output.", runs);
+    Ok(())
+}
+
+/// The kinds of output that `--emit` accepts, and the extension we
+/// add to the source file's name to get the default output path.
+const EMIT_KINDS: &[(&str, &str)] = &[
+    ("exe", ""),
+    ("obj", ".o"),
+    ("asm", ".s"),
+    ("ir", ".ll"),
+    ("bc", ".bc"),
+    ("bf", ".min.bf"),
+    ("bfir", ".bfir"),
+    ("json-ir", ".json"),
+];
+
+/// The -o path that means "write to stdout".
+const STDOUT_PATH: &str = "-";
+
+/// Where to write the output for the program at `path`: `output`
+/// (from -o) if given, otherwise a name based on `path` ending with
+/// `extension`. We create any missing directories, but never
+/// overwrite the program itself.
+fn output_path(
+    output: Option<String>,
+    out_dir: Option<String>,
+    path: &str,
+    extension: &str,
+) -> Result<String, String> {
+    let output = match (output, out_dir) {
+        (Some(output), _) => output,
+        (None, Some(out_dir)) => Path::new(&out_dir)
+            .join(executable_name(path) + extension)
+            .to_str()
+            .expect("path not valid utf-8")
+            .to_owned(),
+        (None, None) => executable_name(path) + extension,
+    };
+    if output == STDOUT_PATH {
+        return Ok(output);
+    }
+
+    if let (Ok(source), Ok(existing)) = (fs::canonicalize(path), fs::canonicalize(&output)) {
+        if source == existing {
+            return Err(format!("Refusing to overwrite the source file {}", path));
+        }
+    }
+
+    if let Some(parent) = Path::new(&output).parent() {
+        if !parent.as_os_str().is_empty() {
+            convert_io_error(fs::create_dir_all(parent))?;
+        }
+    }
+    Ok(output)
+}
+
+#[test]
+fn output_path_default_extension() {
+    assert_eq!(
+        output_path(None, None, "foo.bf", ".ll"),
+        Ok("foo.ll".to_owned())
+    );
+    assert_eq!(output_path(None, None, "foo.bf", ""), Ok("foo".to_owned()));
+}
+
+#[test]
+fn output_path_out_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let out_dir = dir.path().join("build");
+    let out_dir = out_dir.to_str().unwrap().to_owned();
+
+    assert_eq!(
+        output_path(None, Some(out_dir.clone()), "src/foo.bf", ".ll"),
+        Ok(format!("{}/foo.ll", out_dir))
+    );
+    assert!(dir.path().join("build").is_dir());
+    assert_eq!(
+        output_path(Some("bar".to_owned()), Some(out_dir), "foo.bf", ""),
+        Ok("bar".to_owned())
+    );
+}
+
+#[test]
+fn output_path_creates_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("build/bin/hello");
+    let output = output.to_str().unwrap().to_owned();
+
+    assert_eq!(
+        output_path(Some(output.clone()), None, "hello.bf", ""),
+        Ok(output.clone())
+    );
+    assert!(dir.path().join("build/bin").is_dir());
+}
+
+#[test]
+fn output_path_keeps_source() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("hello");
+    fs::write(&source, "+.").unwrap();
+    let source = source.to_str().unwrap();
+
+    assert!(output_path(Some(source.to_owned()), None, source, "").is_err());
+}
+
+/// The arguments for clang to link the object file into an
+/// executable. `cc_args` are passed to clang as they are, and
+/// `link_args` are passed on to the linker.
+fn link_args(
+    object_file_path: &str,
+    executable_path: &str,
+    target_triple: Option<String>,
+    cc_args: &[String],
+    link_args: &[String],
+) -> Vec<String> {
+    let mut args = vec![object_file_path.to_owned()];
+    if let Some(target_triple) = target_triple {
+        args.push("-target".to_owned());
+        args.push(target_triple);
+    }
+    args.push("-o".to_owned());
+    args.push(executable_path.to_owned());
+
+    args.extend(cc_args.iter().cloned());
+    for link_arg in link_args {
+        args.push("-Xlinker".to_owned());
+        args.push(link_arg.clone());
+    }
+    args
+}
+
+#[test]
+fn link_args_passthrough() {
+    assert_eq!(
+        link_args(
+            "foo.o",
+            "foo",
+            None,
+            &["-fuse-ld=lld".to_owned(), "-static".to_owned()],
+            &["-rpath=/opt/lib".to_owned()],
+        ),
+        vec![
+            "foo.o",
+            "-o",
+            "foo",
+            "-fuse-ld=lld",
+            "-static",
+            "-Xlinker",
+            "-rpath=/opt/lib"
+        ]
+    );
+}
+
+#[test]
+fn link_args_target() {
+    assert_eq!(
+        link_args(
+            "foo.o",
+            "foo",
+            Some("i686-pc-linux-gnu".to_owned()),
+            &[],
+            &[]
+        ),
+        vec!["foo.o", "-target", "i686-pc-linux-gnu", "-o", "foo"]
+    );
+}
+
+fn strip_executable(matches: &Matches, executable_path: &str) -> Result<(), String> {
+    let strip_args = match std::env::consts::OS {
+        "macos" => vec![&executable_path[..]],
+        _ => vec!["-s", &executable_path[..]],
+    };
+    run_command(matches, "strip", &strip_args[..])
+}
+
+/// Print the versions and targets that `bfc --version --verbose`
+/// reports, which are useful in bug reports.
+fn print_version_details() {
+    println!("LLVM version: {}", llvm::LLVM_VERSION);
+    let default_triple = llvm::get_default_target_triple();
+    println!("default target: {}", default_triple.to_string_lossy());
+    println!("host CPU: {}", llvm::host_cpu_name());
+    llvm::init_llvm();
+    let names: Vec<_> = llvm::targets().into_iter().map(|(name, _)| name).collect();
+    println!("targets: {}", names.join(", "));
+}
+
+/// Print the targets LLVM can generate code for, for `bfc targets`.
+fn print_targets() -> Result<(), String> {
+    llvm::init_llvm();
+    let targets = llvm::targets();
+    let width = targets
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, description) in targets {
+        println!("{:<width$}  {}", name, description, width = width);
+    }
+    let default_triple = llvm::get_default_target_triple();
+    println!(
+        "\nThe default target is {}. Choose another with --target=TRIPLE, such as \
+         --target=i686-pc-linux-gnu.",
+        default_triple.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// The arguments for the settings in bfc.toml, if there is one,
+/// skipping options that the user gave on the command line.
+fn config_args(opts: &Options, matches: &Matches) -> Result<Vec<String>, String> {
+    let text = match fs::read_to_string(config::CONFIG_PATH) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("{}: {}", config::CONFIG_PATH, e)),
+    };
+    let settings = config::parse(&text).map_err(|e| format!("{}: {}", config::CONFIG_PATH, e))?;
+
+    let mut args = vec![];
+    for setting in settings {
+        // Check the option exists before asking whether the user
+        // gave it.
+        if let Err(Fail::UnrecognizedOption(_)) = opts.parse(&[format!("--{}", setting.name)]) {
+            return Err(format!(
+                "{}: {} is not a bfc option",
+                config::CONFIG_PATH,
+                setting.name
+            ));
+        }
+        if matches.opt_present(&setting.name) {
+            continue;
+        }
+        if let Some(arg) = setting.to_arg() {
+            args.push(arg);
+        }
+    }
+
+    if let Err(e) = opts.parse(&args) {
+        return Err(format!("{}: {}", config::CONFIG_PATH, e));
+    }
+    Ok(args)
+}
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The command line options bfc accepts.
+fn options() -> Options {
+    let mut opts = Options::new();
+
+    opts.optflag("h", "help", "print usage");
+    opts.optopt(
+        "o",
+        "output",
+        "write the --emit output to PATH, or to stdout if PATH is - (default: SOURCE_FILE \
+         without its extension, plus an extension for the output kind)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "out-dir",
+        "write the --emit output to DIR, when not using -o (default: the current directory)",
+        "DIR",
+    );
+    opts.optflag(
+        "V",
+        "version",
+        "print bfc version (with -v, also print the LLVM version and targets)",
+    );
+    opts.optflag("", "dump-ir", "print BF IR generated");
+    opts.optflagopt(
+        "",
+        "dump-bf-ir",
+        "print the BF IR before optimisation, after it (the default), or both, instead of \
+         compiling",
+        "before|after|both",
+    );
+    opts.optopt(
+        "",
+        "dump-ir-after",
+        "print the BF IR to stderr after each run of these bfc optimisations (e.g. \
+         combine_inc,dead_loop), or after every pass with all",
+        "PASS-SPECIFICATION|all",
+    );
+    opts.optflag(
+        "",
+        "dump-structure",
+        "print the loop nesting of the BF IR as a GraphViz graph",
+    );
+    opts.optopt(
+        "",
+        "emit",
+        "the kind of output to write (default: exe)",
+        "exe|obj|asm|ir|bc|bf|bfir|json-ir",
+    );
+    opts.optopt(
+        "",
+        "input-format",
+        "read SOURCE_FILE as BF, as BF IR in the --emit=bfir format, or as Ook! \
+         (default: from the file extension, or bf)",
+        "bf|bfir|ook",
+    );
+    opts.optopt("", "lang", "another name for --input-format", "bf|bfir|ook");
+    opts.optopt(
+        "",
+        "tokens",
+        "read SOURCE_FILE as a BF dialect that spells each command with the string in FILE",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "breakpoints",
+        "treat # as a breakpoint that prints the cell pointer and the first 10 cells to stderr",
+    );
+    opts.optflag(
+        "",
+        "extensions",
+        "accept @ (end program), $ (store cell) and ! (load cell) from Extended Brainfuck Type I",
+    );
+    opts.optflag(
+        "",
+        "stdin",
+        "read the program from stdin, like giving - as SOURCE_FILE",
+    );
+    opts.optflag(
+        "",
+        "bang-input",
+        "treat everything after the first ! as input, and bake it into the executable",
+    );
+    opts.optflag(
+        "",
+        "macros",
+        "expand macros (@name{...} and $name) and repetition counts (+{10}) before parsing",
+    );
+    opts.optmulti(
+        "",
+        "include-path",
+        "with --macros, search DIR for files named by @include (may be repeated)",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "experimental-simd",
+        "pad the tape and use vector operations on adjacent cells",
+    );
+
+    opts.optflag(
+        "",
+        "opt-size",
+        "compile repeated loops once, as functions, to reduce code size",
+    );
+
+    opts.optopt(
+        "",
+        "min-outline-size",
+        "with --opt-size, only compile loops with at least SIZE instructions as functions (default: 8)",
+        "SIZE",
+    );
+    opts.optflag(
+        "",
+        "profile-generate",
+        "count how often each loop runs, and write the counts to NAME.profile on exit",
+    );
+    opts.optopt(
+        "",
+        "profile-use",
+        "use loop counts from --profile-generate to guide optimisation",
+        "FILE",
+    );
+
+    opts.optopt(
+        "",
+        "tape-size",
+        "the number of cells on the tape (default: 100000)",
+        "CELLS",
+    );
+    opts.optopt(
+        "",
+        "bounds",
+        "what happens when the pointer leaves the tape: unchecked, wrap or abort \
+         (default: unchecked)",
+        "BEHAVIOUR",
+    );
+    opts.optopt(
+        "",
+        "cell-size",
+        "the number of bits in each cell: 8, 16 or 32 (default: 8)",
+        "BITS",
+    );
+    opts.optopt(
+        "",
+        "overflow",
+        "what happens when a cell overflows: wrap, abort or saturate (default: wrap)",
+        "BEHAVIOUR",
+    );
+    opts.optopt(
+        "",
+        "eof",
+        "what reads store at the end of input: zero, minus-one or unchanged (default: minus-one)",
+        "BEHAVIOUR",
+    );
+
+    opts.optopt("O", "opt", "optimization level (0 to 3)", "LEVEL");
+    opts.optopt(
+        "",
+        "llvm-opt",
+        "LLVM optimization level (0 to 3), for both LLVM's passes and code generation \
+         (default: 3)",
+        "LEVEL",
+    );
+    opts.optflag(
+        "",
+        "opt-stats",
+        "show how many instructions each bfc optimisation removed",
+    );
+    opts.optflag(
+        "",
+        "stats",
+        "show instruction counts, loop depth, pointer movement and tape use after optimisation",
+    );
+    opts.optopt(
+        "",
+        "passes",
+        "run these bfc optimisations, in this order (e.g. combine_inc,dead_loop)",
+        "PASS-SPECIFICATION",
+    );
+    opts.optmulti(
+        "",
+        "disable-pass",
+        "don't run this bfc optimisation (may be repeated)",
+        "PASS",
+    );
+    opts.optopt(
+        "",
+        "max-unroll",
+        "unroll known trip count loops up to this many instructions (default: 64 at -O3, else 0)",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "max-opt-iterations",
+        "run the bfc optimisations at most N times while looking for a fixed point (default: 40)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "remove-dead-reads",
+        "remove reads whose value is always overwritten (changes which input later reads see)",
+    );
+    opts.optflag(
+        "",
+        "fuse-loops",
+        "combine adjacent loops that run the same number of times",
+    );
+    opts.optflag(
+        "",
+        "explain-removals",
+        "explain why bfc optimisations delete loops and cell changes you wrote",
+    );
+    opts.optflag(
+        "",
+        "verify-opt",
+        "run the program before and after bfc optimisations, and fail if the outputs differ",
+    );
+    opts.optmulti(
+        "",
+        "verify-input",
+        "with --verify-opt, run the program on the contents of FILE (may be repeated)",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "pass-fuel",
+        "stop applying bfc optimisations after N pass runs have changed the program, to \
+         find which pass miscompiles",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "max-speculative-steps",
+        "maximum steps to execute at compile time, 0 to disable (default: 10000000)",
+        "STEPS",
+    );
+    opts.optopt(
+        "",
+        "compile-budget",
+        "stop bfc's peephole optimisation and compile-time execution once each has taken \
+         MS milliseconds, and compile the program as optimised so far (default: no limit)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "runs",
+        "number of compilations to compare with verify-determinism (default: 5), or of \
+         times to run the program with bench (default: 10)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "input",
+        "with bench, the file the program reads as stdin (default: no input)",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "line-width",
+        "with fmt, wrap commands at N characters (default: 80)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "align-comments",
+        "with fmt, line up the comments at the end of consecutive lines",
+    );
+    opts.optopt(
+        "",
+        "strip",
+        "strip symbols from the binary (default: yes)",
+        "yes|no",
+    );
+    opts.optmulti(
+        "",
+        "cc-arg",
+        "pass ARG to clang when linking, e.g. -fuse-ld=lld (may be repeated)",
+        "ARG",
+    );
+    opts.optmulti(
+        "",
+        "link-arg",
+        "pass ARG to the linker, e.g. -rpath=/opt/lib (may be repeated)",
+        "ARG",
+    );
+    opts.optflag(
+        "",
+        "keep-temps",
+        "keep the temporary object file, and write the LLVM IR next to it",
+    );
+    opts.optflag(
+        "",
+        "no-cache",
+        "compile from scratch, without reading or writing the object file cache",
+    );
+    opts.optopt(
+        "",
+        "temp-dir",
+        "create temporary files in DIR (default: the system temporary directory)",
+        "DIR",
+    );
+    opts.optflagmulti(
+        "v",
+        "verbose",
+        "print what bfc is doing, such as the paths of temporary files (-vv also prints \
+         the commands bfc runs)",
+    );
+    opts.optflag("q", "quiet", "only print errors");
+    opts.optopt(
+        "j",
+        "jobs",
+        "compile up to N files at once, or run N compilations at once with verify-determinism \
+         (default: the number of CPUs)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "color",
+        "when to colour output: auto, always or never (default: auto, which colours \
+         output when stderr is a terminal)",
+        "WHEN",
+    );
+    opts.optopt(
+        "",
+        "message-format",
+        "print warnings, outputs and timings as JSON records on stdout (default: human)",
+        "human|json",
+    );
+
+    let default_triple_cstring = llvm::get_default_target_triple();
+    let default_triple = default_triple_cstring.to_str().unwrap();
+
+    opts.optopt(
+        "",
+        "target",
+        &format!("LLVM target triple (default: {})", default_triple),
+        "TARGET",
+    );
+
+    opts
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let opts = options();
+
+    let mut matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(_) => {
+            print_usage(&args[0], opts);
+            std::process::exit(1);
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(&args[0], opts);
+        return;
+    }
+
+    if matches.opt_present("version") {
+        println!("bfc {}", VERSION);
+        if verbosity(&matches) >= 1 {
+            print_version_details();
+        }
+        return;
+    }
+
+    match config_args(&opts, &matches) {
+        Ok(config_args) if !config_args.is_empty() => {
+            let mut all_args = config_args;
+            all_args.extend_from_slice(&args[1..]);
+            matches = opts
+                .parse(&all_args)
+                .expect("bfc.toml arguments should be valid");
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+
+    match json_messages(&matches).and_then(|_| use_color(&matches)) {
+        Ok(color) => colored::control::set_override(color),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+
+    // --stdin is the same as giving - as the source file.
+    if matches.opt_present("stdin") {
+        matches.free.push(STDIN_PATH.to_owned());
+    }
+
+    let result = match matches.free.len() {
+        1 if matches.free[0] == "clean-cache" => clean_cache(),
+        1 if matches.free[0] == "targets" => print_targets(),
+        1 => compile_and_report(&matches, &matches.free[0]),
+        2 if matches.free[0] == "verify-determinism" => {
+            verify_determinism(&matches, &matches.free[1])
+        }
+        2 if matches.free[0] == "fmt" => format_file(&matches, &matches.free[1]),
+        2 if matches.free[0] == "minify" => minify_file(&matches, &matches.free[1]),
+        2 if matches.free[0] == "check" => check_file(&matches, &matches.free[1]),
+        2 if matches.free[0] == "bench" => bench_file(&matches, &matches.free[1]),
+        n if n >= 2 && matches.free[0] == "run" => {
+            match run_file(&matches, &matches.free[1], &matches.free[2..]) {
+                Ok(code) => std::process::exit(code),
+                Err(e) => Err(e),
+            }
+        }
+        n if n > 1 && !SUBCOMMANDS.contains(&matches.free[0].as_str()) => {
+            compile_files(&matches, &matches.free)
+        }
+        _ => {
+            print_usage(&args[0], opts);
+            std::process::exit(1);
+        }
+    };
+
+    if json_messages(&matches).unwrap_or(false) {
+        let exit_code = if result.is_ok() { 0 } else { 2 };
+        println!("{{\"reason\": \"exit\", \"exit_code\": {}}}", exit_code);
+    }
+
+    match result {
         Ok(_) => {}
         Err(e) => {
             eprintln!("{}", e);
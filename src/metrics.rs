@@ -0,0 +1,179 @@
+//! Summary numbers for a program, shown with `--stats`: how many
+//! instructions of each kind it has, how deeply its loops nest, how
+//! far it moves the cell pointer and how much of the tape it uses.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{fingerprint, net_pointer_movement, walk_instr, walk_instrs, AstNode, Visitor};
+use crate::bounds::highest_cell_index;
+
+#[cfg(test)]
+use crate::bounds::DEFAULT_TAPE_SIZE;
+
+#[cfg(test)]
+use crate::bfir::parse;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    /// How many instructions of each kind the program has, including
+    /// instructions inside Loop and If bodies.
+    pub instr_counts: BTreeMap<&'static str, usize>,
+    /// How deeply Loops and Ifs nest. A program without any has a
+    /// depth of 0.
+    pub max_loop_depth: usize,
+    /// How far the program moves the cell pointer, if we know at
+    /// compile time.
+    pub net_pointer_movement: Option<isize>,
+    /// How many cells the program may use, counting from cell #0.
+    /// This is the whole tape if we can't tell.
+    pub tape_cells: usize,
+    /// How many cells the tape has.
+    pub tape_size: usize,
+    /// The program's `bfir::fingerprint`, so users can spot
+    /// programs that optimise to the same instructions.
+    pub fingerprint: u64,
+}
+
+/// The name of the kind of instruction `instr` is.
+fn kind_name(instr: &AstNode) -> &'static str {
+    match *instr {
+        Increment { .. } => "Increment",
+        PointerIncrement { .. } => "PointerIncrement",
+        Read { .. } => "Read",
+        Write { .. } => "Write",
+        Loop { .. } => "Loop",
+        If { .. } => "If",
+        Set { .. } => "Set",
+        MultiplyMove { .. } => "MultiplyMove",
+        WriteConst { .. } => "WriteConst",
+        SetRange { .. } => "SetRange",
+        Scan { .. } => "Scan",
+        Breakpoint { .. } => "Breakpoint",
+        Halt { .. } => "Halt",
+        Store { .. } => "Store",
+        Load { .. } => "Load",
+    }
+}
+
+struct MetricsVisitor {
+    instr_counts: BTreeMap<&'static str, usize>,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Visitor for MetricsVisitor {
+    fn visit_instr(&mut self, instr: &AstNode) {
+        *self.instr_counts.entry(kind_name(instr)).or_insert(0) += 1;
+        walk_instr(self, instr);
+    }
+
+    fn visit_body(&mut self, _parent: &AstNode, body: &[AstNode]) {
+        self.depth += 1;
+        self.max_depth = std::cmp::max(self.max_depth, self.depth);
+        walk_instrs(self, body);
+        self.depth -= 1;
+    }
+}
+
+/// Measure the program `instrs`, running on a tape of `tape_size`
+/// cells.
+pub fn metrics(instrs: &[AstNode], tape_size: usize) -> Metrics {
+    let mut visitor = MetricsVisitor {
+        instr_counts: BTreeMap::new(),
+        depth: 0,
+        max_depth: 0,
+    };
+    walk_instrs(&mut visitor, instrs);
+
+    Metrics {
+        instr_counts: visitor.instr_counts,
+        max_loop_depth: visitor.max_depth,
+        net_pointer_movement: net_pointer_movement(instrs),
+        tape_cells: highest_cell_index(instrs, tape_size) + 1,
+        tape_size,
+        fingerprint: fingerprint(instrs),
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total: usize = self.instr_counts.values().sum();
+        writeln!(f, "{:<24}{:>8}", "instructions", total)?;
+        for (kind, count) in &self.instr_counts {
+            writeln!(f, "  {:<22}{:>8}", kind, count)?;
+        }
+        writeln!(f, "{:<24}{:>8}", "max loop depth", self.max_loop_depth)?;
+
+        let movement = match self.net_pointer_movement {
+            Some(movement) => movement.to_string(),
+            None => "unknown".to_owned(),
+        };
+        writeln!(f, "{:<24}{:>8}", "net pointer movement", movement)?;
+
+        let tape_cells = if self.tape_cells >= self.tape_size {
+            format!("{} (whole tape)", self.tape_cells)
+        } else {
+            self.tape_cells.to_string()
+        };
+        writeln!(f, "{:<24}{:>8}", "tape cells", tape_cells)?;
+        writeln!(f, "{:<24}{:016x}", "fingerprint", self.fingerprint)
+    }
+}
+
+#[test]
+fn metrics_counts_nested_instructions() {
+    let instrs = parse("+>[-[<.]]").unwrap();
+    let m = metrics(&instrs, DEFAULT_TAPE_SIZE);
+
+    let mut expected_counts = BTreeMap::new();
+    expected_counts.insert("Increment", 2);
+    expected_counts.insert("Loop", 2);
+    expected_counts.insert("PointerIncrement", 2);
+    expected_counts.insert("Write", 1);
+    assert_eq!(m.instr_counts, expected_counts);
+    assert_eq!(m.max_loop_depth, 2);
+}
+
+#[test]
+fn metrics_pointer_movement_and_tape() {
+    let m = metrics(&parse(">>+<").unwrap(), DEFAULT_TAPE_SIZE);
+    assert_eq!(m.net_pointer_movement, Some(1));
+    assert_eq!(m.tape_cells, 3);
+    assert_eq!(m.max_loop_depth, 0);
+}
+
+#[test]
+fn metrics_unknown_movement_uses_whole_tape() {
+    let m = metrics(&parse("[>]").unwrap(), DEFAULT_TAPE_SIZE);
+    assert_eq!(m.net_pointer_movement, None);
+    assert_eq!(m.tape_cells, DEFAULT_TAPE_SIZE);
+}
+
+#[test]
+fn metrics_small_tape() {
+    let m = metrics(&parse("[>]").unwrap(), 10);
+    assert_eq!(m.tape_cells, 10);
+    assert!(m.to_string().contains("10 (whole tape)"));
+}
+
+#[test]
+fn metrics_display() {
+    let text = metrics(&parse("+[>]").unwrap(), DEFAULT_TAPE_SIZE).to_string();
+    assert_eq!(
+        text,
+        "instructions                   3
+  Increment                    1
+  Loop                         1
+  PointerIncrement             1
+max loop depth                 1
+net pointer movement     unknown
+tape cells              100000 (whole tape)
+fingerprint             a510ed724114e461
+"
+    );
+}
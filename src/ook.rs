@@ -0,0 +1,119 @@
+//! A frontend for Ook!, which spells each BF command as a pair of
+//! `Ook.`, `Ook?` and `Ook!` words. We translate Ook! to BF and parse
+//! that, so positions point at the Ook! source.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use crate::bfir::{self, AstNode, ParseError, Position};
+
+/// The punctuation of each pair of words, and the BF command it
+/// stands for.
+const COMMANDS: &[(char, char, char)] = &[
+    ('.', '?', '>'),
+    ('?', '.', '<'),
+    ('.', '.', '+'),
+    ('!', '!', '-'),
+    ('!', '.', '.'),
+    ('.', '!', ','),
+    ('!', '?', '['),
+    ('?', '!', ']'),
+];
+
+/// The punctuation and position of each Ook word in `source`.
+/// Anything else is a comment.
+fn words(source: &str) -> Vec<(char, usize)> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut words = vec![];
+
+    let mut index = 0;
+    while index + 3 < chars.len() {
+        if chars[index..index + 3] == ['O', 'o', 'k'] && ".?!".contains(chars[index + 3]) {
+            words.push((chars[index + 3], index));
+            index += 4;
+        } else {
+            index += 1;
+        }
+    }
+    words
+}
+
+/// Parse Ook! source code to our BF IR.
+pub fn parse(source: &str) -> Result<Vec<AstNode>, Vec<ParseError>> {
+    let mut bf = String::new();
+    let mut positions = vec![];
+    let mut errors = vec![];
+
+    for pair in words(source).chunks(2) {
+        match *pair {
+            [(first, start), (second, second_start)] => {
+                let command = COMMANDS
+                    .iter()
+                    .find(|&&(a, b, _)| a == first && b == second);
+                match command {
+                    Some(&(_, _, command)) => {
+                        bf.push(command);
+                        positions.push(start);
+                    }
+                    None => errors.push(ParseError {
+                        message: format!("Ook{} Ook{} is not an Ook! command", first, second),
+                        position: Position {
+                            start,
+                            end: second_start + 3,
+                        },
+                    }),
+                }
+            }
+            [(_, start)] => errors.push(ParseError {
+                message: "This Ook has no second word to make a command".to_owned(),
+                position: Position {
+                    start,
+                    end: start + 3,
+                },
+            }),
+            _ => unreachable!(),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    bfir::parse_expanded(&bf, &positions, false)
+}
+
+#[test]
+fn parse_ook_commands() {
+    let source = "Ook. Ook? Ook. Ook. Ook! Ook? Ook! Ook! Ook? Ook! Ook! Ook.";
+    assert_eq!(
+        parse(source).unwrap(),
+        bfir::parse_expanded(">+[-].", &[0, 10, 20, 30, 40, 50], false).unwrap()
+    );
+}
+
+#[test]
+fn parse_ook_ignores_comments() {
+    assert_eq!(
+        parse("Hello Ook.\nOok. world").unwrap(),
+        bfir::parse_expanded("+", &[6], false).unwrap()
+    );
+}
+
+#[test]
+fn parse_ook_invalid_pair() {
+    let errors = parse("Ook? Ook?").unwrap_err();
+    assert_eq!(errors[0].message, "Ook? Ook? is not an Ook! command");
+    assert_eq!(errors[0].position, Position { start: 0, end: 8 });
+}
+
+#[test]
+fn parse_ook_odd_word() {
+    let errors = parse("Ook. Ook. Ook!").unwrap_err();
+    assert_eq!(errors[0].position, Position { start: 10, end: 13 });
+}
+
+#[test]
+fn parse_ook_unbalanced_loop() {
+    let errors = parse("Ook. Ook. Ook? Ook!").unwrap_err();
+    assert_eq!(errors[0].message, "This ] has no matching [");
+    assert_eq!(errors[0].position, Position { start: 10, end: 10 });
+}
@@ -1,118 +1,679 @@
 //! Optimisations that replace parts of the BF AST with faster
 //! equivalents.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::Hash;
 use std::num::Wrapping;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
 
 use crate::diagnostics::Warning;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{get_position, AstNode, Cell, Combine, Position};
+use crate::bfir::{
+    fold_instructions, get_position, map_instructions, net_pointer_movement, validate, AstNode,
+    Bounds, Cell, CellWidth, Combine, Eof, Overflow, Position,
+};
+use crate::profile::Profile;
+use crate::rewrite::Term::*;
+use crate::rewrite::{apply_rules, Rule, Template};
+
+/// The default for `OptimizeOptions::max_iterations`.
+pub const MAX_OPT_ITERATIONS: usize = 40;
+
+/// The smallest number of adjacent Set instructions that we replace
+/// with a SetRange. Shorter runs are cheap enough as stores.
+const MIN_SET_RANGE_LENGTH: usize = 4;
+
+/// Counter loops that run at most this many times, and whose inlined
+/// body has at most `MAX_INLINED_COUNTER_LOOP_SIZE` instructions, are
+/// always inlined, even when unrolling is disabled.
+const MAX_INLINED_TRIP_COUNT: usize = 4;
+const MAX_INLINED_COUNTER_LOOP_SIZE: usize = 16;
+
+/// A single optimisation over a sequence of BF instructions.
+pub trait Pass: Sync {
+    /// The name used to select this pass with --passes.
+    fn name(&self) -> &'static str;
+
+    /// Apply this pass once, returning the new instructions and any
+    /// warnings found.
+    fn run(&self, instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>);
+}
 
-const MAX_OPT_ITERATIONS: u64 = 40;
+/// A pass that only transforms instructions.
+struct TransformPass {
+    name: &'static str,
+    transform: fn(Vec<AstNode>) -> Vec<AstNode>,
+}
 
-/// Given a sequence of BF instructions, apply peephole optimisations
-/// (repeatedly if necessary).
-pub fn optimize(
-    instrs: Vec<AstNode>,
-    pass_specification: &Option<String>,
-) -> (Vec<AstNode>, Vec<Warning>) {
-    // Many of our individual peephole optimisations remove
-    // instructions, creating new opportunities to combine. We run
-    // until we've found a fixed-point where no further optimisations
-    // can be made.
-    let mut prev = instrs.clone();
-    let mut warnings = vec![];
+impl Pass for TransformPass {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self, instrs: Vec<AstNode>, _: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        ((self.transform)(instrs), vec![])
+    }
+}
 
-    let (mut result, warning) = optimize_once(instrs, pass_specification);
+struct CombineIncrementsPass;
 
-    if let Some(warning) = warning {
-        warnings.push(warning);
+impl Pass for CombineIncrementsPass {
+    fn name(&self) -> &'static str {
+        "combine_inc"
     }
 
-    for _ in 0..MAX_OPT_ITERATIONS {
-        if prev == result {
-            return (result, warnings);
+    fn run(&self, instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        if options.overflow == Overflow::Wrap {
+            (combine_increments(instrs), vec![])
         } else {
-            prev = result.clone();
+            (combine_same_sign_increments(instrs), vec![])
+        }
+    }
+}
 
-            let (new_result, new_warning) = optimize_once(result, pass_specification);
+struct CombinePtrPass;
 
-            if let Some(warning) = new_warning {
-                warnings.push(warning);
-            }
-            result = new_result;
+impl Pass for CombinePtrPass {
+    fn name(&self) -> &'static str {
+        "combine_ptr"
+    }
+
+    fn run(&self, instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        if options.bounds == Bounds::Abort {
+            (combine_same_sign_ptr_increments(instrs), vec![])
+        } else {
+            (combine_ptr_increments(instrs), vec![])
         }
     }
+}
 
-    // TODO: use proper Info here.
-    eprintln!(
-        "Warning: ran peephole optimisations {} times but did not reach a fixed point!",
-        MAX_OPT_ITERATIONS
-    );
+struct MultiplyPass;
 
-    (result, warnings)
+impl Pass for MultiplyPass {
+    fn name(&self) -> &'static str {
+        "multiply"
+    }
+
+    fn run(&self, instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        if options.bounds == Bounds::Abort {
+            let monotonic = options.overflow != Overflow::Wrap;
+            (extract_multiply_loops(instrs, monotonic, true), vec![])
+        } else if options.overflow == Overflow::Wrap {
+            (extract_multiply(instrs), vec![])
+        } else {
+            (extract_monotonic_multiply(instrs), vec![])
+        }
+    }
 }
 
-/// Apply all our peephole optimisations once and return the result.
-fn optimize_once(
-    instrs: Vec<AstNode>,
-    pass_specification: &Option<String>,
-) -> (Vec<AstNode>, Option<Warning>) {
-    let pass_specification = pass_specification.clone().unwrap_or_else(|| {
-        "combine_inc,combine_ptr,known_zero,\
-         multiply,zeroing_loop,combine_set,\
-         dead_loop,redundant_set,read_clobber,\
-         pure_removal,offset_sort"
-            .to_owned()
-    });
-    let passes: Vec<_> = pass_specification.split(',').collect();
+struct InfiniteLoopPass;
 
-    let mut instrs = instrs;
+impl Pass for InfiniteLoopPass {
+    fn name(&self) -> &'static str {
+        "infinite_loop"
+    }
 
-    if passes.contains(&"combine_inc") {
-        instrs = combine_increments(instrs);
+    fn run(&self, instrs: Vec<AstNode>, _: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        remove_code_after_infinite_loops(instrs)
     }
-    if passes.contains(&"combine_ptr") {
-        instrs = combine_ptr_increments(instrs);
+}
+
+struct UnrollPass;
+
+impl Pass for UnrollPass {
+    fn name(&self) -> &'static str {
+        "unroll"
     }
-    if passes.contains(&"known_zero") {
-        instrs = annotate_known_zero(instrs);
+
+    fn run(&self, instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        if options.max_unroll > 0 {
+            let profile = options.profile.as_deref();
+            (unroll_loops(instrs, options.max_unroll, profile), vec![])
+        } else {
+            (instrs, vec![])
+        }
     }
-    if passes.contains(&"multiply") {
-        instrs = extract_multiply(instrs);
+}
+
+struct DeadReadPass;
+
+impl Pass for DeadReadPass {
+    fn name(&self) -> &'static str {
+        "dead_read"
     }
-    if passes.contains(&"zeroing_loop") {
-        instrs = zeroing_loops(instrs);
+
+    fn run(&self, instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        remove_dead_reads(instrs, options.remove_dead_reads, options.eof)
     }
-    if passes.contains(&"combine_set") {
-        instrs = combine_set_and_increments(instrs);
+}
+
+struct FuseLoopsPass;
+
+impl Pass for FuseLoopsPass {
+    fn name(&self) -> &'static str {
+        "fuse_loops"
     }
-    if passes.contains(&"dead_loop") {
-        instrs = remove_dead_loops(instrs);
+
+    fn run(&self, instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        if options.fuse_loops {
+            (fuse_loops(instrs), vec![])
+        } else {
+            (instrs, vec![])
+        }
     }
-    if passes.contains(&"redundant_set") {
-        instrs = remove_redundant_sets(instrs);
+}
+
+struct PureRemovalPass;
+
+impl Pass for PureRemovalPass {
+    fn name(&self) -> &'static str {
+        "pure_removal"
     }
-    if passes.contains(&"read_clobber") {
-        instrs = remove_read_clobber(instrs);
+
+    fn run(&self, instrs: Vec<AstNode>, _: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+        let (removed, warning) = remove_pure_code(instrs);
+        (removed, warning.into_iter().collect())
     }
-    let warning = if passes.contains(&"pure_removal") {
-        let (removed, pure_warning) = remove_pure_code(instrs);
-        instrs = removed;
-        pure_warning
+}
+
+/// Every pass we support, in the order we run them by default.
+static PASSES: &[&dyn Pass] = &[
+    &TransformPass {
+        name: "initial_zero",
+        transform: remove_initial_zeroing,
+    },
+    &CombineIncrementsPass,
+    &CombinePtrPass,
+    &TransformPass {
+        name: "merge_inc",
+        transform: combine_separated_increments,
+    },
+    &TransformPass {
+        name: "known_zero",
+        transform: annotate_known_zero,
+    },
+    &MultiplyPass,
+    &TransformPass {
+        name: "multiply_chain",
+        transform: combine_multiply_chains,
+    },
+    &TransformPass {
+        name: "zeroing_loop",
+        transform: zeroing_loops,
+    },
+    &TransformPass {
+        name: "combine_set",
+        transform: combine_set_and_increments,
+    },
+    &TransformPass {
+        name: "known_values",
+        transform: propagate_known_values,
+    },
+    &TransformPass {
+        name: "combine_writes",
+        transform: combine_const_writes,
+    },
+    &TransformPass {
+        name: "dead_loop",
+        transform: remove_dead_loops,
+    },
+    &FuseLoopsPass,
+    &InfiniteLoopPass,
+    &TransformPass {
+        name: "inline_counter",
+        transform: inline_counter_loops,
+    },
+    &UnrollPass,
+    &TransformPass {
+        name: "if_statement",
+        transform: recognize_ifs,
+    },
+    &TransformPass {
+        name: "redundant_set",
+        transform: remove_redundant_sets,
+    },
+    &TransformPass {
+        name: "read_clobber",
+        transform: remove_read_clobber,
+    },
+    &DeadReadPass,
+    &PureRemovalPass,
+    &TransformPass {
+        name: "offset_sort",
+        transform: sort_by_offset,
+    },
+    &TransformPass {
+        name: "set_range",
+        transform: combine_set_ranges,
+    },
+    &TransformPass {
+        name: "scan",
+        transform: recognize_scans,
+    },
+];
+
+/// Passes that rely on cells wrapping, e.g. because they fold
+/// increments of both signs together or compute how many times a
+/// loop runs. We skip them unless cells wrap on overflow.
+const WRAPPING_PASSES: &[&str] = &[
+    "merge_inc",
+    "multiply_chain",
+    "combine_set",
+    "known_values",
+    "fuse_loops",
+    "inline_counter",
+    "unroll",
+    "if_statement",
+    "pure_removal",
+];
+
+/// Passes that remove increments whose value is never used. That's
+/// fine when cells wrap or saturate, but aborting on overflow makes
+/// every increment observable, so we skip them then.
+const ABORT_UNSAFE_PASSES: &[&str] = &["read_clobber"];
+
+/// Passes that assume a read always overwrites the cell, which
+/// isn't true if reads at EOF leave the cell unchanged.
+const READ_OVERWRITE_PASSES: &[&str] = &["read_clobber"];
+
+/// Passes that remove accesses to cells whose value is known or never
+/// used, fold pointer moves into offsets, or merge multiplies through
+/// an intermediate cell. When the pointer is
+/// checked, an access or move off the tape ends the program, so we
+/// must keep them.
+const ACCESS_REMOVING_PASSES: &[&str] = &[
+    "known_values",
+    "pure_removal",
+    "offset_sort",
+    "multiply_chain",
+];
+
+/// Can we run `pass` with the cell semantics in `options`?
+fn respects_semantics(pass: &dyn Pass, options: &OptimizeOptions) -> bool {
+    let name = pass.name();
+    let overflow_ok = match options.overflow {
+        Overflow::Wrap => true,
+        Overflow::Saturate => !WRAPPING_PASSES.contains(&name),
+        Overflow::Abort => !WRAPPING_PASSES.contains(&name) && !ABORT_UNSAFE_PASSES.contains(&name),
+    };
+    let eof_ok = options.eof != Eof::Unchanged || !READ_OVERWRITE_PASSES.contains(&name);
+    let bounds_ok = options.bounds != Bounds::Abort || !ACCESS_REMOVING_PASSES.contains(&name);
+    overflow_ok && eof_ok && bounds_ok
+}
+
+/// The passes we run when the user doesn't specify any.
+pub fn default_passes() -> Vec<&'static dyn Pass> {
+    PASSES.to_vec()
+}
+
+/// Look up the passes named in a comma-separated pass
+/// specification, e.g. "combine_inc,dead_loop". Passes run in the
+/// order given.
+pub fn passes_from_specification(specification: &str) -> Result<Vec<&'static dyn Pass>, String> {
+    specification.split(',').map(find_pass).collect()
+}
+
+/// The names of the passes selected by `--dump-ir-after`: either a
+/// comma-separated list of passes, or `all`.
+pub fn dump_ir_after_from_specification(specification: &str) -> Result<Vec<&'static str>, String> {
+    let passes = if specification == "all" {
+        PASSES.to_vec()
     } else {
-        None
+        passes_from_specification(specification)?
     };
+    Ok(passes.iter().map(|pass| pass.name()).collect())
+}
 
-    if passes.contains(&"offset_sort") {
-        instrs = sort_by_offset(instrs);
+/// Remove the passes named in `disabled` from `passes`, e.g. to work
+/// around a miscompilation.
+pub fn without_passes(
+    passes: Vec<&'static dyn Pass>,
+    disabled: &[String],
+) -> Result<Vec<&'static dyn Pass>, String> {
+    for name in disabled {
+        find_pass(name)?;
     }
+    Ok(passes
+        .into_iter()
+        .filter(|pass| !disabled.iter().any(|name| name == pass.name()))
+        .collect())
+}
 
-    (instrs, warning)
+fn find_pass(name: &str) -> Result<&'static dyn Pass, String> {
+    PASSES
+        .iter()
+        .find(|pass| pass.name() == name)
+        .cloned()
+        .ok_or_else(|| {
+            let names: Vec<_> = PASSES.iter().map(|pass| pass.name()).collect();
+            format!(
+                "Unknown pass '{}'. Valid passes are: {}",
+                name,
+                names.join(",")
+            )
+        })
+}
+
+/// Settings that control which optimisations we apply.
+#[derive(Clone)]
+pub struct OptimizeOptions {
+    /// The passes to run, in order.
+    pub passes: Vec<&'static dyn Pass>,
+    /// Loops are only unrolled if the unrolled body has at most this
+    /// many instructions, so a limit of 0 disables unrolling.
+    pub max_unroll: usize,
+    /// The maximum number of times we run the passes while looking
+    /// for a fixed point.
+    pub max_iterations: usize,
+    /// Remove reads whose value is always overwritten. This changes
+    /// which input later reads see, so it's off by default.
+    pub remove_dead_reads: bool,
+    /// Combine adjacent loops that run the same number of times.
+    pub fuse_loops: bool,
+    /// Stop after this many pass runs have changed the program, so
+    /// users can bisect a miscompilation to a single pass run. A pass
+    /// run may make several rewrites, so this is coarser than GCC's
+    /// or rustc's per-rewrite fuel. None means no limit.
+    pub pass_fuel: Option<usize>,
+    /// Loop execution counts from a previous run of the program.
+    pub profile: Option<Rc<Profile>>,
+    /// The width of cells in the compiled program. Cell values wrap
+    /// at this width after every pass.
+    pub cell_width: CellWidth,
+    /// Print the IR to stderr after every run of the passes with
+    /// these names, so users can see which pass changed a program.
+    pub dump_ir_after: Vec<&'static str>,
+    /// What happens when a cell overflows. Passes that assume cells
+    /// wrap are skipped unless this is `Overflow::Wrap`.
+    pub overflow: Overflow,
+    /// What reads store at EOF.
+    pub eof: Eof,
+    /// What happens when the pointer leaves the tape.
+    pub bounds: Bounds,
+    /// Stop looking for a fixed point once this time has passed, so
+    /// pathological programs can't make us optimise forever. None
+    /// means no limit.
+    pub deadline: Option<Instant>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        OptimizeOptions {
+            passes: default_passes(),
+            max_unroll: 0,
+            max_iterations: MAX_OPT_ITERATIONS,
+            remove_dead_reads: false,
+            fuse_loops: false,
+            pass_fuel: None,
+            profile: None,
+            cell_width: CellWidth::default(),
+            dump_ir_after: vec![],
+            overflow: Overflow::default(),
+            eof: Eof::default(),
+            bounds: Bounds::default(),
+            deadline: None,
+        }
+    }
+}
+
+/// How much work a single pass did during optimisation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassStats {
+    pub name: &'static str,
+    /// The number of times the pass ran.
+    pub runs: usize,
+    /// The total number of instructions the pass removed. This is
+    /// negative if the pass added instructions, e.g. when unrolling.
+    pub instrs_removed: isize,
+    /// The total time spent in the pass.
+    pub duration: Duration,
+}
+
+/// The number of instructions in `instrs`, including instructions
+/// inside loop bodies.
+pub(crate) fn instr_count(instrs: &[AstNode]) -> usize {
+    fold_instructions(instrs, 0, &mut |count, _| count + 1)
+}
+
+/// Wrap every cell value in `instrs` to `width`.
+///
+/// Passes do their arithmetic on 32-bit values, so e.g. combining
+/// 256 increments gives `Increment 256`. With 8-bit cells that's a
+/// no-op, and wrapping it to `Increment 0` lets later passes see
+/// that. Passes only treat a value as zero when it's exactly zero,
+/// so they're correct for values that haven't been wrapped yet.
+///
+/// Unless cells wrap on `overflow`, `Increment 256` is not a no-op,
+/// so we leave increments and multiply factors alone.
+pub fn wrap_cells(instrs: Vec<AstNode>, width: CellWidth, overflow: Overflow) -> Vec<AstNode> {
+    if width == CellWidth::Bits32 {
+        return instrs;
+    }
+    let wrap_increments = overflow == Overflow::Wrap;
+
+    map_instructions(instrs, &mut |instr| match instr {
+        Increment {
+            amount,
+            offset,
+            position,
+        } if wrap_increments => Increment {
+            amount: width.wrap(amount),
+            offset,
+            position,
+        },
+        Set {
+            amount,
+            offset,
+            position,
+        } => Set {
+            amount: width.wrap(amount),
+            offset,
+            position,
+        },
+        SetRange {
+            amount,
+            offset,
+            length,
+            position,
+        } => SetRange {
+            amount: width.wrap(amount),
+            offset,
+            length,
+            position,
+        },
+        MultiplyMove { changes, position } if wrap_increments => MultiplyMove {
+            changes: changes
+                .into_iter()
+                .map(|(offset, factor)| (offset, width.wrap(factor)))
+                .collect(),
+            position,
+        },
+        other => other,
+    })
+}
+
+/// Given a sequence of BF instructions, apply peephole optimisations
+/// (repeatedly if necessary).
+pub fn optimize(instrs: Vec<AstNode>, options: &OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+    let (instrs, warnings, _) = optimize_with_stats(instrs, options);
+    (instrs, warnings)
+}
+
+/// Apply peephole optimisations, as `optimize`, and also return
+/// statistics for every pass in `options.passes`.
+pub fn optimize_with_stats(
+    instrs: Vec<AstNode>,
+    options: &OptimizeOptions,
+) -> (Vec<AstNode>, Vec<Warning>, Vec<PassStats>) {
+    let mut stats: Vec<_> = options
+        .passes
+        .iter()
+        .map(|pass| PassStats {
+            name: pass.name(),
+            runs: 0,
+            instrs_removed: 0,
+            duration: Duration::default(),
+        })
+        .collect();
+
+    // Many of our individual peephole optimisations remove
+    // instructions, creating new opportunities to combine. For
+    // example, remove_redundant_sets can expose a Set 0 that lets
+    // remove_dead_loops remove the following loop. We run until
+    // we've found a fixed-point where no further optimisations can
+    // be made.
+    let mut result = wrap_cells(instrs, options.cell_width, options.overflow);
+    let mut warnings = vec![];
+    let mut fuel = options.pass_fuel;
+
+    for iteration in 0..options.max_iterations {
+        if options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            // Each iteration leaves a correct program, so we can
+            // stop here, we just haven't optimised it fully.
+            warnings.push(Warning {
+                message: format!(
+                    "Stopped peephole optimisations after {} iterations, as they used the \
+                     whole --compile-budget.",
+                    iteration
+                ),
+                position: None,
+            });
+            return (result, warnings, stats);
+        }
+        let prev = result.clone();
+
+        let (new_result, new_warnings) = optimize_once(result, options, &mut stats, &mut fuel);
+        add_new_warnings(&mut warnings, new_warnings);
+        result = new_result;
+
+        if prev == result {
+            return (result, warnings, stats);
+        }
+    }
+
+    warnings.push(Warning {
+        message: format!(
+            "Ran peephole optimisations {} times but did not reach a fixed point.",
+            options.max_iterations
+        ),
+        position: None,
+    });
+
+    (result, warnings, stats)
+}
+
+/// Append `new_warnings` to `warnings`, ignoring any warnings that
+/// an earlier iteration already reported.
+fn add_new_warnings(warnings: &mut Vec<Warning>, new_warnings: Vec<Warning>) {
+    for warning in new_warnings {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+}
+
+/// Apply all our peephole optimisations once and return the result.
+/// Every pass run that changes the program uses one unit of `fuel`,
+/// and we stop running passes when it reaches zero.
+fn optimize_once(
+    instrs: Vec<AstNode>,
+    options: &OptimizeOptions,
+    stats: &mut [PassStats],
+    fuel: &mut Option<usize>,
+) -> (Vec<AstNode>, Vec<Warning>) {
+    let mut instrs = instrs;
+    let mut warnings = vec![];
+
+    for (pass, pass_stats) in options.passes.iter().zip(stats.iter_mut()) {
+        if *fuel == Some(0) {
+            break;
+        }
+        if !respects_semantics(*pass, options) {
+            continue;
+        }
+        let dump_ir = options.dump_ir_after.contains(&pass.name());
+        // In debug builds, check that passes keep valid IR valid, so
+        // we can tell which pass broke it.
+        let check_valid = cfg!(debug_assertions) && validate(&instrs).is_ok();
+        // We only need the old instructions to compare against when
+        // we're counting changes or dumping IR, or to fall back to
+        // if the pass breaks the IR.
+        let prev = if fuel.is_some() || dump_ir || check_valid {
+            Some(instrs.clone())
+        } else {
+            None
+        };
+
+        let count_before = instr_count(&instrs) as isize;
+        let start = Instant::now();
+
+        let (new_instrs, new_warnings) = pass.run(instrs, options);
+        // We check before wrapping, because wrapping can turn e.g.
+        // Increment 256 into Increment 0, which a later pass removes.
+        let problem = if check_valid {
+            validate(&new_instrs).err()
+        } else {
+            None
+        };
+        let new_instrs = wrap_cells(new_instrs, options.cell_width, options.overflow);
+
+        pass_stats.duration += start.elapsed();
+        pass_stats.runs += 1;
+
+        if let Some(problem) = problem {
+            // Carry on with the IR from before this pass, so a broken
+            // pass can't miscompile the program.
+            warnings.push(Warning {
+                message: format!(
+                    "Internal error: {} produced invalid IR ({}), so its changes were discarded. Please report this as a bfc bug.",
+                    pass.name(),
+                    problem
+                ),
+                position: None,
+            });
+            instrs = prev.expect("we keep the old IR when checking validity");
+            continue;
+        }
+        pass_stats.instrs_removed += count_before - instr_count(&new_instrs) as isize;
+
+        if dump_ir {
+            if prev.as_ref() == Some(&new_instrs) {
+                eprintln!("*** IR after {} (unchanged) ***", pass.name());
+            } else {
+                eprintln!("*** IR after {} ***", pass.name());
+                for instr in &new_instrs {
+                    eprintln!("{}", instr);
+                }
+            }
+        }
+
+        if let (Some(remaining), Some(prev)) = (fuel.as_mut(), prev) {
+            if prev != new_instrs {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    warnings.push(Warning {
+                        message: format!(
+                            "Ran out of pass fuel. The last change was made by {}.",
+                            pass.name()
+                        ),
+                        position: None,
+                    });
+                }
+            }
+        }
+
+        instrs = new_instrs;
+        warnings.extend(new_warnings);
+    }
+
+    (instrs, warnings)
 }
 
 /// Defines a method on iterators to map a function over all loop bodies.
@@ -126,6 +687,10 @@ trait MapLoopsExt: Iterator<Item = AstNode> {
                 body: f(body),
                 position,
             },
+            If { body, position } => If {
+                body: f(body),
+                position,
+            },
             other => other,
         })
         .collect()
@@ -134,6 +699,12 @@ trait MapLoopsExt: Iterator<Item = AstNode> {
 
 impl<I> MapLoopsExt for I where I: Iterator<Item = AstNode> {}
 
+/// Does a SetRange of `length` cells starting at `offset` include the
+/// cell at `index`?
+fn in_range(offset: isize, length: usize, index: isize) -> bool {
+    offset <= index && index < offset + length as isize
+}
+
 /// Given an index into a vector of instructions, find the index of
 /// the previous instruction that modified the current cell. If we're
 /// unsure, or there isn't one, return None.
@@ -166,11 +737,24 @@ pub fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            SetRange { offset, length, .. } => {
+                // We don't try to split ranges, so we don't know.
+                if in_range(offset, length, needed_offset) {
+                    return None;
+                }
+            }
             // No cells changed, so just keep working backwards.
-            Write { .. } => {}
-            // These instructions may have modified the cell, so
-            // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Write { .. } | WriteConst { .. } => {}
+            // These instructions may have modified or printed the
+            // cell, so we return None for "I don't know".
+            Read { .. }
+            | Loop { .. }
+            | If { .. }
+            | Scan { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Store { .. }
+            | Load { .. } => return None,
         }
     }
     None
@@ -209,87 +793,269 @@ pub fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            SetRange { offset, length, .. } => {
+                // We don't try to split ranges, so we don't know.
+                if in_range(offset, length, needed_offset) {
+                    return None;
+                }
+            }
             // No cells changed, so just keep working backwards.
-            Write { .. } => {}
-            // These instructions may have modified the cell, so
-            // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Write { .. } | WriteConst { .. } => {}
+            // These instructions may have modified or printed the
+            // cell, so we return None for "I don't know".
+            Read { .. }
+            | Loop { .. }
+            | If { .. }
+            | Scan { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Store { .. }
+            | Load { .. } => return None,
         }
     }
     None
 }
 
+/// Increment x, Increment y -> Increment x+y on the same cell, and
+/// remove any increments of 0.
+const COMBINE_INCREMENT_RULES: &[Rule] = &[
+    Rule {
+        pattern: &[
+            Template::Increment {
+                amount: Var("x"),
+                offset: Var("o"),
+            },
+            Template::Increment {
+                amount: Var("y"),
+                offset: Var("o"),
+            },
+        ],
+        replacement: &[Template::Increment {
+            amount: Sum("x", "y"),
+            offset: Var("o"),
+        }],
+    },
+    Rule {
+        pattern: &[Template::Increment {
+            amount: Lit(0),
+            offset: Any,
+        }],
+        replacement: &[],
+    },
+];
+
 /// Combine consecutive increments into a single increment
 /// instruction.
 pub fn combine_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    instrs
+    apply_rules(instrs, COMBINE_INCREMENT_RULES)
         .into_iter()
-        .coalesce(|prev_instr, instr| {
-            // Collapse consecutive increments.
-            if let Increment {
+        .map_loops(combine_increments)
+}
+
+/// Combine consecutive increments of the same cell that change it in
+/// the same direction, and remove any increments of 0.
+///
+/// This is `combine_increments` for cells that don't wrap: with
+/// saturating cells, "Increment 1, Increment -1" on a cell holding
+/// 255 gives 254, not 255.
+pub fn combine_same_sign_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = vec![];
+    for instr in instrs {
+        if let Increment {
+            amount,
+            offset,
+            position,
+        } = instr
+        {
+            if amount.0 == 0 {
+                continue;
+            }
+            if let Some(Increment {
                 amount: prev_amount,
                 offset: prev_offset,
-                position: prev_pos,
-            } = prev_instr
+                position: prev_position,
+            }) = result.last_mut()
             {
-                if let Increment {
-                    amount,
-                    offset,
-                    position,
-                } = instr
-                {
-                    if prev_offset == offset {
-                        return Ok(Increment {
-                            amount: amount + prev_amount,
-                            offset,
-                            position: prev_pos.combine(position),
-                        });
-                    }
+                if *prev_offset == offset && (prev_amount.0 > 0) == (amount.0 > 0) {
+                    *prev_amount += amount;
+                    *prev_position = prev_position.combine(position);
+                    continue;
                 }
             }
-            Err((prev_instr, instr))
-        })
-        .filter(|instr| {
-            // Remove any increments of 0.
-            if let Increment {
-                amount: Wrapping(0),
-                ..
-            } = *instr
-            {
-                return false;
+        }
+        result.push(instr);
+    }
+
+    result.into_iter().map_loops(combine_same_sign_increments)
+}
+
+/// Return the index of the next Increment of the same cell as the
+/// Increment or Set at `index`, provided nothing in between reads or
+/// modifies that cell.
+fn next_increment_of_cell(instrs: &[AstNode], index: usize) -> Option<usize> {
+    let mut needed_offset = match instrs[index] {
+        Increment { offset, .. } | Set { offset, .. } => offset,
+        _ => return None,
+    };
+
+    for (i, instr) in instrs.iter().enumerate().skip(index + 1) {
+        match *instr {
+            Increment { offset, .. } => {
+                if offset == needed_offset {
+                    return Some(i);
+                }
+            }
+            Set { offset, .. } => {
+                if offset == needed_offset {
+                    return None;
+                }
+            }
+            SetRange { offset, length, .. } => {
+                if in_range(offset, length, needed_offset) {
+                    return None;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                needed_offset -= amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                // MultiplyMove reads and zeroes the current cell, and
+                // writes to the cells in `changes`.
+                if needed_offset == 0 || changes.contains_key(&needed_offset) {
+                    return None;
+                }
+            }
+            Write { .. } => {
+                if needed_offset == 0 {
+                    return None;
+                }
+            }
+            WriteConst { .. } => {}
+            Read { .. }
+            | Loop { .. }
+            | If { .. }
+            | Scan { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Store { .. }
+            | Load { .. } => return None,
+        }
+    }
+    None
+}
+
+/// Add any later increments of the same cell into the Increment or
+/// Set at `index`, removing them.
+fn fold_later_increments(instrs: &mut Vec<AstNode>, index: usize) {
+    while let Some(next_index) = next_increment_of_cell(instrs, index) {
+        if let Increment {
+            amount, position, ..
+        } = instrs.remove(next_index)
+        {
+            match instrs[index] {
+                Increment {
+                    amount: ref mut prev_amount,
+                    position: ref mut prev_position,
+                    ..
+                }
+                | Set {
+                    amount: ref mut prev_amount,
+                    position: ref mut prev_position,
+                    ..
+                } => {
+                    *prev_amount += amount;
+                    *prev_position = prev_position.combine(position);
+                }
+                _ => unreachable!(),
             }
-            true
+        }
+    }
+}
+
+/// Combine increments of the same cell, even when there are
+/// instructions on other cells in between.
+///
+/// "Increment 1, Increment 2 (offset 1), Increment 3" becomes
+/// "Increment 4, Increment 2 (offset 1)".
+pub fn combine_separated_increments(mut instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut index = 0;
+    while index < instrs.len() {
+        if let Increment { .. } = instrs[index] {
+            fold_later_increments(&mut instrs, index);
+        }
+        index += 1;
+    }
+
+    instrs
+        .into_iter()
+        .filter(|instr| {
+            !matches!(
+                *instr,
+                Increment {
+                    amount: Wrapping(0),
+                    ..
+                }
+            )
         })
-        .map_loops(combine_increments)
+        .map_loops(combine_separated_increments)
 }
 
+/// PointerIncrement x, PointerIncrement y -> PointerIncrement x+y,
+/// and remove any pointer increments of 0.
+const COMBINE_PTR_INCREMENT_RULES: &[Rule] = &[
+    Rule {
+        pattern: &[
+            Template::PointerIncrement { amount: Var("x") },
+            Template::PointerIncrement { amount: Var("y") },
+        ],
+        replacement: &[Template::PointerIncrement {
+            amount: Sum("x", "y"),
+        }],
+    },
+    Rule {
+        pattern: &[Template::PointerIncrement { amount: Lit(0) }],
+        replacement: &[],
+    },
+];
+
 pub fn combine_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    instrs
+    apply_rules(instrs, COMBINE_PTR_INCREMENT_RULES)
         .into_iter()
-        .coalesce(|prev_instr, instr| {
-            // Collapse consecutive increments.
-            if let PointerIncrement {
+        .map_loops(combine_ptr_increments)
+}
+
+/// Combine consecutive pointer increments that move in the same
+/// direction, and remove any increments of 0.
+///
+/// This is `combine_ptr_increments` for a checked pointer: `<>` on
+/// cell #0 moves off the tape and back, which ends the program, so
+/// we can't cancel it out. Moves in one direction pass through every
+/// cell between the start and the end, so combining them is safe.
+pub fn combine_same_sign_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = vec![];
+    for instr in instrs {
+        if let PointerIncrement { amount, position } = instr {
+            if amount == 0 {
+                continue;
+            }
+            if let Some(PointerIncrement {
                 amount: prev_amount,
-                position: prev_pos,
-            } = prev_instr
+                position: prev_position,
+            }) = result.last_mut()
             {
-                if let PointerIncrement { amount, position } = instr {
-                    return Ok(PointerIncrement {
-                        amount: amount + prev_amount,
-                        position: prev_pos.combine(position),
-                    });
+                if (*prev_amount > 0) == (amount > 0) {
+                    *prev_amount += amount;
+                    *prev_position = prev_position.combine(position);
+                    continue;
                 }
             }
-            Err((prev_instr, instr))
-        })
-        .filter(|instr| {
-            // Remove any pointer increments of 0.
-            if let PointerIncrement { amount: 0, .. } = *instr {
-                return false;
-            }
-            true
-        })
-        .map_loops(combine_ptr_increments)
+        }
+        result.push(instr);
+    }
+
+    result
+        .into_iter()
+        .map_loops(combine_same_sign_ptr_increments)
 }
 
 /// Don't bother updating cells if they're immediately overwritten
@@ -324,78 +1090,1347 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
             Write { .. } => {
                 last_write_index = Some(index);
             }
-            _ => {}
+            _ => {}
+        }
+    }
+
+    instrs
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, _)| !redundant_instr_positions.contains(&index))
+        .map(|(_, instr)| instr)
+        .map_loops(remove_read_clobber)
+}
+
+/// Is the value stored by the Read at `index` always overwritten,
+/// by a Set or another Read, before anything uses it? Another Read
+/// only overwrites it if reads at EOF change the cell, as `eof` says.
+fn is_dead_read(instrs: &[AstNode], index: usize, eof: Eof) -> bool {
+    let mut needed_offset = 0;
+
+    for instr in &instrs[index + 1..] {
+        match *instr {
+            // Increments use the previous value of the cell.
+            Increment { offset, .. } => {
+                if offset == needed_offset {
+                    return false;
+                }
+            }
+            Set { offset, .. } => {
+                if offset == needed_offset {
+                    return true;
+                }
+            }
+            SetRange { offset, length, .. } => {
+                if in_range(offset, length, needed_offset) {
+                    return true;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                needed_offset -= amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                if needed_offset == 0 || changes.contains_key(&needed_offset) {
+                    return false;
+                }
+            }
+            Read { .. } => {
+                if needed_offset == 0 {
+                    return eof != Eof::Unchanged;
+                }
+            }
+            Write { .. } => {
+                if needed_offset == 0 {
+                    return false;
+                }
+            }
+            WriteConst { .. } => {}
+            Loop { .. }
+            | If { .. }
+            | Scan { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Store { .. }
+            | Load { .. } => return false,
+        }
+    }
+    false
+}
+
+/// Warn about reads whose value is never used, as this usually
+/// indicates a bug. If `remove` is true, remove them too.
+///
+/// Removing a read changes which input later reads see, so this
+/// isn't a safe optimisation in general.
+pub fn remove_dead_reads(
+    instrs: Vec<AstNode>,
+    remove: bool,
+    eof: Eof,
+) -> (Vec<AstNode>, Vec<Warning>) {
+    let mut result = vec![];
+    let mut warnings = vec![];
+
+    let dead: Vec<_> = (0..instrs.len())
+        .map(|index| matches!(instrs[index], Read { .. }) && is_dead_read(&instrs, index, eof))
+        .collect();
+
+    for (instr, is_dead) in instrs.into_iter().zip(dead) {
+        match instr {
+            Read { position } if is_dead => {
+                warnings.push(Warning {
+                    message: "This read is always overwritten before its value is used.".to_owned(),
+                    position,
+                });
+                if !remove {
+                    result.push(Read { position });
+                }
+            }
+            Loop { body, position } => {
+                let (body, body_warnings) = remove_dead_reads(body, remove, eof);
+                warnings.extend(body_warnings);
+                result.push(Loop { body, position });
+            }
+            If { body, position } => {
+                let (body, body_warnings) = remove_dead_reads(body, remove, eof);
+                warnings.extend(body_warnings);
+                result.push(If { body, position });
+            }
+            other => result.push(other),
+        }
+    }
+
+    (result, warnings)
+}
+
+/// Convert [-] to Set 0.
+pub fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    map_instructions(instrs, &mut |instr| {
+        if let Loop { ref body, position } = instr {
+            // If the loop is [-]
+            if body.len() == 1 {
+                if let Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    ..
+                } = body[0]
+                {
+                    return Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position,
+                    };
+                }
+            }
+        }
+        instr
+    })
+}
+
+/// Convert loops that only move the pointer, such as [>] or [<<],
+/// to Scan.
+pub fn recognize_scans(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    map_instructions(instrs, &mut |instr| {
+        if let Loop { ref body, position } = instr {
+            if let [PointerIncrement { amount, .. }] = body[..] {
+                if amount != 0 {
+                    return Scan {
+                        step: amount,
+                        position,
+                    };
+                }
+            }
+        }
+        instr
+    })
+}
+
+/// Remove any loops where we know the current cell is zero. This
+/// includes loops straight after another loop, or after a
+/// MultiplyMove, at any nesting depth.
+pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let keep: Vec<_> = instrs
+        .iter()
+        .enumerate()
+        .map(|(index, instr)| match *instr {
+            Loop { .. } | If { .. } | Scan { .. } => !cell_is_known_zero(&instrs, index, 0),
+            // Keep all instructions that aren't loops.
+            _ => true,
+        })
+        .collect();
+
+    instrs
+        .into_iter()
+        .zip(keep)
+        .filter(|&(_, keep)| keep)
+        .map(|(instr, _)| instr)
+        .map_loops(remove_dead_loops)
+}
+
+/// Explain why optimising will delete code the user wrote in
+/// `instrs`, the program as parsed. We explain loops that are never
+/// entered, and changes to cells that a read always overwrites, if
+/// `options` runs the passes that remove them.
+pub fn explain_removals(instrs: &[AstNode], options: &OptimizeOptions) -> Vec<Warning> {
+    let mut explanations = vec![];
+    explain_removals_in(instrs, options, true, &mut explanations);
+    explanations
+}
+
+fn explain_removals_in(
+    instrs: &[AstNode],
+    options: &OptimizeOptions,
+    top_level: bool,
+    explanations: &mut Vec<Warning>,
+) {
+    let runs = |name| {
+        options
+            .passes
+            .iter()
+            .any(|pass| pass.name() == name && respects_semantics(*pass, options))
+    };
+    let mut last_write_index = None;
+
+    for (index, instr) in instrs.iter().enumerate() {
+        match *instr {
+            Loop { ref body, position } => {
+                let reason = if runs("dead_loop") && cell_is_known_zero(instrs, index, 0) {
+                    Some("the cell is always 0 here")
+                } else if runs("initial_zero")
+                    && top_level
+                    // remove_initial_zeroing stops at the first loop.
+                    && !instrs[..index].iter().any(|instr| matches!(instr, Loop { .. }))
+                    && cell_is_zero_before(instrs, index, 0, true)
+                {
+                    Some("cells start at 0 and nothing has changed this cell")
+                } else {
+                    None
+                };
+
+                match reason {
+                    Some(reason) => explanations.push(Warning {
+                        message: format!("This loop is never entered, because {}.", reason),
+                        position,
+                    }),
+                    None => explain_removals_in(body, options, false, explanations),
+                }
+            }
+            Read { .. } if runs("read_clobber") => {
+                // Work backwards through the changes to this cell,
+                // as remove_read_clobber removes each of them.
+                let mut clobbered: Option<Position> = None;
+                let mut search_index = index;
+                while let Some(change_index) = previous_cell_change(instrs, search_index) {
+                    if last_write_index.is_some_and(|write_index| write_index > change_index) {
+                        break;
+                    }
+                    let position = match instrs[change_index] {
+                        Increment { position, .. } | Set { position, .. } => position,
+                        _ => break,
+                    };
+                    if let (Some(span), Some(position)) = (clobbered, position) {
+                        if position.end + 1 < span.start {
+                            explain_clobber(clobbered, explanations);
+                            clobbered = None;
+                        }
+                    }
+                    clobbered = position.combine(clobbered);
+                    search_index = change_index;
+                }
+                explain_clobber(clobbered, explanations);
+            }
+            Write { .. } => {
+                last_write_index = Some(index);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn explain_clobber(position: Option<Position>, explanations: &mut Vec<Warning>) {
+    if position.is_some() {
+        explanations.push(Warning {
+            message: "This change is never used, because a read always overwrites the cell first."
+                .to_owned(),
+            position,
+        });
+    }
+}
+
+/// The tape starts zeroed, so code at the start of the program that
+/// zeroes cells does nothing. Remove Sets of cells to the value they
+/// already have, and loops and multiply-moves on cells that are still
+/// zero, until we reach a loop that may run.
+///
+/// This is cheaper than propagate_known_values, and runs first so
+/// later passes don't spend time on code we'll throw away.
+pub fn remove_initial_zeroing(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut known = KnownCells {
+        cells: HashMap::new(),
+        others_zero: true,
+    };
+    let mut cell_index = 0;
+
+    let mut result = vec![];
+    let mut instrs = instrs.into_iter();
+    for instr in &mut instrs {
+        match instr {
+            Loop { .. } | If { .. } | MultiplyMove { .. } | Scan { .. } => {
+                if known.get(cell_index) != Some(Wrapping(0)) {
+                    // This may run, so we no longer know which cells
+                    // are zero.
+                    result.push(instr);
+                    break;
+                }
+            }
+            Set { amount, offset, .. } => {
+                if known.get(cell_index + offset) != Some(amount) {
+                    known.set(cell_index + offset, Some(amount));
+                    result.push(instr);
+                }
+            }
+            SetRange {
+                amount,
+                offset,
+                length,
+                ..
+            } => {
+                let indexes = (0..length as isize).map(|i| cell_index + offset + i);
+                let already_set = indexes
+                    .clone()
+                    .all(|index| known.get(index) == Some(amount));
+                if !already_set {
+                    for index in indexes {
+                        known.set(index, Some(amount));
+                    }
+                    result.push(instr);
+                }
+            }
+            Increment { amount, offset, .. } => {
+                let value = known.get(cell_index + offset).map(|value| value + amount);
+                known.set(cell_index + offset, value);
+                result.push(instr);
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+                result.push(instr);
+            }
+            Read { .. } | Load { .. } => {
+                known.set(cell_index, None);
+                result.push(instr);
+            }
+            Write { .. } | WriteConst { .. } | Breakpoint { .. } | Halt { .. } | Store { .. } => {
+                result.push(instr);
+            }
+        }
+    }
+
+    result.extend(instrs);
+    result
+}
+
+/// What we know about the values of cells at a point in the
+/// program.
+struct KnownCells {
+    /// Cells we know about, indexed relative to the cell pointer at
+    /// the start of the instruction sequence. None means that the
+    /// value is unknown.
+    cells: HashMap<isize, Option<Cell>>,
+    /// Are all the cells that aren't in `cells` zero?
+    others_zero: bool,
+}
+
+impl KnownCells {
+    fn get(&self, index: isize) -> Option<Cell> {
+        match self.cells.get(&index) {
+            Some(value) => *value,
+            None if self.others_zero => Some(Wrapping(0)),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, index: isize, value: Option<Cell>) {
+        self.cells.insert(index, value);
+    }
+
+    /// Forget everything we know, e.g. after a loop that may have
+    /// modified any cell.
+    fn forget_all(&mut self) {
+        self.cells.clear();
+        self.others_zero = false;
+    }
+
+    /// Forget the values of the cells that `body` may modify, when
+    /// the cell pointer is at `index` before it runs.
+    fn forget_modified(&mut self, index: isize, body: &[AstNode]) {
+        match modified_cells(body) {
+            Some(offsets) => {
+                for offset in offsets {
+                    self.set(index + offset, None);
+                }
+            }
+            None => self.forget_all(),
+        }
+    }
+
+    /// What we know inside `body`, which starts with the cell pointer
+    /// at `index`. If `body` is a loop body, cells it modifies are
+    /// unknown after the first iteration.
+    fn for_body(&self, index: isize, body: &[AstNode], is_loop: bool) -> KnownCells {
+        let mut known = KnownCells {
+            cells: self
+                .cells
+                .iter()
+                .map(|(cell_index, value)| (cell_index - index, *value))
+                .collect(),
+            others_zero: self.others_zero,
+        };
+        if is_loop {
+            known.forget_modified(0, body);
+        }
+        known
+    }
+}
+
+/// The offsets of all the cells that `instrs` may modify, relative
+/// to the cell pointer at the start. Returns None if we can't tell,
+/// because the cell pointer doesn't return to where it started.
+fn modified_cells(instrs: &[AstNode]) -> Option<HashSet<isize>> {
+    if net_pointer_movement(instrs) != Some(0) {
+        return None;
+    }
+
+    let mut modified = HashSet::new();
+    let mut cell_index = 0;
+    for instr in instrs {
+        match *instr {
+            Increment { offset, .. } | Set { offset, .. } => {
+                modified.insert(cell_index + offset);
+            }
+            SetRange { offset, length, .. } => {
+                for i in 0..length as isize {
+                    modified.insert(cell_index + offset + i);
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            Read { .. } | Load { .. } => {
+                modified.insert(cell_index);
+            }
+            Write { .. } | WriteConst { .. } | Breakpoint { .. } | Halt { .. } | Store { .. } => {}
+            MultiplyMove { ref changes, .. } => {
+                modified.insert(cell_index);
+                for offset in changes.keys() {
+                    modified.insert(cell_index + offset);
+                }
+            }
+            Loop { ref body, .. } | If { ref body, .. } => {
+                for offset in modified_cells(body)? {
+                    modified.insert(cell_index + offset);
+                }
+            }
+            // We don't know where the pointer ends up.
+            Scan { .. } => return None,
+        }
+    }
+    Some(modified)
+}
+
+/// Is `body` a single step of the current cell, such as `[--]`,
+/// that reaches zero when the cell starts at `initial`?
+///
+/// zeroing_loops only handles `[-]`, because `[--]` never terminates
+/// when the cell is odd. Cells wrap, so the loop terminates exactly
+/// when `initial` has at least as many trailing zero bits as the
+/// step, e.g. `[--]` terminates for even values. This doesn't depend
+/// on the cell width.
+fn is_terminating_step_loop(body: &[AstNode], initial: Cell) -> bool {
+    match *body {
+        [Increment {
+            amount, offset: 0, ..
+        }] => initial.0.trailing_zeros() >= amount.0.trailing_zeros(),
+        _ => false,
+    }
+}
+
+/// Track the values of cells through the program, and use them to
+/// simplify instructions:
+///
+/// * Increments of cells with known values become Set.
+/// * Sets that don't change the cell value are removed.
+/// * Loops and multiply-moves on cells known to be zero are removed.
+/// * Loops like `[--]` that we can prove reach zero become Set 0.
+///
+/// Unlike combine_set_and_increments, this works when instructions
+/// aren't adjacent, e.g. "Set 1, Write, Increment 1" becomes "Set 1,
+/// Write, Set 2".
+pub fn propagate_known_values(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    // All cells are zero when the program starts.
+    let known = KnownCells {
+        cells: HashMap::new(),
+        others_zero: true,
+    };
+    propagate_known_values_inner(instrs, known)
+}
+
+fn propagate_known_values_inner(instrs: Vec<AstNode>, mut known: KnownCells) -> Vec<AstNode> {
+    let mut result = vec![];
+    let mut cell_index = 0;
+
+    for instr in instrs {
+        match instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => match known.get(cell_index + offset) {
+                Some(value) => {
+                    known.set(cell_index + offset, Some(value + amount));
+                    result.push(Set {
+                        amount: value + amount,
+                        offset,
+                        position,
+                    });
+                }
+                None => result.push(Increment {
+                    amount,
+                    offset,
+                    position,
+                }),
+            },
+            Set { amount, offset, .. } => {
+                if known.get(cell_index + offset) != Some(amount) {
+                    known.set(cell_index + offset, Some(amount));
+                    result.push(instr);
+                }
+            }
+            SetRange {
+                amount,
+                offset,
+                length,
+                ..
+            } => {
+                for i in 0..length as isize {
+                    known.set(cell_index + offset + i, Some(amount));
+                }
+                result.push(instr);
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+                result.push(instr);
+            }
+            Read { .. } | Load { .. } => {
+                known.set(cell_index, None);
+                result.push(instr);
+            }
+            Write { position } => match known.get(cell_index) {
+                // Like putchar, we only write the low byte.
+                Some(value) => result.push(WriteConst {
+                    values: vec![value.0 as i8],
+                    position,
+                }),
+                None => result.push(instr),
+            },
+            WriteConst { .. } | Breakpoint { .. } | Halt { .. } | Store { .. } => {
+                result.push(instr);
+            }
+            MultiplyMove { ref changes, .. } => {
+                let source = known.get(cell_index);
+                if source == Some(Wrapping(0)) {
+                    // Multiplying by zero does nothing.
+                    continue;
+                }
+
+                for (offset, factor) in changes {
+                    let dest_index = cell_index + offset;
+                    let new_value = match (source, known.get(dest_index)) {
+                        (Some(source), Some(dest)) => Some(dest + source * *factor),
+                        _ => None,
+                    };
+                    known.set(dest_index, new_value);
+                }
+                known.set(cell_index, Some(Wrapping(0)));
+                result.push(instr);
+            }
+            Scan { .. } => {
+                if known.get(cell_index) == Some(Wrapping(0)) {
+                    // We're already on a zero cell.
+                    continue;
+                }
+
+                // We don't know which cell we end up on, except that
+                // it's zero.
+                known.forget_all();
+                known.set(cell_index, Some(Wrapping(0)));
+                result.push(instr);
+            }
+            Loop { body, position } => {
+                if known.get(cell_index) == Some(Wrapping(0)) {
+                    // We never enter this loop.
+                    continue;
+                }
+
+                if let Some(value) = known.get(cell_index) {
+                    if is_terminating_step_loop(&body, value) {
+                        known.set(cell_index, Some(Wrapping(0)));
+                        result.push(Set {
+                            amount: Wrapping(0),
+                            offset: 0,
+                            position,
+                        });
+                        continue;
+                    }
+                }
+
+                // Cells that the loop body doesn't modify keep their
+                // values on every iteration.
+                let body_known = known.for_body(cell_index, &body, true);
+                known.forget_modified(cell_index, &body);
+
+                let body = propagate_known_values_inner(body, body_known);
+                result.push(Loop { body, position });
+
+                // We know the current cell is zero after any loop.
+                known.set(cell_index, Some(Wrapping(0)));
+            }
+            If { body, position } => {
+                if known.get(cell_index) == Some(Wrapping(0)) {
+                    continue;
+                }
+
+                // We only enter the body once, so everything we know
+                // holds at the start of the body.
+                let body_known = known.for_body(cell_index, &body, false);
+                known.forget_modified(cell_index, &body);
+
+                let body = propagate_known_values_inner(body, body_known);
+                result.push(If { body, position });
+
+                known.set(cell_index, Some(Wrapping(0)));
+            }
+        }
+    }
+
+    result
+}
+
+/// Merge WriteConst instructions so we only make one write call.
+/// Instructions that only modify cells can't affect a WriteConst, so
+/// "WriteConst [1], Set 2, WriteConst [3]" becomes
+/// "WriteConst [1, 3], Set 2".
+pub fn combine_const_writes(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result = vec![];
+    // The index in `result` of the WriteConst we're appending to.
+    let mut write_index = None;
+
+    for instr in instrs {
+        match instr {
+            WriteConst { values, position } => match write_index {
+                Some(index) => {
+                    if let WriteConst {
+                        values: ref mut prev_values,
+                        position: ref mut prev_position,
+                    } = result[index]
+                    {
+                        prev_values.extend(values);
+                        *prev_position = prev_position.combine(position);
+                    }
+                }
+                None => {
+                    write_index = Some(result.len());
+                    result.push(WriteConst { values, position });
+                }
+            },
+            Increment { .. }
+            | Set { .. }
+            | SetRange { .. }
+            | PointerIncrement { .. }
+            | MultiplyMove { .. }
+            | Store { .. }
+            | Load { .. } => {
+                result.push(instr);
+            }
+            // We can't reorder writes with other I/O or move them
+            // past the end of the program, and we don't know what
+            // loops will do.
+            Read { .. }
+            | Write { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Loop { .. }
+            | If { .. }
+            | Scan { .. } => {
+                write_index = None;
+                result.push(instr);
+            }
+        }
+    }
+
+    result.into_iter().map_loops(combine_const_writes)
+}
+
+/// Does this loop body leave the current cell untouched? If so, the
+/// loop can never terminate once we've entered it.
+fn loop_body_preserves_cell(body: &[AstNode]) -> bool {
+    let mut cell_index = 0;
+
+    for instr in body {
+        match *instr {
+            Increment { offset, .. } | Set { offset, .. } => {
+                if cell_index + offset == 0 {
+                    return false;
+                }
+            }
+            SetRange { offset, length, .. } => {
+                if in_range(cell_index + offset, length, 0) {
+                    return false;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                // MultiplyMove zeroes the cell it's on, and modifies
+                // the cells at its offsets.
+                if cell_index == 0 || changes.contains_key(&-cell_index) {
+                    return false;
+                }
+            }
+            Write { .. } | WriteConst { .. } | Breakpoint { .. } | Store { .. } => {}
+            // These instructions may modify the current cell.
+            Read { .. } | Load { .. } | Loop { .. } | If { .. } | Scan { .. } => return false,
+            // The loop ends if the program does.
+            Halt { .. } => return false,
+        }
+    }
+
+    // If the loop body moves the pointer, each iteration tests a
+    // different cell.
+    cell_index == 0
+}
+
+/// Given the index of a loop, return true if we can prove that the
+/// loop is entered and never terminates.
+fn is_infinite_loop(instrs: &[AstNode], index: usize) -> bool {
+    if let Loop { ref body, .. } = instrs[index] {
+        if !loop_body_preserves_cell(body) {
+            return false;
+        }
+
+        if let Some(prev_change_index) = previous_cell_change(instrs, index) {
+            if let Set {
+                amount, offset: 0, ..
+            } = instrs[prev_change_index]
+            {
+                return amount.0 != 0;
+            }
+        }
+    }
+    false
+}
+
+/// Remove instructions that can never execute because they follow a
+/// loop that never terminates, e.g. `+[].` never reaches the `.`.
+pub fn remove_code_after_infinite_loops(instrs: Vec<AstNode>) -> (Vec<AstNode>, Vec<Warning>) {
+    let mut result = vec![];
+    let mut warnings = vec![];
+
+    let infinite_index = (0..instrs.len()).find(|&index| is_infinite_loop(&instrs, index));
+    let mut unreachable_warning = None;
+    if let Some(index) = infinite_index {
+        // annotate_known_zero adds a Set 0 after every loop,
+        // which isn't worth warning about.
+        let mut unreachable = &instrs[index + 1..];
+        if let Some(&Set {
+            amount: Wrapping(0),
+            offset: 0,
+            ..
+        }) = unreachable.first()
+        {
+            unreachable = &unreachable[1..];
+        }
+
+        if !unreachable.is_empty() {
+            unreachable_warning = Some(Warning {
+                message: "This loop never terminates, so the instructions after it \
+                          are never executed."
+                    .to_owned(),
+                position: get_position(&instrs[index]),
+            });
+        }
+    }
+
+    for (index, instr) in instrs.into_iter().enumerate() {
+        if Some(index) == infinite_index {
+            result.push(instr);
+            warnings.extend(unreachable_warning);
+            break;
+        }
+
+        match instr {
+            Loop { body, position } => {
+                let (body, body_warnings) = remove_code_after_infinite_loops(body);
+                warnings.extend(body_warnings);
+                result.push(Loop { body, position });
+            }
+            If { body, position } => {
+                let (body, body_warnings) = remove_code_after_infinite_loops(body);
+                warnings.extend(body_warnings);
+                result.push(If { body, position });
+            }
+            other => result.push(other),
+        }
+    }
+
+    (result, warnings)
+}
+
+/// If this loop body changes the current cell by the same amount on
+/// every iteration, and doesn't contain any loops, reads,
+/// breakpoints or Extended Type I commands, return that amount.
+fn loop_body_step(body: &[AstNode]) -> Option<Cell> {
+    let mut step = Wrapping(0);
+    let mut cell_index = 0;
+
+    for instr in body {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                if cell_index + offset == 0 {
+                    step += amount;
+                }
+            }
+            Set { offset, .. } => {
+                // We don't try to reason about loops that overwrite
+                // the current cell.
+                if cell_index + offset == 0 {
+                    return None;
+                }
+            }
+            SetRange { offset, length, .. } => {
+                if in_range(cell_index + offset, length, 0) {
+                    return None;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            Write { .. } | WriteConst { .. } => {}
+            Read { .. }
+            | Loop { .. }
+            | If { .. }
+            | Scan { .. }
+            | MultiplyMove { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Store { .. }
+            | Load { .. } => return None,
+        }
+    }
+
+    // The loop must finish each iteration on the cell it started
+    // on, and must make progress towards zero.
+    if cell_index != 0 || step.0 == 0 {
+        return None;
+    }
+    Some(step)
+}
+
+/// How many times will a loop execute, if the current cell starts
+/// at `initial` and changes by `step` on every iteration? Returns
+/// None if the loop never terminates, or if it only reaches zero by
+/// wrapping around, as the count then depends on the cell width.
+fn trip_count(initial: Cell, step: Cell) -> Option<usize> {
+    let mut value = initial;
+    for iterations in 1..=256 {
+        value += step;
+        if value.0 == 0 {
+            return Some(iterations);
+        }
+    }
+    None
+}
+
+/// Combine adjacent loops that run the same number of times and
+/// touch different cells. For example, "Set 2, [>+.<-], Set 2,
+/// [>>+<<-]" becomes "Set 2, [>+.<->>+<<]".
+///
+/// Both loops must be entered with the same value in the current
+/// cell, and must change it by the same step on every iteration.
+/// This saves a loop per pair, but makes --dump-ir harder to relate
+/// to the source, so we only do it with --fuse-loops.
+pub fn fuse_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = vec![];
+
+    for instr in instrs {
+        if let Loop { ref body, .. } = instr {
+            if let Some(fused) = fuse_with_previous_loop(&result, body) {
+                // Drop the second Set and the first loop.
+                result.truncate(result.len() - 2);
+                result.push(fused);
+                continue;
+            }
+        }
+        result.push(instr);
+    }
+
+    result.into_iter().map_loops(fuse_loops)
+}
+
+/// If `instrs` ends with "Set x, Loop, Set x", and we can fuse that
+/// loop with a following loop with body `body`, return the fused
+/// loop.
+fn fuse_with_previous_loop(instrs: &[AstNode], body: &[AstNode]) -> Option<AstNode> {
+    match *instrs {
+        [.., Set {
+            amount: first_value,
+            offset: 0,
+            ..
+        }, Loop {
+            body: ref first_body,
+            position,
+        }, Set {
+            amount: second_value,
+            offset: 0,
+            ..
+        }] if first_value == second_value => {
+            fuse_loop_bodies(first_body, body).map(|body| Loop { body, position })
+        }
+        _ => None,
+    }
+}
+
+/// If the loops `[first]` and `[second]` always run the same number
+/// of times, and running their iterations interleaved is equivalent
+/// to running one loop after the other, return the body of the
+/// combined loop.
+fn fuse_loop_bodies(first: &[AstNode], second: &[AstNode]) -> Option<Vec<AstNode>> {
+    if loop_body_step(first)? != loop_body_step(second)? {
+        return None;
+    }
+
+    // Interleaving two loops that both write would change the order
+    // of the output.
+    let first_written = written_cells(first);
+    let second_written = written_cells(second);
+    if has_writes(first) && has_writes(second) {
+        return None;
+    }
+
+    // We drop the second loop's step, so it mustn't read the current
+    // cell.
+    if second_written.contains(&0) {
+        return None;
+    }
+
+    // Neither loop may touch a cell that the other modifies.
+    let first_modified = modified_cells(first)?;
+    let second_modified = modified_cells(second)?;
+    let first_touched: HashSet<_> = first_modified.union(&first_written).cloned().collect();
+    let second_touched: HashSet<_> = second_modified.union(&second_written).cloned().collect();
+    if first_modified
+        .intersection(&second_touched)
+        .chain(second_modified.intersection(&first_touched))
+        .any(|index| *index != 0)
+    {
+        return None;
+    }
+
+    let mut body = first.to_vec();
+    let mut cell_index = 0;
+    for instr in second {
+        match *instr {
+            Increment { offset, .. } if cell_index + offset == 0 => {}
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+                body.push(instr.clone());
+            }
+            _ => body.push(instr.clone()),
+        }
+    }
+    Some(body)
+}
+
+/// Does `instrs` write any output?
+fn has_writes(instrs: &[AstNode]) -> bool {
+    instrs
+        .iter()
+        .any(|instr| matches!(instr, Write { .. } | WriteConst { .. }))
+}
+
+/// The offsets of the cells that a loop body without nested loops
+/// writes as output, relative to the cell pointer at the start.
+fn written_cells(body: &[AstNode]) -> HashSet<isize> {
+    let mut written = HashSet::new();
+    let mut cell_index = 0;
+    for instr in body {
+        match *instr {
+            PointerIncrement { amount, .. } => cell_index += amount,
+            Write { .. } => {
+                written.insert(cell_index);
+            }
+            _ => {}
+        }
+    }
+    written
+}
+
+/// Replace loops that run a known number of times with that many
+/// copies of the loop body. For example, "Set 3, [->+<]" becomes
+/// "Set 3, ->+< ->+< ->+<".
+///
+/// We only unroll when the result has at most `max_size`
+/// instructions, so we don't bloat the program. Loops that
+/// `profile` shows never ran are left alone.
+pub fn unroll_loops(
+    instrs: Vec<AstNode>,
+    max_size: usize,
+    profile: Option<&Profile>,
+) -> Vec<AstNode> {
+    let mut result = vec![];
+
+    let trip_counts: Vec<_> = (0..instrs.len())
+        .map(|index| match instrs[index] {
+            Loop { position, .. } => {
+                let is_cold = profile.map_or(false, |profile| profile.is_cold(position));
+                if is_cold {
+                    None
+                } else {
+                    known_trip_count(&instrs, index)
+                }
+            }
+            _ => None,
+        })
+        .collect();
+
+    for (instr, trip_count) in instrs.into_iter().zip(trip_counts) {
+        if let (Loop { ref body, .. }, Some(iterations)) = (&instr, trip_count) {
+            if iterations * body.len() <= max_size {
+                for _ in 0..iterations {
+                    result.extend(body.iter().cloned());
+                }
+                continue;
+            }
+        }
+        result.push(instr);
+    }
+
+    result
+        .into_iter()
+        .map_loops(|body| unroll_loops(body, max_size, profile))
+}
+
+/// Inline small counter loops, where the instruction immediately
+/// before the loop sets the counter. For example, "Set 2, [->+<]"
+/// becomes "Set 2, ->+< ->+<".
+///
+/// Unlike unroll_loops, this is cheap enough to do at every
+/// optimisation level: the loops are tiny, and inlining them lets
+/// the other passes fold the copies together.
+pub fn inline_counter_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result = vec![];
+
+    let trip_counts: Vec<_> = (0..instrs.len())
+        .map(|index| {
+            let counter_is_set = index > 0 && matches!(instrs[index - 1], Set { offset: 0, .. });
+            if counter_is_set && matches!(instrs[index], Loop { .. }) {
+                known_trip_count(&instrs, index)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    for (instr, trip_count) in instrs.into_iter().zip(trip_counts) {
+        if let (Loop { ref body, .. }, Some(iterations)) = (&instr, trip_count) {
+            if iterations <= MAX_INLINED_TRIP_COUNT
+                && iterations * body.len() <= MAX_INLINED_COUNTER_LOOP_SIZE
+            {
+                for _ in 0..iterations {
+                    result.extend(body.iter().cloned());
+                }
+                continue;
+            }
+        }
+        result.push(instr);
+    }
+
+    result.into_iter().map_loops(inline_counter_loops)
+}
+
+/// Given the index of a loop, return the number of iterations it
+/// will execute, if we know the value of the current cell when we
+/// enter the loop.
+fn known_trip_count(instrs: &[AstNode], index: usize) -> Option<usize> {
+    let step = match instrs[index] {
+        Loop { ref body, .. } => loop_body_step(body)?,
+        _ => return None,
+    };
+
+    let prev_change_index = previous_cell_change(instrs, index)?;
+    match instrs[prev_change_index] {
+        // Dead loops are handled by remove_dead_loops.
+        Set {
+            amount, offset: 0, ..
+        } if amount.0 != 0 => trip_count(amount, step),
+        _ => None,
+    }
+}
+
+/// Does this loop body always finish with the current cell set to
+/// zero? If so, the loop can only execute once.
+fn loop_body_zeroes_cell(body: &[AstNode]) -> bool {
+    if net_pointer_movement(body) != Some(0) {
+        return false;
+    }
+
+    // Work backwards to find the last change to the current cell.
+    let mut needed_offset = 0;
+    for instr in body.iter().rev() {
+        match *instr {
+            Set { amount, offset, .. } if offset == needed_offset => {
+                return amount.0 == 0;
+            }
+            Increment { offset, .. } if offset == needed_offset => {
+                return false;
+            }
+            SetRange {
+                amount,
+                offset,
+                length,
+                ..
+            } if in_range(offset, length, needed_offset) => {
+                return amount.0 == 0;
+            }
+            Increment { .. }
+            | Set { .. }
+            | SetRange { .. }
+            | Write { .. }
+            | WriteConst { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Store { .. } => {}
+            PointerIncrement { amount, .. } => {
+                needed_offset += amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                // MultiplyMove zeroes the cell it's on.
+                if needed_offset == 0 {
+                    return true;
+                }
+                if changes.contains_key(&needed_offset) {
+                    return false;
+                }
+            }
+            // Loops always finish on a zero cell.
+            Loop { .. } | If { .. } | Scan { .. } => return needed_offset == 0,
+            Read { .. } | Load { .. } => return false,
+        }
+    }
+    false
+}
+
+/// How a sequence of instructions leaves a cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CellEffect {
+    /// The cell ends up with one of these values.
+    OneOf(HashSet<Cell>),
+    /// The cell's value changes by this amount.
+    Add(Cell),
+}
+
+impl CellEffect {
+    fn exactly(value: Cell) -> CellEffect {
+        CellEffect::OneOf(vec![value].into_iter().collect())
+    }
+
+    /// The effect of running `self` and then `next`.
+    fn then(self, next: CellEffect) -> CellEffect {
+        match (self, next) {
+            (_, CellEffect::OneOf(values)) => CellEffect::OneOf(values),
+            (CellEffect::OneOf(values), CellEffect::Add(amount)) => {
+                CellEffect::OneOf(values.into_iter().map(|v| v + amount).collect())
+            }
+            (CellEffect::Add(amount1), CellEffect::Add(amount2)) => {
+                CellEffect::Add(amount1 + amount2)
+            }
+        }
+    }
+
+    /// An effect covering both `self` and `other`, if we can express
+    /// it.
+    fn either(self, other: CellEffect) -> Option<CellEffect> {
+        match (self, other) {
+            (CellEffect::OneOf(mut values), CellEffect::OneOf(other_values)) => {
+                values.extend(other_values);
+                Some(CellEffect::OneOf(values))
+            }
+            (CellEffect::Add(amount1), CellEffect::Add(amount2)) if amount1 == amount2 => {
+                Some(CellEffect::Add(amount1))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How do `instrs` leave the cell at `offset`, relative to the cell
+/// pointer at the end? Returns None if we can't tell.
+fn cell_effect(instrs: &[AstNode], offset: isize) -> Option<CellEffect> {
+    let mut needed_offset = offset;
+    let mut added = Wrapping(0);
+
+    for (index, instr) in instrs.iter().enumerate().rev() {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                if offset == needed_offset {
+                    added += amount;
+                }
+            }
+            Set { amount, offset, .. } => {
+                if offset == needed_offset {
+                    return Some(CellEffect::exactly(amount + added));
+                }
+            }
+            SetRange {
+                amount,
+                offset,
+                length,
+                ..
+            } => {
+                if in_range(offset, length, needed_offset) {
+                    return Some(CellEffect::exactly(amount + added));
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                needed_offset += amount;
+            }
+            Write { .. } | WriteConst { .. } | Breakpoint { .. } | Halt { .. } | Store { .. } => {}
+            Read { .. } | Load { .. } => {
+                if needed_offset == 0 {
+                    return None;
+                }
+            }
+            MultiplyMove { ref changes, .. } => {
+                if needed_offset == 0 {
+                    return Some(CellEffect::exactly(added));
+                }
+                if changes.contains_key(&needed_offset) {
+                    return None;
+                }
+            }
+            // Loops only exit when the current cell is zero.
+            Loop { .. } | If { .. } | Scan { .. } if needed_offset == 0 => {
+                return Some(CellEffect::exactly(added));
+            }
+            // We don't know which cell we were on before the scan.
+            Scan { .. } => return None,
+            Loop { ref body, .. } => {
+                if modified_cells(body)?.contains(&needed_offset) {
+                    return None;
+                }
+            }
+            If { ref body, .. } => {
+                if modified_cells(body)?.contains(&needed_offset) {
+                    // The body runs at most once, so the cell ends
+                    // up as either the value before or the value
+                    // after the body.
+                    let before = cell_effect(&instrs[..index], needed_offset)?;
+                    let after = before.clone().then(cell_effect(body, needed_offset)?);
+                    return Some(before.either(after)?.then(CellEffect::Add(added)));
+                }
+            }
+        }
+    }
+
+    Some(CellEffect::Add(added))
+}
+
+/// If the only change this loop body makes to the current cell is a
+/// single decrement, return the index of that decrement.
+fn single_decrement_index(body: &[AstNode]) -> Option<usize> {
+    if net_pointer_movement(body) != Some(0) {
+        return None;
+    }
+
+    let mut decrement_index = None;
+    let mut cell_index = 0;
+    for (index, instr) in body.iter().enumerate() {
+        match *instr {
+            Increment { amount, offset, .. } if cell_index + offset == 0 => {
+                if amount != Wrapping(-1) || decrement_index.is_some() {
+                    return None;
+                }
+                decrement_index = Some(index);
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            // A nested loop on the current cell would zero it.
+            Loop { .. } | If { .. } if cell_index == 0 => return None,
+            _ => {
+                if modified_cells(std::slice::from_ref(instr))?.contains(&-cell_index) {
+                    return None;
+                }
+            }
         }
     }
-
-    instrs
-        .into_iter()
-        .enumerate()
-        .filter(|&(index, _)| !redundant_instr_positions.contains(&index))
-        .map(|(_, instr)| instr)
-        .map_loops(remove_read_clobber)
+    decrement_index
 }
 
-/// Convert [-] to Set 0.
-pub fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    instrs
-        .into_iter()
-        .map(|instr| {
-            if let Loop { ref body, position } = instr {
-                // If the loop is [-]
-                if body.len() == 1 {
-                    if let Increment {
-                        amount: Wrapping(-1),
-                        offset: 0,
-                        ..
-                    } = body[0]
-                    {
-                        return Set {
-                            amount: Wrapping(0),
-                            offset: 0,
-                            position,
-                        };
-                    }
-                }
-            }
-            instr
-        })
-        .map_loops(zeroing_loops)
+/// If the loop at `index` is on a flag cell, which is always 0 or 1,
+/// and its body decrements that cell once, return the index of the
+/// decrement in the body. The loop can only run once.
+fn flag_loop_decrement(instrs: &[AstNode], index: usize) -> Option<usize> {
+    let body = match instrs[index] {
+        Loop { ref body, .. } => body,
+        _ => return None,
+    };
+    let decrement_index = single_decrement_index(body)?;
+
+    match cell_effect(&instrs[..index], 0)? {
+        CellEffect::OneOf(values) if values.iter().all(|value| value.0 == 0 || value.0 == 1) => {
+            Some(decrement_index)
+        }
+        _ => None,
+    }
 }
 
-/// Remove any loops where we know the current cell is zero.
-pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+/// Replace loops that can only execute once with an If, so we don't
+/// check the loop condition again. For example, "[>+<[-]]" becomes
+/// "If (>+< Set 0)".
+///
+/// This includes loops on flag cells, which are always 0 or 1, that
+/// decrement the flag. The decrement becomes a Set 0.
+pub fn recognize_ifs(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let flag_decrements: HashMap<usize, usize> = (0..instrs.len())
+        .filter_map(|index| flag_loop_decrement(&instrs, index).map(|i| (index, i)))
+        .collect();
+
     instrs
-        .clone()
         .into_iter()
         .enumerate()
-        .filter(|&(index, ref instr)| {
-            match *instr {
-                Loop { .. } => {}
-                // Keep all instructions that aren't loops.
-                _ => {
-                    return true;
-                }
-            }
-
-            // Find the previous change instruction:
-            if let Some(prev_change_index) = previous_cell_change(&instrs, index) {
-                let prev_instr = &instrs[prev_change_index];
-                // If the previous instruction set to zero, our loop is dead.
-                // TODO: MultiplyMove also zeroes the current cell.
-                if let Set {
-                    amount: Wrapping(0),
-                    offset: 0,
+        .map(|(index, instr)| match instr {
+            Loop { body, position } if loop_body_zeroes_cell(&body) => If { body, position },
+            Loop { mut body, position } if flag_decrements.contains_key(&index) => {
+                // We only enter the loop when the flag is 1, so the
+                // decrement always sets it to zero.
+                let decrement_index = flag_decrements[&index];
+                if let Increment {
+                    offset,
+                    position: decrement_position,
                     ..
-                } = *prev_instr
+                } = body[decrement_index]
                 {
-                    return false;
+                    body[decrement_index] = Set {
+                        amount: Wrapping(0),
+                        offset,
+                        position: decrement_position,
+                    };
                 }
+                If { body, position }
             }
-            true
+            other => other,
         })
-        .map(|(_, instr)| instr)
-        .map_loops(remove_dead_loops)
+        .map_loops(recognize_ifs)
 }
 
 /// Reorder flat sequences of instructions so we use offsets and only
@@ -405,13 +2440,22 @@ pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
 /// Increment { amount: 1, offset: 1 }
 /// Increment { amount: 2, offset: 2 }
 /// PointerIncrement(1)
+///
+/// A sequence only ends at an instruction that needs the cell
+/// pointer itself. SetRange takes an offset, and WriteConst doesn't
+/// use the cell pointer, so we defer pointer increments past them
+/// too.
 pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut sequence = vec![];
     let mut result = vec![];
 
     for instr in instrs {
         match instr {
-            Increment { .. } | Set { .. } | PointerIncrement { .. } => {
+            Increment { .. }
+            | Set { .. }
+            | SetRange { .. }
+            | WriteConst { .. }
+            | PointerIncrement { .. } => {
                 sequence.push(instr);
             }
             _ => {
@@ -419,13 +2463,16 @@ pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     result.extend(sort_sequence_by_offset(sequence));
                     sequence = vec![];
                 }
-                if let Loop { body, position } = instr {
-                    result.push(Loop {
+                match instr {
+                    Loop { body, position } => result.push(Loop {
                         body: sort_by_offset(body),
                         position,
-                    });
-                } else {
-                    result.push(instr);
+                    }),
+                    If { body, position } => result.push(If {
+                        body: sort_by_offset(body),
+                        position,
+                    }),
+                    _ => result.push(instr),
                 }
             }
         }
@@ -453,6 +2500,7 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut instrs_by_offset: HashMap<isize, Vec<AstNode>> = HashMap::new();
     let mut current_offset = 0;
     let mut last_ptr_inc_pos = None;
+    let mut results: Vec<AstNode> = vec![];
 
     for instr in instrs {
         match instr {
@@ -484,20 +2532,45 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     position,
                 });
             }
+            SetRange {
+                amount,
+                offset,
+                length,
+                position,
+            } => {
+                // A SetRange may overlap cells we've already
+                // modified, so we can't reorder it. Flush everything
+                // before it.
+                for same_offset_instrs in ordered_values(instrs_by_offset) {
+                    results.extend(same_offset_instrs);
+                }
+                instrs_by_offset = HashMap::new();
+
+                results.push(SetRange {
+                    amount,
+                    offset: offset + current_offset,
+                    length,
+                    position,
+                });
+            }
+            WriteConst { .. } => {
+                // This doesn't depend on any cells, so it can go
+                // before the increments and sets we haven't flushed
+                // yet.
+                results.push(instr);
+            }
             PointerIncrement { amount, position } => {
                 current_offset += amount;
                 last_ptr_inc_pos = Some(position);
             }
-            // We assume that we were only given a Vec of
-            // Increment/Set/PointerIncrement instructions. It's
-            // the job of this function to create instructions with
-            // offset.
+            // We assume that we were only given a Vec of the
+            // instructions above. It's the job of this function to
+            // create instructions with offset.
             _ => unreachable!(),
         }
     }
 
     // Append the increment/set instructions, in offset order.
-    let mut results: Vec<AstNode> = vec![];
     for same_offset_instrs in ordered_values(instrs_by_offset) {
         results.extend(same_offset_instrs.into_iter());
     }
@@ -513,95 +2586,216 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     results
 }
 
+/// Replace runs of Set instructions that set adjacent cells to the
+/// same value with a single SetRange, e.g. "Set 0 (offset 1), Set 0
+/// (offset 2), Set 0 (offset 3), Set 0 (offset 4)" becomes "SetRange
+/// 0 (offset 1, length 4)". sort_by_offset puts Sets in offset order,
+/// so we only need to look at consecutive instructions.
+pub fn combine_set_ranges(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result = vec![];
+    let mut run: Vec<AstNode> = vec![];
+
+    for instr in instrs {
+        if let Set { amount, offset, .. } = instr {
+            let continues_run = match run.last() {
+                Some(&Set {
+                    amount: prev_amount,
+                    offset: prev_offset,
+                    ..
+                }) => prev_amount == amount && prev_offset + 1 == offset,
+                _ => false,
+            };
+            if !continues_run {
+                result.extend(set_range_from_run(run));
+                run = vec![];
+            }
+            run.push(instr);
+        } else {
+            result.extend(set_range_from_run(run));
+            run = vec![];
+            result.push(instr);
+        }
+    }
+    result.extend(set_range_from_run(run));
+
+    result.into_iter().map_loops(combine_set_ranges)
+}
+
+/// Given a run of Set instructions on adjacent cells with the same
+/// value, return a SetRange if the run is long enough.
+fn set_range_from_run(run: Vec<AstNode>) -> Vec<AstNode> {
+    if run.len() < MIN_SET_RANGE_LENGTH {
+        return run;
+    }
+
+    let (amount, offset) = match run[0] {
+        Set { amount, offset, .. } => (amount, offset),
+        _ => unreachable!(),
+    };
+    let position = run
+        .iter()
+        .map(get_position)
+        .fold1(|pos1, pos2| pos1.combine(pos2))
+        .unwrap();
+
+    vec![SetRange {
+        amount,
+        offset,
+        length: run.len(),
+        position,
+    }]
+}
+
+/// Rules for combining a Set with an adjacent Set or Increment of
+/// the same cell.
+// TODO: Set, Write, Increment -> Set, Write, Set
+const COMBINE_SET_RULES: &[Rule] = &[
+    // Inc x, Set y -> Set y
+    Rule {
+        pattern: &[
+            Template::Increment {
+                amount: Any,
+                offset: Var("o"),
+            },
+            Template::Set {
+                amount: Var("y"),
+                offset: Var("o"),
+            },
+        ],
+        replacement: &[Template::Set {
+            amount: Var("y"),
+            offset: Var("o"),
+        }],
+    },
+    // Set x, Inc y -> Set x+y
+    Rule {
+        pattern: &[
+            Template::Set {
+                amount: Var("x"),
+                offset: Var("o"),
+            },
+            Template::Increment {
+                amount: Var("y"),
+                offset: Var("o"),
+            },
+        ],
+        replacement: &[Template::Set {
+            amount: Sum("x", "y"),
+            offset: Var("o"),
+        }],
+    },
+    // Set x, Set y -> Set y
+    Rule {
+        pattern: &[
+            Template::Set {
+                amount: Any,
+                offset: Var("o"),
+            },
+            Template::Set {
+                amount: Var("y"),
+                offset: Var("o"),
+            },
+        ],
+        replacement: &[Template::Set {
+            amount: Var("y"),
+            offset: Var("o"),
+        }],
+    },
+];
+
 /// Combine set instructions with other set instructions or
 /// increments.
-pub fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    // It's sufficient to consider immediately adjacent instructions
-    // as sort_sequence_by_offset ensures that if the offset is the
-    // same, the instruction is adjacent.
-    instrs
+pub fn combine_set_and_increments(mut instrs: Vec<AstNode>) -> Vec<AstNode> {
+    // Loop, ..., Inc y -> Loop, ..., Set y
+    // when the increment is on a cell we know is zero.
+    set_increments_of_zero_cells(&mut instrs);
+
+    // Set x, ..., Inc y -> Set x+y, ...
+    // provided nothing in between uses or modifies the cell.
+    let mut index = 0;
+    while index < instrs.len() {
+        if let Set { .. } = instrs[index] {
+            fold_later_increments(&mut instrs, index);
+        }
+        index += 1;
+    }
+
+    // Otherwise, it's sufficient to consider immediately adjacent
+    // instructions as sort_sequence_by_offset ensures that if the
+    // offset is the same, the instruction is adjacent.
+    apply_rules(instrs, COMBINE_SET_RULES)
         .into_iter()
-        .coalesce(|prev_instr, instr| {
-            // TODO: Set, Write, Increment -> Set, Write, Set
-            // Inc x, Set y -> Set y
-            if let (
-                &Increment {
-                    offset: inc_offset,
-                    position: inc_pos,
-                    ..
-                },
-                &Set {
-                    amount: set_amount,
-                    offset: set_offset,
-                    position: set_pos,
-                },
-            ) = (&prev_instr, &instr)
-            {
-                if inc_offset == set_offset {
-                    return Ok(Set {
-                        amount: set_amount,
-                        offset: set_offset,
-                        // Whilst the Inc is dead here, by including
-                        // it in the position tracking we can show better warnings.
-                        position: set_pos.combine(inc_pos),
-                    });
+        .map_loops(combine_set_and_increments)
+}
+
+/// Replace increments of cells that we know are zero with sets.
+///
+/// A cell is zero after a Set 0 or SetRange 0 on it, and the current
+/// cell is zero after a loop or a MultiplyMove. This means we don't
+/// need a Set 0 after every loop to combine with.
+fn set_increments_of_zero_cells(instrs: &mut [AstNode]) {
+    // Offsets relative to the cell pointer at the start of `instrs`.
+    let mut zero_cells = HashSet::new();
+    let mut cell_index = 0;
+
+    for instr in instrs.iter_mut() {
+        match *instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => {
+                if zero_cells.remove(&(cell_index + offset)) {
+                    *instr = Set {
+                        amount,
+                        offset,
+                        position,
+                    };
                 }
             }
-            Err((prev_instr, instr))
-        })
-        .coalesce(|prev_instr, instr| {
-            // Set x, Inc y -> Set x+y
-            if let Set {
-                amount: set_amount,
-                offset: set_offset,
-                position: set_pos,
-            } = prev_instr
-            {
-                if let Increment {
-                    amount: inc_amount,
-                    offset: inc_offset,
-                    position: inc_pos,
-                } = instr
-                {
-                    if inc_offset == set_offset {
-                        return Ok(Set {
-                            amount: set_amount + inc_amount,
-                            offset: set_offset,
-                            position: set_pos.combine(inc_pos),
-                        });
+            Set { amount, offset, .. } => {
+                if amount.0 == 0 {
+                    zero_cells.insert(cell_index + offset);
+                } else {
+                    zero_cells.remove(&(cell_index + offset));
+                }
+            }
+            SetRange {
+                amount,
+                offset,
+                length,
+                ..
+            } => {
+                for i in 0..length as isize {
+                    if amount.0 == 0 {
+                        zero_cells.insert(cell_index + offset + i);
+                    } else {
+                        zero_cells.remove(&(cell_index + offset + i));
                     }
                 }
             }
-            Err((prev_instr, instr))
-        })
-        .coalesce(|prev_instr, instr| {
-            // Set x, Set y -> Set y
-            if let (
-                &Set {
-                    offset: offset1,
-                    position: position1,
-                    ..
-                },
-                &Set {
-                    amount,
-                    offset: offset2,
-                    position: position2,
-                },
-            ) = (&prev_instr, &instr)
-            {
-                if offset1 == offset2 {
-                    return Ok(Set {
-                        amount,
-                        offset: offset1,
-                        // Whilst the first Set is dead here, by including
-                        // it in the position tracking we can show better warnings.
-                        position: position1.combine(position2),
-                    });
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                for offset in changes.keys() {
+                    zero_cells.remove(&(cell_index + offset));
                 }
+                zero_cells.insert(cell_index);
             }
-            Err((prev_instr, instr))
-        })
-        .map_loops(combine_set_and_increments)
+            Read { .. } | Load { .. } => {
+                zero_cells.remove(&cell_index);
+            }
+            Write { .. } | WriteConst { .. } | Breakpoint { .. } | Halt { .. } | Store { .. } => {}
+            Loop { .. } | If { .. } | Scan { .. } => {
+                // We don't know which cells the body modified, or
+                // where a scan stopped, but the current cell is zero
+                // when we exit.
+                zero_cells.clear();
+                zero_cells.insert(cell_index);
+            }
+        }
+    }
 }
 
 pub fn remove_redundant_sets(instrs: Vec<AstNode>) -> Vec<AstNode> {
@@ -626,7 +2820,7 @@ fn remove_redundant_sets_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
 
     for (index, instr) in instrs.iter().enumerate() {
         match *instr {
-            Loop { .. } | MultiplyMove { .. } => {
+            Loop { .. } | If { .. } | MultiplyMove { .. } | Scan { .. } => {
                 // There's no point setting to zero after a loop, as
                 // the cell is already zero.
                 if let Some(next_index) = next_cell_change(&instrs, index) {
@@ -683,15 +2877,22 @@ pub fn annotate_known_zero(instrs: Vec<AstNode>) -> Vec<AstNode> {
 fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut result = vec![];
 
-    for (i, instr) in instrs.iter().enumerate() {
-        let instr = instr.clone();
-
+    let mut instrs = instrs.into_iter().peekable();
+    while let Some(instr) = instrs.next() {
         match instr {
             // After a loop, we know the cell is currently zero.
-            Loop { body, position } => {
-                result.push(Loop {
-                    body: annotate_known_zero_inner(body),
-                    position,
+            Loop { .. } | If { .. } | Scan { .. } => {
+                let position = get_position(&instr);
+                result.push(match instr {
+                    Loop { body, position } => Loop {
+                        body: annotate_known_zero_inner(body),
+                        position,
+                    },
+                    If { body, position } => If {
+                        body: annotate_known_zero_inner(body),
+                        position,
+                    },
+                    other => other,
                 });
                 // Treat this set as positioned at the ].
                 let set_pos = position.map(|loop_pos| Position {
@@ -704,8 +2905,8 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     offset: 0,
                     position: set_pos,
                 };
-                if instrs.get(i + 1) != Some(&set_instr) {
-                    result.push(set_instr.clone());
+                if instrs.peek() != Some(&set_instr) {
+                    result.push(set_instr);
                 }
             }
             _ => {
@@ -717,16 +2918,48 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
     result
 }
 
+/// Is this loop free of side effects, and guaranteed to terminate
+/// regardless of the current cell value? E.g. `[->+<]` always
+/// terminates, but `[-->+<]` may not.
+fn is_pure_terminating_loop(body: &[AstNode]) -> bool {
+    let has_write = body
+        .iter()
+        .any(|instr| matches!(instr, Write { .. } | WriteConst { .. }));
+    if has_write {
+        return false;
+    }
+
+    // An odd step visits every cell value before wrapping around, so
+    // it always reaches zero.
+    match loop_body_step(body) {
+        Some(step) => step.0 % 2 != 0,
+        None => false,
+    }
+}
+
 /// Remove code at the end of the program that has no side
 /// effects. This means we have no write commands afterwards, nor
-/// loops (which may not terminate so we should not remove).
+/// loops that may not terminate (so removing them would change
+/// behaviour).
 pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>) {
     let mut pure_instrs = vec![];
     while !instrs.is_empty() {
         let last_instr = instrs.pop().unwrap();
 
         match last_instr {
-            Read { .. } | Write { .. } | Loop { .. } => {
+            Loop { ref body, .. } if is_pure_terminating_loop(body) => {
+                pure_instrs.push(last_instr);
+            }
+            // A scan may run past the end of the tape, like the
+            // loop it came from.
+            Read { .. }
+            | Write { .. }
+            | WriteConst { .. }
+            | Breakpoint { .. }
+            | Halt { .. }
+            | Loop { .. }
+            | If { .. }
+            | Scan { .. } => {
                 instrs.push(last_instr);
                 break;
             }
@@ -736,6 +2969,24 @@ pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warni
         }
     }
 
+    // Known value propagation can make earlier code pure, e.g. `+.`
+    // becomes a Set followed by a constant write. Only warn about
+    // instructions that come after everything we've kept, so we don't
+    // blame the user for code whose effect we've folded into a write.
+    let last_kept_end = instrs
+        .iter()
+        .filter_map(get_position)
+        .map(|pos| pos.end)
+        .max();
+    let pure_instrs: Vec<_> = pure_instrs
+        .into_iter()
+        .filter(|instr| match (last_kept_end, get_position(instr)) {
+            (Some(end), Some(pos)) => pos.start > end,
+            (Some(_), None) => false,
+            (None, _) => true,
+        })
+        .collect();
+
     let warning = if pure_instrs.is_empty() {
         None
     } else {
@@ -767,13 +3018,7 @@ fn is_multiply_loop_body(body: &[AstNode]) -> bool {
 
     // A multiply loop must have a net pointer movement of
     // zero.
-    let mut net_movement = 0;
-    for body_instr in body {
-        if let PointerIncrement { amount, .. } = *body_instr {
-            net_movement += amount;
-        }
-    }
-    if net_movement != 0 {
+    if net_pointer_movement(body) != Some(0) {
         return false;
     }
 
@@ -787,18 +3032,20 @@ fn is_multiply_loop_body(body: &[AstNode]) -> bool {
     changes.len() >= 2
 }
 
-/// Return a hashmap of all the cells that are affected by this
+/// Return a map of all the cells that are affected by this
 /// sequence of instructions, and how much they change.
 /// E.g. "->>+++>+" -> {0: -1, 2: 3, 3: 1}
-fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, Cell> {
-    let mut changes = HashMap::new();
+///
+/// Cells whose changes cancel out, such as cell #1 in ">+<>-<",
+/// aren't included.
+fn cell_changes(instrs: &[AstNode]) -> BTreeMap<isize, Cell> {
+    let mut changes = BTreeMap::new();
     let mut cell_index: isize = 0;
 
     for instr in instrs {
         match *instr {
             Increment { amount, offset, .. } => {
-                let current_amount = *changes.get(&(cell_index + offset)).unwrap_or(&Wrapping(0));
-                changes.insert(cell_index, current_amount + amount);
+                *changes.entry(cell_index + offset).or_insert(Wrapping(0)) += amount;
             }
             PointerIncrement { amount, .. } => {
                 cell_index += amount;
@@ -809,15 +3056,90 @@ fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, Cell> {
     }
 
     changes
+        .into_iter()
+        .filter(|&(_, amount)| amount.0 != 0)
+        .collect()
+}
+
+/// Are the increments of each cell in this loop body all in the
+/// same direction? If so, saturating or aborting on overflow gives
+/// the same result for the whole loop as for its total change.
+fn has_monotonic_increments(body: &[AstNode]) -> bool {
+    let mut increasing = HashMap::new();
+    let mut cell_index: isize = 0;
+
+    for instr in body {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let is_increase = amount.0 > 0;
+                if *increasing.entry(cell_index + offset).or_insert(is_increase) != is_increase {
+                    return false;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            _ => return false,
+        }
+    }
+    true
 }
 
 pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    extract_multiply_loops(instrs, false, false)
+}
+
+/// As `extract_multiply`, but only for loops with monotonic
+/// increments, so it's correct when cells don't wrap.
+pub fn extract_monotonic_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    extract_multiply_loops(instrs, true, false)
+}
+
+/// Does every cell this loop body visits lie between the cells it
+/// changes? If so, a MultiplyMove that checks the changed cells
+/// still aborts whenever the loop would have walked off the tape.
+/// E.g. "-<+<>>" visits cell #-2 but only changes #-1, so it doesn't.
+fn visits_only_changed_range(body: &[AstNode]) -> bool {
+    let changes = cell_changes(body);
+    // Multiply loops always change cell #0.
+    let lowest = *changes.keys().next().unwrap_or(&0);
+    let highest = *changes.keys().next_back().unwrap_or(&0);
+    let in_range = |index: isize| lowest <= index && index <= highest;
+
+    let mut cell_index: isize = 0;
+    for instr in body {
+        match *instr {
+            Increment { offset, .. } => {
+                if !in_range(cell_index + offset) {
+                    return false;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+                if !in_range(cell_index) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Replace multiply loops with MultiplyMove. If `monotonic`, only
+/// loops whose increments don't change direction are replaced. If
+/// `checked`, only loops that stay between the cells they change are
+/// replaced, so the pointer is still checked against the tape.
+fn extract_multiply_loops(instrs: Vec<AstNode>, monotonic: bool, checked: bool) -> Vec<AstNode> {
     instrs
         .into_iter()
         .map(|instr| {
             match instr {
                 Loop { body, position } => {
-                    if is_multiply_loop_body(&body) {
+                    if is_multiply_loop_body(&body)
+                        && (!monotonic || has_monotonic_increments(&body))
+                        && (!checked || visits_only_changed_range(&body))
+                    {
                         let mut changes = cell_changes(&body);
                         // MultiplyMove is for where we move to, so ignore
                         // the cell we're moving from.
@@ -826,13 +3148,144 @@ pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
                         MultiplyMove { changes, position }
                     } else {
                         Loop {
-                            body: extract_multiply(body),
+                            body: extract_multiply_loops(body, monotonic, checked),
                             position,
                         }
                     }
                 }
+                If { body, position } => If {
+                    body: extract_multiply_loops(body, monotonic, checked),
+                    position,
+                },
                 i => i,
             }
         })
         .collect()
 }
+
+/// Is the cell at `offset` from the cell pointer at `index` known
+/// to be zero before the instruction at `index` runs?
+fn cell_is_known_zero(instrs: &[AstNode], index: usize, offset: isize) -> bool {
+    cell_is_zero_before(instrs, index, offset, false)
+}
+
+/// As `cell_is_known_zero`, but `at_start` says whether the cell is
+/// zero if nothing in `instrs` changes it. That's true at the top
+/// level of the program, where the tape starts zeroed.
+fn cell_is_zero_before(instrs: &[AstNode], index: usize, offset: isize, at_start: bool) -> bool {
+    let mut needed_offset = offset;
+    for instr in instrs[..index].iter().rev() {
+        match *instr {
+            Set { amount, offset, .. } => {
+                if offset == needed_offset {
+                    return amount == Wrapping(0);
+                }
+            }
+            SetRange {
+                amount,
+                offset,
+                length,
+                ..
+            } => {
+                if in_range(offset, length, needed_offset) {
+                    return amount == Wrapping(0);
+                }
+            }
+            Increment { offset, .. } => {
+                if offset == needed_offset {
+                    return false;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                needed_offset += amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                // MultiplyMove always leaves the current cell at zero.
+                if needed_offset == 0 {
+                    return true;
+                }
+                if changes.contains_key(&needed_offset) {
+                    return false;
+                }
+            }
+            Read { .. } | Load { .. } => {
+                if needed_offset == 0 {
+                    return false;
+                }
+            }
+            Write { .. } | WriteConst { .. } | Breakpoint { .. } | Halt { .. } | Store { .. } => {}
+            // Loops only exit when the current cell is zero.
+            Loop { .. } | If { .. } | Scan { .. } => return needed_offset == 0,
+        }
+    }
+    at_start
+}
+
+/// Combine a multiply loop that moves into a cell known to be zero
+/// with a following multiply loop out of that cell, so we don't
+/// store the intermediate value. For example, if cell #1 is zero,
+/// `[->++<]>[->+++<]` becomes a single multiply of cell #0 into cell
+/// #2 by 6.
+///
+/// The second loop must not move back into the first loop's source,
+/// as MultiplyMove can't add to the cell it's moving from.
+pub fn combine_multiply_chains(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = vec![];
+
+    for instr in instrs {
+        if let MultiplyMove {
+            ref changes,
+            position,
+        } = instr
+        {
+            if let Some(combined) = chained_multiply_changes(&result, changes) {
+                let first_index = result.len() - 2;
+                let first_position = get_position(&result[first_index]);
+                result[first_index] = MultiplyMove {
+                    changes: combined,
+                    position: first_position.combine(position),
+                };
+                continue;
+            }
+        }
+        result.push(instr);
+    }
+
+    result.into_iter().map_loops(combine_multiply_chains)
+}
+
+/// If `instrs` ends with a MultiplyMove and a PointerIncrement that
+/// we can combine with a MultiplyMove of `second_changes`, return
+/// the changes of the combined MultiplyMove.
+fn chained_multiply_changes(
+    instrs: &[AstNode],
+    second_changes: &BTreeMap<isize, Cell>,
+) -> Option<BTreeMap<isize, Cell>> {
+    if instrs.len() < 2 {
+        return None;
+    }
+    let first_index = instrs.len() - 2;
+
+    let (first_changes, distance) = match (&instrs[first_index], &instrs[first_index + 1]) {
+        (MultiplyMove { changes, .. }, PointerIncrement { amount, .. }) => (changes, *amount),
+        _ => return None,
+    };
+    let factor = *first_changes.get(&distance)?;
+
+    if second_changes.contains_key(&-distance) || !cell_is_known_zero(instrs, first_index, distance)
+    {
+        return None;
+    }
+
+    let mut changes = first_changes.clone();
+    changes.remove(&distance);
+    for (offset, second_factor) in second_changes {
+        *changes.entry(offset + distance).or_insert(Wrapping(0)) += factor * *second_factor;
+    }
+    Some(
+        changes
+            .into_iter()
+            .filter(|&(_, factor)| factor != Wrapping(0))
+            .collect(),
+    )
+}
@@ -1,15 +1,17 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::num::Wrapping;
+use std::time::Instant;
 
 use pretty_assertions::assert_eq;
 use quickcheck::quickcheck;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, Position};
+use crate::bfir::{AstNode, Bounds, CellWidth, Eof, Overflow, Position};
 use crate::diagnostics::Warning;
 
-use crate::bfir::parse;
+use crate::bfir::{parse, parse_with_breakpoints};
 use crate::peephole::*;
+use crate::profile::Profile;
 use quickcheck::{Arbitrary, Gen, TestResult};
 
 impl Arbitrary for AstNode {
@@ -21,7 +23,7 @@ impl Arbitrary for AstNode {
 // We define a separate function so we can recurse on max_depth.
 // See https://github.com/BurntSushi/quickcheck/issues/23
 fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
-    let modulus = if max_depth == 0 { 8 } else { 9 };
+    let modulus = if max_depth == 0 { 14 } else { 15 };
 
     // If max_depth is zero, don't create loops.
     match g.next_u32() % modulus {
@@ -48,7 +50,7 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             position: Some(Position { start: 0, end: 0 }),
         },
         5 => {
-            let mut changes = HashMap::new();
+            let mut changes = BTreeMap::new();
             changes.insert(1, Wrapping(-1));
             MultiplyMove {
                 changes,
@@ -56,7 +58,7 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             }
         }
         6 => {
-            let mut changes = HashMap::new();
+            let mut changes = BTreeMap::new();
             changes.insert(1, Wrapping(2));
             changes.insert(4, Wrapping(10));
             MultiplyMove {
@@ -93,7 +95,30 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
                 position: None,
             }
         }
-        8 => {
+        8 => WriteConst {
+            values: Arbitrary::arbitrary(g),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        9 => SetRange {
+            amount: Wrapping(Arbitrary::arbitrary(g)),
+            offset: 0,
+            length: 1 + g.next_u32() as usize % 4,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        10 => Scan {
+            step: if g.next_u32() % 2 == 0 { 1 } else { -1 },
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        11 => Store {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        12 => Load {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        13 => Halt {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        14 => {
             assert!(max_depth > 0);
             let loop_length = g.next_u32() % 10;
             let mut body: Vec<_> = vec![];
@@ -147,6 +172,65 @@ fn combine_increments_remove_redundant() {
     assert_eq!(combine_increments(initial), vec![]);
 }
 
+#[test]
+fn combine_separated_increments_same_cell() {
+    let initial = parse("+>+<+").unwrap();
+    let expected = vec![
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 4, end: 4 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    assert_eq!(combine_separated_increments(initial), expected);
+}
+
+#[test]
+fn combine_separated_increments_not_across_write() {
+    let initial = parse("+.>+<+").unwrap();
+    let expected = initial.clone();
+    assert_eq!(combine_separated_increments(initial), expected);
+}
+
+#[test]
+fn combine_separated_increments_not_across_breakpoint() {
+    let initial = parse_with_breakpoints("+#+").unwrap();
+    let expected = initial.clone();
+    assert_eq!(combine_separated_increments(initial), expected);
+}
+
+#[test]
+fn combine_separated_increments_remove_redundant() {
+    let initial = parse("+>.<-").unwrap();
+    let expected = vec![
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Write {
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    assert_eq!(combine_separated_increments(initial), expected);
+}
+
 #[test]
 fn quickcheck_combine_increments_remove_zero_any_offset() {
     fn combine_increments_remove_zero_any_offset(offset: isize) -> bool {
@@ -223,7 +307,7 @@ fn should_combine_before_read() {
             position: Some(Position { start: 2, end: 2 }),
         },
     ];
-    assert_eq!(optimize(initial, &None).0, expected);
+    assert_eq!(optimize(initial, &OptimizeOptions::default()).0, expected);
 }
 
 #[test]
@@ -259,7 +343,7 @@ fn should_combine_before_read_nested() {
             position: Some(Position { start: 1, end: 4 }),
         },
     ];
-    assert_eq!(optimize(initial, &None).0, expected);
+    assert_eq!(optimize(initial, &OptimizeOptions::default()).0, expected);
 }
 
 #[test]
@@ -305,7 +389,7 @@ fn no_combine_before_read_after_write() {
 
 #[test]
 fn no_combine_before_read_after_multiply() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(-1));
     let initial = vec![
         MultiplyMove {
@@ -318,6 +402,59 @@ fn no_combine_before_read_after_multiply() {
     assert_eq!(remove_read_clobber(initial), expected);
 }
 
+#[test]
+fn should_warn_on_dead_read() {
+    let initial = parse(",,.").unwrap();
+    let (result, warnings) = remove_dead_reads(initial.clone(), false, Eof::MinusOne);
+    assert_eq!(result, initial);
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "This read is always overwritten before its value is used.".to_owned(),
+            position: Some(Position { start: 0, end: 0 }),
+        }]
+    );
+}
+
+#[test]
+fn read_keeps_cell_at_eof() {
+    // If the second read leaves the cell unchanged at EOF, we use the
+    // first read's value.
+    let initial = parse(",,.").unwrap();
+    let (result, warnings) = remove_dead_reads(initial.clone(), true, Eof::Unchanged);
+    assert_eq!(result, initial);
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn should_remove_dead_read_when_requested() {
+    let initial = vec![
+        Read { position: None },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: None,
+        },
+    ];
+    let expected = vec![initial[1].clone(), initial[2].clone()];
+    let (result, warnings) = remove_dead_reads(initial, true, Eof::MinusOne);
+    assert_eq!(result, expected);
+    assert_eq!(warnings.len(), 1);
+}
+
+#[test]
+fn should_not_warn_on_used_read() {
+    let initial = parse(",.,>,<+").unwrap();
+    let (result, warnings) = remove_dead_reads(initial.clone(), true, Eof::MinusOne);
+    assert_eq!(result, initial);
+    assert_eq!(warnings, vec![]);
+}
+
 #[test]
 fn simplify_zeroing_loop() {
     let initial = parse("[-]").unwrap();
@@ -380,7 +517,7 @@ fn remove_repeated_loops() {
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(optimize(initial, &None).0, expected);
+    assert_eq!(optimize(initial, &OptimizeOptions::default()).0, expected);
 }
 
 #[test]
@@ -465,482 +602,2026 @@ fn remove_dead_loops_not_adjacent() {
 }
 
 #[test]
-fn quickcheck_should_combine_set_and_increment() {
-    fn should_combine_set_and_increment(
-        offset: isize,
-        set_amount: i8,
-        increment_amount: i8,
-    ) -> bool {
-        let set_amount = Wrapping(set_amount);
-        let increment_amount = Wrapping(increment_amount);
-
-        let initial = vec![
-            Set {
-                amount: set_amount,
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Increment {
-                amount: increment_amount,
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = vec![Set {
-            amount: set_amount + increment_amount,
-            offset,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        combine_set_and_increments(initial) == expected
-    }
-    quickcheck(should_combine_set_and_increment as fn(isize, i8, i8) -> bool);
+fn remove_dead_loops_after_loop_nested() {
+    // The second inner loop can only be reached when the cell is zero.
+    let initial = parse("[[-][-]]").unwrap();
+    let expected = vec![Loop {
+        body: vec![Loop {
+            body: vec![Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            }],
+            position: Some(Position { start: 1, end: 3 }),
+        }],
+        position: Some(Position { start: 0, end: 7 }),
+    }];
+    assert_eq!(remove_dead_loops(initial), expected);
 }
 
-// TODO: rename our quickcheck property functions to something shorter.
 #[test]
-fn quickcheck_combine_set_and_increment_different_offsets() {
-    fn combine_set_and_increment_different_offsets(
-        set_offset: isize,
-        set_amount: i8,
-        inc_offset: isize,
-        inc_amount: i8,
-    ) -> TestResult {
-        if set_offset == inc_offset {
-            return TestResult::discard();
-        }
+fn remove_dead_loops_after_multiply() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(2));
+    let initial = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            position: None,
+        },
+        Loop {
+            body: vec![],
+            position: None,
+        },
+    ];
+    let expected = vec![MultiplyMove {
+        changes,
+        position: None,
+    }];
+    assert_eq!(remove_dead_loops(initial), expected);
+}
 
-        let initial = vec![
-            Set {
-                amount: Wrapping(set_amount),
-                offset: set_offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Increment {
-                amount: Wrapping(inc_amount),
-                offset: inc_offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = initial.clone();
+#[test]
+fn remove_dead_loops_after_set_range() {
+    let initial = vec![
+        SetRange {
+            amount: Wrapping(0),
+            offset: -1,
+            length: 4,
+            position: None,
+        },
+        Loop {
+            body: vec![],
+            position: None,
+        },
+    ];
+    let expected = vec![SetRange {
+        amount: Wrapping(0),
+        offset: -1,
+        length: 4,
+        position: None,
+    }];
+    assert_eq!(remove_dead_loops(initial), expected);
+}
 
-        TestResult::from_bool(combine_set_and_increments(initial) == expected)
-    }
-    quickcheck(
-        combine_set_and_increment_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
-    );
+#[test]
+fn remove_initial_zeroing_loops() {
+    let initial = parse("[-]>[-]+.").unwrap();
+    let expected = vec![initial[1].clone(), initial[3].clone(), initial[4].clone()];
+    assert_eq!(remove_initial_zeroing(initial), expected);
 }
 
 #[test]
-fn quickcheck_combine_increment_and_set_different_offsets() {
-    fn combine_increment_and_set_different_offsets(
-        set_offset: isize,
-        set_amount: i8,
-        inc_offset: isize,
-        inc_amount: i8,
-    ) -> TestResult {
-        if set_offset == inc_offset {
-            return TestResult::discard();
-        }
+fn remove_initial_zeroing_sets() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 1,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+    ];
+    let expected = vec![Increment {
+        amount: Wrapping(1),
+        offset: 0,
+        position: None,
+    }];
+    assert_eq!(remove_initial_zeroing(initial), expected);
+}
 
-        let initial = vec![
-            Increment {
-                amount: Wrapping(inc_amount),
-                offset: inc_offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Set {
-                amount: Wrapping(set_amount),
-                offset: set_offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = initial.clone();
+#[test]
+fn remove_initial_zeroing_stops_at_live_loop() {
+    // After the read, the second loop may run, and it may modify
+    // the cell that the third loop tests.
+    let initial = parse(",[>+<-]>[-]").unwrap();
+    assert_eq!(remove_initial_zeroing(initial.clone()), initial);
+}
 
-        TestResult::from_bool(combine_set_and_increments(initial) == expected)
-    }
-    quickcheck(
-        combine_increment_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
-    );
+#[test]
+fn remove_initial_zeroing_after_read() {
+    let initial = parse(",>[-]<.").unwrap();
+    let expected = vec![
+        initial[0].clone(),
+        initial[1].clone(),
+        initial[3].clone(),
+        initial[4].clone(),
+    ];
+    assert_eq!(remove_initial_zeroing(initial), expected);
 }
 
 #[test]
-fn quickcheck_combine_set_and_set() {
-    fn combine_set_and_set(offset: isize, set_amount_before: i8, set_amount_after: i8) -> bool {
-        let initial = vec![
-            Set {
-                amount: Wrapping(set_amount_before),
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Set {
-                amount: Wrapping(set_amount_after),
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = vec![Set {
-            amount: Wrapping(set_amount_after),
-            offset,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        combine_set_and_increments(initial) == expected
-    }
-    quickcheck(combine_set_and_set as fn(isize, i8, i8) -> bool);
+fn should_not_remove_loops_after_unrelated_loop() {
+    // `[>]` leaves the current cell at zero, but `>` moves to a cell
+    // we know nothing about.
+    let initial = parse("[>]>[-]").unwrap();
+    assert_eq!(remove_dead_loops(initial.clone()), initial);
 }
 
 #[test]
-fn quickcheck_combine_set_and_set_different_offsets() {
-    fn combine_set_and_set_different_offsets(
-        offset1: isize,
-        amount1: i8,
-        offset2: isize,
-        amount2: i8,
-    ) -> TestResult {
-        if offset1 == offset2 {
-            return TestResult::discard();
-        }
-
-        let initial = vec![
-            Set {
-                amount: Wrapping(amount1),
-                offset: offset1,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Set {
-                amount: Wrapping(amount2),
-                offset: offset2,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = initial.clone();
+fn should_remove_code_after_infinite_loop() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![Write {
+                position: Some(Position { start: 2, end: 2 }),
+            }],
+            position: Some(Position { start: 1, end: 3 }),
+        },
+        Write {
+            position: Some(Position { start: 4, end: 4 }),
+        },
+    ];
+    let expected = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![Write {
+                position: Some(Position { start: 2, end: 2 }),
+            }],
+            position: Some(Position { start: 1, end: 3 }),
+        },
+    ];
 
-        TestResult::from_bool(combine_set_and_increments(initial) == expected)
-    }
-    quickcheck(combine_set_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult);
+    let (result, warnings) = remove_code_after_infinite_loops(initial);
+    assert_eq!(result, expected);
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "This loop never terminates, so the instructions after it \
+                      are never executed."
+                .to_owned(),
+            position: Some(Position { start: 1, end: 3 }),
+        }]
+    );
 }
 
 #[test]
-fn should_combine_set_and_set_nested() {
+fn should_remove_code_after_nested_infinite_loop() {
     let initial = vec![Loop {
         body: vec![
             Set {
-                amount: Wrapping(0),
+                amount: Wrapping(2),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
-            Set {
-                amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+            Loop {
+                body: vec![],
+                position: None,
+            },
+            PointerIncrement {
+                amount: 1,
+                position: None,
             },
         ],
-        position: Some(Position { start: 0, end: 0 }),
+        position: None,
     }];
     let expected = vec![Loop {
-        body: vec![Set {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        }],
-        position: Some(Position { start: 0, end: 0 }),
+        body: vec![
+            Set {
+                amount: Wrapping(2),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![],
+                position: None,
+            },
+        ],
+        position: None,
     }];
-    assert_eq!(combine_set_and_increments(initial), expected);
+
+    let (result, warnings) = remove_code_after_infinite_loops(initial);
+    assert_eq!(result, expected);
+    assert_eq!(warnings.len(), 1);
 }
 
 #[test]
-fn quickcheck_should_combine_increment_and_set() {
-    fn should_combine_increment_and_set(offset: isize) -> bool {
-        let initial = vec![
-            Increment {
-                amount: Wrapping(2),
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Set {
-                amount: Wrapping(3),
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = vec![Set {
-            amount: Wrapping(3),
-            offset,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        combine_set_and_increments(initial) == expected
-    }
-    quickcheck(should_combine_increment_and_set as fn(isize) -> bool);
+fn dont_remove_code_after_loop_that_may_not_run() {
+    // The loop is either skipped or runs forever, so we can't remove
+    // the write.
+    let initial = parse(",[].").unwrap();
+    let (result, warnings) = remove_code_after_infinite_loops(initial.clone());
+    assert_eq!(result, initial);
+    assert_eq!(warnings, vec![]);
 }
 
 #[test]
-fn should_remove_redundant_set() {
+fn dont_remove_code_after_terminating_loop() {
     let initial = vec![
-        Loop {
-            body: vec![],
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Set {
-            amount: Wrapping(0),
-            offset: -1,
-            position: Some(Position { start: 0, end: 0 }),
-        },
         Set {
-            amount: Wrapping(0),
+            amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: None,
         },
-    ];
-    let expected = vec![
         Loop {
-            body: vec![],
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Set {
-            amount: Wrapping(0),
-            offset: -1,
-            position: Some(Position { start: 0, end: 0 }),
+            body: parse(">+<-").unwrap(),
+            position: None,
         },
+        Write { position: None },
     ];
-    assert_eq!(remove_redundant_sets(initial), expected);
+    let (result, _) = remove_code_after_infinite_loops(initial.clone());
+    assert_eq!(result, initial);
 }
 
 #[test]
-fn should_remove_redundant_set_multiply() {
-    let mut changes = HashMap::new();
-    changes.insert(1, Wrapping(1));
-
-    let initial = vec![
-        MultiplyMove {
-            changes: changes.clone(),
-            position: Some(Position { start: 0, end: 0 }),
+fn should_unroll_known_trip_count() {
+    let body = vec![
+        Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: Some(Position { start: 2, end: 2 }),
         },
+    ];
+    let initial = vec![
         Set {
-            amount: Wrapping(0),
+            amount: Wrapping(3),
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
+        Loop {
+            body: body.clone(),
+            position: Some(Position { start: 1, end: 3 }),
+        },
     ];
-    let expected = vec![MultiplyMove {
-        changes,
+
+    let mut expected = vec![Set {
+        amount: Wrapping(3),
+        offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
-    assert_eq!(remove_redundant_sets(initial), expected);
+    for _ in 0..3 {
+        expected.extend(body.clone());
+    }
+
+    assert_eq!(unroll_loops(initial, 100, None), expected);
 }
 
-/// After a loop, if we set to a value other than zero, we shouldn't
-/// remove it.
 #[test]
-fn not_redundant_set_when_nonzero() {
-    let instrs = vec![
+fn should_unroll_wrapping_trip_count() {
+    // Incrementing from 254 reaches zero after two iterations.
+    let initial = vec![
+        Set {
+            amount: Wrapping(-2),
+            offset: 0,
+            position: None,
+        },
         Loop {
-            body: vec![],
-            position: Some(Position { start: 0, end: 0 }),
+            body: vec![Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            }],
+            position: None,
         },
+    ];
+    let expected = vec![
         Set {
+            amount: Wrapping(-2),
+            offset: 0,
+            position: None,
+        },
+        Increment {
             amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
         },
     ];
-    assert_eq!(remove_redundant_sets(instrs.clone()), instrs);
-}
-
-fn is_pure(instrs: &[AstNode]) -> bool {
-    for instr in instrs {
-        match *instr {
-            Loop { .. } => {
-                return false;
-            }
-            Read { .. } => {
-                return false;
-            }
-            Write { .. } => {
-                return false;
-            }
-            _ => (),
-        }
-    }
-    true
+    assert_eq!(unroll_loops(initial, 100, None), expected);
 }
 
 #[test]
-fn quickcheck_should_annotate_known_zero_at_start() {
-    fn should_annotate_known_zero_at_start(instrs: Vec<AstNode>) -> bool {
-        let annotated = annotate_known_zero(instrs);
-        matches!(annotated[0], Set { amount: Wrapping(0), offset: 0, .. })
-    }
-    quickcheck(should_annotate_known_zero_at_start as fn(Vec<AstNode>) -> bool);
+fn dont_unroll_unknown_trip_count() {
+    let initial = parse(",[->+<]").unwrap();
+    assert_eq!(unroll_loops(initial.clone(), 100, None), initial);
 }
 
 #[test]
-fn annotate_known_zero_idempotent() {
-    fn is_idempotent(instrs: Vec<AstNode>) -> bool {
-        let annotated = annotate_known_zero(instrs);
-        let annotated_again = annotate_known_zero(annotated.clone());
-        if annotated == annotated_again {
-            true
-        } else {
-            println!("intermediate: {:?}", annotated);
-            println!("final: {:?}", annotated_again);
-            false
-        }
-    }
-    quickcheck(is_idempotent as fn(Vec<AstNode>) -> bool);
+fn dont_unroll_loop_with_net_movement() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: None,
+        },
+        Loop {
+            body: parse("->").unwrap(),
+            position: None,
+        },
+    ];
+    assert_eq!(unroll_loops(initial.clone(), 100, None), initial);
 }
 
 #[test]
-fn should_annotate_known_zero() {
-    let initial = parse("+[]").unwrap();
-    let expected = vec![
+fn dont_unroll_beyond_size_limit() {
+    let initial = vec![
         Set {
-            amount: Wrapping(0),
+            amount: Wrapping(10),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: None,
         },
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
+        Loop {
+            body: parse("->+<").unwrap(),
+            position: None,
+        },
+    ];
+    assert_eq!(unroll_loops(initial.clone(), 39, None), initial);
+}
+
+#[test]
+fn dont_unroll_cold_loops() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: parse("->+<").unwrap(),
+            position: Some(Position { start: 1, end: 6 }),
+        },
+    ];
+    let profile = Profile::parse("1 0 0\n").unwrap();
+    assert_eq!(unroll_loops(initial.clone(), 100, Some(&profile)), initial);
+}
+
+/// "Set `counter`, [body]", with positions from parsing `body`.
+#[cfg(test)]
+fn counter_loop(counter: i32, body: &str) -> Vec<AstNode> {
+    let mut instrs = vec![Set {
+        amount: Wrapping(counter),
+        offset: 0,
+        position: None,
+    }];
+    instrs.extend(parse(body).unwrap());
+    instrs
+}
+
+#[test]
+fn should_inline_counter_loops() {
+    let initial = counter_loop(2, "[->+.<]");
+    let body = match initial[1] {
+        Loop { ref body, .. } => body.clone(),
+        _ => unreachable!(),
+    };
+
+    let mut expected = vec![initial[0].clone()];
+    expected.extend(body.clone());
+    expected.extend(body);
+
+    assert_eq!(inline_counter_loops(initial), expected);
+}
+
+#[test]
+fn dont_inline_counter_loops_with_many_iterations() {
+    let initial = counter_loop(10, "[->+.<]");
+    assert_eq!(inline_counter_loops(initial.clone()), initial);
+}
+
+#[test]
+fn dont_inline_counter_loops_set_earlier() {
+    // unroll_loops handles this, but it's not a counter loop.
+    let mut initial = counter_loop(2, "[->+.<]");
+    initial.insert(
+        1,
+        WriteConst {
+            values: vec![1],
+            position: None,
+        },
+    );
+    assert_eq!(inline_counter_loops(initial.clone()), initial);
+}
+
+/// "Set 2, [first], Set 2, [second]", with positions from parsing
+/// `first` and `second`.
+#[cfg(test)]
+fn set_and_loops(first: &str, second: &str) -> Vec<AstNode> {
+    let set = Set {
+        amount: Wrapping(2),
+        offset: 0,
+        position: None,
+    };
+    let mut instrs = vec![set.clone()];
+    instrs.extend(parse(first).unwrap());
+    instrs.push(set);
+    instrs.extend(parse(second).unwrap());
+    instrs
+}
+
+#[test]
+fn should_fuse_loops() {
+    let initial = set_and_loops("[>+.<-]", "[>>+<<-]");
+
+    // The fused loop runs the first body, then the second body
+    // without its decrement.
+    let mut body = match initial[1] {
+        Loop { ref body, .. } => body.clone(),
+        _ => unreachable!(),
+    };
+    match initial[3] {
+        Loop {
+            body: ref second_body,
+            ..
+        } => body.extend_from_slice(&second_body[..5]),
+        _ => unreachable!(),
+    }
+    let expected = vec![
+        initial[0].clone(),
+        Loop {
+            body,
+            position: Some(Position { start: 0, end: 6 }),
+        },
+    ];
+
+    assert_eq!(fuse_loops(initial), expected);
+}
+
+#[test]
+fn dont_fuse_loops_touching_same_cell() {
+    // The second loop reads the cell the first loop modifies.
+    let initial = set_and_loops("[>+<-]", "[>.<-]");
+    assert_eq!(fuse_loops(initial.clone()), initial);
+}
+
+#[test]
+fn dont_fuse_loops_that_both_write() {
+    let initial = set_and_loops("[>.<-]", "[>>.<<-]");
+    assert_eq!(fuse_loops(initial.clone()), initial);
+}
+
+#[test]
+fn dont_fuse_loops_with_different_steps() {
+    let initial = set_and_loops("[>+<-]", "[>>+<<--]");
+    assert_eq!(fuse_loops(initial.clone()), initial);
+}
+
+#[test]
+fn quickcheck_should_combine_set_and_increment() {
+    fn should_combine_set_and_increment(
+        offset: isize,
+        set_amount: i32,
+        increment_amount: i32,
+    ) -> bool {
+        let set_amount = Wrapping(set_amount);
+        let increment_amount = Wrapping(increment_amount);
+
+        let initial = vec![
+            Set {
+                amount: set_amount,
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: increment_amount,
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![Set {
+            amount: set_amount + increment_amount,
+            offset,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        combine_set_and_increments(initial) == expected
+    }
+    quickcheck(should_combine_set_and_increment as fn(isize, i32, i32) -> bool);
+}
+
+// TODO: rename our quickcheck property functions to something shorter.
+#[test]
+fn quickcheck_combine_set_and_increment_different_offsets() {
+    fn combine_set_and_increment_different_offsets(
+        set_offset: isize,
+        set_amount: i32,
+        inc_offset: isize,
+        inc_amount: i32,
+    ) -> TestResult {
+        if set_offset == inc_offset {
+            return TestResult::discard();
+        }
+
+        let initial = vec![
+            Set {
+                amount: Wrapping(set_amount),
+                offset: set_offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(inc_amount),
+                offset: inc_offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = initial.clone();
+
+        TestResult::from_bool(combine_set_and_increments(initial) == expected)
+    }
+    quickcheck(
+        combine_set_and_increment_different_offsets as fn(isize, i32, isize, i32) -> TestResult,
+    );
+}
+
+#[test]
+fn quickcheck_combine_increment_and_set_different_offsets() {
+    fn combine_increment_and_set_different_offsets(
+        set_offset: isize,
+        set_amount: i32,
+        inc_offset: isize,
+        inc_amount: i32,
+    ) -> TestResult {
+        if set_offset == inc_offset {
+            return TestResult::discard();
+        }
+
+        let initial = vec![
+            Increment {
+                amount: Wrapping(inc_amount),
+                offset: inc_offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(set_amount),
+                offset: set_offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = initial.clone();
+
+        TestResult::from_bool(combine_set_and_increments(initial) == expected)
+    }
+    quickcheck(
+        combine_increment_and_set_different_offsets as fn(isize, i32, isize, i32) -> TestResult,
+    );
+}
+
+#[test]
+fn quickcheck_combine_set_and_set() {
+    fn combine_set_and_set(offset: isize, set_amount_before: i32, set_amount_after: i32) -> bool {
+        let initial = vec![
+            Set {
+                amount: Wrapping(set_amount_before),
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(set_amount_after),
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![Set {
+            amount: Wrapping(set_amount_after),
+            offset,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        combine_set_and_increments(initial) == expected
+    }
+    quickcheck(combine_set_and_set as fn(isize, i32, i32) -> bool);
+}
+
+#[test]
+fn quickcheck_combine_set_and_set_different_offsets() {
+    fn combine_set_and_set_different_offsets(
+        offset1: isize,
+        amount1: i32,
+        offset2: isize,
+        amount2: i32,
+    ) -> TestResult {
+        if offset1 == offset2 {
+            return TestResult::discard();
+        }
+
+        let initial = vec![
+            Set {
+                amount: Wrapping(amount1),
+                offset: offset1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(amount2),
+                offset: offset2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = initial.clone();
+
+        TestResult::from_bool(combine_set_and_increments(initial) == expected)
+    }
+    quickcheck(combine_set_and_set_different_offsets as fn(isize, i32, isize, i32) -> TestResult);
+}
+
+#[test]
+fn should_combine_set_and_set_nested() {
+    let initial = vec![Loop {
+        body: vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ],
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let expected = vec![Loop {
+        body: vec![Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }],
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    assert_eq!(combine_set_and_increments(initial), expected);
+}
+
+#[test]
+fn quickcheck_should_combine_increment_and_set() {
+    fn should_combine_increment_and_set(offset: isize) -> bool {
+        let initial = vec![
+            Increment {
+                amount: Wrapping(2),
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(3),
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![Set {
+            amount: Wrapping(3),
+            offset,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        combine_set_and_increments(initial) == expected
+    }
+    quickcheck(should_combine_increment_and_set as fn(isize) -> bool);
+}
+
+#[test]
+fn should_remove_redundant_set() {
+    let initial = vec![
+        Loop {
+            body: vec![],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    let expected = vec![
+        Loop {
+            body: vec![],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(remove_redundant_sets(initial), expected);
+}
+
+#[test]
+fn should_remove_redundant_set_multiply() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(1));
+
+    let initial = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    let expected = vec![MultiplyMove {
+        changes,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    assert_eq!(remove_redundant_sets(initial), expected);
+}
+
+/// After a loop, if we set to a value other than zero, we shouldn't
+/// remove it.
+#[test]
+fn not_redundant_set_when_nonzero() {
+    let instrs = vec![
+        Loop {
+            body: vec![],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(remove_redundant_sets(instrs.clone()), instrs);
+}
+
+fn is_pure(instrs: &[AstNode]) -> bool {
+    for instr in instrs {
+        match *instr {
+            Loop { .. } | Scan { .. } => {
+                return false;
+            }
+            Read { .. } => {
+                return false;
+            }
+            Write { .. } | WriteConst { .. } | Halt { .. } => {
+                return false;
+            }
+            _ => (),
+        }
+    }
+    true
+}
+
+#[test]
+fn quickcheck_should_annotate_known_zero_at_start() {
+    fn should_annotate_known_zero_at_start(instrs: Vec<AstNode>) -> bool {
+        let annotated = annotate_known_zero(instrs);
+        matches!(annotated[0], Set { amount: Wrapping(0), offset: 0, .. })
+    }
+    quickcheck(should_annotate_known_zero_at_start as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn annotate_known_zero_idempotent() {
+    fn is_idempotent(instrs: Vec<AstNode>) -> bool {
+        let annotated = annotate_known_zero(instrs);
+        let annotated_again = annotate_known_zero(annotated.clone());
+        if annotated == annotated_again {
+            true
+        } else {
+            println!("intermediate: {:?}", annotated);
+            println!("final: {:?}", annotated_again);
+            false
+        }
+    }
+    quickcheck(is_idempotent as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn should_annotate_known_zero() {
+    let initial = parse("+[]").unwrap();
+    let expected = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![],
+            position: Some(Position { start: 1, end: 2 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    assert_eq!(annotate_known_zero(initial), expected);
+}
+
+#[test]
+fn should_annotate_known_zero_nested() {
+    let initial = parse("[[]]").unwrap();
+    let expected = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![
+                Loop {
+                    body: vec![],
+                    position: Some(Position { start: 1, end: 2 }),
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+            ],
+            position: Some(Position { start: 0, end: 3 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    assert_eq!(annotate_known_zero(initial), expected);
+}
+
+/// When we annotate known zeroes, we have new opportunities for
+/// combining instructions and loop removal. However, we should later
+/// remove the Set 0 if we haven't combined it.
+#[test]
+fn should_annotate_known_zero_cleaned_up() {
+    let initial = vec![Write {
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    // We know the cell is zero, so the write becomes a WriteConst.
+    let expected = vec![WriteConst {
+        values: vec![0],
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    assert_eq!(optimize(initial, &OptimizeOptions::default()).0, expected);
+}
+
+#[test]
+fn should_preserve_set_0_in_loop() {
+    // Regression test.
+    let initial = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    // The loop body zeroes the cell, so it becomes an If.
+    let expected = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        If {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(optimize(initial, &OptimizeOptions::default()).0, expected);
+}
+
+#[test]
+fn should_remove_pure_code() {
+    // The final increment here is side-effect free and can be
+    // removed. Once the write is constant, so is the first
+    // increment.
+    let initial = parse("+.+").unwrap();
+    let expected = vec![WriteConst {
+        values: vec![1],
+        position: Some(Position { start: 1, end: 1 }),
+    }];
+
+    let (result, warnings) = optimize(initial, &OptimizeOptions::default());
+
+    assert_eq!(result, expected);
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "These instructions have no effect.".to_owned(),
+            position: Some(Position { start: 2, end: 2 }),
+        }]
+    );
+}
+
+#[test]
+fn should_not_warn_about_code_folded_into_writes() {
+    // Once the writes are constant, `>+` is removed because it's no
+    // longer needed, but it did contribute to the output.
+    let initial = parse("+.>+.").unwrap();
+    let (_, warnings) = optimize(initial, &OptimizeOptions::default());
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn should_remove_terminating_loops_at_end() {
+    let initial = vec![
+        Write { position: None },
+        Loop {
+            body: vec![
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: None,
+                },
+                PointerIncrement {
+                    amount: 1,
+                    position: None,
+                },
+                Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: None,
+                },
+                PointerIncrement {
+                    amount: -1,
+                    position: None,
+                },
+            ],
+            position: Some(Position { start: 1, end: 6 }),
+        },
+    ];
+    let expected = vec![Write { position: None }];
+
+    let (result, warning) = remove_pure_code(initial);
+    assert_eq!(result, expected);
+    assert_eq!(
+        warning,
+        Some(Warning {
+            message: "These instructions have no effect.".to_owned(),
+            position: Some(Position { start: 1, end: 6 }),
+        })
+    );
+}
+
+#[test]
+fn should_not_remove_loops_that_may_not_terminate_at_end() {
+    // [--] never terminates if the cell is odd.
+    let initial = vec![
+        Read { position: None },
+        Loop {
+            body: vec![Increment {
+                amount: Wrapping(-2),
+                offset: 0,
+                position: None,
+            }],
+            position: None,
+        },
+    ];
+    assert_eq!(remove_pure_code(initial.clone()), (initial, None));
+}
+
+#[test]
+fn should_not_remove_breakpoint_at_end() {
+    let initial = parse_with_breakpoints("+#").unwrap();
+    assert_eq!(remove_pure_code(initial.clone()), (initial, None));
+}
+
+#[test]
+fn should_not_remove_loops_with_writes_at_end() {
+    let initial = vec![Loop {
+        body: vec![
+            Write { position: None },
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: None,
+            },
+        ],
+        position: None,
+    }];
+    assert_eq!(remove_pure_code(initial.clone()), (initial, None));
+}
+
+#[test]
+fn quickcheck_should_remove_dead_pure_code() {
+    fn should_remove_dead_pure_code(instrs: Vec<AstNode>) -> TestResult {
+        if !is_pure(&instrs) {
+            return TestResult::discard();
+        }
+        TestResult::from_bool(optimize(instrs, &OptimizeOptions::default()).0 == vec![])
+    }
+    quickcheck(should_remove_dead_pure_code as fn(Vec<AstNode>) -> TestResult);
+}
+
+#[test]
+fn quickcheck_optimize_should_be_idempotent() {
+    fn optimize_should_be_idempotent(instrs: Vec<AstNode>) -> bool {
+        // Once we've optimized once, running again shouldn't reduce the
+        // instructions further. If it does, we're probably running our
+        // optimisations in the wrong order.
+        let minimal = optimize(instrs, &OptimizeOptions::default()).0;
+        optimize(minimal.clone(), &OptimizeOptions::default()).0 == minimal
+    }
+    quickcheck(optimize_should_be_idempotent as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn pathological_optimisation_opportunity() {
+    let instrs = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Write {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+
+    let expected = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Write {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+
+    assert_eq!(optimize(instrs, &OptimizeOptions::default()).0, expected);
+}
+
+fn count_instrs(instrs: &[AstNode]) -> u64 {
+    let mut count = 0;
+    for instr in instrs {
+        if let Loop { ref body, .. } = *instr {
+            count += count_instrs(body);
+        }
+        count += 1;
+    }
+    count
+}
+
+#[test]
+fn quickcheck_optimize_should_decrease_size() {
+    fn optimize_should_decrease_size(instrs: Vec<AstNode>) -> bool {
+        // The result of optimize() should never increase the number of
+        // instructions.
+        let result = optimize(instrs.clone(), &OptimizeOptions::default()).0;
+        count_instrs(&result) <= count_instrs(&instrs)
+    }
+    quickcheck(optimize_should_decrease_size as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn should_extract_multiply_simple() {
+    let instrs = parse("[->+++<]").unwrap();
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(3));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: Some(Position { start: 0, end: 7 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_multiply_nested() {
+    let instrs = parse("[[->+<]]").unwrap();
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(1));
+    let expected = vec![Loop {
+        body: vec![MultiplyMove {
+            changes: dest_cells,
+            position: Some(Position { start: 1, end: 6 }),
+        }],
+        position: Some(Position { start: 0, end: 7 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_multiply_negative_number() {
+    let instrs = parse("[->--<]").unwrap();
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(-2));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: Some(Position { start: 0, end: 6 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_multiply_multiple_cells() {
+    let instrs = parse("[->+++>>>+<<<<]").unwrap();
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(3));
+    dest_cells.insert(4, Wrapping(1));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: Some(Position { start: 0, end: 14 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_multiply_mixed_signs_interleaved() {
+    let instrs = parse("[>+<->>-<<]").unwrap();
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(1));
+    dest_cells.insert(2, Wrapping(-1));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: Some(Position { start: 0, end: 10 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_multiply_with_offsets() {
+    // This is "[->+>+<<]" after sort_by_offset.
+    let instrs = vec![Loop {
+        body: vec![
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 2,
+                position: None,
+            },
+        ],
+        position: None,
+    }];
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(1));
+    dest_cells.insert(2, Wrapping(1));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: None,
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_multiply_ignoring_cancelled_changes() {
+    let instrs = parse("[->+>+<-<]").unwrap();
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(2, Wrapping(1));
+    let expected = vec![MultiplyMove {
+        changes: dest_cells,
+        position: Some(Position { start: 0, end: 9 }),
+    }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_not_extract_multiply_net_movement() {
+    let instrs = parse("[->+++<<]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_not_extract_multiply_from_clear_loop() {
+    let instrs = parse("[-]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_not_extract_multiply_with_inner_loop() {
+    let instrs = parse("[->+++<[]]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+/// We need to decrement the initial cell in order for this to be a
+/// multiply.
+#[test]
+fn should_not_extract_multiply_without_decrement() {
+    let instrs = parse("[+>++<]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_not_extract_multiply_with_read() {
+    let instrs = parse("[+>++<,]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_not_extract_multiply_with_write() {
+    let instrs = parse("[+>++<.]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_combine_multiply_chain() {
+    let mut instrs = vec![Set {
+        amount: Wrapping(0),
+        offset: 1,
+        position: None,
+    }];
+    instrs.extend(extract_multiply(parse("[->++<]>[->+++<]").unwrap()));
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(2, Wrapping(6));
+    let expected = vec![
+        instrs[0].clone(),
+        MultiplyMove {
+            changes: dest_cells,
+            position: Some(Position { start: 8, end: 15 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 7, end: 7 }),
+        },
+    ];
+
+    assert_eq!(combine_multiply_chains(instrs), expected);
+}
+
+/// If the intermediate cell might not be zero, its old value is also
+/// multiplied by the second loop.
+#[test]
+fn should_not_combine_multiply_chain_without_known_zero() {
+    let instrs = extract_multiply(parse("[->++<]>[->+++<]").unwrap());
+    assert_eq!(combine_multiply_chains(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_not_combine_multiply_chain_into_source() {
+    let mut instrs = vec![Set {
+        amount: Wrapping(0),
+        offset: 1,
+        position: None,
+    }];
+    instrs.extend(extract_multiply(parse("[->++<]>[-<++++>]").unwrap()));
+    assert_eq!(combine_multiply_chains(instrs.clone()), instrs);
+}
+
+#[test]
+fn sort_by_offset_increment() {
+    let instrs = parse("+>+>").unwrap();
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn sort_by_offset_increment_nested() {
+    let instrs = parse("[+>+>]").unwrap();
+    let expected = vec![Loop {
+        body: (vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            PointerIncrement {
+                amount: 2,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+        ]),
+        position: Some(Position { start: 0, end: 5 }),
+    }];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn sort_by_offset_remove_redundant() {
+    let initial = parse("><").unwrap();
+    assert_eq!(sort_by_offset(initial), vec![]);
+}
+
+// If there's a read instruction, we should only combine before and
+// after.
+#[test]
+fn sort_by_offset_read() {
+    let instrs = parse(">>,>>").unwrap();
+    let expected = vec![
+        PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Read {
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 4, end: 4 }),
+        },
+    ];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn sort_by_offset_past_write_const() {
+    let instrs = vec![
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        WriteConst {
+            values: vec![65],
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let expected = vec![
+        WriteConst {
+            values: vec![65],
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+// SetRange may overlap other cells, so we keep the order of
+// instructions around it, but still use offsets.
+#[test]
+fn sort_by_offset_past_set_range() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 2,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        SetRange {
+            amount: Wrapping(0),
+            offset: 0,
+            length: 4,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 2,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        SetRange {
+            amount: Wrapping(0),
+            offset: 1,
+            length: 4,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+    ];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
+#[test]
+fn quickcheck_sort_by_offset_set() {
+    fn sort_by_offset_set(amount1: i32, amount2: i32) -> bool {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(amount1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(amount2),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let expected = vec![
+            Set {
+                amount: Wrapping(amount2),
+                offset: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(amount1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        sort_by_offset(instrs) == expected
+    }
+    quickcheck(sort_by_offset_set as fn(i32, i32) -> bool);
+}
+
+#[test]
+fn quickcheck_sort_by_offset_pointer_increments() {
+    fn sort_by_offset_pointer_increments(amount1: isize, amount2: isize) -> TestResult {
+        // Although in principle our optimisations would work outside
+        // the tape, we restrict the range to avoid overflow.
+        if amount1 < -30000 || amount1 > 30000 || amount2 < -30000 || amount2 > 30000 {
+            return TestResult::discard();
+        }
+        // We should discard the pointer increment if the two cancel out,
+        // but we don't test that here.
+        if amount1 + amount2 == 0 {
+            return TestResult::discard();
+        }
+
+        let instrs = vec![
+            PointerIncrement {
+                amount: amount1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: amount2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![PointerIncrement {
+            amount: amount1 + amount2,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        TestResult::from_bool(sort_by_offset(instrs) == expected)
+    }
+    quickcheck(sort_by_offset_pointer_increments as fn(isize, isize) -> TestResult);
+}
+
+// Don't combine instruction positions when they weren't originally
+// adjacent.
+#[test]
+fn combine_increments_non_adjacent_instrs() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let expected = vec![Increment {
+        amount: Wrapping(2),
+        offset: 0,
+        position: Some(Position { start: 2, end: 2 }),
+    }];
+    assert_eq!(combine_increments(instrs), expected);
+}
+
+// Don't combine instruction positions when they weren't originally
+// adjacent.
+#[test]
+fn combine_set_and_increment_non_adjacent_instrs() {
+    let instrs = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let expected = vec![Set {
+        amount: Wrapping(2),
+        offset: 0,
+        position: Some(Position { start: 2, end: 2 }),
+    }];
+    assert_eq!(combine_set_and_increments(instrs), expected);
+}
+
+#[test]
+fn combine_set_and_later_increment_in_loop() {
+    // Set 1, Write (offset 1), Increment 2 inside a loop.
+    let initial = vec![Loop {
+        body: vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Write {
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            Increment {
+                amount: Wrapping(2),
+                offset: -1,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+        ],
+        position: Some(Position { start: 0, end: 5 }),
+    }];
+    let expected = vec![Loop {
+        body: vec![
+            Set {
+                amount: Wrapping(3),
+                offset: 0,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Write {
+                position: Some(Position { start: 3, end: 3 }),
+            },
+        ],
+        position: Some(Position { start: 0, end: 5 }),
+    }];
+    assert_eq!(combine_set_and_increments(initial), expected);
+}
+
+#[test]
+fn combine_set_and_increments_after_loop() {
+    // The cell is zero after the loop, even without a Set 0.
+    let initial = parse(",[.-].+").unwrap();
+    let mut expected = initial.clone();
+    expected[3] = Set {
+        amount: Wrapping(1),
+        offset: 0,
+        position: Some(Position { start: 6, end: 6 }),
+    };
+    assert_eq!(combine_set_and_increments(initial), expected);
+}
+
+#[test]
+fn combine_set_and_increments_after_multiply() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(2));
+    let initial = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(3),
+            offset: 0,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(4),
+            offset: 1,
+            position: None,
+        },
+    ];
+    let expected = vec![
+        MultiplyMove {
+            changes,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(3),
+            offset: 0,
+            position: None,
+        },
+        // The multiply modified this cell.
+        Increment {
+            amount: Wrapping(4),
+            offset: 1,
+            position: None,
+        },
+    ];
+    assert_eq!(combine_set_and_increments(initial), expected);
+}
+
+#[test]
+fn no_combine_set_and_increment_after_read() {
+    let initial = parse("[.-],+").unwrap();
+    assert_eq!(combine_set_and_increments(initial.clone()), initial);
+}
+
+#[test]
+fn no_combine_set_and_increment_across_write() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 1,
+            position: None,
+        },
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+        Write { position: None },
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: None,
+        },
+    ];
+    let expected = initial.clone();
+    assert_eq!(combine_set_and_increments(initial), expected);
+}
+
+#[test]
+fn should_combine_set_range() {
+    let initial = parse("[-]>[-]>[-]>[-]").unwrap();
+    let initial = sort_by_offset(zeroing_loops(initial));
+    let expected = vec![
+        SetRange {
+            amount: Wrapping(0),
+            offset: 0,
+            length: 4,
+            position: Some(Position { start: 12, end: 14 }),
+        },
+        PointerIncrement {
+            amount: 3,
+            position: Some(Position { start: 11, end: 11 }),
+        },
+    ];
+    assert_eq!(combine_set_ranges(initial), expected);
+}
+
+#[test]
+fn should_not_combine_short_set_range() {
+    let initial = sort_by_offset(zeroing_loops(parse("[-]>[-]>[-]").unwrap()));
+    assert_eq!(combine_set_ranges(initial.clone()), initial);
+}
+
+#[test]
+fn should_not_combine_set_range_different_values() {
+    let initial: Vec<_> = (0..4)
+        .map(|offset| Set {
+            amount: Wrapping(offset as i32),
+            offset,
+            position: None,
+        })
+        .collect();
+    assert_eq!(combine_set_ranges(initial.clone()), initial);
+}
+
+/// Ensure that we combine after sorting, since sorting creates new
+/// combination opportunities.
+#[test]
+fn combine_increments_after_sort() {
+    let instrs = parse(",+>+<+.").unwrap();
+    let expected = vec![
+        Read {
             position: Some(Position { start: 0, end: 0 }),
         },
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 5, end: 5 }),
+        },
+        // Cell #1 was zero, so we know its new value.
+        Set {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+        Write {
+            position: Some(Position { start: 6, end: 6 }),
+        },
+    ];
+    assert_eq!(optimize(instrs, &OptimizeOptions::default()).0, expected);
+}
+
+#[test]
+fn prev_mutate_loop() {
+    // If we see a loop, we don't know when the current cell was last
+    // mutated.
+    let instrs = vec![
         Loop {
             body: vec![],
-            position: Some(Position { start: 1, end: 2 }),
+            position: Some(Position { start: 0, end: 0 }),
         },
-        Set {
-            amount: Wrapping(0),
-            offset: 0,
-            position: Some(Position { start: 2, end: 2 }),
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(annotate_known_zero(initial), expected);
+    assert_eq!(previous_cell_change(&instrs, 1), None);
 }
 
 #[test]
-fn should_annotate_known_zero_nested() {
-    let initial = parse("[[]]").unwrap();
-    let expected = vec![
-        Set {
-            amount: Wrapping(0),
+fn prev_mutate_increment() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        Loop {
-            body: vec![
-                Loop {
-                    body: vec![],
-                    position: Some(Position { start: 1, end: 2 }),
-                },
-                Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    position: Some(Position { start: 2, end: 2 }),
-                },
-            ],
-            position: Some(Position { start: 0, end: 3 }),
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
         },
-        Set {
-            amount: Wrapping(0),
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+}
+
+#[test]
+fn prev_mutate_ignores_offset_at_index() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 3, end: 3 }),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        // The fact that this instruction is at offset 1 should be irrelevant.
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(annotate_known_zero(initial), expected);
+    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
 }
 
-/// When we annotate known zeroes, we have new opportunities for
-/// combining instructions and loop removal. However, we should later
-/// remove the Set 0 if we haven't combined it.
 #[test]
-fn should_annotate_known_zero_cleaned_up() {
-    let initial = vec![Write {
+fn prev_mutate_multiply_offset_matches() {
+    let mut changes = BTreeMap::new();
+    changes.insert(-1, Wrapping(-1));
+
+    let instrs = vec![
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+}
+
+#[test]
+fn prev_mutate_multiply_offset_doesnt_match() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(2));
+
+    let instrs = vec![
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2), None);
+}
+
+/// MultiplyMove zeroes the current cell, so it counts as a mutation
+/// of the current value.
+#[test]
+fn prev_mutate_multiply_ignore_offset() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(-1));
+
+    let instrs = vec![
+        MultiplyMove {
+            changes,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+}
+
+#[test]
+fn prev_mutate_no_predecessors() {
+    let instrs = vec![Read {
         position: Some(Position { start: 0, end: 0 }),
     }];
-    assert_eq!(optimize(initial.clone(), &None).0, initial);
+    assert_eq!(previous_cell_change(&instrs, 0), None);
 }
 
 #[test]
-fn should_preserve_set_0_in_loop() {
-    // Regression test.
-    let initial = vec![
-        Read {
+fn prev_mutate_increment_matching_offset() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        Loop {
-            body: vec![Set {
-                amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            }],
+        Increment {
+            amount: Wrapping(10),
+            offset: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(optimize(initial.clone(), &None).0, initial);
+    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
 }
 
 #[test]
-fn should_remove_pure_code() {
-    // The final increment here is side-effect free and can be
-    // removed.
-    let initial = parse("+.+").unwrap();
-    let expected = vec![
-        Set {
+fn prev_mutate_ignore_write() {
+    let instrs = vec![
+        Increment {
             amount: Wrapping(1),
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Write {
-            position: Some(Position { start: 1, end: 1 }),
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
         },
     ];
+    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+}
 
-    let (result, warnings) = optimize(initial, &None);
-
-    assert_eq!(result, expected);
-    assert_eq!(
-        warnings,
-        vec![Warning {
-            message: "These instructions have no effect.".to_owned(),
-            position: Some(Position { start: 2, end: 2 }),
-        }]
-    );
+#[test]
+fn prev_mutate_consider_pointer_increment() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
 }
 
 #[test]
-fn quickcheck_should_remove_dead_pure_code() {
-    fn should_remove_dead_pure_code(instrs: Vec<AstNode>) -> TestResult {
-        if !is_pure(&instrs) {
-            return TestResult::discard();
-        }
-        TestResult::from_bool(optimize(instrs, &None).0 == vec![])
-    }
-    quickcheck(should_remove_dead_pure_code as fn(Vec<AstNode>) -> TestResult);
+fn prev_mutate_set() {
+    let instrs = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
 }
 
 #[test]
-fn quickcheck_optimize_should_be_idempotent() {
-    fn optimize_should_be_idempotent(instrs: Vec<AstNode>) -> bool {
-        // Once we've optimized once, running again shouldn't reduce the
-        // instructions further. If it does, we're probably running our
-        // optimisations in the wrong order.
-        let minimal = optimize(instrs, &None).0;
-        optimize(minimal.clone(), &None).0 == minimal
-    }
-    quickcheck(optimize_should_be_idempotent as fn(Vec<AstNode>) -> bool);
+fn next_mutate_loop() {
+    // If we see a loop, we don't know when the current cell is next
+    // mutated.
+    let instrs = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(next_cell_change(&instrs, 0), None);
 }
 
 #[test]
-fn pathological_optimisation_opportunity() {
+fn next_mutate_increment() {
     let instrs = vec![
         Read {
             position: Some(Position { start: 0, end: 0 }),
         },
         Increment {
             amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        PointerIncrement {
-            amount: 1,
+            offset: -1,
             position: Some(Position { start: 0, end: 0 }),
         },
         Increment {
@@ -948,634 +2629,945 @@ fn pathological_optimisation_opportunity() {
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        PointerIncrement {
-            amount: 1,
+    ];
+    assert_eq!(next_cell_change(&instrs, 0), Some(2));
+}
+
+#[test]
+fn next_mutate_consider_pointer_increment() {
+    let instrs = vec![
+        Read {
             position: Some(Position { start: 0, end: 0 }),
         },
         PointerIncrement {
-            amount: -1,
+            amount: 1,
             position: Some(Position { start: 0, end: 0 }),
         },
         Increment {
-            amount: Wrapping(-1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        PointerIncrement {
-            amount: -1,
+            amount: Wrapping(1),
+            offset: 1,
             position: Some(Position { start: 0, end: 0 }),
         },
         Increment {
-            amount: Wrapping(-1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Write {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-
-    let expected = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Write {
+            amount: Wrapping(1),
+            offset: -1,
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
 
-    assert_eq!(optimize(instrs, &None).0, expected);
+    assert_eq!(next_cell_change(&instrs, 0), Some(3));
 }
 
-fn count_instrs(instrs: &[AstNode]) -> u64 {
-    let mut count = 0;
-    for instr in instrs {
-        if let Loop { ref body, .. } = *instr {
-            count += count_instrs(body);
-        }
-        count += 1;
-    }
-    count
+#[test]
+fn passes_from_specification_preserves_order() {
+    let passes = passes_from_specification("dead_loop,combine_inc").unwrap();
+    let names: Vec<_> = passes.iter().map(|pass| pass.name()).collect();
+    assert_eq!(names, vec!["dead_loop", "combine_inc"]);
 }
 
 #[test]
-fn quickcheck_optimize_should_decrease_size() {
-    fn optimize_should_decrease_size(instrs: Vec<AstNode>) -> bool {
-        // The result of optimize() should never increase the number of
-        // instructions.
-        let result = optimize(instrs.clone(), &None).0;
-        count_instrs(&result) <= count_instrs(&instrs)
-    }
-    quickcheck(optimize_should_decrease_size as fn(Vec<AstNode>) -> bool);
+fn passes_from_specification_unknown_pass() {
+    let result = passes_from_specification("combine_inc,no_such_pass");
+    assert!(result.is_err());
 }
 
 #[test]
-fn should_extract_multiply_simple() {
-    let instrs = parse("[->+++<]").unwrap();
-
-    let mut dest_cells = HashMap::new();
-    dest_cells.insert(1, Wrapping(3));
-    let expected = vec![MultiplyMove {
-        changes: dest_cells,
-        position: Some(Position { start: 0, end: 7 }),
-    }];
+fn dump_ir_after_all_passes() {
+    let names = dump_ir_after_from_specification("all").unwrap();
+    assert!(names.contains(&"combine_inc"));
+    assert!(names.contains(&"dead_loop"));
+}
 
-    assert_eq!(extract_multiply(instrs), expected);
+#[test]
+fn dump_ir_after_named_passes() {
+    assert_eq!(
+        dump_ir_after_from_specification("dead_loop,combine_inc"),
+        Ok(vec!["dead_loop", "combine_inc"])
+    );
+    assert!(dump_ir_after_from_specification("no_such_pass").is_err());
 }
 
 #[test]
-fn should_extract_multiply_nested() {
-    let instrs = parse("[[->+<]]").unwrap();
+fn without_passes_removes_named_passes() {
+    let passes = passes_from_specification("combine_inc,dead_loop,combine_ptr").unwrap();
+    let passes = without_passes(passes, &["dead_loop".to_owned()]).unwrap();
+    let names: Vec<_> = passes.iter().map(|pass| pass.name()).collect();
+    assert_eq!(names, vec!["combine_inc", "combine_ptr"]);
+}
 
-    let mut dest_cells = HashMap::new();
-    dest_cells.insert(1, Wrapping(1));
-    let expected = vec![Loop {
-        body: vec![MultiplyMove {
-            changes: dest_cells,
-            position: Some(Position { start: 1, end: 6 }),
-        }],
-        position: Some(Position { start: 0, end: 7 }),
-    }];
+#[test]
+fn without_passes_unknown_pass() {
+    let result = without_passes(default_passes(), &["no_such_pass".to_owned()]);
+    assert!(result.is_err());
+}
 
-    assert_eq!(extract_multiply(instrs), expected);
+#[test]
+fn optimize_with_custom_passes() {
+    // Only combine increments, so the loop isn't simplified.
+    let initial = parse("++[-]").unwrap();
+    let options = OptimizeOptions {
+        passes: passes_from_specification("combine_inc").unwrap(),
+        ..OptimizeOptions::default()
+    };
+    let expected = vec![
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 1 }),
+        },
+        Loop {
+            body: vec![Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 3, end: 3 }),
+            }],
+            position: Some(Position { start: 2, end: 4 }),
+        },
+    ];
+    assert_eq!(optimize(initial, &options).0, expected);
 }
 
 #[test]
-fn should_extract_multiply_negative_number() {
-    let instrs = parse("[->--<]").unwrap();
+fn optimize_wraps_to_cell_width() {
+    // 256 increments are a no-op with 8-bit cells, but not with
+    // 16-bit cells.
+    let initial = parse(&"+".repeat(256)).unwrap();
+    let options = OptimizeOptions {
+        passes: passes_from_specification("combine_inc").unwrap(),
+        ..OptimizeOptions::default()
+    };
+    assert_eq!(optimize(initial.clone(), &options).0, vec![]);
 
-    let mut dest_cells = HashMap::new();
-    dest_cells.insert(1, Wrapping(-2));
-    let expected = vec![MultiplyMove {
-        changes: dest_cells,
-        position: Some(Position { start: 0, end: 6 }),
+    let options = OptimizeOptions {
+        cell_width: CellWidth::Bits16,
+        ..options
+    };
+    let expected = vec![Increment {
+        amount: Wrapping(256),
+        offset: 0,
+        position: Some(Position { start: 0, end: 255 }),
     }];
-
-    assert_eq!(extract_multiply(instrs), expected);
+    assert_eq!(optimize(initial, &options).0, expected);
 }
 
 #[test]
-fn should_extract_multiply_multiple_cells() {
-    let instrs = parse("[->+++>>>+<<<<]").unwrap();
-
-    let mut dest_cells = HashMap::new();
-    dest_cells.insert(1, Wrapping(3));
-    dest_cells.insert(4, Wrapping(1));
-    let expected = vec![MultiplyMove {
-        changes: dest_cells,
-        position: Some(Position { start: 0, end: 14 }),
+fn optimize_saturating_keeps_increments() {
+    // 256 increments saturate rather than wrapping to a no-op.
+    let initial = parse(&"+".repeat(256)).unwrap();
+    let options = OptimizeOptions {
+        passes: passes_from_specification("combine_inc").unwrap(),
+        overflow: Overflow::Saturate,
+        ..OptimizeOptions::default()
+    };
+    let expected = vec![Increment {
+        amount: Wrapping(256),
+        offset: 0,
+        position: Some(Position { start: 0, end: 255 }),
     }];
-
-    assert_eq!(extract_multiply(instrs), expected);
+    assert_eq!(optimize(initial, &options).0, expected);
 }
 
 #[test]
-fn should_not_extract_multiply_net_movement() {
-    let instrs = parse("[->+++<<]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+fn optimize_aborting_skips_wrapping_passes() {
+    let initial = parse("+>+<-").unwrap();
+    let options = OptimizeOptions {
+        passes: passes_from_specification("merge_inc").unwrap(),
+        overflow: Overflow::Abort,
+        ..OptimizeOptions::default()
+    };
+    assert_eq!(optimize(initial.clone(), &options).0, initial);
 }
 
 #[test]
-fn should_not_extract_multiply_from_clear_loop() {
-    let instrs = parse("[-]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+fn optimize_eof_unchanged_keeps_increments_before_reads() {
+    let initial = parse("+,.").unwrap();
+    let options = OptimizeOptions {
+        passes: passes_from_specification("read_clobber").unwrap(),
+        eof: Eof::Unchanged,
+        ..OptimizeOptions::default()
+    };
+    assert_eq!(optimize(initial.clone(), &options).0, initial);
 }
 
 #[test]
-fn should_not_extract_multiply_with_inner_loop() {
-    let instrs = parse("[->+++<[]]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+fn optimize_bounds_abort_keeps_accesses() {
+    // Cell -1 is known to be zero, but the program aborts when it
+    // writes it.
+    let initial = parse(",<.").unwrap();
+    let options = OptimizeOptions {
+        bounds: Bounds::Abort,
+        ..OptimizeOptions::default()
+    };
+    assert_eq!(optimize(initial.clone(), &options).0, initial);
 }
 
-/// We need to decrement the initial cell in order for this to be a
-/// multiply.
 #[test]
-fn should_not_extract_multiply_without_decrement() {
-    let instrs = parse("[+>++<]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+fn optimize_bounds_abort_keeps_cancelling_moves() {
+    // '<' moves off the start of the tape, even though '>' moves back.
+    let initial = parse("<>+.").unwrap();
+    let options = OptimizeOptions {
+        bounds: Bounds::Abort,
+        ..OptimizeOptions::default()
+    };
+    let result = optimize(initial, &options).0;
+    assert!(matches!(result[0], PointerIncrement { amount: -1, .. }));
 }
 
 #[test]
-fn should_not_extract_multiply_with_read() {
-    let instrs = parse("[+>++<,]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+fn optimize_bounds_abort_keeps_multiply_excursions() {
+    // The multiply only changes cell #-1, but the loop visits cell #-2.
+    let initial = parse(",[-<+<>>]").unwrap();
+    let options = OptimizeOptions {
+        bounds: Bounds::Abort,
+        ..OptimizeOptions::default()
+    };
+    let result = optimize(initial, &options).0;
+    assert!(matches!(result[1], Loop { .. }));
 }
 
 #[test]
-fn should_not_extract_multiply_with_write() {
-    let instrs = parse("[+>++<.]").unwrap();
-    assert_eq!(extract_multiply(instrs.clone()), instrs);
+fn combine_same_sign_ptr_increments_flat() {
+    let initial = parse(">><<<>").unwrap();
+    let expected = vec![
+        PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 0, end: 1 }),
+        },
+        PointerIncrement {
+            amount: -3,
+            position: Some(Position { start: 2, end: 4 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 5, end: 5 }),
+        },
+    ];
+    assert_eq!(combine_same_sign_ptr_increments(initial), expected);
 }
 
 #[test]
-fn sort_by_offset_increment() {
-    let instrs = parse("+>+>").unwrap();
+fn combine_same_sign_increments_flat() {
+    let initial = parse("++--").unwrap();
     let expected = vec![
         Increment {
-            amount: Wrapping(1),
+            amount: Wrapping(2),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 0, end: 1 }),
         },
         Increment {
-            amount: Wrapping(1),
-            offset: 1,
-            position: Some(Position { start: 2, end: 2 }),
-        },
-        PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 3, end: 3 }),
+            amount: Wrapping(-2),
+            offset: 0,
+            position: Some(Position { start: 2, end: 3 }),
         },
     ];
-    assert_eq!(sort_by_offset(instrs), expected);
+    assert_eq!(combine_same_sign_increments(initial), expected);
 }
 
 #[test]
-fn sort_by_offset_increment_nested() {
-    let instrs = parse("[+>+>]").unwrap();
+fn combine_same_sign_increments_nested() {
+    let initial = parse("[--]").unwrap();
     let expected = vec![Loop {
-        body: (vec![
-            Increment {
-                amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 1, end: 1 }),
-            },
-            Increment {
-                amount: Wrapping(1),
-                offset: 1,
-                position: Some(Position { start: 3, end: 3 }),
-            },
-            PointerIncrement {
-                amount: 2,
-                position: Some(Position { start: 4, end: 4 }),
-            },
-        ]),
-        position: Some(Position { start: 0, end: 5 }),
+        body: vec![Increment {
+            amount: Wrapping(-2),
+            offset: 0,
+            position: Some(Position { start: 1, end: 2 }),
+        }],
+        position: Some(Position { start: 0, end: 3 }),
     }];
-    assert_eq!(sort_by_offset(instrs), expected);
+    assert_eq!(combine_same_sign_increments(initial), expected);
+}
+
+#[test]
+fn extract_monotonic_multiply_mixed_signs() {
+    // With saturating cells, cell #1 may change on every iteration
+    // even though its increments cancel out.
+    let instrs = parse("[->+-+<]").unwrap();
+    assert_eq!(extract_monotonic_multiply(instrs.clone()), instrs);
+
+    let instrs = parse("[->++<]").unwrap();
+    assert_eq!(
+        extract_monotonic_multiply(instrs.clone()),
+        extract_multiply(instrs)
+    );
+}
+
+#[test]
+fn optimize_with_stats_counts_removed_instrs() {
+    // combine_inc turns three increments into one.
+    let initial = parse("+++.").unwrap();
+    let options = OptimizeOptions {
+        passes: passes_from_specification("combine_inc,dead_loop").unwrap(),
+        ..OptimizeOptions::default()
+    };
+    let (_, _, stats) = optimize_with_stats(initial, &options);
+
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].name, "combine_inc");
+    assert_eq!(stats[0].instrs_removed, 2);
+    assert_eq!(stats[1].name, "dead_loop");
+    assert_eq!(stats[1].instrs_removed, 0);
+    // The second iteration finds nothing to change.
+    assert_eq!(stats[0].runs, 2);
+}
+
+#[test]
+fn optimize_reruns_passes_until_fixed_point() {
+    // known_zero runs after dead_loop, so the loop is only removed
+    // on the second iteration, once we know the cell is zero.
+    let initial = parse("[>].").unwrap();
+    let options = OptimizeOptions {
+        passes: passes_from_specification("dead_loop,known_zero").unwrap(),
+        ..OptimizeOptions::default()
+    };
+    let (result, warnings, stats) = optimize_with_stats(initial, &options);
+
+    assert_eq!(warnings, vec![]);
+    assert_eq!(stats[0].instrs_removed, 2);
+    assert!(!result.iter().any(|instr| matches!(instr, Loop { .. })));
+}
+
+#[test]
+fn optimize_warns_without_fixed_point() {
+    let initial = parse("++.").unwrap();
+    let options = OptimizeOptions {
+        max_iterations: 1,
+        ..OptimizeOptions::default()
+    };
+    let (_, warnings) = optimize(initial, &options);
+
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "Ran peephole optimisations 1 times but did not reach a fixed point."
+                .to_owned(),
+            position: None,
+        }]
+    );
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn optimize_discards_invalid_ir() {
+    /// A pass that appends an increment by zero, which isn't valid IR.
+    struct InvalidIrPass;
+
+    impl Pass for InvalidIrPass {
+        fn name(&self) -> &'static str {
+            "invalid_ir"
+        }
+
+        fn run(
+            &self,
+            mut instrs: Vec<AstNode>,
+            _: &OptimizeOptions,
+        ) -> (Vec<AstNode>, Vec<Warning>) {
+            instrs.push(Increment {
+                amount: Wrapping(0),
+                offset: 0,
+                position: None,
+            });
+            (instrs, vec![])
+        }
+    }
+
+    let initial = parse("+.").unwrap();
+    let options = OptimizeOptions {
+        passes: vec![&InvalidIrPass],
+        ..OptimizeOptions::default()
+    };
+    let (result, warnings) = optimize(initial.clone(), &options);
+
+    assert_eq!(result, initial);
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "Internal error: invalid_ir produced invalid IR (Increment by zero), \
+                      so its changes were discarded. Please report this as a bfc bug."
+                .to_owned(),
+            position: None,
+        }]
+    );
+}
+
+#[test]
+fn optimize_stops_at_deadline() {
+    let initial = parse("++.").unwrap();
+    let options = OptimizeOptions {
+        deadline: Some(Instant::now()),
+        ..OptimizeOptions::default()
+    };
+    let (result, warnings) = optimize(initial.clone(), &options);
+
+    assert_eq!(result, initial);
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "Stopped peephole optimisations after 0 iterations, as they used the \
+                      whole --compile-budget."
+                .to_owned(),
+            position: None,
+        }]
+    );
 }
 
 #[test]
-fn sort_by_offset_remove_redundant() {
-    let initial = parse("><").unwrap();
-    assert_eq!(sort_by_offset(initial), vec![]);
+fn optimize_without_fuel_changes_nothing() {
+    let initial = parse("++>>").unwrap();
+    let options = OptimizeOptions {
+        pass_fuel: Some(0),
+        ..OptimizeOptions::default()
+    };
+    let (result, warnings) = optimize(initial.clone(), &options);
+
+    assert_eq!(result, initial);
+    assert_eq!(warnings, vec![]);
 }
 
-// If there's a read instruction, we should only combine before and
-// after.
 #[test]
-fn sort_by_offset_read() {
-    let instrs = parse(">>,>>").unwrap();
+fn optimize_stops_when_fuel_runs_out() {
+    let initial = parse("++>>").unwrap();
+    let options = OptimizeOptions {
+        pass_fuel: Some(1),
+        ..OptimizeOptions::default()
+    };
+    let (result, warnings) = optimize(initial, &options);
+
+    // Only combine_inc has run.
     let expected = vec![
-        PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 1, end: 1 }),
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 1 }),
         },
-        Read {
+        PointerIncrement {
+            amount: 1,
             position: Some(Position { start: 2, end: 2 }),
         },
         PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 4, end: 4 }),
+            amount: 1,
+            position: Some(Position { start: 3, end: 3 }),
         },
     ];
-    assert_eq!(sort_by_offset(instrs), expected);
+    assert_eq!(result, expected);
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "Ran out of pass fuel. The last change was made by combine_inc.".to_owned(),
+            position: None,
+        }]
+    );
 }
 
 #[test]
-fn quickcheck_sort_by_offset_set() {
-    fn sort_by_offset_set(amount1: i8, amount2: i8) -> bool {
-        let instrs = vec![
-            Set {
-                amount: Wrapping(amount1),
+fn optimize_with_one_more_fuel_makes_one_more_change() {
+    let initial = parse("++>>").unwrap();
+    let with_fuel = |pass_fuel| {
+        let options = OptimizeOptions {
+            pass_fuel: Some(pass_fuel),
+            ..OptimizeOptions::default()
+        };
+        optimize(initial.clone(), &options)
+    };
+    let (before, _) = with_fuel(1);
+    let (after, warnings) = with_fuel(2);
+
+    // The second unit of fuel only combines the pointer increments.
+    assert_eq!(after, combine_ptr_increments(before));
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            message: "Ran out of pass fuel. The last change was made by combine_ptr.".to_owned(),
+            position: None,
+        }]
+    );
+}
+
+#[test]
+fn optimize_with_spare_fuel_matches_unlimited() {
+    let initial = parse("+[->++<]>.").unwrap();
+    let options = OptimizeOptions {
+        pass_fuel: Some(1000),
+        ..OptimizeOptions::default()
+    };
+    assert_eq!(
+        optimize(initial.clone(), &options),
+        optimize(initial, &OptimizeOptions::default())
+    );
+}
+
+#[test]
+fn should_recognize_if() {
+    // [>+<[-]] can only run once.
+    let initial = vec![Loop {
+        body: vec![
+            PointerIncrement {
+                amount: 1,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(1),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
             PointerIncrement {
                 amount: -1,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
             Set {
-                amount: Wrapping(amount2),
+                amount: Wrapping(0),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
-        ];
-
-        let expected = vec![
-            Set {
-                amount: Wrapping(amount2),
-                offset: -1,
-                position: Some(Position { start: 0, end: 0 }),
+        ],
+        position: None,
+    }];
+    let expected = vec![If {
+        body: vec![
+            PointerIncrement {
+                amount: 1,
+                position: None,
             },
-            Set {
-                amount: Wrapping(amount1),
+            Increment {
+                amount: Wrapping(1),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
             PointerIncrement {
                 amount: -1,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
-        ];
-        sort_by_offset(instrs) == expected
-    }
-    quickcheck(sort_by_offset_set as fn(i8, i8) -> bool);
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: None,
+            },
+        ],
+        position: None,
+    }];
+    assert_eq!(recognize_ifs(initial), expected);
 }
 
 #[test]
-fn quickcheck_sort_by_offset_pointer_increments() {
-    fn sort_by_offset_pointer_increments(amount1: isize, amount2: isize) -> TestResult {
-        // Although in principle our optimisations would work outside
-        // MAX_CELL_INDEX, we restrict the range to avoid overflow.
-        if amount1 < -30000 || amount1 > 30000 || amount2 < -30000 || amount2 > 30000 {
-            return TestResult::discard();
-        }
-        // We should discard the pointer increment if the two cancel out,
-        // but we don't test that here.
-        if amount1 + amount2 == 0 {
-            return TestResult::discard();
-        }
-
-        let instrs = vec![
-            PointerIncrement {
-                amount: amount1,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            PointerIncrement {
-                amount: amount2,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let expected = vec![PointerIncrement {
-            amount: amount1 + amount2,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        TestResult::from_bool(sort_by_offset(instrs) == expected)
-    }
-    quickcheck(sort_by_offset_pointer_increments as fn(isize, isize) -> TestResult);
+fn should_recognize_if_ending_with_loop() {
+    // The inner loop always leaves the current cell at zero.
+    let initial = parse("[.[,]]").unwrap();
+    let result = recognize_ifs(initial);
+    assert!(matches!(result[0], If { .. }));
 }
 
-// Don't combine instruction positions when they weren't originally
-// adjacent.
 #[test]
-fn combine_increments_non_adjacent_instrs() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 2, end: 2 }),
-        },
-    ];
-    let expected = vec![Increment {
-        amount: Wrapping(2),
-        offset: 0,
-        position: Some(Position { start: 2, end: 2 }),
-    }];
-    assert_eq!(combine_increments(instrs), expected);
+fn should_not_recognize_if_when_cell_may_be_nonzero() {
+    // The body decrements rather than zeroing.
+    let initial = parse("[->+<]").unwrap();
+    assert_eq!(recognize_ifs(initial.clone()), initial);
+
+    // The body zeroes a different cell.
+    let initial = parse("[>[-]]").unwrap();
+    assert_eq!(recognize_ifs(initial.clone()), initial);
+
+    // The body might not return to the cell it started on.
+    let initial = parse("[[-]>[<]]").unwrap();
+    assert_eq!(recognize_ifs(initial.clone()), initial);
 }
 
-// Don't combine instruction positions when they weren't originally
-// adjacent.
-#[test]
-fn combine_set_and_increment_non_adjacent_instrs() {
-    let instrs = vec![
+/// The "else" half of the flag idiom: cell #1 is a flag that starts
+/// at `flag`, and an If on cell #0 may clear it. We then loop on the
+/// flag, decrementing it.
+fn flag_loop(flag: i32) -> Vec<AstNode> {
+    vec![
         Set {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            amount: Wrapping(flag),
+            offset: 1,
+            position: None,
         },
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 2, end: 2 }),
+        Read { position: None },
+        If {
+            body: vec![
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 1,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: None,
+                },
+            ],
+            position: None,
         },
-    ];
-    let expected = vec![Set {
-        amount: Wrapping(2),
-        offset: 0,
-        position: Some(Position { start: 2, end: 2 }),
-    }];
-    assert_eq!(combine_set_and_increments(instrs), expected);
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+        Loop {
+            body: vec![
+                Write { position: None },
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 5, end: 5 }),
+                },
+            ],
+            position: None,
+        },
+    ]
 }
 
-/// Ensure that we combine after sorting, since sorting creates new
-/// combination opportunities.
 #[test]
-fn combine_increments_after_sort() {
-    let instrs = parse(",+>+<+.").unwrap();
-    let expected = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(2),
-            offset: 0,
-            position: Some(Position { start: 5, end: 5 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: 1,
-            position: Some(Position { start: 3, end: 3 }),
-        },
-        Write {
-            position: Some(Position { start: 6, end: 6 }),
-        },
-    ];
-    assert_eq!(optimize(instrs, &None).0, expected);
+fn should_recognize_if_on_flag_cell() {
+    let mut expected = flag_loop(1);
+    expected[4] = If {
+        body: vec![
+            Write { position: None },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 5, end: 5 }),
+            },
+        ],
+        position: None,
+    };
+    assert_eq!(recognize_ifs(flag_loop(1)), expected);
 }
 
 #[test]
-fn prev_mutate_loop() {
-    // If we see a loop, we don't know when the current cell was last
-    // mutated.
-    let instrs = vec![
-        Loop {
-            body: vec![],
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 1), None);
+fn should_not_recognize_if_on_non_flag_cell() {
+    // The cell may be 2 or 1, so the loop may run twice.
+    let initial = flag_loop(2);
+    assert_eq!(recognize_ifs(initial.clone()), initial);
 }
 
 #[test]
-fn prev_mutate_increment() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+fn should_not_recognize_if_when_flag_decremented_twice() {
+    let mut initial = flag_loop(1);
+    if let Loop { ref mut body, .. } = initial[4] {
+        body.insert(
+            0,
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: None,
+            },
+        );
+    }
+    assert_eq!(recognize_ifs(initial.clone()), initial);
 }
 
 #[test]
-fn prev_mutate_ignores_offset_at_index() {
-    let instrs = vec![
-        Increment {
+fn should_propagate_known_values_past_write() {
+    let initial = parse("+.+").unwrap();
+    let expected = vec![
+        Set {
             amount: Wrapping(1),
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        // The fact that this instruction is at offset 1 should be irrelevant.
-        Increment {
+        WriteConst {
+            values: vec![1],
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Set {
             amount: Wrapping(2),
-            offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+    assert_eq!(propagate_known_values(initial), expected);
 }
 
 #[test]
-fn prev_mutate_multiply_offset_matches() {
-    let mut changes = HashMap::new();
-    changes.insert(-1, Wrapping(-1));
-
-    let instrs = vec![
-        MultiplyMove {
-            changes,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        PointerIncrement {
-            amount: -1,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+fn should_propagate_known_values_after_pointer_moves() {
+    // We know cell #1 is still 2 when we return to it.
+    let initial = parse("++>,<+").unwrap();
+    let result = propagate_known_values(initial);
+    assert_eq!(
+        result.last(),
+        Some(&Set {
+            amount: Wrapping(3),
+            offset: 0,
+            position: Some(Position { start: 5, end: 5 }),
+        })
+    );
 }
 
 #[test]
-fn prev_mutate_multiply_offset_doesnt_match() {
-    let mut changes = HashMap::new();
-    changes.insert(1, Wrapping(2));
+fn should_not_propagate_known_values_after_read() {
+    let initial = parse(",+").unwrap();
+    assert_eq!(propagate_known_values(initial.clone()), initial);
+}
 
-    let instrs = vec![
-        MultiplyMove {
-            changes,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 2), None);
+#[test]
+fn should_remove_loops_on_known_zero_cells() {
+    // The first loop leaves the cell at zero, so the second loop is
+    // dead.
+    let initial = parse(",[-.][.]").unwrap();
+    let result = propagate_known_values(initial);
+    assert_eq!(result.len(), 2);
 }
 
-/// MultiplyMove zeroes the current cell, so it counts as a mutation
-/// of the current value.
 #[test]
-fn prev_mutate_multiply_ignore_offset() {
-    let mut changes = HashMap::new();
-    changes.insert(1, Wrapping(-1));
+fn should_zero_step_loops_that_terminate() {
+    // The cell is even, so [--] reaches zero.
+    let initial = combine_increments(parse("++++[--]").unwrap());
+    let result = propagate_known_values(initial);
+    assert_eq!(
+        result.last(),
+        Some(&Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 4, end: 7 }),
+        })
+    );
+}
 
-    let instrs = vec![
-        MultiplyMove {
-            changes,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
+#[test]
+fn should_not_zero_step_loops_that_never_terminate() {
+    // The cell is odd, so [--] wraps around forever.
+    let initial = combine_increments(parse("+++[--]").unwrap());
+    let result = propagate_known_values(initial);
+    assert!(matches!(result.last(), Some(&Loop { .. })));
 }
 
 #[test]
-fn prev_mutate_no_predecessors() {
-    let instrs = vec![Read {
-        position: Some(Position { start: 0, end: 0 }),
-    }];
-    assert_eq!(previous_cell_change(&instrs, 0), None);
+fn should_forget_values_after_loop() {
+    // The loop may have modified cell #1.
+    let initial = parse(">+<,[>-<-]>+").unwrap();
+    let result = propagate_known_values(initial);
+    assert!(matches!(result.last(), Some(&Increment { .. })));
 }
 
 #[test]
-fn prev_mutate_increment_matching_offset() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
+fn should_keep_values_of_cells_loop_does_not_modify() {
+    // The loop only modifies cell #0, so cell #1 is still 2
+    // afterwards.
+    let initial = parse(">++<,[-]>+").unwrap();
+    let result = propagate_known_values(initial);
+    assert_eq!(
+        result.last(),
+        Some(&Set {
+            amount: Wrapping(3),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(10),
-            offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+            position: Some(Position { start: 9, end: 9 }),
+        })
+    );
 }
 
 #[test]
-fn prev_mutate_ignore_write() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Write {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-    ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+fn should_propagate_known_values_into_loop_body() {
+    // Cell #1 is 2 on every iteration, so we know what we write.
+    let initial = parse(">++<,[->.<]").unwrap();
+    let result = propagate_known_values(initial);
+    match result.last() {
+        Some(Loop { body, .. }) => assert_eq!(
+            body[2],
+            WriteConst {
+                values: vec![2],
+                position: Some(Position { start: 8, end: 8 }),
+            }
+        ),
+        other => panic!("Expected a loop, got {:?}", other),
+    }
 }
 
 #[test]
-fn prev_mutate_consider_pointer_increment() {
-    let instrs = vec![
-        Increment {
-            amount: Wrapping(1),
+fn should_not_propagate_modified_values_into_loop_body() {
+    // The loop body increments cell #1, so it's only 2 on the first
+    // iteration.
+    let initial = parse(">++<,[->+.<]").unwrap();
+    let result = propagate_known_values(initial);
+    match result.last() {
+        Some(Loop { body, .. }) => {
+            assert!(matches!(body[2], Increment { .. }));
+            assert!(matches!(body[3], Write { .. }));
+        }
+        other => panic!("Expected a loop, got {:?}", other),
+    }
+}
+
+#[test]
+fn should_propagate_known_values_into_if_body() {
+    let initial = vec![
+        Set {
+            amount: Wrapping(5),
             offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            position: None,
         },
-        PointerIncrement {
-            amount: 1,
-            position: Some(Position { start: 0, end: 0 }),
+        Read { position: None },
+        If {
+            body: vec![
+                Increment {
+                    amount: Wrapping(1),
+                    offset: 1,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: None,
+                },
+            ],
+            position: None,
         },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+    ];
+    let expected = vec![
+        initial[0].clone(),
+        initial[1].clone(),
+        If {
+            body: vec![
+                Set {
+                    amount: Wrapping(6),
+                    offset: 1,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: None,
+                },
+            ],
+            position: None,
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 2), Some(0));
+    assert_eq!(propagate_known_values(initial), expected);
 }
 
 #[test]
-fn prev_mutate_set() {
-    let instrs = vec![
+fn should_combine_const_writes() {
+    let initial = vec![
+        WriteConst {
+            values: vec![1],
+            position: Some(Position { start: 0, end: 0 }),
+        },
         Set {
-            amount: Wrapping(1),
+            amount: Wrapping(2),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 1, end: 1 }),
         },
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+        WriteConst {
+            values: vec![2],
+            position: Some(Position { start: 2, end: 2 }),
         },
     ];
-    assert_eq!(previous_cell_change(&instrs, 1), Some(0));
-}
-
-#[test]
-fn next_mutate_loop() {
-    // If we see a loop, we don't know when the current cell is next
-    // mutated.
-    let instrs = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+    let expected = vec![
+        WriteConst {
+            values: vec![1, 2],
+            position: Some(Position { start: 2, end: 2 }),
         },
-        Loop {
-            body: vec![],
-            position: Some(Position { start: 0, end: 0 }),
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 1, end: 1 }),
         },
     ];
-    assert_eq!(next_cell_change(&instrs, 0), None);
+    assert_eq!(combine_const_writes(initial), expected);
 }
 
 #[test]
-fn next_mutate_increment() {
-    let instrs = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: -1,
-            position: Some(Position { start: 0, end: 0 }),
+fn should_not_combine_const_writes_across_io() {
+    let initial = vec![
+        WriteConst {
+            values: vec![1],
+            position: None,
         },
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+        Read { position: None },
+        WriteConst {
+            values: vec![2],
+            position: None,
         },
     ];
-    assert_eq!(next_cell_change(&instrs, 0), Some(2));
+    assert_eq!(combine_const_writes(initial.clone()), initial);
 }
 
 #[test]
-fn next_mutate_consider_pointer_increment() {
-    let instrs = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        PointerIncrement {
-            amount: 1,
-            position: Some(Position { start: 0, end: 0 }),
+fn should_recognize_scans() {
+    let initial = combine_ptr_increments(parse("[>]+[[<<]-]").unwrap());
+    let expected = vec![
+        Scan {
+            step: 1,
+            position: Some(Position { start: 0, end: 2 }),
         },
         Increment {
             amount: Wrapping(1),
-            offset: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            offset: 0,
+            position: Some(Position { start: 3, end: 3 }),
         },
-        Increment {
-            amount: Wrapping(1),
-            offset: -1,
-            position: Some(Position { start: 0, end: 0 }),
+        Loop {
+            body: vec![
+                Scan {
+                    step: -2,
+                    position: Some(Position { start: 5, end: 8 }),
+                },
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 9, end: 9 }),
+                },
+            ],
+            position: Some(Position { start: 4, end: 10 }),
         },
     ];
+    assert_eq!(recognize_scans(initial), expected);
+}
 
-    assert_eq!(next_cell_change(&instrs, 0), Some(3));
+#[test]
+fn should_not_recognize_scan_with_other_instructions() {
+    let initial = parse("[>+]").unwrap();
+    assert_eq!(recognize_scans(initial.clone()), initial);
+}
+
+#[test]
+fn explain_dead_loops() {
+    let instrs = parse(">+<[.]+[-][.]").unwrap();
+    let explanations = explain_removals(&instrs, &OptimizeOptions::default());
+
+    assert_eq!(
+        explanations,
+        vec![
+            Warning {
+                message: "This loop is never entered, because cells start at 0 and nothing \
+                          has changed this cell."
+                    .to_owned(),
+                position: Some(Position { start: 3, end: 5 }),
+            },
+            Warning {
+                message: "This loop is never entered, because the cell is always 0 here."
+                    .to_owned(),
+                position: Some(Position { start: 10, end: 12 }),
+            },
+        ]
+    );
+}
+
+#[test]
+fn explain_read_clobber() {
+    let instrs = parse("+.++>+<-,").unwrap();
+    let explanations = explain_removals(&instrs, &OptimizeOptions::default());
+
+    let message = "This change is never used, because a read always overwrites the cell first.";
+    assert_eq!(
+        explanations,
+        vec![
+            Warning {
+                message: message.to_owned(),
+                position: Some(Position { start: 7, end: 7 }),
+            },
+            Warning {
+                message: message.to_owned(),
+                position: Some(Position { start: 2, end: 3 }),
+            },
+        ]
+    );
+}
+
+#[test]
+fn explain_removals_only_for_passes_we_run() {
+    let instrs = parse("+[-][.]++,").unwrap();
+    let options = OptimizeOptions {
+        passes: without_passes(default_passes(), &["dead_loop".to_owned()]).unwrap(),
+        eof: Eof::Unchanged,
+        ..OptimizeOptions::default()
+    };
+
+    assert_eq!(explain_removals(&instrs, &options), vec![]);
 }
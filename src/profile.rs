@@ -0,0 +1,140 @@
+//! Loop execution counts, gathered by running a program compiled
+//! with `--profile-generate`, and used by `--profile-use` to guide
+//! optimisation.
+//!
+//! A profile has one line per loop: the position of the loop's `[`
+//! in the source, the number of times we entered the loop, and the
+//! number of times we executed its body.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use std::collections::HashMap;
+
+use crate::bfir::Position;
+
+/// A loop is hot if it accounts for at least this percentage of all
+/// loop iterations in the profile.
+const HOT_LOOP_PERCENT: u64 = 1;
+
+/// How often a loop ran.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoopCounts {
+    pub entries: u64,
+    pub iterations: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Counts for each loop, indexed by the start of its position.
+    loops: HashMap<usize, LoopCounts>,
+    total_iterations: u64,
+}
+
+impl Profile {
+    /// Parse a profile written by a program compiled with
+    /// `--profile-generate`.
+    pub fn parse(text: &str) -> Result<Profile, String> {
+        let mut profile = Profile::default();
+
+        for (line_index, line) in text.lines().enumerate() {
+            let invalid_line = || format!("Invalid profile line {}: {}", line_index + 1, line);
+
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(invalid_line());
+            }
+            let start: usize = fields[0].parse().map_err(|_| invalid_line())?;
+            let entries: u64 = fields[1].parse().map_err(|_| invalid_line())?;
+            let iterations: u64 = fields[2].parse().map_err(|_| invalid_line())?;
+
+            // A loop may have been compiled more than once, e.g. if
+            // it's inside a loop we unrolled.
+            let counts = profile.loops.entry(start).or_default();
+            counts.entries += entries;
+            counts.iterations += iterations;
+            profile.total_iterations += iterations;
+        }
+
+        Ok(profile)
+    }
+
+    /// The counts for the loop at `position`, if it was profiled.
+    pub fn loop_counts(&self, position: Option<Position>) -> Option<LoopCounts> {
+        position.and_then(|position| self.loops.get(&position.start).cloned())
+    }
+
+    /// Was the loop at `position` profiled, but never entered?
+    pub fn is_cold(&self, position: Option<Position>) -> bool {
+        match self.loop_counts(position) {
+            Some(counts) => counts.entries == 0,
+            None => false,
+        }
+    }
+
+    /// Does the loop at `position` account for a large share of the
+    /// time we spent in loops?
+    pub fn is_hot(&self, position: Option<Position>) -> bool {
+        match self.loop_counts(position) {
+            Some(counts) => {
+                counts.iterations > 0
+                    && counts.iterations * 100 >= self.total_iterations * HOT_LOOP_PERCENT
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+fn position_at(start: usize) -> Option<Position> {
+    Some(Position { start, end: start })
+}
+
+#[test]
+fn parse_profile() {
+    let profile = Profile::parse("0 1 10\n5 2 3\n").unwrap();
+    assert_eq!(
+        profile.loop_counts(position_at(5)),
+        Some(LoopCounts {
+            entries: 2,
+            iterations: 3,
+        })
+    );
+    assert_eq!(profile.loop_counts(position_at(1)), None);
+    assert_eq!(profile.loop_counts(None), None);
+}
+
+#[test]
+fn parse_profile_sums_repeated_loops() {
+    let profile = Profile::parse("3 1 2\n3 1 4\n").unwrap();
+    assert_eq!(
+        profile.loop_counts(position_at(3)),
+        Some(LoopCounts {
+            entries: 2,
+            iterations: 6,
+        })
+    );
+}
+
+#[test]
+fn parse_invalid_profile() {
+    assert_eq!(
+        Profile::parse("0 1 10\n5 x 3\n"),
+        Err("Invalid profile line 2: 5 x 3".to_owned())
+    );
+    assert!(Profile::parse("0 1").is_err());
+}
+
+#[test]
+fn hot_and_cold_loops() {
+    let profile = Profile::parse("0 1 1000\n10 5 5\n20 0 0\n").unwrap();
+
+    assert!(profile.is_hot(position_at(0)));
+    assert!(!profile.is_hot(position_at(10)));
+    assert!(!profile.is_cold(position_at(10)));
+    assert!(profile.is_cold(position_at(20)));
+
+    // We know nothing about loops that aren't in the profile.
+    assert!(!profile.is_hot(position_at(30)));
+    assert!(!profile.is_cold(position_at(30)));
+}
@@ -0,0 +1,252 @@
+//! A small pattern-rewrite engine for peephole optimisations.
+//!
+//! A rule matches a window of adjacent instructions and replaces it
+//! with new instructions. Patterns use named variables, so a rule can
+//! require two instructions to touch the same offset, and the
+//! replacement can use the amounts that were matched.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use std::collections::HashMap;
+use std::num::Wrapping;
+
+use itertools::Itertools;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Combine, Position};
+
+#[cfg(test)]
+use crate::bfir::parse;
+
+/// A value in a rule.
+#[derive(Debug, Clone, Copy)]
+pub enum Term {
+    /// In a pattern, binds the value on first use and must match the
+    /// same value on later uses. In a replacement, the bound value.
+    Var(&'static str),
+    /// A literal value.
+    Lit(isize),
+    /// Matches any value without binding it. Only valid in patterns.
+    Any,
+    /// The sum of two bound values. Only valid in replacements.
+    Sum(&'static str, &'static str),
+}
+
+/// An instruction in a rule, either to match or to produce.
+#[derive(Debug, Clone, Copy)]
+pub enum Template {
+    Increment { amount: Term, offset: Term },
+    Set { amount: Term, offset: Term },
+    PointerIncrement { amount: Term },
+}
+
+/// Replace adjacent instructions matching `pattern` with
+/// `replacement`. The replacement must be shorter than the pattern,
+/// so rewriting always terminates.
+pub struct Rule {
+    pub pattern: &'static [Template],
+    pub replacement: &'static [Template],
+}
+
+type Bindings = HashMap<&'static str, isize>;
+
+/// Apply `rules` to `instrs` until no rule matches. This doesn't
+/// look inside loop bodies. Replacement instructions take the
+/// combined position of the instructions they replace.
+pub fn apply_rules(instrs: Vec<AstNode>, rules: &[Rule]) -> Vec<AstNode> {
+    debug_assert!(rules
+        .iter()
+        .all(|rule| rule.replacement.len() < rule.pattern.len()));
+
+    let mut result = vec![];
+    for instr in instrs {
+        result.push(instr);
+        rewrite_tail(&mut result, rules);
+    }
+    result
+}
+
+/// Rewrite the end of `instrs` until no rule matches there. Since
+/// we call this after every new instruction, any match ends at the
+/// last instruction.
+fn rewrite_tail(instrs: &mut Vec<AstNode>, rules: &[Rule]) {
+    'rewrite: loop {
+        for rule in rules {
+            if rule.pattern.len() > instrs.len() {
+                continue;
+            }
+
+            let start = instrs.len() - rule.pattern.len();
+            if let Some(bindings) = match_window(&instrs[start..], rule.pattern) {
+                let position = instrs[start..]
+                    .iter()
+                    .map(get_position)
+                    .fold1(|pos1, pos2| pos1.combine(pos2))
+                    .unwrap();
+
+                instrs.truncate(start);
+                instrs.extend(
+                    rule.replacement
+                        .iter()
+                        .map(|template| instantiate(template, &bindings, position)),
+                );
+                continue 'rewrite;
+            }
+        }
+        break;
+    }
+}
+
+fn match_window(instrs: &[AstNode], pattern: &[Template]) -> Option<Bindings> {
+    let mut bindings = HashMap::new();
+
+    for (instr, template) in instrs.iter().zip(pattern) {
+        let matched = match (*template, instr) {
+            (
+                Template::Increment {
+                    amount: amount_term,
+                    offset: offset_term,
+                },
+                &Increment { amount, offset, .. },
+            )
+            | (
+                Template::Set {
+                    amount: amount_term,
+                    offset: offset_term,
+                },
+                &Set { amount, offset, .. },
+            ) => {
+                bind(amount_term, amount.0 as isize, &mut bindings)
+                    && bind(offset_term, offset, &mut bindings)
+            }
+            (
+                Template::PointerIncrement {
+                    amount: amount_term,
+                },
+                &PointerIncrement { amount, .. },
+            ) => bind(amount_term, amount, &mut bindings),
+            _ => false,
+        };
+
+        if !matched {
+            return None;
+        }
+    }
+
+    Some(bindings)
+}
+
+/// Does `term` match `value`? Binds `value` if `term` is a new
+/// variable.
+fn bind(term: Term, value: isize, bindings: &mut Bindings) -> bool {
+    match term {
+        Term::Var(name) => *bindings.entry(name).or_insert(value) == value,
+        Term::Lit(lit) => lit == value,
+        Term::Any => true,
+        Term::Sum(..) => panic!("Sum can only be used in replacements"),
+    }
+}
+
+fn eval(term: Term, bindings: &Bindings) -> isize {
+    let lookup = |name| match bindings.get(name) {
+        Some(value) => *value,
+        None => panic!("Variable {} is not bound by the pattern", name),
+    };
+
+    match term {
+        Term::Var(name) => lookup(name),
+        Term::Lit(lit) => lit,
+        Term::Sum(name1, name2) => lookup(name1) + lookup(name2),
+        Term::Any => panic!("Any can only be used in patterns"),
+    }
+}
+
+fn instantiate(template: &Template, bindings: &Bindings, position: Option<Position>) -> AstNode {
+    match *template {
+        Template::Increment { amount, offset } => Increment {
+            amount: Wrapping(eval(amount, bindings) as i32),
+            offset: eval(offset, bindings),
+            position,
+        },
+        Template::Set { amount, offset } => Set {
+            amount: Wrapping(eval(amount, bindings) as i32),
+            offset: eval(offset, bindings),
+            position,
+        },
+        Template::PointerIncrement { amount } => PointerIncrement {
+            amount: eval(amount, bindings),
+            position,
+        },
+    }
+}
+
+#[cfg(test)]
+const COMBINE_TWO_INCREMENTS: &[Rule] = &[Rule {
+    pattern: &[
+        Template::Increment {
+            amount: Term::Var("x"),
+            offset: Term::Var("o"),
+        },
+        Template::Increment {
+            amount: Term::Var("y"),
+            offset: Term::Var("o"),
+        },
+    ],
+    replacement: &[Template::Increment {
+        amount: Term::Sum("x", "y"),
+        offset: Term::Var("o"),
+    }],
+}];
+
+#[test]
+fn apply_rules_combines_chains() {
+    let instrs = parse("+++").unwrap();
+    let expected = vec![Increment {
+        amount: Wrapping(3),
+        offset: 0,
+        position: Some(Position { start: 0, end: 2 }),
+    }];
+    assert_eq!(apply_rules(instrs, COMBINE_TWO_INCREMENTS), expected);
+}
+
+#[test]
+fn apply_rules_requires_variables_to_match() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: None,
+        },
+    ];
+    assert_eq!(apply_rules(instrs.clone(), COMBINE_TWO_INCREMENTS), instrs);
+}
+
+#[test]
+fn apply_rules_wraps_amounts() {
+    // Amounts wrap at 32 bits. optimize wraps them to the cell width
+    // after each pass.
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(i32::MAX),
+            offset: 0,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: None,
+        },
+    ];
+    let expected = vec![Increment {
+        amount: Wrapping(i32::MIN + 1),
+        offset: 0,
+        position: None,
+    }];
+    assert_eq!(apply_rules(instrs, COMBINE_TWO_INCREMENTS), expected);
+}
@@ -1,8 +1,11 @@
-use quickcheck::{quickcheck, TestResult};
+use std::num::Wrapping;
 
-use crate::bfir::AstNode;
+use quickcheck::{quickcheck, Arbitrary, Gen, TestResult};
+
+use crate::bfir::{parse, AstNode, Bounds, Eof};
+use crate::bounds::DEFAULT_TAPE_SIZE;
 use crate::execution::Outcome::*;
-use crate::execution::{execute_with_state, ExecutionState};
+use crate::execution::{execute_with_state, ExecutionState, Outcome};
 use crate::peephole::*;
 
 fn transform_is_sound<F>(
@@ -17,8 +20,8 @@ where
     let max_steps = 1000;
 
     // First, we execute the program given.
-    let mut state = ExecutionState::initial(&instrs[..]);
-    let result = execute_with_state(&instrs[..], &mut state, max_steps, dummy_read_value);
+    let mut state = ExecutionState::initial(&instrs[..], DEFAULT_TAPE_SIZE);
+    let result = execute_with_state(&instrs[..], &mut state, max_steps, None, dummy_read_value);
 
     // Optimisations may change malformed programs to well-formed
     // programs, so we ignore programs that don't terminate nicely.
@@ -33,11 +36,12 @@ where
     // get the same number of cells. Otherwise we could get in messy
     // situations where a dead loop that makes us think we use
     // MAX_CELLS so state2 has fewer cells.
-    let mut state2 = ExecutionState::initial(&instrs[..]);
+    let mut state2 = ExecutionState::initial(&instrs[..], DEFAULT_TAPE_SIZE);
     let result2 = execute_with_state(
         &optimised_instrs[..],
         &mut state2,
         max_steps,
+        None,
         dummy_read_value,
     );
 
@@ -47,6 +51,7 @@ where
         // completed too. We allow them to take a different amount of
         // steps.
         (Completed(_), Completed(_)) => (),
+        (Halted, Halted) => (),
         (ReachedRuntimeValue, ReachedRuntimeValue) => (),
         // Any other situation means that the first program terminated
         // but the optimised program did not.
@@ -94,6 +99,14 @@ fn combine_ptr_increments_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn combine_separated_increments_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, combine_separated_increments, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn annotate_known_zero_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -110,6 +123,14 @@ fn extract_multiply_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn combine_multiply_chains_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, combine_multiply_chains, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn simplify_loops_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -126,6 +147,22 @@ fn combine_set_and_increments_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn propagate_known_values_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
+        transform_is_sound(instrs, propagate_known_values, true, read_value)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
+}
+
+#[test]
+fn combine_const_writes_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
+        transform_is_sound(instrs, combine_const_writes, true, read_value)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
+}
+
 #[test]
 fn remove_dead_loops_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -134,6 +171,67 @@ fn remove_dead_loops_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn remove_initial_zeroing_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
+        transform_is_sound(instrs, remove_initial_zeroing, true, read_value)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
+}
+
+#[test]
+fn fuse_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, fuse_loops, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn remove_code_after_infinite_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(
+            instrs,
+            |instrs| remove_code_after_infinite_loops(instrs).0,
+            true,
+            None,
+        )
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn unroll_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, |instrs| unroll_loops(instrs, 100, None), true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn inline_counter_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, inline_counter_loops, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn recognize_ifs_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, recognize_ifs, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn recognize_scans_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, recognize_scans, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn remove_redundant_sets_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -154,6 +252,21 @@ fn combine_before_read_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
 }
 
+#[test]
+fn remove_dead_reads_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>, read_value: i8) -> TestResult {
+        // Removing a read changes which input later reads see, so
+        // this is only sound when every read gets the same value.
+        transform_is_sound(
+            instrs,
+            |instrs| remove_dead_reads(instrs, true, Eof::MinusOne).0,
+            true,
+            Some(read_value),
+        )
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>, i8) -> TestResult)
+}
+
 #[test]
 fn remove_pure_code_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -173,10 +286,18 @@ fn sort_by_offset_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn combine_set_ranges_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, combine_set_ranges, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn test_overall_optimize_is_sound() {
     fn optimize_ignore_warnings(instrs: Vec<AstNode>) -> Vec<AstNode> {
-        optimize(instrs, &None).0
+        optimize(instrs, &OptimizeOptions::default()).0
     }
 
     fn optimizations_sound_together(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
@@ -188,3 +309,73 @@ fn test_overall_optimize_is_sound() {
 
     quickcheck(optimizations_sound_together as fn(Vec<AstNode>, Option<i8>) -> TestResult);
 }
+
+/// A BF program with balanced brackets, as source. We generate source
+/// rather than IR so every instruction is one BF command, as it is
+/// when bfc compiles without optimisations.
+#[derive(Debug, Clone)]
+struct BfSource(String);
+
+impl Arbitrary for BfSource {
+    fn arbitrary<G: Gen>(g: &mut G) -> BfSource {
+        let len = g.size();
+        let mut source = String::new();
+        let mut depth = 0;
+        for _ in 0..len {
+            let c = b"+-<>[].,"[(g.next_u32() % 8) as usize] as char;
+            match c {
+                '[' => depth += 1,
+                ']' if depth == 0 => continue,
+                ']' => depth -= 1,
+                _ => {}
+            }
+            source.push(c);
+        }
+        for _ in 0..depth {
+            source.push(']');
+        }
+        BfSource(source)
+    }
+}
+
+#[test]
+fn optimize_keeps_bounds_aborts() {
+    const TAPE_SIZE: usize = 4;
+
+    fn run(instrs: &[AstNode], max_steps: u64) -> (Outcome, Vec<i8>) {
+        let mut state = ExecutionState::initial_with_bounds(instrs, TAPE_SIZE, Bounds::Abort);
+        state.cells = vec![Wrapping(0); TAPE_SIZE];
+        let outcome = execute_with_state(instrs, &mut state, max_steps, None, Some(1));
+        (outcome, state.outputs)
+    }
+
+    fn same_aborts(source: BfSource) -> TestResult {
+        let instrs = parse(&source.0).unwrap();
+        let (outcome, outputs) = run(&instrs, 1000);
+        if matches!(outcome, OutOfSteps) {
+            return TestResult::discard();
+        }
+
+        let options = OptimizeOptions {
+            bounds: Bounds::Abort,
+            ..OptimizeOptions::default()
+        };
+        let optimised = optimize(instrs, &options).0;
+        let (optimised_outcome, optimised_outputs) = run(&optimised, 10_000);
+
+        let same_outcome = matches!(
+            (&outcome, &optimised_outcome),
+            (RuntimeError(_), RuntimeError(_)) | (Completed(_), Completed(_)) | (Halted, Halted)
+        );
+        if !same_outcome || outputs != optimised_outputs {
+            println!(
+                "{}: {:?} {:?}, optimised {:?} {:?}",
+                source.0, outcome, outputs, optimised_outcome, optimised_outputs
+            );
+            return TestResult::failed();
+        }
+        TestResult::passed()
+    }
+
+    quickcheck(same_aborts as fn(BfSource) -> TestResult);
+}
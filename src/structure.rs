@@ -0,0 +1,102 @@
+//! Render the loop nesting of a program as a GraphViz graph, so
+//! users can see the shape of large programs at a glance.
+//!
+//! There's one node for the program and one for each Loop or If,
+//! labelled with its source position and how many instructions it
+//! contains.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use std::fmt::Write as _;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{fold_instructions, walk_instrs, AstNode, Position, Visitor};
+
+#[cfg(test)]
+use crate::bfir::parse;
+
+/// The number of instructions in `instrs`, including nested bodies.
+fn total_instrs(instrs: &[AstNode]) -> usize {
+    fold_instructions(instrs, 0, &mut |count, _| count + 1)
+}
+
+/// Builds the DOT graph, one node per body.
+struct DotVisitor {
+    dot: String,
+    next_id: usize,
+    /// The node IDs of the bodies we're currently inside.
+    parents: Vec<usize>,
+}
+
+impl DotVisitor {
+    /// Add a node for `body` to the graph, returning its ID.
+    fn add_node(&mut self, name: &str, position: Option<Position>, body: &[AstNode]) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let position_text = match position {
+            Some(position) => format!(" {:?}", position),
+            None => String::new(),
+        };
+        let _ = writeln!(
+            self.dot,
+            "    n{} [label=\"{}{}\\n{} instrs ({} total)\"];",
+            id,
+            name,
+            position_text,
+            body.len(),
+            total_instrs(body)
+        );
+        id
+    }
+}
+
+impl Visitor for DotVisitor {
+    fn visit_body(&mut self, parent: &AstNode, body: &[AstNode]) {
+        let (name, position) = match *parent {
+            Loop { position, .. } => ("Loop", position),
+            If { position, .. } => ("If", position),
+            _ => unreachable!(),
+        };
+        let id = self.add_node(name, position, body);
+
+        self.parents.push(id);
+        walk_instrs(self, body);
+        self.parents.pop();
+
+        let parent_id = *self.parents.last().unwrap();
+        let _ = writeln!(self.dot, "    n{} -> n{};", parent_id, id);
+    }
+}
+
+/// Convert the loop nesting of `instrs` to a DOT graph.
+pub fn to_dot(instrs: &[AstNode]) -> String {
+    let mut visitor = DotVisitor {
+        dot: "digraph structure {\n    node [shape=box];\n".to_owned(),
+        next_id: 0,
+        parents: vec![],
+    };
+    let program_id = visitor.add_node("Program", None, instrs);
+
+    visitor.parents.push(program_id);
+    walk_instrs(&mut visitor, instrs);
+
+    visitor.dot.push_str("}\n");
+    visitor.dot
+}
+
+#[test]
+fn dot_nested_loops() {
+    let instrs = parse("+[>[-]<-].").unwrap();
+    assert_eq!(
+        to_dot(&instrs),
+        "digraph structure {\n    node [shape=box];\n    \
+         n0 [label=\"Program\\n3 instrs (8 total)\"];\n    \
+         n1 [label=\"Loop 1-8\\n4 instrs (5 total)\"];\n    \
+         n2 [label=\"Loop 3-5\\n1 instrs (1 total)\"];\n    \
+         n1 -> n2;\n    \
+         n0 -> n1;\n\
+         }\n"
+    );
+}
@@ -0,0 +1,153 @@
+//! Trivial substitution dialects of BF, which spell each of the
+//! eight commands with a different string. With `--tokens=FILE`, we
+//! read the spellings from FILE and translate the source to BF
+//! before parsing it.
+//!
+//! The file has one line per command: the BF command, whitespace,
+//! then the string that stands for it, such as `+ Ook. Ook.`. Blank
+//! lines are ignored.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use crate::bfir::{self, AstNode, ParseError};
+
+#[cfg(test)]
+use crate::bfir::Position;
+
+const COMMANDS: &str = "+-<>,.[]";
+
+/// The string that stands for each BF command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenMap {
+    /// Each token and its command, longest token first, so we
+    /// prefer the longest match.
+    tokens: Vec<(String, char)>,
+}
+
+impl TokenMap {
+    /// Parse a token file, which must give a token for each of the
+    /// eight commands.
+    pub fn parse(text: &str) -> Result<TokenMap, String> {
+        let mut tokens: Vec<(String, char)> = vec![];
+
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid_line = || format!("Invalid token line {}: {}", line_index + 1, line);
+
+            let mut chars = line.chars();
+            let command = chars.next().unwrap();
+            let token = chars.as_str().trim();
+            if !COMMANDS.contains(command) || token.is_empty() {
+                return Err(invalid_line());
+            }
+            if tokens.iter().any(|&(_, existing)| existing == command) {
+                return Err(format!(
+                    "Token line {} gives a second token for {}",
+                    line_index + 1,
+                    command
+                ));
+            }
+            if tokens.iter().any(|(existing, _)| existing == token) {
+                return Err(format!(
+                    "Token line {} reuses the token '{}'",
+                    line_index + 1,
+                    token
+                ));
+            }
+            tokens.push((token.to_owned(), command));
+        }
+
+        if let Some(missing) = COMMANDS
+            .chars()
+            .find(|&command| !tokens.iter().any(|&(_, existing)| existing == command))
+        {
+            return Err(format!("No token given for {}", missing));
+        }
+
+        tokens.sort_by_key(|(token, _)| std::cmp::Reverse(token.chars().count()));
+        Ok(TokenMap { tokens })
+    }
+
+    /// Parse `source`, written with these tokens, to our BF IR.
+    /// Anything that isn't a token is a comment.
+    pub fn parse_source(&self, source: &str) -> Result<Vec<AstNode>, Vec<ParseError>> {
+        let mut bf = String::new();
+        let mut positions = vec![];
+
+        // Char index and byte offset of where we are in `source`.
+        let mut index = 0;
+        let mut offset = 0;
+        while offset < source.len() {
+            let rest = &source[offset..];
+            match self
+                .tokens
+                .iter()
+                .find(|(token, _)| rest.starts_with(token.as_str()))
+            {
+                Some((token, command)) => {
+                    bf.push(*command);
+                    positions.push(index);
+                    index += token.chars().count();
+                    offset += token.len();
+                }
+                None => {
+                    index += 1;
+                    offset += rest.chars().next().unwrap().len_utf8();
+                }
+            }
+        }
+
+        bfir::parse_expanded(&bf, &positions, false)
+    }
+}
+
+#[cfg(test)]
+fn word_tokens() -> TokenMap {
+    TokenMap::parse("+ inc\n- dec\n> right\n< left\n\n, in\n. out\n[ loop\n] end\n").unwrap()
+}
+
+#[test]
+fn parse_with_tokens() {
+    assert_eq!(
+        word_tokens()
+            .parse_source("inc loop dec right end")
+            .unwrap(),
+        bfir::parse_expanded("+[->]", &[0, 4, 9, 13, 19], false).unwrap()
+    );
+}
+
+#[test]
+fn parse_prefers_longest_token() {
+    // "in" is a prefix of "inc".
+    assert_eq!(
+        word_tokens().parse_source("incin").unwrap(),
+        bfir::parse_expanded("+,", &[0, 3], false).unwrap()
+    );
+}
+
+#[test]
+fn parse_with_tokens_unbalanced() {
+    let errors = word_tokens().parse_source("é loop").unwrap_err();
+    assert_eq!(errors[0].message, "This [ has no matching ]");
+    assert_eq!(errors[0].position, Position { start: 2, end: 2 });
+}
+
+#[test]
+fn token_map_missing_command() {
+    assert_eq!(
+        TokenMap::parse("+ a\n- b\n> c\n< d\n, e\n. f\n[ g\n"),
+        Err("No token given for ]".to_owned())
+    );
+}
+
+#[test]
+fn token_map_invalid_lines() {
+    assert!(TokenMap::parse("x foo").is_err());
+    assert!(TokenMap::parse("+").is_err());
+    assert!(TokenMap::parse("+ a\n+ b").is_err());
+    assert!(TokenMap::parse("+ a\n- a").is_err());
+}
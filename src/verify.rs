@@ -0,0 +1,204 @@
+//! Translation validation for the optimiser: run the original and
+//! optimised programs in the interpreter on the same inputs, and
+//! check that they write the same output.
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+use crate::bfir::{AstNode, Bounds, CellWidth, Eof, Overflow};
+use crate::bounds::DEFAULT_TAPE_SIZE;
+use crate::execution::{execute_with_input, max_steps, ExecutionState, Outcome};
+
+#[cfg(test)]
+use crate::bfir::parse;
+#[cfg(test)]
+use crate::peephole::{optimize, OptimizeOptions};
+
+/// How many inputs we generate when the user doesn't provide any,
+/// in addition to the empty input.
+const GENERATED_INPUTS: usize = 8;
+
+/// The number of printable characters in each generated input,
+/// before the trailing newline.
+const GENERATED_INPUT_LEN: usize = 16;
+
+/// Inputs to check when the user doesn't provide any: the empty
+/// input, and some lines of printable characters. We use a fixed
+/// seed, so verification is reproducible.
+pub fn generated_inputs() -> Vec<Vec<u8>> {
+    let mut inputs = vec![vec![]];
+
+    let mut seed: u32 = 1;
+    for _ in 0..GENERATED_INPUTS {
+        let mut input: Vec<u8> = (0..GENERATED_INPUT_LEN)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b' ' + ((seed >> 16) % 95) as u8
+            })
+            .collect();
+        input.push(b'\n');
+        inputs.push(input);
+    }
+    inputs
+}
+
+fn as_text(bytes: &[i8]) -> String {
+    let bytes: Vec<u8> = bytes.iter().map(|byte| *byte as u8).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// How the programs we compare run.
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// We skip inputs where the original program doesn't finish
+    /// within this many steps.
+    pub steps: u64,
+    pub cell_width: CellWidth,
+    pub overflow: Overflow,
+    pub bounds: Bounds,
+    pub tape_size: usize,
+    /// What reads store at EOF.
+    pub eof: Eof,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        VerifyOptions {
+            steps: max_steps(),
+            cell_width: CellWidth::default(),
+            overflow: Overflow::default(),
+            bounds: Bounds::default(),
+            tape_size: DEFAULT_TAPE_SIZE,
+            eof: Eof::default(),
+        }
+    }
+}
+
+/// Run `original` and `optimised` on every input, as `options` says,
+/// and return an error describing the first input where they behave
+/// differently. Otherwise, return the number of inputs we checked.
+///
+/// We skip inputs where the original program doesn't finish in time
+/// or hits a runtime error, since the optimiser may change the
+/// behaviour of malformed programs.
+pub fn verify_optimisation(
+    original: &[AstNode],
+    optimised: &[AstNode],
+    inputs: &[Vec<u8>],
+    options: &VerifyOptions,
+) -> Result<usize, String> {
+    let mut checked = 0;
+
+    for input in inputs {
+        let mut state =
+            ExecutionState::initial_with_bounds(original, options.tape_size, options.bounds);
+        state.cell_width = options.cell_width;
+        state.overflow = options.overflow;
+        if !matches!(
+            execute_with_input(original, &mut state, options.steps, input, options.eof),
+            Outcome::Completed(_) | Outcome::Halted
+        ) {
+            continue;
+        }
+
+        // Deliberately start from the original program's cells, so
+        // both programs have the same number of cells.
+        let mut optimised_state =
+            ExecutionState::initial_with_bounds(original, options.tape_size, options.bounds);
+        optimised_state.cell_width = options.cell_width;
+        optimised_state.overflow = options.overflow;
+        let outcome = execute_with_input(
+            optimised,
+            &mut optimised_state,
+            options.steps,
+            input,
+            options.eof,
+        );
+
+        if !matches!(outcome, Outcome::Completed(_) | Outcome::Halted) {
+            return Err(format!(
+                "The optimised program did not finish on input {:?}, but the original did.",
+                String::from_utf8_lossy(input)
+            ));
+        }
+        if state.outputs != optimised_state.outputs {
+            return Err(format!(
+                "The optimised program wrote different output on input {:?}.\n\
+                 Original:  {:?}\nOptimised: {:?}",
+                String::from_utf8_lossy(input),
+                as_text(&state.outputs),
+                as_text(&optimised_state.outputs)
+            ));
+        }
+
+        checked += 1;
+    }
+
+    Ok(checked)
+}
+
+#[test]
+fn generated_inputs_are_reproducible() {
+    let inputs = generated_inputs();
+    assert_eq!(inputs.len(), GENERATED_INPUTS + 1);
+    assert_eq!(inputs[0], Vec::<u8>::new());
+    assert_eq!(inputs, generated_inputs());
+}
+
+#[test]
+fn verify_optimised_program() {
+    let original = parse(",+[-.,+]++++++++[>++++++++<-]>+.").unwrap();
+    let (optimised, _) = optimize(original.clone(), &OptimizeOptions::default());
+
+    assert_eq!(
+        verify_optimisation(
+            &original,
+            &optimised,
+            &generated_inputs(),
+            &VerifyOptions {
+                steps: 10_000,
+                ..VerifyOptions::default()
+            },
+        ),
+        Ok(GENERATED_INPUTS + 1)
+    );
+}
+
+#[test]
+fn verify_detects_different_output() {
+    let original = parse(",+.").unwrap();
+    let miscompiled = parse(",.").unwrap();
+
+    let result = verify_optimisation(
+        &original,
+        &miscompiled,
+        &[b"a".to_vec()],
+        &VerifyOptions {
+            steps: 100,
+            ..VerifyOptions::default()
+        },
+    );
+    let expected = "The optimised program wrote different output on input \"a\".\n\
+                    Original:  \"b\"\nOptimised: \"a\"";
+    assert_eq!(result, Err(expected.to_owned()));
+}
+
+#[test]
+fn verify_skips_inputs_original_cannot_finish() {
+    // With no input, the read gives -1 and the loop never ends.
+    let original = parse(",[.]").unwrap();
+    let optimised = vec![];
+
+    assert_eq!(
+        verify_optimisation(
+            &original,
+            &optimised,
+            &[vec![]],
+            &VerifyOptions {
+                steps: 100,
+                ..VerifyOptions::default()
+            },
+        ),
+        Ok(0)
+    );
+}